@@ -0,0 +1,34 @@
+//! Split an archive with the library, leaving out the entries with
+//! a suffix; more flags, like `--si`, may follow:
+//!
+//!     cargo run --example split_filtered -- <input.tar> <prefix> <max size> <suffix> [flags]
+
+use std::path::PathBuf;
+
+fn main() -> splitar::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [input, prefix, max_size, suffix, flags @ ..] = args.as_slice() else {
+        eprintln!("usage: split_filtered <input.tar> <prefix> <max size> <suffix> [flags]");
+        std::process::exit(2);
+    };
+    let mut builder = splitar::Splitter::builder(prefix.as_str())
+        .option("--max-size", max_size)
+        .recreate_dirs();
+    for flag in flags {
+        builder = builder.flag(flag);
+    }
+    let mut splitter = builder.build()?;
+    let mut archive = tar::Archive::new(std::fs::File::open(PathBuf::from(input))?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.path_bytes().ends_with(suffix.as_bytes()) {
+            splitter.push_entry(entry)?;
+        }
+    }
+    let summary = splitter.finish()?;
+    for volume in &summary.volumes {
+        println!("{}", volume.display());
+    }
+    println!("{} entries, {} bytes", summary.entries, summary.bytes);
+    Ok(())
+}
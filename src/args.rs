@@ -0,0 +1,2055 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Command line options of the split, and their parsing.
+
+use crate::{
+    checksum::ChecksumAlgorithm,
+    compress::{compressor_extension, decompress_command, volume_extension},
+    header::{is_dir_header, strip_components},
+    padded_size, shell,
+    volume::{shell_word, volume_info},
+    MIN_VOLUME_SIZE, TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use clap::Parser;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Environment variable with the --max-size to use if it is not given.
+const MAX_SIZE_ENV: &str = "SPLITAR_MAX_SIZE";
+
+// Simple wrapper for binary one-letter units (like 300G).
+fn clap_parse_size(src: &str) -> std::result::Result<u64, parse_size::Error> {
+    parse_size(src, false)
+}
+
+/// Sizes are binary, unless --si is given.
+fn parse_size(src: &str, si: bool) -> std::result::Result<u64, parse_size::Error> {
+    match si {
+        true => parse_size::Config::new().parse_size(src),
+        false => parse_size::Config::new().with_binary().parse_size(src),
+    }
+}
+
+// Octal permission bits, like chmod's.
+fn clap_parse_mode(src: &str) -> ah::Result<u32> {
+    let mode = u32::from_str_radix(src.strip_prefix("0o").unwrap_or(src), 8)
+        .with_context(|| format!("{:?} is not an octal mode", src))?;
+    ah::ensure!(mode <= 0o7777, "mode {:o} is over 7777", mode);
+    Ok(mode)
+}
+
+/// Max volume sizes by volume index, from --size-schedule.
+#[derive(Clone, Debug)]
+struct SizeSchedule {
+    sizes: HashMap<usize, u64>,
+    default: u64,
+}
+
+impl SizeSchedule {
+    fn get(&self, vol_idx: usize) -> u64 {
+        self.sizes.get(&vol_idx).copied().unwrap_or(self.default)
+    }
+
+    fn all_sizes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sizes.values().copied().chain([self.default])
+    }
+}
+
+fn clap_parse_size_schedule(src: &str) -> ah::Result<SizeSchedule> {
+    parse_size_schedule(src, false)
+}
+
+// Comma-separated "<volume index>:<size>" pairs and a "*:<size>" default.
+fn parse_size_schedule(src: &str, si: bool) -> ah::Result<SizeSchedule> {
+    let mut sizes = HashMap::new();
+    let mut default = None;
+    for item in src.split(',') {
+        let (index, size) = item
+            .split_once(':')
+            .with_context(|| format!("{:?} is not in \"<index>:<size>\" form", item))?;
+        let size = parse_size(size, si).with_context(|| format!("invalid size in {:?}", item))?;
+        let duplicate = if index == "*" {
+            default.replace(size).is_some()
+        } else {
+            let index = index
+                .parse()
+                .with_context(|| format!("invalid volume index in {:?}", item))?;
+            sizes.insert(index, size).is_some()
+        };
+        ah::ensure!(!duplicate, "volume index {:?} is given twice", index);
+    }
+    let default = default.context("`*:<size>` is required for the rest of volumes")?;
+    Ok(SizeSchedule { sizes, default })
+}
+
+/// A --name-template piece.
+#[derive(Clone, Debug)]
+enum NamePart {
+    Literal(String),
+    Prefix,
+    // Zero-padded to this width, or to --suffix-length.
+    Index(Option<usize>),
+    Ext,
+}
+
+/// Volume file names from --name-template.
+#[derive(Clone, Debug)]
+pub(crate) struct NameTemplate {
+    parts: Vec<NamePart>,
+}
+
+impl NameTemplate {
+    const DEFAULT: &'static str = "{prefix}{index}";
+
+    fn uses_ext(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, NamePart::Ext))
+    }
+
+    /// `index` is `None` for the --dirs-first volume.
+    pub(crate) fn render(&self, args: &Args, stream: &str, index: Option<usize>) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                NamePart::Literal(text) => name += text,
+                NamePart::Prefix => name += &args.output_prefix,
+                NamePart::Index(width) => match index {
+                    Some(index) => {
+                        if !stream.is_empty() {
+                            name += stream;
+                            name += "-";
+                        }
+                        let width = width.unwrap_or(args.suffix_length as _);
+                        name += &format!("{index:0>width$}");
+                    }
+                    None => name += "dirs",
+                },
+                // Checked by Args::validate.
+                NamePart::Ext => name += volume_extension(args).as_deref().unwrap_or("tar"),
+            }
+        }
+        name
+    }
+}
+
+// Placeholders in braces, `{{` and `}}` for literal braces.
+fn clap_parse_name_template(src: &str) -> ah::Result<NameTemplate> {
+    let mut parts = vec![];
+    let mut literal = String::new();
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '}' => ah::bail!("unmatched `}}`; write `}}}}` for a literal brace"),
+            '{' => {
+                let (placeholder, rest) = chars
+                    .as_str()
+                    .split_once('}')
+                    .context("unterminated `{`; write `{{` for a literal brace")?;
+                chars = rest.chars();
+                let part = match placeholder.split_once(':') {
+                    None if placeholder == "prefix" => NamePart::Prefix,
+                    None if placeholder == "index" => NamePart::Index(None),
+                    None if placeholder == "ext" => NamePart::Ext,
+                    Some(("index", width)) => {
+                        let padded = width.len() > 1
+                            && width.starts_with('0')
+                            && width.bytes().all(|c| c.is_ascii_digit());
+                        ah::ensure!(
+                            padded,
+                            "invalid `{{{}}}`: the index width is written like `{{index:05}}`",
+                            placeholder
+                        );
+                        NamePart::Index(Some(width.parse()?))
+                    }
+                    _ => ah::bail!(
+                        "unknown placeholder `{{{}}}`; known are `{{prefix}}`, `{{index}}`, \
+                         `{{index:0N}}` and `{{ext}}`",
+                        placeholder
+                    ),
+                };
+                if !literal.is_empty() {
+                    parts.push(NamePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(part);
+            }
+            _ => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(NamePart::Literal(literal));
+    }
+    ah::ensure!(
+        parts.iter().any(|part| matches!(part, NamePart::Index(_))),
+        "no `{{index}}` placeholder, so all volumes would have the same name"
+    );
+    Ok(NameTemplate { parts })
+}
+
+/// A --transform expression, `s|<old>|<new>|` with any delimiter: the
+/// first occurrence of `old` in a path is replaced with `new`, or only the
+/// one at the start with `^<old>`.  Unlike with tar, `old` is a literal.
+#[derive(Clone, Debug)]
+struct Transform {
+    old: Vec<u8>,
+    new: Vec<u8>,
+    anchored: bool,
+}
+
+impl Transform {
+    /// The path with the replacement, if it matches.
+    fn apply(&self, path: &[u8]) -> Option<Vec<u8>> {
+        let pos = match self.anchored {
+            true => path.starts_with(&self.old).then_some(0)?,
+            false => path
+                .windows(self.old.len())
+                .position(|window| window == self.old)?,
+        };
+        let mut renamed = path[..pos].to_vec();
+        renamed.extend_from_slice(&self.new);
+        renamed.extend_from_slice(&path[pos + self.old.len()..]);
+        Some(renamed)
+    }
+}
+
+fn clap_parse_transform(src: &str) -> ah::Result<Transform> {
+    let expression = src
+        .strip_prefix('s')
+        .context("expecting \"s|<old>|<new>|\"")?;
+    let delimiter = expression
+        .chars()
+        .next()
+        .context("expecting \"s|<old>|<new>|\"")?;
+    let parts: Vec<_> = expression[delimiter.len_utf8()..]
+        .split(delimiter)
+        .collect();
+    let [old, new, ""] = parts[..] else {
+        ah::bail!(
+            "expecting \"s{0}<old>{0}<new>{0}\" without flags or escapes",
+            delimiter
+        );
+    };
+    let (old, anchored) = match old.strip_prefix('^') {
+        Some(old) => (old, true),
+        None => (old, false),
+    };
+    ah::ensure!(
+        anchored || !old.is_empty(),
+        "nothing to replace in {:?}",
+        src
+    );
+    Ok(Transform {
+        old: old.as_bytes().to_vec(),
+        new: new.as_bytes().to_vec(),
+        anchored,
+    })
+}
+
+/// A category of entries for --route-by-extension.
+#[derive(Clone, Debug)]
+pub(crate) struct Route {
+    category: String,
+    // Lowercase, without the dot.
+    extensions: Vec<String>,
+}
+
+// "<category>=<ext>,<ext>..."
+fn clap_parse_route(src: &str) -> ah::Result<Route> {
+    let (category, extensions) = src
+        .split_once('=')
+        .context("expecting \"<category>=<extension>,...\"")?;
+    ah::ensure!(
+        !category.is_empty() && !category.contains('/'),
+        "category {:?} cannot be a volume name part",
+        category,
+    );
+    let extensions = extensions
+        .split(',')
+        .map(|ext| ext.trim_start_matches('.').to_lowercase())
+        .collect::<Vec<_>>();
+    ah::ensure!(
+        extensions.iter().all(|ext| !ext.is_empty()),
+        "empty extension in {:?}",
+        src,
+    );
+    Ok(Route {
+        category: category.to_owned(),
+        extensions,
+    })
+}
+
+/// The --route-by-extension category of the entry; directories always
+/// stay in the main sequence.
+pub(crate) fn route_for<'a>(
+    routes: &'a [Route],
+    header: &tar::Header,
+    path: &[u8],
+) -> Option<&'a str> {
+    if is_dir_header(header) {
+        return None;
+    }
+    let ext = path_extension(path)?;
+    routes
+        .iter()
+        .find(|route| route.extensions.contains(&ext))
+        .map(|route| route.category.as_str())
+}
+
+/// Lowercase extension of the path's file name; dotfiles have none.
+pub(crate) fn path_extension(path: &[u8]) -> Option<String> {
+    let name = path.rsplit(|&c| c == b'/').next().unwrap_or(path);
+    match name.iter().rposition(|&c| c == b'.') {
+        Some(pos) if pos > 0 => Some(String::from_utf8_lossy(&name[pos + 1..]).to_lowercase()),
+        _ => None,
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(
+    about,
+    version,
+    author,
+    after_help = "To reassemble the volumes, see `splitar join --help`; to check their \
+                  checksums, see `splitar verify --help`."
+)]
+pub(crate) struct Args {
+    #[arg(
+        short = 'S',
+        long,
+        value_parser(clap_parse_size),
+        required_unless_present_any = [
+            "num_volumes",
+            "layout",
+            "boundaries",
+            "size_schedule",
+            "max_files",
+        ],
+        conflicts_with = "num_volumes",
+        help = "max data size per output volume, from $SPLITAR_MAX_SIZE if not given; \
+                with --layout, it is checked, not enforced"
+    )]
+    pub(crate) max_size: Option<u64>,
+
+    #[arg(
+        long,
+        help = "decimal size units, e.g. 1G is 10^9 bytes instead of 2^30; KiB, MiB \
+                and so on are binary anyway"
+    )]
+    si: bool,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_size_schedule),
+        conflicts_with_all = ["max_size", "num_volumes"],
+        help = "max data size by volume index, like \"0:100M,*:4G\"; `*` is for \
+                volumes not listed and is required"
+    )]
+    size_schedule: Option<SizeSchedule>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SizeMetric::Archive,
+        help = "what counts towards --max-size; with payload, volumes may be larger \
+                than --max-size on disk"
+    )]
+    pub(crate) size_metric: SizeMetric,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["num_volumes", "layout", "boundaries"],
+        help = "max number of entries per output volume, not counting recreated dirs \
+                and --volume-note; can be combined with --max-size"
+    )]
+    pub(crate) max_files: Option<u64>,
+
+    #[arg(
+        short = 'n',
+        long,
+        visible_alias = "volumes",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "split into this number of balanced volumes (or less); \
+                the input has to be a regular file as it is read twice"
+    )]
+    pub(crate) num_volumes: Option<u64>,
+
+    #[arg(
+        long,
+        requires = "max_size",
+        conflicts_with_all = [
+            "num_volumes",
+            "layout",
+            "boundaries",
+            "files_from",
+            "include",
+            "exclude",
+            "max_files",
+            "multi_volume",
+            "recreate_dirs",
+            "route_by_extension",
+            "start_index",
+        ],
+        help = "spread the entries evenly over as few volumes as --max-size allows, \
+                instead of filling them one by one; the input has to be a regular \
+                file as it is read twice"
+    )]
+    pub(crate) pack: bool,
+
+    #[arg(
+        long,
+        requires = "num_volumes",
+        conflicts_with = "entries_manifest_in",
+        value_hint = clap::ValueHint::FilePath,
+        help = "save the entries scanned by --num-volumes to a file for \
+                --entries-manifest-in"
+    )]
+    pub(crate) entries_manifest_out: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "num_volumes",
+        value_hint = clap::ValueHint::FilePath,
+        help = "take the entries for --num-volumes from a file saved by \
+                --entries-manifest-out instead of scanning the input"
+    )]
+    pub(crate) entries_manifest_in: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "num_volumes",
+        value_hint = clap::ValueHint::DirPath,
+        help = "keep the entries scanned by --num-volumes beyond --memory-limit in \
+                a temp file in this dir"
+    )]
+    pub(crate) spill_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "spill_dir",
+        value_parser(clap_parse_size),
+        help = "memory for the scanned entries before they are spilled to --spill-dir, \
+                16 bytes an entry; 0 by default"
+    )]
+    pub(crate) memory_limit: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Balance::Bytes,
+        help = "what --num-volumes balances between volumes"
+    )]
+    pub(crate) balance: Balance,
+
+    #[arg(
+        long,
+        conflicts_with = "num_volumes",
+        value_hint = clap::ValueHint::FilePath,
+        help = "file with \"<volume index><TAB><path>\" lines that assign entries to volumes"
+    )]
+    pub(crate) layout: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["num_volumes", "layout", "files_from", "include", "exclude"],
+        value_hint = clap::ValueHint::FilePath,
+        help = "file with input byte offsets, one per line, at which volumes have to start; \
+                each has to be at an entry boundary"
+    )]
+    pub(crate) boundaries: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with = "num_volumes",
+        value_hint = clap::ValueHint::FilePath,
+        help = "split only the entries with paths listed in this file, one per line, \
+                or `-` for stdin; dirs are not recursed into"
+    )]
+    pub(crate) files_from: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        conflicts_with = "num_volumes",
+        help = "split only the entries with paths matching this glob; can be repeated"
+    )]
+    pub(crate) include: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        conflicts_with = "num_volumes",
+        help = "skip the entries with paths matching this glob, even if included; \
+                can be repeated"
+    )]
+    pub(crate) exclude: Vec<String>,
+
+    #[arg(long, help = "fail if a file is too large to fit into single volume")]
+    pub(crate) fail_on_large_file: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "fail_on_large_file",
+            "verbatim",
+            "num_volumes",
+            "layout",
+            "boundaries",
+            "compression_ratio",
+            "emit_extract_script",
+            "restore_script",
+            "total_size",
+        ],
+        help = "split files that don't fit into an empty volume across volumes, \
+                continuing them with `M` entries; the volumes are not GNU `tar -M` \
+                volumes, as the whole size is in a private `SPLITAR.multivolume.size` \
+                PAX record, and only `splitar join` puts the files back together"
+    )]
+    pub(crate) multi_volume: bool,
+
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        conflicts_with_all = ["num_volumes", "pack", "layout", "boundaries"],
+        help = "start a new volume early at an entry of a new dir at this depth, e.g. \
+                1 for top-level dirs, if less than --dir-min-space is left in the volume"
+    )]
+    pub(crate) keep_dirs_together: Option<usize>,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_size),
+        requires = "keep_dirs_together",
+        help = "space a volume needs left for a new dir with --keep-dirs-together; \
+                a quarter of the volume size by default"
+    )]
+    dir_min_space: Option<u64>,
+
+    #[arg(
+        long,
+        help = "fail with exit code 4 on an input with more entries than this, \
+                not counting extension members"
+    )]
+    pub(crate) max_entries_total: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "multi_volume",
+        help = "stop after this many volumes, not counting the --dirs-first one, and \
+                exit with code 5; the volumes are complete, but the rest of the input \
+                is not split"
+    )]
+    pub(crate) max_volumes: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_size),
+        conflicts_with_all = ["dirs_first", "route_by_extension", "dry_run"],
+        help = "stop before a volume that may take all the volumes over this size on \
+                disk, and exit with code 7; a volume counts as its max size until it \
+                is finished, and volumes are finished one by one to measure them"
+    )]
+    pub(crate) total_size: Option<u64>,
+
+    #[arg(
+        short = 'v',
+        long,
+        help = "output files info prefixed with volume number"
+    )]
+    pub(crate) verbose: bool,
+
+    #[arg(
+        long,
+        requires = "verbose",
+        help = "show --verbose mtimes in UTC as ISO 8601, like \
+                1970-01-01T00:00:00Z, instead of the local time"
+    )]
+    pub(crate) utc: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t = VerboseFormat::Pretty,
+        requires = "verbose",
+        conflicts_with_all = ["list_volumes", "dry_run"],
+        help = "--verbose listing format"
+    )]
+    pub(crate) verbose_format: VerboseFormat,
+
+    #[arg(
+        long,
+        help = "print \"creating file '<path>'\" to stdout for every written volume, \
+                like GNU split --verbose, for scripts that parse it"
+    )]
+    pub(crate) list_volumes: bool,
+
+    #[arg(
+        short = 'q',
+        long,
+        help = "log warnings and errors only, even if RUST_LOG asks for more"
+    )]
+    pub(crate) quiet: bool,
+
+    #[arg(
+        long,
+        help = "print the number of volumes and entries and the sizes read and \
+                written to stderr at the end"
+    )]
+    pub(crate) summary: bool,
+
+    #[arg(
+        long,
+        help = "print the summary to stdout at the end as one JSON object with volumes, \
+                entries, oversized, input_bytes, bytes (tar streams) and stored_bytes \
+                (volume files) keys"
+    )]
+    pub(crate) summary_json: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "list_volumes", "entries_manifest_out", "tape_command", "sign", "checksum",
+            "checksums", "emit_extract_script", "restore_script", "bundle_metadata",
+            "manifest", "resume",
+        ],
+        help = "write nothing, but print each volume the split would write to stdout \
+                as <volume>TAB<entries>TAB<size>; -v lists the entries too"
+    )]
+    pub(crate) dry_run: bool,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_route),
+        conflicts_with_all = ["num_volumes", "layout", "boundaries"],
+        help = "put entries with these extensions into a separate sequence of volumes \
+                named <prefix><category>-<index>, e.g. imgs=jpg,png; can be repeated"
+    )]
+    pub(crate) route_by_extension: Vec<Route>,
+
+    #[arg(short = 'd', long, help = "recreate dirs in new volumes")]
+    pub(crate) recreate_dirs: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        requires = "recreate_dirs",
+        default_value_t = RecreateDirsMode::Full,
+        help = "which dirs --recreate-dirs puts before an entry in a new volume"
+    )]
+    pub(crate) recreate_dirs_mode: RecreateDirsMode,
+
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        requires = "recreate_dirs",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "keep only dirs up to this depth for --recreate-dirs, e.g. 2 for a/b/, \
+                to bound the memory for huge trees; deeper dirs are not recreated"
+    )]
+    pub(crate) recreate_dirs_depth: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "recreate_dirs",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "keep at most this many dirs for --recreate-dirs; the later ones are \
+                not recreated"
+    )]
+    pub(crate) recreate_dirs_limit: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        requires = "recreate_dirs",
+        default_value_t = SizeCounts::All,
+        help = "what counts towards the max size of a volume with --recreate-dirs"
+    )]
+    pub(crate) size_counts: SizeCounts,
+
+    #[arg(
+        long,
+        conflicts_with = "recreate_dirs",
+        help = "put all dirs into a separate volume with \"dirs\" suffix instead of \
+                regular volumes, to be extracted first"
+    )]
+    pub(crate) dirs_first: bool,
+
+    #[arg(
+        long,
+        help = "copy entries as is, including GNU/PAX extension members; \
+                ignored when entries are rewritten or injected"
+    )]
+    verbatim: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SizeZeroPolicy::Fail,
+        help = "what to do with an entry that has zero size in its header, but is \
+                followed by data instead of the next header"
+    )]
+    pub(crate) on_size_zero: SizeZeroPolicy,
+
+    #[arg(
+        long,
+        conflicts_with = "copy_bad_headers",
+        help = "skip entries with malformed mode, owner, mtime or device header \
+                fields instead of failing on them"
+    )]
+    pub(crate) skip_bad_headers: bool,
+
+    #[arg(
+        long,
+        help = "copy entries with malformed mode, owner, mtime or device header \
+                fields with their header block as is, instead of failing on them"
+    )]
+    pub(crate) copy_bad_headers: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Tar,
+        help = "format of output volumes; zip loses some tar metadata"
+    )]
+    pub(crate) output_format: OutputFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "tar header format of the written entries, converting the input \
+                headers; by default, they are kept as they are"
+    )]
+    pub(crate) format: Option<TarFormat>,
+
+    #[arg(long, group = "compression")]
+    compress: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        group = "compression",
+        help = "compress volumes with this program run directly, without a shell, \
+                unlike --compress; portable to systems without a POSIX shell"
+    )]
+    compress_program: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        requires = "compress_program",
+        help = "an argument of --compress-program, e.g. -9; can be repeated"
+    )]
+    compress_arg: Vec<String>,
+
+    #[arg(
+        long,
+        groups = ["compression", "builtin_compression"],
+        help = "compress volumes with the built-in gzip, without a --compress subprocess"
+    )]
+    gzip: bool,
+
+    #[arg(
+        long,
+        groups = ["compression", "builtin_compression"],
+        help = "compress volumes with the built-in zstd, without a --compress subprocess"
+    )]
+    zstd: bool,
+
+    #[arg(
+        long,
+        groups = ["compression", "builtin_compression"],
+        help = "compress volumes with the built-in bzip2, without a --compress subprocess"
+    )]
+    bzip2: bool,
+
+    #[arg(
+        long,
+        requires = "builtin_compression",
+        value_parser = clap::value_parser!(u32).range(0..=22),
+        help = "compression level of --gzip, from 0 (store) to 9 (best), 6 by default; \
+                of --zstd, from 1 to 22, 3 by default; of --bzip2, from 1 to 9, the \
+                default"
+    )]
+    compress_level: Option<u32>,
+
+    #[arg(
+        long,
+        requires = "compression",
+        help = "expected compressed to uncompressed size ratio, e.g. 0.4; --max-size \
+                is applied to the estimated compressed size.  It is a heuristic, \
+                actual sizes vary"
+    )]
+    compression_ratio: Option<f64>,
+
+    #[arg(
+        long,
+        requires = "compress",
+        help = "capture stderr of the compress subprocess and report it on failure"
+    )]
+    pub(crate) capture_compress_stderr: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "keep_partial",
+        help = "run a failed --compress or --compress-program command again up to \
+                this many times; the volume is written uncompressed to a temp file \
+                first, and compressed from it when complete"
+    )]
+    pub(crate) compress_retries: u32,
+
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "comma-separated header fields to zero, e.g. uid,gid,uname,gname,mtime"
+    )]
+    pub(crate) scrub: Vec<ScrubField>,
+
+    #[arg(
+        long,
+        help = "clear the owner and group names, so that extraction goes by the ids, \
+                like tar --numeric-owner"
+    )]
+    pub(crate) numeric_owner: bool,
+
+    #[arg(long, value_name = "UID", help = "set the owner id of the entries")]
+    pub(crate) owner: Option<u64>,
+
+    #[arg(long, value_name = "GID", help = "set the group id of the entries")]
+    pub(crate) group: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "EPOCH",
+        help = "set the mtime of the entries, in seconds since the epoch"
+    )]
+    pub(crate) mtime: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["num_volumes", "pack"],
+        help = "remove the first N components from entry paths, like tar \
+                --strip-components; entries with nothing left are skipped"
+    )]
+    strip_components: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "EXPRESSION",
+        value_parser(clap_parse_transform),
+        conflicts_with_all = ["num_volumes", "pack"],
+        help = "rename entries with s|<old>|<new>|, replacing the first occurrence \
+                of the literal <old>, or only at the start with ^<old>; can be \
+                repeated, applied in order after --strip-components"
+    )]
+    transform: Vec<Transform>,
+
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        help = "prepend this to entry paths, after --transform; a dir needs a \
+                trailing slash"
+    )]
+    add_prefix: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["num_volumes", "pack"],
+        help = "write a hard link whose target is in another volume as a copy of \
+                the target file, reading it from the input file again"
+    )]
+    pub(crate) dereference_split_links: bool,
+
+    #[arg(
+        long,
+        help = "warn about hard links whose targets are in other volumes, which \
+                fail to extract from their volume alone"
+    )]
+    warn_broken_links: bool,
+
+    #[arg(
+        long,
+        help = "put a text note as `.splitar-note` file at the start of every volume"
+    )]
+    pub(crate) volume_note: Option<String>,
+
+    #[arg(
+        long,
+        help = "put a `VOLUME_INFO.txt` file at the start of every volume that tells \
+                which volume of how many it is and how to join them; `join` leaves \
+                it out"
+    )]
+    pub(crate) volume_info: bool,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_size),
+        help = "pad every volume to a multiple of this size, e.g. tape record size 10K"
+    )]
+    record_size: Option<u64>,
+
+    #[arg(
+        short = 'b',
+        long,
+        value_name = "N",
+        value_parser(clap::value_parser!(u64).range(1..)),
+        conflicts_with = "record_size",
+        help = "pad every volume to a multiple of N 512-byte blocks, like tar -b; \
+                tar uses 20"
+    )]
+    blocking_factor: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "OCTAL",
+        value_parser(clap_parse_mode),
+        default_value = "666",
+        help = "permissions of the volume files, masked by the umask, e.g. 600; \
+                ignored on non-Unix systems"
+    )]
+    pub(crate) mode: u32,
+
+    #[arg(
+        long,
+        help = "shell command to run after each volume is written, e.g. to write it to \
+                a tape and advance it; the volume path is in $SPLITAR_VOLUME"
+    )]
+    pub(crate) tape_command: Option<String>,
+
+    #[arg(
+        long,
+        help = "shell command to sign each written volume, e.g. \
+                'gpg --batch --detach-sign \"$SPLITAR_VOLUME\"'; it has to create \
+                the $SPLITAR_SIGNATURE file, which is the volume path with \".sig\""
+    )]
+    pub(crate) sign: Option<String>,
+
+    #[arg(
+        long,
+        requires = "sign",
+        help = "warn instead of failing if --sign fails"
+    )]
+    pub(crate) allow_sign_failure: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "write a `<volume>.<algorithm>` checksum file for each volume, as \
+                `sha256sum` does; may be repeated.  `splitar verify` checks them"
+    )]
+    pub(crate) checksum: Vec<ChecksumAlgorithm>,
+
+    #[arg(
+        long,
+        value_hint = clap::ValueHint::FilePath,
+        help = "write the checksums of all the volumes into this file instead, as \
+                `sha256sum --tag` does; SHA-256 unless --checksum is given"
+    )]
+    pub(crate) checksums: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "write a `<prefix>extract.sh` script that extracts all the volumes \
+                without splitar"
+    )]
+    pub(crate) emit_extract_script: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "write the --emit-extract-script script to this path, e.g. outside the \
+                output dir; it finds the volumes where they were written, or in the \
+                dir given as its argument"
+    )]
+    pub(crate) restore_script: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "also write `<prefix>manifest.tar` with the list of volumes and the other \
+                written metadata files: extract and unpack scripts, signatures, \
+                manifests"
+    )]
+    pub(crate) bundle_metadata: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "dry_run",
+        help = "also write `<prefix>.splitar` with the volumes, their sizes and \
+                checksums and the options of the split; `splitar join` and \
+                `splitar verify` use it for the prefix"
+    )]
+    pub(crate) control_file: bool,
+
+    #[arg(
+        long,
+        value_hint = clap::ValueHint::FilePath,
+        help = "write a JSON Lines file with a record for every written entry: volume, \
+                path, size, type, offset in the volume and whether it is a recreated dir"
+    )]
+    pub(crate) manifest: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with = "tape_command",
+        help = "keep the volumes that exist already instead of writing them again, \
+                e.g. after an interrupted run; the input and options have to be the same"
+    )]
+    pub(crate) resume: bool,
+
+    #[arg(
+        long,
+        requires = "resume",
+        value_hint = clap::ValueHint::FilePath,
+        help = "a --manifest of an earlier run to check the kept volumes against"
+    )]
+    pub(crate) resume_manifest: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_hint = clap::ValueHint::FilePath,
+        help = "put this file (e.g. README) as the first entry of the first volume only"
+    )]
+    pub(crate) prepend: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "make reruns byte-for-byte identical: the entries that splitar adds itself \
+                get the SOURCE_DATE_EPOCH or zero mtime and no owner"
+    )]
+    pub(crate) reproducible: bool,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_size),
+        help = "experimental: pack consecutive regular files smaller than this into \
+                `.splitar-pack.*` entries, saving their headers and padding; run the \
+                `<prefix>unpack-small.sh` script after extraction to unpack them"
+    )]
+    pub(crate) coalesce_small: Option<u64>,
+
+    #[arg(
+        long,
+        help = "on interruption, keep the volume being written as `<volume>.partial` \
+                instead of deleting it; it is not a valid complete volume"
+    )]
+    pub(crate) keep_partial: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "dry_run",
+        help = "read every volume back, decompressing it, before it is renamed into \
+                place, and fail unless it is a complete tar with all the entries \
+                written into it"
+    )]
+    pub(crate) self_check: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "atomic",
+        help = "on the first SIGINT or SIGTERM, stop before the next entry and finish \
+                the volumes, exiting with code 6; a second signal aborts as usual"
+    )]
+    pub(crate) graceful_interrupt: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["keep_partial", "tape_command"],
+        help = "on any error or interruption, remove all the volumes and other files \
+                written by this run, so that no half-complete set is left; with \
+                --resume, the kept volumes stay"
+    )]
+    pub(crate) atomic: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "write volumes into temp files in this dir, e.g. on a fast local disk, \
+                instead of the output dir; they are copied if it is another filesystem"
+    )]
+    pub(crate) temp_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "write volumes and other output files into this dir, created if \
+                missing, the output prefix being only the start of their names"
+    )]
+    output_dir: Option<PathBuf>,
+
+    #[arg(short = 'a', long, default_value = "5")]
+    pub(crate) suffix_length: u8,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        conflicts_with_all = ["num_volumes", "layout", "boundaries"],
+        help = "index of the first volume, e.g. to continue the numbering of \
+                an earlier split; --size-schedule uses the same indices"
+    )]
+    pub(crate) start_index: usize,
+
+    #[arg(
+        long,
+        value_parser(clap_parse_name_template),
+        default_value = NameTemplate::DEFAULT,
+        help = "volume file name with `{prefix}` for the output prefix, `{index}` \
+                or `{index:05}` for the zero-padded volume index, and `{ext}` for \
+                tar, tar.gz, zip etc., e.g. \"{prefix}.part{index:03}.{ext}\""
+    )]
+    pub(crate) name_template: NameTemplate,
+
+    #[arg(
+        long,
+        conflicts_with = "name_template",
+        help = "put this between the output prefix and the volume index, e.g. `.part` \
+                for myarchive.part00000; a shorthand for \
+                --name-template \"{prefix}<separator>{index}\""
+    )]
+    separator: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "name_template",
+        help = "append the compressor's extension to volume names, e.g. `.gz` for --gzip \
+                or --compress gzip"
+    )]
+    auto_extension: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["name_template", "auto_extension"],
+        help = "append this extension to volume names, e.g. `lzo` for --compress lzop"
+    )]
+    extension: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorMode::Auto,
+        help = "colorize output; `auto` honors NO_COLOR"
+    )]
+    pub(crate) color: ColorMode,
+
+    #[arg(
+        long,
+        help = "decompress gzip, zstd and bzip2 input in a separate thread, overlapping \
+                it with splitting; xz input is decompressed by its command as usual"
+    )]
+    pub(crate) parallel_decompress: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "parallel_decompress",
+        help = "decompress the input with this shell command, e.g. \"lzip -dc\"; \
+                gzip, zstd, bzip2 and xz input is detected without it"
+    )]
+    pub(crate) decompress_input: Option<String>,
+
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_hint = clap::ValueHint::FilePath,
+        help = "serve progress on this Unix socket: every connection gets a \
+                \"volume=<index> bytes=<input bytes> entries=<count>\" line"
+    )]
+    pub(crate) status_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        overrides_with = "no_progress",
+        help = "show a progress bar on stderr even if it is not a terminal"
+    )]
+    progress: bool,
+
+    #[arg(
+        long,
+        overrides_with = "progress",
+        help = "don't show the progress bar, which is shown on a terminal by default"
+    )]
+    no_progress: bool,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "with 2 or more, read the input and write the output in separate \
+                threads, this many chunks buffered between them; the splitting stays \
+                on the main thread"
+    )]
+    pub(crate) threads: u64,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser(clap_parse_size),
+        help = "buffer this much of a volume before writing it to the file or the \
+                --compress command [default: 8K]"
+    )]
+    write_buffer: Option<u64>,
+
+    #[arg(
+        short = 'j',
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "let --compress commands of up to this many volumes run at once: \
+                the next volume is written while the previous ones are compressed"
+    )]
+    pub(crate) jobs: u64,
+
+    #[arg(help = "input file path or `-` for stdin", value_hint = clap::ValueHint::FilePath)]
+    pub(crate) input_file: PathBuf,
+
+    #[arg(help = "output volume name prefix, or `-` to write a single volume to stdout")]
+    pub(crate) output_prefix: String,
+
+    // The command line, for --control-file.
+    #[arg(skip)]
+    pub(crate) command_line: Vec<String>,
+
+    // The number of volumes of a planned split, for --volume-info.
+    #[arg(skip)]
+    pub(crate) volume_count: Option<usize>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VerboseFormat {
+    /// `tar -tv`-like lines on stderr, prefixed with the volume name.
+    Pretty,
+    /// A JSON object per entry on stdout; paths that are not UTF-8 are
+    /// replaced and marked with `"lossy":true`.
+    Json,
+}
+
+/// Compressions of the volumes that are done in-process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BuiltinCompression {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl BuiltinCompression {
+    fn option(self) -> &'static str {
+        match self {
+            Self::Gzip => "--gzip",
+            Self::Zstd => "--zstd",
+            Self::Bzip2 => "--bzip2",
+        }
+    }
+
+    fn levels(self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Self::Gzip => 0..=9,
+            Self::Zstd => 1..=22,
+            Self::Bzip2 => 1..=9,
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Self::Gzip => 6,
+            Self::Zstd => 3,
+            Self::Bzip2 => 9,
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+            Self::Bzip2 => "bz2",
+        }
+    }
+
+    /// For the extract scripts.
+    pub(crate) fn decompress_command(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip -dc",
+            Self::Zstd => "zstd -dc",
+            Self::Bzip2 => "bzip2 -dc",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Balance {
+    /// Roughly equal data size.
+    Bytes,
+    /// Roughly equal number of entries.
+    Entries,
+    /// Roughly equal compressed size, estimated by compressing samples of
+    /// each file extension with --compress.
+    Compressed,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RecreateDirsMode {
+    /// Only the nearest known parent; its ancestors get their metadata
+    /// only if the volumes are extracted in order.
+    Minimal,
+    /// The whole chain of known parents.
+    Full,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SizeCounts {
+    /// Everything written, including the dirs recreated before an entry.
+    All,
+    /// Only the entries of the input, which have --max-size less the room
+    /// of all the dirs that may be recreated in the volume.
+    DataOnly,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SizeMetric {
+    /// The bytes of the tar stream: headers, extension members, padding and
+    /// the end of archive.
+    Archive,
+    /// Only the data of the entries, as it is extracted.
+    Payload,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Tar,
+    /// Zip archives of the tar entries; hardlinks, devices and FIFOs are
+    /// skipped, owners are lost.
+    Zip,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TarFormat {
+    /// GNU tar's: long names in `././@LongLink` members, large numbers in
+    /// base-256.
+    Gnu,
+    /// POSIX.1-2001: ustar headers with PAX records for long names and
+    /// large numbers.
+    Pax,
+    /// POSIX.1-1988: ustar headers only; what they cannot hold is an error.
+    Ustar,
+}
+
+impl TarFormat {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Gnu => "gnu",
+            Self::Pax => "pax",
+            Self::Ustar => "ustar",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SizeZeroPolicy {
+    /// Drop the entry with its data.
+    Skip,
+    /// Stop with an error.
+    Fail,
+    /// Keep the entry with the data found up to the next valid header.
+    TrustStream,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ScrubField {
+    Uid,
+    Gid,
+    Uname,
+    Gname,
+    Mtime,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Colors are used for stderr only.  In the auto mode, termcolor
+    /// disables them for `NO_COLOR` and `TERM=dumb` by itself.
+    pub(crate) fn stderr_choice(self) -> termcolor::ColorChoice {
+        use is_terminal::IsTerminal;
+        use termcolor::ColorChoice;
+
+        match self {
+            ColorMode::Auto if io::stderr().is_terminal() => ColorChoice::Auto,
+            ColorMode::Auto | ColorMode::Never => ColorChoice::Never,
+            ColorMode::Always => ColorChoice::Always,
+        }
+    }
+}
+
+impl Args {
+    /// The progress bar is shown on a terminal unless it is forced either way.
+    pub(crate) fn show_progress(&self) -> bool {
+        use is_terminal::IsTerminal;
+
+        match (self.progress, self.no_progress) {
+            (true, _) => true,
+            (_, true) => false,
+            _ => io::stderr().is_terminal(),
+        }
+    }
+
+    /// The extension --auto-extension or --extension add to volume names,
+    /// without the dot.
+    pub(crate) fn appended_extension(&self) -> Option<&str> {
+        match &self.extension {
+            Some(extension) => Some(extension.strip_prefix('.').unwrap_or(extension)),
+            None if self.auto_extension => {
+                match (self.compress_line(), self.builtin_compression()) {
+                    (_, Some(compression)) => Some(compression.extension()),
+                    (Some(compress), None) => {
+                        compressor_extension(&compress).filter(|extension| !extension.is_empty())
+                    }
+                    (None, None) => None,
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// The mtime of the entries that splitar makes, like the --volume-note.
+    pub(crate) fn creation_mtime(&self) -> ah::Result<u64> {
+        if !self.reproducible {
+            return Ok(std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0));
+        }
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => epoch
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid SOURCE_DATE_EPOCH {:?}", epoch)),
+            Err(_) => Ok(0),
+        }
+    }
+
+    /// Checks that cannot be expressed with clap attributes.
+    pub(crate) fn validate(&self) -> ah::Result<()> {
+        self.creation_mtime()?;
+        if let (Some(compression), Some(level)) = (self.builtin_compression(), self.compress_level)
+        {
+            ah::ensure!(
+                compression.levels().contains(&level),
+                "--compress-level {} is out of range {:?} of {}",
+                level,
+                compression.levels(),
+                compression.option(),
+            );
+        }
+        if let (true, Some(compress)) = (self.auto_extension, self.compress_line()) {
+            ah::ensure!(
+                compressor_extension(&compress).is_some(),
+                "cannot find out the extension for --compress {:?}, use --extension",
+                compress
+            );
+        }
+        if let Some(separator) = &self.separator {
+            ah::ensure!(
+                !separator.contains(std::path::is_separator),
+                "--separator {:?} cannot contain path separators",
+                separator
+            );
+        }
+        if let Some(extension) = &self.extension {
+            ah::ensure!(
+                !extension.trim_start_matches('.').is_empty() && !extension.contains('/'),
+                "--extension {:?} is not a file name extension",
+                extension
+            );
+        }
+        if self.name_template.uses_ext() {
+            ah::ensure!(
+                volume_extension(self).is_some(),
+                "cannot find out `{{ext}}` of --name-template for --compress {:?}",
+                self.compress_line().unwrap_or_default()
+            );
+        }
+        if let Some(dir) = &self.temp_dir {
+            tempfile::tempfile_in(dir)
+                .with_context(|| format!("--temp-dir {:?} is not writable", dir))?;
+        }
+        ah::ensure!(
+            !self.dereference_split_links || !self.input_is_stream(),
+            "--dereference-split-links cannot be used with stdin or a pipe, as link \
+             targets are read from the input again"
+        );
+        ah::ensure!(
+            !self.multi_volume || !self.all_max_sizes().is_empty(),
+            "--multi-volume needs --max-size or --size-schedule"
+        );
+        ah::ensure!(
+            !self.multi_volume || matches!(self.format, None | Some(TarFormat::Gnu)),
+            "--multi-volume needs --format gnu, as its continued parts are GNU members"
+        );
+        ah::ensure!(
+            self.compress_retries == 0 || self.compress_command().is_some(),
+            "--compress-retries needs --compress or --compress-program"
+        );
+        if let Some(depth) = self.keep_dirs_together {
+            ah::ensure!(depth > 0, "--keep-dirs-together depth has to be at least 1");
+            ah::ensure!(
+                !self.all_max_sizes().is_empty(),
+                "--keep-dirs-together needs --max-size or --size-schedule"
+            );
+        }
+        if let Some(total_size) = self.total_size {
+            let first_size = self
+                .volume_max_size(0)
+                .ok_or_else(|| ah::anyhow!("--total-size needs --max-size or --size-schedule"))?;
+            ah::ensure!(
+                first_size <= total_size,
+                "--total-size {} is smaller than the first volume's {} {}",
+                total_size,
+                self.max_size_option(),
+                first_size,
+            );
+            ah::ensure!(
+                self.size_metric == SizeMetric::Archive,
+                "--total-size cannot be used with --size-metric payload, as volumes \
+                 may be larger than --max-size"
+            );
+        }
+        if self.size_metric == SizeMetric::Payload {
+            // They count or plan the archive bytes.
+            for (present, option) in [
+                (self.num_volumes.is_some(), "--num-volumes"),
+                (self.pack, "--pack"),
+                (self.multi_volume, "--multi-volume"),
+                (self.compression_ratio.is_some(), "--compression-ratio"),
+                (self.coalesce_small.is_some(), "--coalesce-small"),
+            ] {
+                ah::ensure!(
+                    !present,
+                    "{} cannot be used with --size-metric payload",
+                    option
+                );
+            }
+        }
+        // Any data size is fine for the payload.
+        let min_sizes = match self.size_metric {
+            SizeMetric::Archive => self.all_max_sizes(),
+            SizeMetric::Payload => vec![],
+        };
+        for max_size in min_sizes {
+            let min_size = MIN_VOLUME_SIZE + self.injected_size();
+            ah::ensure!(
+                max_size >= min_size,
+                "{} {} is too small: a volume needs at least {} bytes for \
+                 an entry header, two EOF blocks and fixed entries; forgot a size unit?",
+                self.max_size_option(),
+                max_size,
+                min_size,
+            );
+        }
+        let mut routed = HashMap::new();
+        for route in &self.route_by_extension {
+            ah::ensure!(
+                self.route_by_extension
+                    .iter()
+                    .filter(|other| other.category == route.category)
+                    .count()
+                    == 1,
+                "--route-by-extension category {:?} is given twice",
+                route.category,
+            );
+            for ext in &route.extensions {
+                if let Some(other) = routed.insert(ext, &route.category) {
+                    ah::bail!(
+                        "--route-by-extension extension {:?} is in both {:?} and {:?}",
+                        ext,
+                        other,
+                        route.category,
+                    );
+                }
+            }
+        }
+        if self.stdout_output() {
+            // They need volume files, more volumes or stdout.
+            for (present, option) in [
+                (self.output_dir.is_some(), "--output-dir"),
+                (self.dirs_first, "--dirs-first"),
+                (!self.route_by_extension.is_empty(), "--route-by-extension"),
+                (!self.checksum.is_empty(), "--checksum"),
+                (self.checksums.is_some(), "--checksums"),
+                (self.sign.is_some(), "--sign"),
+                (self.tape_command.is_some(), "--tape-command"),
+                (self.emit_extract_script, "--emit-extract-script"),
+                (self.restore_script.is_some(), "--restore-script"),
+                (self.bundle_metadata, "--bundle-metadata"),
+                (self.control_file, "--control-file"),
+                (self.total_size.is_some(), "--total-size"),
+                (self.coalesce_small.is_some(), "--coalesce-small"),
+                (self.resume, "--resume"),
+                (self.atomic, "--atomic"),
+                (self.keep_partial, "--keep-partial"),
+                (self.self_check, "--self-check"),
+                (self.compress_retries > 0, "--compress-retries"),
+                (self.list_volumes, "--list-volumes"),
+                (self.dry_run, "--dry-run"),
+                (self.summary_json, "--summary-json"),
+                (
+                    self.verbose_format == VerboseFormat::Json,
+                    "--verbose-format json",
+                ),
+            ] {
+                ah::ensure!(!present, "{} cannot be used with output to stdout", option);
+            }
+        }
+        if self.output_format == OutputFormat::Zip {
+            for (present, option) in [
+                (self.compress.is_some(), "--compress"),
+                (self.compress_program.is_some(), "--compress-program"),
+                (self.gzip, "--gzip"),
+                (self.zstd, "--zstd"),
+                (self.bzip2, "--bzip2"),
+                (self.verbatim, "--verbatim"),
+                (self.record_size().is_some(), self.record_size_option()),
+                (self.emit_extract_script, "--emit-extract-script"),
+                (self.restore_script.is_some(), "--restore-script"),
+                (self.coalesce_small.is_some(), "--coalesce-small"),
+                (self.multi_volume, "--multi-volume"),
+                (self.copy_bad_headers, "--copy-bad-headers"),
+                (self.format.is_some(), "--format"),
+                (self.self_check, "--self-check"),
+            ] {
+                ah::ensure!(
+                    !present,
+                    "{} cannot be used with --output-format zip, as it is for tar streams",
+                    option,
+                );
+            }
+        }
+        if self.balance == Balance::Compressed {
+            ah::ensure!(
+                self.num_volumes.is_some()
+                    && (self.compress_line().is_some() || self.builtin_compression().is_some()),
+                "--balance compressed needs --num-volumes and --compress, --gzip, --zstd \
+                 or --bzip2"
+            );
+            ah::ensure!(
+                self.entries_manifest_in.is_none(),
+                "--balance compressed cannot be used with --entries-manifest-in, \
+                 as it samples the input data"
+            );
+        }
+        ah::ensure!(
+            !(self.input_file == Path::new("-")
+                && self.files_from.as_deref() == Some(Path::new("-"))),
+            "--files-from and the input cannot be both stdin"
+        );
+        if let Some(ratio) = self.compression_ratio {
+            ah::ensure!(
+                ratio.is_finite() && ratio > 0.0,
+                "--compression-ratio {} has to be a positive number",
+                ratio,
+            );
+        }
+        for (present, option) in [
+            (self.emit_extract_script, "--emit-extract-script"),
+            (self.restore_script.is_some(), "--restore-script"),
+            (self.self_check, "--self-check"),
+        ] {
+            if let (true, Some(compress)) = (present, self.compress_line()) {
+                ah::ensure!(
+                    decompress_command(&compress).is_some(),
+                    "cannot find out decompressor for --compress {:?} for {}",
+                    compress,
+                    option,
+                );
+            }
+        }
+        if let Some(record_size) = self.record_size() {
+            ah::ensure!(
+                record_size > 0 && record_size % TAR_HEADER_SIZE == 0,
+                "--record-size {} is not a multiple of the tar block size {}",
+                record_size,
+                TAR_HEADER_SIZE,
+            );
+            // Padded volumes has to fit too.
+            for max_size in self.all_max_sizes() {
+                ah::ensure!(
+                    max_size % record_size == 0,
+                    "{} {} is not a multiple of the {} record size {}",
+                    self.max_size_option(),
+                    max_size,
+                    self.record_size_option(),
+                    record_size,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Max size of the volume with the given index, if any.
+    pub(crate) fn volume_max_size(&self, vol_idx: usize) -> Option<u64> {
+        match &self.size_schedule {
+            Some(schedule) => Some(schedule.get(vol_idx)),
+            None => self.max_size,
+        }
+    }
+
+    /// Space a volume of this size needs left for a new dir with
+    /// --keep-dirs-together.
+    pub(crate) fn dir_min_space(&self, max_size: u64) -> u64 {
+        self.dir_min_space.unwrap_or(max_size / 4)
+    }
+
+    pub(crate) fn write_buffer(&self) -> usize {
+        /* 16384 is default pipe buffer size for Linux;
+         * on MacOS, it can grow on demand up to this value.
+         * We are using half of this value.
+         */
+        self.write_buffer.unwrap_or(1 << 13) as usize
+    }
+
+    pub(crate) fn builtin_compression(&self) -> Option<BuiltinCompression> {
+        [
+            (self.gzip, BuiltinCompression::Gzip),
+            (self.zstd, BuiltinCompression::Zstd),
+            (self.bzip2, BuiltinCompression::Bzip2),
+        ]
+        .into_iter()
+        .find(|(given, _)| *given)
+        .map(|(_, compression)| compression)
+    }
+
+    /// The --compress-level of the built-in compression, or its default.
+    pub(crate) fn compress_level(&self) -> u32 {
+        match self.builtin_compression() {
+            Some(compression) => self
+                .compress_level
+                .unwrap_or_else(|| compression.default_level()),
+            None => 0,
+        }
+    }
+
+    /// The --compress command line, or --compress-program with its args,
+    /// quoted for a shell, for messages and for guessing the decompressor.
+    pub(crate) fn compress_line(&self) -> Option<String> {
+        let Some(program) = &self.compress_program else {
+            return self.compress.clone();
+        };
+        let words: Vec<_> = std::iter::once(program)
+            .chain(&self.compress_arg)
+            .map(|word| shell_word(word))
+            .collect();
+        Some(words.join(" "))
+    }
+
+    /// The command that compresses a volume: --compress-program as is, or
+    /// the --compress command line run by the shell.
+    pub(crate) fn compress_command(&self) -> Option<Command> {
+        if let Some(program) = &self.compress_program {
+            let mut command = Command::new(program);
+            command.args(&self.compress_arg);
+            return Some(command);
+        }
+        let compress = self.compress.as_ref()?;
+        let mut command = Command::new(shell());
+        command.arg("-c").arg(compress);
+        Some(command)
+    }
+
+    fn all_max_sizes(&self) -> Vec<u64> {
+        match &self.size_schedule {
+            Some(schedule) => schedule.all_sizes().collect(),
+            None => self.max_size.into_iter().collect(),
+        }
+    }
+
+    /// Option name for messages about max sizes.
+    pub(crate) fn max_size_option(&self) -> &'static str {
+        match self.size_schedule {
+            Some(_) => "--size-schedule",
+            None => "--max-size",
+        }
+    }
+
+    /// Size every volume is padded to a multiple of, if any.
+    pub(crate) fn record_size(&self) -> Option<u64> {
+        self.record_size
+            .or(self.blocking_factor.map(|n| n * TAR_HEADER_SIZE))
+    }
+
+    /// Option name for messages about record sizes.
+    fn record_size_option(&self) -> &'static str {
+        match self.blocking_factor {
+            Some(_) => "--blocking-factor",
+            None => "--record-size",
+        }
+    }
+
+    /// Output size estimated for tar data of the given size, to be compared
+    /// with --max-size.
+    pub(crate) fn estimated_size(&self, size: u64) -> u64 {
+        match self.compression_ratio {
+            Some(ratio) => (size as f64 * ratio).ceil() as u64,
+            None => size,
+        }
+    }
+
+    /// Checksums computed for each volume.
+    pub(crate) fn checksum_algorithms(&self) -> Vec<ChecksumAlgorithm> {
+        if self.checksum.is_empty() && (self.checksums.is_some() || self.control_file) {
+            return vec![ChecksumAlgorithm::Sha256];
+        }
+        ChecksumAlgorithm::ALL
+            .into_iter()
+            .filter(|algorithm| self.checksum.contains(algorithm))
+            .collect()
+    }
+
+    /// With the `-` prefix, the volume goes to stdout; the whole input has to
+    /// fit into it.
+    pub(crate) fn stdout_output(&self) -> bool {
+        self.output_prefix == "-"
+    }
+
+    /// Whether the input is stdin or another file that is not regular, like
+    /// a pipe or the `/dev/fd/N` of a process substitution: it is read once,
+    /// without seeking, and its size is unknown.
+    pub(crate) fn input_is_stream(&self) -> bool {
+        self.input_file == Path::new("-")
+            || std::fs::metadata(&self.input_file).is_ok_and(|metadata| !metadata.is_file())
+    }
+
+    /// Size of entries that are put into every volume.
+    pub(crate) fn injected_size(&self) -> u64 {
+        let info_size = match self.volume_info {
+            // The info of other volumes differs in the numbers only.
+            true => {
+                let info = volume_info(self, "", Some(self.start_index));
+                TAR_HEADER_SIZE + padded_size(info.len() as _)
+            }
+            false => 0,
+        };
+        info_size
+            + self
+                .volume_note
+                .as_ref()
+                .map(|note| TAR_HEADER_SIZE + padded_size(note.len() as _))
+                .unwrap_or(0)
+    }
+
+    /// If any option requires parsed (and possibly modified) entries instead
+    /// of raw blocks copied from the input.
+    fn needs_parsed_entries(&self) -> bool {
+        self.recreate_dirs
+            || self.dirs_first
+            || self.layout.is_some()
+            || self.rewrites_metadata()
+            || self.coalesce_small.is_some()
+            || self.renames_paths()
+            || self.checks_links()
+            || self.format.is_some()
+    }
+
+    /// If hard links are checked against the files of their volumes.
+    pub(crate) fn checks_links(&self) -> bool {
+        self.dereference_split_links || self.warn_broken_links
+    }
+
+    /// If header fields are zeroed or set, not counting paths.
+    pub(crate) fn rewrites_metadata(&self) -> bool {
+        !self.scrub.is_empty()
+            || self.numeric_owner
+            || self.owner.is_some()
+            || self.group.is_some()
+            || self.mtime.is_some()
+    }
+
+    pub(crate) fn renames_paths(&self) -> bool {
+        self.strip_components.is_some() || !self.transform.is_empty() || self.add_prefix.is_some()
+    }
+
+    /// The path wrt --strip-components, --transform and --add-prefix, or
+    /// `None` if nothing is left of it.
+    pub(crate) fn renamed_path(&self, path: &[u8]) -> Option<Vec<u8>> {
+        let mut path = match self.strip_components {
+            Some(count) => strip_components(path, count)?.to_vec(),
+            None => path.to_vec(),
+        };
+        for transform in &self.transform {
+            if let Some(renamed) = transform.apply(&path) {
+                path = renamed;
+            }
+        }
+        if let Some(prefix) = &self.add_prefix {
+            path.splice(0..0, prefix.bytes());
+        }
+        (!path.is_empty()).then_some(path)
+    }
+
+    /// Raw input blocks are copied only if nothing is going to touch them.
+    pub(crate) fn is_verbatim(&self) -> bool {
+        self.verbatim && !self.needs_parsed_entries()
+    }
+
+    /// Parse the command line; --max-size comes from $SPLITAR_MAX_SIZE if it is
+    /// required, but not given.  Clap exits on invalid arguments.
+    pub(crate) fn from_command_line() -> ah::Result<Self> {
+        use clap::CommandFactory;
+
+        let mut argv: Vec<OsString> = std::env::args_os().collect();
+        let env_size = std::env::var_os(MAX_SIZE_ENV).filter(|size| !size.is_empty());
+        let matches = match (Self::command().try_get_matches_from(&argv), &env_size) {
+            (Ok(matches), _) => matches,
+            (Err(e), Some(size)) if e.kind() == clap::error::ErrorKind::MissingRequiredArgument => {
+                clap_parse_size(&size.to_string_lossy())
+                    .with_context(|| format!("invalid ${} {:?}", MAX_SIZE_ENV, size))?;
+                argv.splice(1..1, [OsString::from("--max-size"), size.clone()]);
+                Self::command().get_matches_from(&argv)
+            }
+            (Err(e), _) => e.exit(),
+        };
+        let args = Self::from_matches(&argv, &matches)?;
+        if let (Some(max_size), Some(size)) = (args.max_size, &env_size) {
+            let size = size.to_string_lossy();
+            let env_max_size = parse_size(&size, args.si)
+                .with_context(|| format!("invalid ${} {:?}", MAX_SIZE_ENV, size))?;
+            ah::ensure!(
+                max_size == env_max_size,
+                "--max-size {} differs from ${} {:?}; give only one of them",
+                max_size,
+                MAX_SIZE_ENV,
+                size,
+            );
+        }
+        Ok(args)
+    }
+
+    /// The options of parsed arguments, for the command and the library
+    /// alike.
+    pub(crate) fn from_matches(argv: &[OsString], matches: &clap::ArgMatches) -> ah::Result<Self> {
+        use clap::FromArgMatches;
+
+        let mut args = Self::from_arg_matches(matches)?;
+        args.command_line = argv
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        if args.si {
+            args.apply_si_units(matches)?;
+        }
+        Ok(args)
+    }
+
+    /// Clap parses sizes with binary units; with --si, they are parsed again.
+    fn apply_si_units(&mut self, matches: &clap::ArgMatches) -> ah::Result<()> {
+        let raw = |id: &str| {
+            matches
+                .get_raw(id)
+                .and_then(|mut values| values.next_back())
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        for (id, size) in [
+            ("max_size", &mut self.max_size),
+            ("memory_limit", &mut self.memory_limit),
+            ("dir_min_space", &mut self.dir_min_space),
+            ("record_size", &mut self.record_size),
+            ("coalesce_small", &mut self.coalesce_small),
+            ("total_size", &mut self.total_size),
+            ("write_buffer", &mut self.write_buffer),
+        ] {
+            if let Some(value) = raw(id) {
+                *size =
+                    Some(parse_size(&value, true).with_context(|| {
+                        format!("invalid --{} {:?}", id.replace('_', "-"), value)
+                    })?);
+            }
+        }
+        if let Some(value) = raw("size_schedule") {
+            self.size_schedule = Some(
+                parse_size_schedule(&value, true)
+                    .with_context(|| format!("invalid --size-schedule {:?}", value))?,
+            );
+        }
+        Ok(())
+    }
+
+    /// --separator is a shorthand for the `{prefix}<separator>{index}`
+    /// --name-template.
+    pub(crate) fn apply_separator(&mut self) {
+        if let Some(separator) = &self.separator {
+            self.name_template = NameTemplate {
+                parts: vec![
+                    NamePart::Prefix,
+                    NamePart::Literal(separator.clone()),
+                    NamePart::Index(None),
+                ],
+            };
+        }
+    }
+
+    /// Put the output prefix under --output-dir, creating it, so that all
+    /// the output files are there.
+    pub(crate) fn resolve_output_dir(&mut self) -> ah::Result<()> {
+        let Some(dir) = &self.output_dir else {
+            // Volumes go next to the dir then, e.g. `out00000`.
+            if !self.output_prefix.ends_with('/') && Path::new(&self.output_prefix).is_dir() {
+                log::warn!(
+                    "Output prefix {:?} is a dir, but volumes are named like {:?} \
+                     next to it; add a trailing slash or use --output-dir to write into it",
+                    self.output_prefix,
+                    self.name_template.render(self, "", Some(self.start_index)),
+                );
+            }
+            return Ok(());
+        };
+        if !self.dry_run {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create --output-dir {:?}", dir))?;
+        }
+        self.output_prefix = dir
+            .join(&self.output_prefix)
+            .into_os_string()
+            .into_string()
+            .map_err(|dir| ah::anyhow!("--output-dir {:?} is not valid UTF-8", dir))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Volume checksums and their files.
+
+use crate::set_umasked_mode;
+use anyhow::{self as ah, Context as _};
+use sha2::{Digest as _, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChecksumAlgorithm {
+    Sha256,
+    Crc32,
+}
+
+impl ChecksumAlgorithm {
+    pub(crate) const ALL: [Self; 2] = [Self::Sha256, Self::Crc32];
+
+    /// The checksum file extension.
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Crc32 => "crc32",
+        }
+    }
+
+    /// The `--tag` name of the --checksums file.
+    pub(crate) fn tag(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256",
+            Self::Crc32 => "CRC32",
+        }
+    }
+}
+
+/// Running checksums of a volume file.
+pub(crate) struct Checksums {
+    sha256: Option<Sha256>,
+    crc32: Option<crc32fast::Hasher>,
+}
+
+impl Checksums {
+    pub(crate) fn new(algorithms: &[ChecksumAlgorithm]) -> Self {
+        Self {
+            sha256: algorithms
+                .contains(&ChecksumAlgorithm::Sha256)
+                .then(Sha256::new),
+            crc32: algorithms
+                .contains(&ChecksumAlgorithm::Crc32)
+                .then(crc32fast::Hasher::new),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(data);
+        }
+        if let Some(crc32) = &mut self.crc32 {
+            crc32.update(data);
+        }
+    }
+
+    /// Hex digests, in the order of `ChecksumAlgorithm::ALL`.
+    pub(crate) fn finish(self) -> Vec<(ChecksumAlgorithm, String)> {
+        let mut digests = vec![];
+        if let Some(sha256) = self.sha256 {
+            let digest = sha256
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            digests.push((ChecksumAlgorithm::Sha256, digest));
+        }
+        if let Some(crc32) = self.crc32 {
+            digests.push((
+                ChecksumAlgorithm::Crc32,
+                format!("{:08x}", crc32.finalize()),
+            ));
+        }
+        digests
+    }
+}
+
+/// A volume file that updates the checksums with the data that lands in it.
+pub(crate) struct ChecksummedFile {
+    pub(crate) file: std::fs::File,
+    pub(crate) checksums: Arc<Mutex<Checksums>>,
+}
+
+impl io::Write for ChecksummedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.checksums.lock().unwrap().update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+pub(crate) fn checksum_file_path(volume: &Path, algorithm: ChecksumAlgorithm) -> PathBuf {
+    let mut path = volume.as_os_str().to_owned();
+    path.push(".");
+    path.push(algorithm.extension());
+    PathBuf::from(path)
+}
+
+/// Write `<volume>.<algorithm>` in the `sha256sum` format, with the volume
+/// file name, so that it can be checked in the volume's dir.
+pub(crate) fn write_checksum_file(
+    volume: &Path,
+    algorithm: ChecksumAlgorithm,
+    digest: &str,
+) -> ah::Result<()> {
+    let path = checksum_file_path(volume, algorithm);
+    // The volume names are derived from the prefix, which is a String.
+    let name = volume.file_name().unwrap().to_string_lossy();
+    std::fs::write(&path, format!("{}  {}\n", digest, name))
+        .with_context(|| format!("failed to write checksum file {:?}", path))?;
+    set_umasked_mode(&path, 0o666)
+}
+
+type VolumeChecksums = Vec<(PathBuf, Vec<(ChecksumAlgorithm, String)>)>;
+
+/// Read the `<volume>.<algorithm>` files of a volume; at least one has to
+/// be there.
+pub(crate) fn read_checksum_files(volume: &Path) -> ah::Result<Vec<(ChecksumAlgorithm, String)>> {
+    let mut checksums = vec![];
+    for algorithm in ChecksumAlgorithm::ALL {
+        let path = checksum_file_path(volume, algorithm);
+        let data = match std::fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read checksum file {:?}", path))
+            }
+        };
+        // The file name is the volume's own.
+        let digest = data
+            .split_ascii_whitespace()
+            .next()
+            .ok_or_else(|| ah::anyhow!("checksum file {:?} is empty", path))?;
+        checksums.push((algorithm, digest.to_ascii_lowercase()));
+    }
+    ah::ensure!(
+        !checksums.is_empty(),
+        "volume {:?} has no checksum files",
+        volume
+    );
+    Ok(checksums)
+}
+
+/// Read `<TAG> (<path>) = <digest>` lines of a --checksums file.
+pub(crate) fn read_checksums(path: &Path) -> ah::Result<VolumeChecksums> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read checksums file {:?}", path))?;
+    let mut volumes: VolumeChecksums = vec![];
+    for (lineno, line) in data.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(" (").and_then(|(tag, rest)| {
+            let algorithm = ChecksumAlgorithm::ALL
+                .into_iter()
+                .find(|algorithm| algorithm.tag() == tag)?;
+            let (volume, digest) = rest.rsplit_once(") = ")?;
+            Some((algorithm, volume, digest))
+        });
+        let (algorithm, volume, digest) = parsed.ok_or_else(|| {
+            ah::anyhow!("malformed checksums file {:?} at line {}", path, lineno + 1)
+        })?;
+        let checksum = (algorithm, digest.to_ascii_lowercase());
+        match volumes.last_mut() {
+            Some((last, checksums)) if last == Path::new(volume) => checksums.push(checksum),
+            _ => volumes.push((PathBuf::from(volume), vec![checksum])),
+        }
+    }
+    Ok(volumes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        let mut checksums = Checksums::new(&[ChecksumAlgorithm::Sha256]);
+        // Split updates are the same as a single one.
+        let (head, tail) = data.split_at(data.len() / 3);
+        checksums.update(head);
+        checksums.update(tail);
+        checksums.finish().pop().unwrap().1
+    }
+
+    // The examples of FIPS 180-4.
+    #[test]
+    fn sha256_empty() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn sha256_abc() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn sha256_448_bits() {
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+}
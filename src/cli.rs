@@ -0,0 +1,307 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! The `splitar` command.
+
+use crate::{
+    args::{Args, ColorMode},
+    header::{is_extension, stream_size},
+    input::{decompress_in_background, decompressed_input, SizeZeroFilter},
+    input_error,
+    join::{join, JoinArgs},
+    padded_size,
+    progress::{format_bytes, Logger, Progress, ProgressBar, ProgressReader, StatusSocket},
+    threads::read_in_background,
+    verify::{verify, VerifyArgs},
+    Error, Result, Splitter, TAR_HEADER_SIZE,
+};
+use anyhow as ah;
+use clap::Parser;
+use interruptable::Interruptable;
+use std::{
+    io::{self, Write as _},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// `stop_flag` is set on the first signal with --graceful-interrupt.
+pub(crate) fn run(
+    args: Args,
+    interrupt_flag: Arc<AtomicBool>,
+    stop_flag: &AtomicBool,
+) -> Result<()> {
+    args.validate().map_err(Error::Usage)?;
+
+    let progress = Arc::new(Progress::default());
+    #[cfg(unix)]
+    let _status_socket = match &args.status_socket {
+        Some(path) => Some(StatusSocket::bind(path, progress.clone())?),
+        None => None,
+    };
+
+    let raw = args.is_verbatim();
+    let (summary, summary_json) = (args.summary, args.summary_json);
+    let mut splitter = Splitter::start(args, interrupt_flag.clone())?;
+    let args = &splitter.args;
+
+    // Compressed input is detected by reading it, so the errors above are
+    // reported first.
+    // Nothing seeks in the input, so pipes work like stdin.
+    let (input, input_size): (Box<dyn io::Read + Send>, _) = if args.input_file == Path::new("-") {
+        (Box::new(io::stdin()), None)
+    } else {
+        let file = std::fs::File::open(&args.input_file)?;
+        let metadata = file.metadata()?;
+        let size = metadata.is_file().then_some(metadata.len());
+        (Box::new(file), size)
+    };
+    let _progress_bar = match args.show_progress() {
+        true => Some(ProgressBar::start(progress.clone(), input_size)?),
+        false => None,
+    };
+    let input = Box::new(ProgressReader {
+        inner: input,
+        progress: progress.clone(),
+    });
+    let file: Box<dyn io::Read> = if args.parallel_decompress {
+        decompress_in_background(input)?
+    } else {
+        let input = decompressed_input(input, args.decompress_input.as_deref())?;
+        if args.threads > 1 {
+            Box::new(read_in_background("input", input, args.threads as _)?)
+        } else {
+            Box::new(io::BufReader::new(input))
+        }
+    };
+    let mut archive = tar::Archive::new(SizeZeroFilter::new(
+        Interruptable::new(file, interrupt_flag),
+        args.on_size_zero,
+    ));
+    let mut truncated = None;
+    let mut stopped = None;
+    // With --total-size hit, the rest of the input is still read to tell
+    // how much of it is left: its entries and where they end.
+    let mut over_total = None;
+    let mut left_entries = 0;
+    let mut input_end = 0;
+    for ent in archive.entries()?.raw(raw) {
+        let ent = ent.map_err(input_error)?;
+        if stop_flag.load(Ordering::SeqCst) && over_total.is_none() {
+            stopped = Some(splitter.stop());
+            break;
+        }
+        log::debug!("entry: {:?}@{}", ent.path()?, ent.size());
+        let footprint = TAR_HEADER_SIZE + padded_size(stream_size(&ent)?);
+        if over_total.is_some() {
+            left_entries += !is_extension(ent.header()) as u64;
+            input_end = ent.raw_header_position() + footprint;
+            continue;
+        }
+        let entry_end = ent.raw_header_position() + footprint;
+        match splitter.push_entry(ent) {
+            Err(Error::TooManyVolumes(max)) => {
+                // Where the entry starts, with its extension members.
+                truncated = Some((max, splitter.entry_start));
+                break;
+            }
+            Err(Error::OverTotalSize(total_size)) => {
+                over_total = Some((total_size, splitter.entry_start));
+                left_entries = 1;
+                input_end = entry_end;
+                continue;
+            }
+            res => res?,
+        }
+        progress.update(splitter.vol_idx, splitter.finished_volumes.len(), footprint);
+    }
+    let written = splitter.finish()?;
+
+    // Some archives have junk after the trailer; it is not a reason to fail.
+    let mut input = archive.into_inner();
+    if truncated.is_none() && stopped.is_none() {
+        io::copy(&mut input, &mut io::sink())?;
+    }
+    if input.trailing_garbage {
+        log::warn!(
+            "Ignoring {} bytes after the end-of-archive marker, not all of them zeros",
+            input.trailing
+        );
+    }
+    let input_bytes = progress.input_read.load(Ordering::Relaxed);
+    if summary {
+        eprintln!(
+            "{} volumes, {} entries ({} oversized), {} read, {} written, {} on disk",
+            written.volumes.len(),
+            written.entries,
+            written.oversized,
+            format_bytes(input_bytes),
+            format_bytes(written.bytes),
+            format_bytes(written.stored_bytes),
+        );
+    }
+    if summary_json {
+        println!(
+            "{{\"volumes\":{},\"entries\":{},\"oversized\":{},\"input_bytes\":{},\
+             \"bytes\":{},\"stored_bytes\":{}}}",
+            written.volumes.len(),
+            written.entries,
+            written.oversized,
+            input_bytes,
+            written.bytes,
+            written.stored_bytes,
+        );
+    }
+    if let Some((max, offset)) = truncated {
+        // The rest of the input is not read, so its entries are not known.
+        log::warn!(
+            "Stopped after {} entries; the input from offset {} (in the tar stream) is \
+             not split",
+            written.entries,
+            offset,
+        );
+        return Err(Error::TooManyVolumes(max));
+    }
+    if let Some((total_size, offset)) = over_total {
+        log::warn!(
+            "Stopped before --total-size {} is exceeded; {} entries, {} bytes of the \
+             input from offset {} (in the tar stream), are not split",
+            total_size,
+            left_entries,
+            input_end - offset,
+            offset,
+        );
+        return Err(Error::OverTotalSize(total_size));
+    }
+    if let Some(offset) = stopped {
+        log::warn!(
+            "Stopped after {} entries; the input from offset {} (in the tar stream) is \
+             not split",
+            written.entries,
+            offset,
+        );
+        return Err(Error::Stopped);
+    }
+
+    Ok(())
+}
+
+fn eprintln_error<E: std::fmt::Debug>(color: ColorMode, e: E) {
+    use termcolor::{Color, ColorSpec, StandardStream, WriteColor as _};
+
+    let mut stderr = StandardStream::stderr(color.stderr_choice());
+
+    let mut bold_red = ColorSpec::new();
+    bold_red.set_fg(Some(Color::Red)).set_bold(true);
+
+    // Nothing to do if stderr fails.
+    let _ = stderr.set_color(&bold_red);
+    let _ = write!(stderr, "error:");
+    let _ = stderr.reset();
+    let _ = writeln!(stderr, " {:?}", e);
+}
+
+/// The `splitar` command, returning its exit code.
+#[doc(hidden)]
+pub fn cli_main() -> i32 {
+    let interrupt_flag = Arc::new(AtomicBool::new(false));
+
+    Logger::init();
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "join") {
+        let args = JoinArgs::parse_from(std::env::args_os().skip(1));
+        log::debug!("Args: {:?}", args);
+        if let Err(e) = join(args) {
+            let e = Error::from(e);
+            let retcode = e.exit_code();
+            eprintln_error(ColorMode::Auto, ah::Error::from(e));
+            return retcode;
+        }
+        return 0;
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "verify")
+    {
+        let args = VerifyArgs::parse_from(std::env::args_os().skip(1));
+        log::debug!("Args: {:?}", args);
+        if let Err(e) = verify(args) {
+            let e = Error::from(e);
+            let retcode = e.exit_code();
+            eprintln_error(ColorMode::Auto, ah::Error::from(e));
+            return retcode;
+        }
+        return 0;
+    }
+    let args = match Args::from_command_line() {
+        Ok(args) => args,
+        Err(e) => {
+            let e = Error::Usage(e);
+            let retcode = e.exit_code();
+            eprintln_error(ColorMode::Auto, ah::Error::from(e));
+            return retcode;
+        }
+    };
+    if args.quiet {
+        log::set_max_level(log::max_level().min(log::LevelFilter::Warn));
+    }
+
+    log::debug!("Args: {:?}", args);
+    let color = args.color;
+
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let interrput_flag2 = interrupt_flag.clone();
+        let stop_flag2 = stop_flag.clone();
+        let graceful = args.graceful_interrupt;
+        // SIGTERM too, on Unix.
+        let res = ctrlc::set_handler(move || {
+            if graceful && !stop_flag2.swap(true, Ordering::SeqCst) {
+                log::warn!("Interrupted; finishing the volumes, interrupt again to abort");
+                return;
+            }
+            interrput_flag2.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+        if let Err(e) = res {
+            log::error!("failed to set SIGINT handler: {}. Ignoring...", e);
+        }
+    }
+
+    if let Err(e) = run(args, interrupt_flag.clone(), &stop_flag) {
+        // The commands get the signal too, and may fail first.
+        let e = match e {
+            Error::SubprocessFailed(e) | Error::Other(e)
+                if interrupt_flag.load(Ordering::SeqCst) =>
+            {
+                Error::Interrupted(e)
+            }
+            e => e,
+        };
+        let retcode = e.exit_code();
+        // Convert to ah::Erorr for pretty output.
+        eprintln_error(color, Into::<ah::Error>::into(e));
+        return retcode;
+    }
+    0
+}
@@ -0,0 +1,264 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Compression of volumes, by a command or built in.
+
+use crate::{
+    args::{Args, BuiltinCompression, OutputFormat},
+    checksum::{ChecksumAlgorithm, ChecksummedFile, Checksums},
+    SubprocessFailure,
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    io,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Blames the compressor for broken pipe errors: it has exited before
+/// reading all the data, and the volume would be truncated.
+pub(crate) struct CompressorStdin {
+    pub(crate) stdin: ChildStdin,
+    pub(crate) command: String,
+}
+
+impl CompressorStdin {
+    fn blame(&self, err: io::Error) -> io::Error {
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            return err;
+        }
+        io::Error::new(
+            err.kind(),
+            SubprocessFailure(format!(
+                "--compress command {:?} exited before reading all the data: {}",
+                self.command, err
+            )),
+        )
+    }
+}
+
+impl io::Write for CompressorStdin {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf).map_err(|err| self.blame(err))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush().map_err(|err| self.blame(err))
+    }
+}
+
+/// Start a --compress command writing into the file, checksummed on the
+/// way if needed, or to the splitar stdout, with the threads that read its
+/// stdout and stderr.
+#[allow(clippy::type_complexity)]
+pub(crate) fn spawn_compressor(
+    command: &mut Command,
+    stdin: Stdio,
+    out_file: Option<std::fs::File>,
+    checksums: Option<&Arc<Mutex<Checksums>>>,
+    capture_stderr: bool,
+) -> ah::Result<(
+    Child,
+    Option<thread::JoinHandle<io::Result<u64>>>,
+    Option<thread::JoinHandle<io::Result<Vec<u8>>>>,
+)> {
+    // The output goes to the file directly, unless it is checksummed on the
+    // way.
+    let (stdout, checksummed_file) = match (out_file, checksums) {
+        (Some(file), Some(checksums)) => (
+            Stdio::piped(),
+            Some(ChecksummedFile {
+                file,
+                checksums: checksums.clone(),
+            }),
+        ),
+        (Some(file), None) => (Stdio::from(file), None),
+        (None, _) => (Stdio::inherit(), None),
+    };
+    let mut subprocess = command
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(if capture_stderr {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .spawn()
+        .with_context(|| format!("failed to start {:?}", command))?;
+    log::info!("Executing subprocess {}", subprocess.id());
+    let checksum_thread = checksummed_file.map(|mut file| {
+        let mut stdout = subprocess.stdout.take().unwrap();
+        thread::spawn(move || io::copy(&mut stdout, &mut file))
+    });
+    // The stderr has to be read concurrently, otherwise the subprocess may
+    // block on a full pipe.
+    let stderr_thread = subprocess.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            io::Read::read_to_end(&mut stderr, &mut buf)?;
+            Ok(buf)
+        })
+    });
+    Ok((subprocess, checksum_thread, stderr_thread))
+}
+
+/// With --compress-retries, the tar stream of a volume goes to a temp file,
+/// and the compressor is run on it when the volume is complete, so that it
+/// can be run again if it fails.
+pub(crate) struct Replay {
+    pub(crate) tar: tempfile::TempPath,
+    pub(crate) command: Command,
+    pub(crate) retries: u32,
+    pub(crate) capture_stderr: bool,
+    pub(crate) algorithms: Vec<ChecksumAlgorithm>,
+}
+
+/// A built-in encoder of --gzip, --zstd or --bzip2.
+pub(crate) enum Encoder<W: io::Write> {
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+}
+
+impl<W: io::Write> Encoder<W> {
+    pub(crate) fn new(compression: BuiltinCompression, level: u32, out: W) -> io::Result<Self> {
+        Ok(match compression {
+            BuiltinCompression::Gzip => Self::Gzip(flate2::write::GzEncoder::new(
+                out,
+                flate2::Compression::new(level),
+            )),
+            BuiltinCompression::Zstd => Self::Zstd(zstd::Encoder::new(out, level as _)?),
+            BuiltinCompression::Bzip2 => Self::Bzip2(bzip2::write::BzEncoder::new(
+                out,
+                bzip2::Compression::new(level),
+            )),
+        })
+    }
+
+    /// Write the trailer, returning the output.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Bzip2(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Bzip2(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Bzip2(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// The built-in encoder of the volume.  It is shared with the volume, as
+/// the output chain may be owned by the --threads writer, and the trailer
+/// can only be written after the chain is done.
+#[derive(Clone)]
+pub(crate) struct EncodedOutput(pub(crate) Arc<Mutex<Encoder<Box<dyn io::Write + Send>>>>);
+
+impl EncodedOutput {
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let encoder = Arc::into_inner(self.0)
+            .expect("internal: encoder is still in use")
+            .into_inner()
+            .expect("internal: encoder is poisoned");
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+impl io::Write for EncodedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Guess the decompressor by the compressor's program name.
+pub(crate) fn decompress_command(compress: &str) -> Option<&'static str> {
+    let program = match compress.strip_prefix('\'') {
+        Some(quoted) => quoted.split('\'').next()?,
+        None => compress.split_whitespace().next()?,
+    };
+    let program = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let program = program.strip_suffix(".exe").unwrap_or(program);
+    Some(match program {
+        "gzip" | "pigz" => "gzip -dc",
+        "bzip2" | "pbzip2" | "lbzip2" => "bzip2 -dc",
+        "xz" | "pixz" => "xz -dc",
+        "zstd" | "pzstd" => "zstd -dc",
+        "lz4" => "lz4 -dc",
+        "lzip" | "plzip" => "lzip -dc",
+        "cat" => "cat",
+        _ => return None,
+    })
+}
+
+/// The volume file extension for `{ext}` in --name-template, if known.
+pub(crate) fn volume_extension(args: &Args) -> Option<String> {
+    if args.output_format == OutputFormat::Zip {
+        return Some("zip".to_owned());
+    }
+    let compression = match (args.compress_line(), args.builtin_compression()) {
+        (_, Some(compression)) => compression.extension(),
+        (Some(compress), None) => compressor_extension(&compress)?,
+        (None, None) => "",
+    };
+    Some(match compression {
+        "" => "tar".to_owned(),
+        compression => format!("tar.{}", compression),
+    })
+}
+
+/// Extensions of compressed files, without the dot.
+pub(crate) const COMPRESSOR_EXTENSIONS: [&str; 6] = ["gz", "bz2", "xz", "zst", "lz4", "lz"];
+
+/// The file extension of the --compress output, if the compressor is known;
+/// it is empty for `cat`.
+pub(crate) fn compressor_extension(compress: &str) -> Option<&'static str> {
+    Some(match decompress_command(compress)? {
+        "gzip -dc" => "gz",
+        "bzip2 -dc" => "bz2",
+        "xz -dc" => "xz",
+        "zstd -dc" => "zst",
+        "lz4 -dc" => "lz4",
+        "lzip -dc" => "lz",
+        _ => "",
+    })
+}
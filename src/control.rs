@@ -0,0 +1,217 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! The --control-file, which lists the volumes of a split.
+
+use crate::{
+    args::Args,
+    checksum::ChecksumAlgorithm,
+    set_umasked_mode,
+    volume::{shell_quote, stored_size},
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    collections::HashMap,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// Format version of the --control-file; files of newer versions are
+/// refused, as their volumes may need more than this splitar knows.
+const CONTROL_FILE_VERSION: u32 = 1;
+
+/// The --control-file of a split: its volumes in the extraction order, and
+/// what they are read with.
+pub(crate) struct ControlFile {
+    pub(crate) compress: Option<String>,
+    pub(crate) volumes: Vec<ControlVolume>,
+}
+
+pub(crate) struct ControlVolume {
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) checksums: Vec<(ChecksumAlgorithm, String)>,
+}
+
+impl ControlFile {
+    /// Check that the volumes are there, and have the sizes they were
+    /// written with.
+    pub(crate) fn check_sizes(&self) -> ah::Result<()> {
+        for volume in &self.volumes {
+            let size = stored_size(&volume.path)?;
+            ah::ensure!(
+                size == volume.size,
+                "volume {:?} is {} bytes, but the control file has {}",
+                volume.path,
+                size,
+                volume.size,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `<prefix>.splitar`; a prefix ending with a dot doesn't get another.
+pub(crate) fn control_file_path(prefix: &str) -> PathBuf {
+    PathBuf::from(format!(
+        "{}.splitar",
+        prefix.strip_suffix('.').unwrap_or(prefix)
+    ))
+}
+
+/// Write the --control-file, lines of keys and values:
+///
+/// ```text
+/// splitar-control 1
+/// version 0.1.3
+/// options '-S' '4G' '--control-file' 'input.tar' 'out/volume.'
+/// compress zstd
+/// record-size 10240
+/// volumes 2
+/// volume 4294966272 SHA256:1b4f...,CRC32:9a0c1ef2 volume.00000
+/// volume 1048576 SHA256:56ea... volume.00001
+/// ```
+///
+/// The keys other than `volume` are written only if they apply; volumes
+/// without checksums, like the resumed ones, have `-` instead of them.
+/// Like volumes, the file appears only when it is complete.
+pub(crate) fn write_control_file(
+    args: &Args,
+    volumes: &[PathBuf],
+    checksums: &HashMap<PathBuf, Vec<(ChecksumAlgorithm, String)>>,
+) -> ah::Result<()> {
+    let path = control_file_path(&args.output_prefix);
+    let options: Vec<_> = args
+        .command_line
+        .iter()
+        .skip(1)
+        .map(|arg| shell_quote(arg))
+        .collect();
+    let mut control = format!(
+        "splitar-control {}\nversion {}\noptions {}\n",
+        CONTROL_FILE_VERSION,
+        env!("CARGO_PKG_VERSION"),
+        options.join(" "),
+    );
+    if let Some(compress) = args.compress_line() {
+        control += &format!("compress {}\n", compress);
+    }
+    if let Some(record_size) = args.record_size() {
+        control += &format!("record-size {}\n", record_size);
+    }
+    control += &format!("volumes {}\n", volumes.len());
+    for volume in volumes {
+        let digests: Vec<_> = checksums
+            .get(volume)
+            .into_iter()
+            .flatten()
+            .map(|(algorithm, digest)| format!("{}:{}", algorithm.tag(), digest))
+            .collect();
+        control += &format!(
+            "volume {} {} {}\n",
+            stored_size(volume)?,
+            match digests.is_empty() {
+                true => "-".to_owned(),
+                false => digests.join(","),
+            },
+            volume.file_name().unwrap().to_string_lossy(),
+        );
+    }
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(path.file_name().unwrap_or_default())
+        .suffix(".tmp")
+        .tempfile_in(path.parent().unwrap_or_else(|| Path::new(".")))
+        .context("failed to create control file tempfile")?;
+    temp_file
+        .write_all(control.as_bytes())
+        .context("failed to write control file")?;
+    temp_file
+        .persist(&path)
+        .with_context(|| format!("failed to write control file {:?}", path))?;
+    set_umasked_mode(&path, 0o666)
+}
+
+/// Read a --control-file; the volumes are in its dir.  Unknown keys are
+/// skipped, they are for the other tools.
+pub(crate) fn read_control_file(path: &Path) -> ah::Result<ControlFile> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read control file {:?}", path))?;
+    let mut lines = data.lines();
+    let version = lines
+        .next()
+        .and_then(|line| line.strip_prefix("splitar-control "))
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or_else(|| ah::anyhow!("{:?} is not a splitar control file", path))?;
+    ah::ensure!(
+        version <= CONTROL_FILE_VERSION,
+        "control file {:?} has format version {}, but this splitar reads only up to {}",
+        path,
+        version,
+        CONTROL_FILE_VERSION,
+    );
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut control = ControlFile {
+        compress: None,
+        volumes: vec![],
+    };
+    let mut count = None;
+    for (lineno, line) in lines.enumerate() {
+        let malformed = || ah::anyhow!("malformed control file {:?} at line {}", path, lineno + 2);
+        let (key, value) = line.split_once(' ').ok_or_else(malformed)?;
+        match key {
+            "compress" => control.compress = Some(value.to_owned()),
+            "volumes" => count = Some(value.parse::<usize>().map_err(|_| malformed())?),
+            "volume" => {
+                let mut fields = value.splitn(3, ' ');
+                let (Some(size), Some(digests), Some(name)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(malformed());
+                };
+                let mut checksums = vec![];
+                for digest in digests.split(',').filter(|_| digests != "-") {
+                    let (tag, digest) = digest.split_once(':').ok_or_else(malformed)?;
+                    let algorithm = ChecksumAlgorithm::ALL
+                        .into_iter()
+                        .find(|algorithm| algorithm.tag() == tag)
+                        .ok_or_else(malformed)?;
+                    checksums.push((algorithm, digest.to_ascii_lowercase()));
+                }
+                control.volumes.push(ControlVolume {
+                    path: dir.join(name),
+                    size: size.parse().map_err(|_| malformed())?,
+                    checksums,
+                });
+            }
+            _ => {}
+        }
+    }
+    let count = count.ok_or_else(|| ah::anyhow!("control file {:?} has no volume count", path))?;
+    ah::ensure!(
+        count == control.volumes.len(),
+        "control file {:?} lists {} volumes instead of {}; is it truncated?",
+        path,
+        control.volumes.len(),
+        count,
+    );
+    Ok(control)
+}
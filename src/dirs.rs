@@ -0,0 +1,136 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Dirs recreated in volumes with --recreate-dirs.
+
+use crate::{args::RecreateDirsMode, header::Extensions, padded_size, TAR_HEADER_SIZE};
+
+/// A dir entry, or a symlink that may be a dir in the paths of other entries,
+/// kept for re-creation in other volumes, with its extension members.
+pub(crate) struct StoredDir {
+    pub(crate) header: tar::Header,
+    pub(crate) extensions: Extensions,
+}
+
+impl StoredDir {
+    /// The dir a symlink kept as `<path>/` points to, with a trailing slash,
+    /// if it is relative and stays in the archive.
+    fn symlink_target(&self, path: &[u8]) -> Option<Vec<u8>> {
+        if !self.header.entry_type().is_symlink() {
+            return None;
+        }
+        let link_name = match &self.extensions.link_name {
+            Some(link_name) => link_name.clone(),
+            None => self.header.link_name_bytes()?.into_owned(),
+        };
+        if link_name.starts_with(b"/") {
+            return None;
+        }
+        let (prefix, path) = match path.strip_prefix(b"./") {
+            Some(path) => (&b"./"[..], path),
+            None => (&b""[..], path),
+        };
+        let link = path.strip_suffix(b"/").unwrap_or(path);
+        let mut parts: Vec<&[u8]> = link.split(|&c| c == b'/').collect();
+        parts.pop();
+        for part in link_name.split(|&c| c == b'/') {
+            match part {
+                b"" | b"." => {}
+                b".." => {
+                    parts.pop()?;
+                }
+                _ => parts.push(part),
+            }
+        }
+        parts.retain(|part| !part.is_empty() && *part != b".");
+        if parts.is_empty() {
+            return None;
+        }
+        let mut target = prefix.to_vec();
+        target.extend(parts.join(&b'/'));
+        target.push(b'/');
+        Some(target)
+    }
+
+    /// Bytes the dir takes when it is recreated.
+    pub(crate) fn footprint(&self) -> u64 {
+        TAR_HEADER_SIZE + padded_size(self.header.size().unwrap_or(0)) + self.extensions.footprint()
+    }
+}
+
+/// The known dirs that --recreate-dirs puts before an entry in the dir, from
+/// the root to the nearest parent: parents always come before their
+/// subdirs, as some extractors need.
+pub(crate) fn recreated_dirs<'a>(
+    dirname: &[u8],
+    known_dirs: &'a patricia_tree::PatriciaMap<Box<StoredDir>>,
+    mode: RecreateDirsMode,
+) -> Vec<(Vec<u8>, &'a StoredDir)> {
+    let parents = |dirname: &[u8]| {
+        let mut dirs: Vec<_> = known_dirs
+            .common_prefixes(dirname)
+            .map(|(path, dir)| (path.to_vec(), dir.as_ref()))
+            .collect();
+        // Not to depend on the map's iteration order; the prefixes of one
+        // path are the shorter, the closer to the root.
+        dirs.sort_by_key(|(path, _)| path.len());
+        let skip = match mode {
+            RecreateDirsMode::Minimal => dirs.len().saturating_sub(1),
+            RecreateDirsMode::Full => 0,
+        };
+        dirs.split_off(skip)
+    };
+    let mut dirs = parents(dirname);
+    // A symlink needs the dirs it points to, if they are known, for the
+    // volume to be extracted alone; they may come after it.
+    let mut i = 0;
+    while i < dirs.len() {
+        if let Some(target) = dirs[i].1.symlink_target(&dirs[i].0) {
+            for dir in parents(&target) {
+                if !dirs.iter().any(|(path, _)| *path == dir.0) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        i += 1;
+    }
+    // The targets' dirs go by depth too, the sort being stable.
+    dirs.sort_by_key(|(path, _)| path.iter().filter(|&&c| c == b'/').count());
+    dirs
+}
+
+/// The dir of the entry whose parents --recreate-dirs checks, unless it is
+/// the dir of the previous entry.
+pub(crate) fn recreated_dirname<'a>(path: &'a [u8], prev_dir: &[u8]) -> Option<&'a [u8]> {
+    let same_dir = path
+        .strip_prefix(prev_dir)
+        .map(|p| !p.is_empty() && !p.contains(&b'/'))
+        .unwrap_or(false);
+    if same_dir {
+        return None;
+    }
+    let path = path.strip_suffix(b"/").unwrap_or(path);
+    // std::path::Path is OS-dependent and cannot be used.  It would be
+    // nice to have something like Python's posixpath.
+    let pos = path.iter().rposition(|&c| c == b'/')?;
+    Some(&path[..=pos])
+}
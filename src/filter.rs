@@ -0,0 +1,177 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Entries selected by --files-from, --include and --exclude.
+
+use crate::plan::layout_key;
+use anyhow as ah;
+use globset::{GlobBuilder, GlobMatcher};
+use std::{collections::HashMap, io, path::Path};
+
+/// Paths requested by --files-from, and whether they are found.
+pub(crate) struct Selection {
+    requested: HashMap<Vec<u8>, bool>,
+}
+
+impl Selection {
+    /// `find .` lists paths with `./`, and tar archives may have them or not.
+    fn key(mut path: &[u8]) -> &[u8] {
+        while let Some(rest) = path.strip_prefix(b"./") {
+            path = rest;
+        }
+        layout_key(path)
+    }
+
+    pub(crate) fn read<R: io::BufRead>(input: R) -> io::Result<Self> {
+        let mut requested = HashMap::new();
+        for (lineno, line) in input.split(b'\n').enumerate() {
+            let line = line?;
+            let path = Self::parse_line(&line).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} at line {}", err, lineno + 1),
+                )
+            })?;
+            if let Some(path) = path {
+                requested.insert(Self::key(&path).to_vec(), false);
+            }
+        }
+        Ok(Self { requested })
+    }
+
+    /// Lists edited on Windows have CRLF line ends, and hand-written ones
+    /// may be indented, so surrounding whitespace is trimmed and blank lines
+    /// are skipped.  A path that starts or ends with whitespace has to be
+    /// double-quoted, with `\"` and `\\` escapes inside.
+    fn parse_line(line: &[u8]) -> std::result::Result<Option<Vec<u8>>, &'static str> {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let Some(quoted) = line.strip_prefix(b"\"") else {
+            return Ok(Some(line.to_vec()));
+        };
+        let mut path = vec![];
+        let mut chars = quoted.iter();
+        while let Some(&c) = chars.next() {
+            match c {
+                b'"' if chars.as_slice().is_empty() => return Ok(Some(path)),
+                b'"' => return Err("text after the closing quote"),
+                b'\\' => path.push(*chars.next().ok_or("unterminated quote")?),
+                _ => path.push(c),
+            }
+        }
+        Err("unterminated quote")
+    }
+
+    pub(crate) fn select(&mut self, path: &[u8]) -> bool {
+        match self.requested.get_mut(Self::key(path)) {
+            Some(found) => {
+                *found = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Report the paths that are not in the input.
+    pub(crate) fn check_complete(&self) -> ah::Result<()> {
+        let mut missing: Vec<_> = self
+            .requested
+            .iter()
+            .filter(|(_, &found)| !found)
+            .map(|(path, _)| path)
+            .collect();
+        missing.sort();
+        for path in &missing {
+            log::error!(
+                "{:?} from --files-from is not found in the input",
+                String::from_utf8_lossy(path)
+            );
+        }
+        ah::ensure!(
+            missing.is_empty(),
+            "{} paths from --files-from are not found in the input",
+            missing.len()
+        );
+        Ok(())
+    }
+}
+
+/// Entries selected by --include and --exclude.  An entry is split if it
+/// matches any include glob, or there are none, and no exclude glob.
+pub(crate) struct PathFilter {
+    include: Vec<GlobMatcher>,
+    exclude: Vec<GlobMatcher>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(include: &[String], exclude: &[String]) -> ah::Result<Option<Self>> {
+        if include.is_empty() && exclude.is_empty() {
+            return Ok(None);
+        }
+        let compile = |option: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    // `*` and `?` match within one path component, and `**`
+                    // as a whole component across them.
+                    let glob = GlobBuilder::new(pattern)
+                        .literal_separator(true)
+                        .backslash_escape(true)
+                        .build()
+                        .map_err(|err| {
+                            ah::anyhow!("invalid {} glob {:?}: {}", option, pattern, err.kind())
+                        })?;
+                    Ok(glob.compile_matcher())
+                })
+                .collect::<ah::Result<Vec<_>>>()
+        };
+        Ok(Some(Self {
+            include: compile("--include", include)?,
+            exclude: compile("--exclude", exclude)?,
+        }))
+    }
+
+    /// Paths are matched without leading `./` and trailing `/`, like in
+    /// --files-from lists.
+    pub(crate) fn accepts(&self, path: &[u8]) -> bool {
+        let path = path_of_bytes(Selection::key(path));
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.is_match(&path)))
+            && !self.exclude.iter().any(|glob| glob.is_match(&path))
+    }
+}
+
+/// Entry paths are bytes; globset matches the bytes of a Unix path as is.
+#[cfg(unix)]
+fn path_of_bytes(path: &[u8]) -> std::borrow::Cow<'_, Path> {
+    use std::os::unix::ffi::OsStrExt as _;
+    Path::new(std::ffi::OsStr::from_bytes(path)).into()
+}
+
+#[cfg(not(unix))]
+fn path_of_bytes(path: &[u8]) -> std::borrow::Cow<'_, Path> {
+    match String::from_utf8_lossy(path) {
+        std::borrow::Cow::Borrowed(path) => Path::new(path).into(),
+        std::borrow::Cow::Owned(path) => std::path::PathBuf::from(path).into(),
+    }
+}
@@ -0,0 +1,791 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Tar headers: printing, checking and rewriting them.
+
+use crate::{
+    args::{Args, ScrubField, TarFormat},
+    manifest::{entry_type_name, json_string},
+    padded_size,
+    progress::suspend_progress_bar,
+    TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use chrono::TimeZone;
+use std::io::{self, Write as _};
+
+/// A header for the entries that splitar adds itself, in the --format.
+pub(crate) fn new_header(format: Option<TarFormat>) -> tar::Header {
+    match format {
+        Some(TarFormat::Pax | TarFormat::Ustar) => tar::Header::new_ustar(),
+        Some(TarFormat::Gnu) | None => tar::Header::new_gnu(),
+    }
+}
+
+/// Zero the fields that may reveal private details of the host.
+pub(crate) fn scrub_header(
+    header: &mut tar::Header,
+    extensions: &mut Extensions,
+    fields: &[ScrubField],
+) {
+    for field in fields {
+        match field {
+            ScrubField::Uid => {
+                header.set_uid(0);
+                extensions.remove_pax("uid");
+            }
+            ScrubField::Gid => {
+                header.set_gid(0);
+                extensions.remove_pax("gid");
+            }
+            // V7 headers have no names; nothing to scrub.
+            ScrubField::Uname => {
+                let _ = header.set_username("");
+                extensions.remove_pax("uname");
+            }
+            ScrubField::Gname => {
+                let _ = header.set_groupname("");
+                extensions.remove_pax("gname");
+            }
+            ScrubField::Mtime => set_entry_mtime(header, extensions, 0),
+        }
+    }
+}
+
+/// Set the mtime, dropping GNU and PAX atime and ctime, which would tell
+/// the original one.
+fn set_entry_mtime(header: &mut tar::Header, extensions: &mut Extensions, mtime: u64) {
+    header.set_mtime(mtime);
+    for key in ["mtime", "atime", "ctime"] {
+        extensions.remove_pax(key);
+    }
+    // Blank rather than zero: ustar readers take these bytes for the name
+    // prefix.
+    if let Some(gnu) = header.as_gnu_mut() {
+        gnu.atime = [0; 12];
+        gnu.ctime = [0; 12];
+    }
+}
+
+/// Apply --numeric-owner, --owner, --group and --mtime, after --scrub.
+pub(crate) fn normalize_header(header: &mut tar::Header, extensions: &mut Extensions, args: &Args) {
+    if args.numeric_owner {
+        // V7 headers have no names; nothing to clear.
+        let _ = header.set_username("");
+        let _ = header.set_groupname("");
+        extensions.remove_pax("uname");
+        extensions.remove_pax("gname");
+    }
+    if let Some(uid) = args.owner {
+        header.set_uid(uid);
+        extensions.remove_pax("uid");
+    }
+    if let Some(gid) = args.group {
+        header.set_gid(gid);
+        extensions.remove_pax("gid");
+    }
+    if let Some(mtime) = args.mtime {
+        set_entry_mtime(header, extensions, mtime);
+    }
+}
+
+pub(crate) fn print_header(volume_name: &str, header: &tar::Header, utc: bool) -> io::Result<()> {
+    // The bar is redrawn below the line.
+    suspend_progress_bar(|| write_header(&mut io::stderr().lock(), volume_name, header, utc))
+}
+
+fn write_header(
+    stderr: &mut impl io::Write,
+    volume_name: &str,
+    header: &tar::Header,
+    utc: bool,
+) -> io::Result<()> {
+    // Malformed fields are shown as `?`, as the entry may be copied anyway;
+    // so are mtimes out of the chrono range.
+    let mtime = header.mtime().ok().and_then(|mtime| mtime.try_into().ok());
+    let timestamp = if utc {
+        mtime
+            .and_then(|mtime| chrono::Utc.timestamp_opt(mtime, 0).single())
+            .map(|datetime| datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|| format!("{:>20}", "?"))
+    } else {
+        mtime
+            .and_then(|mtime| chrono::Local.timestamp_opt(mtime, 0).single())
+            .map(|datetime| datetime.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| format!("{:>19}", "?"))
+    };
+    let device = |number: io::Result<Option<u32>>| match number {
+        Ok(number) => number.unwrap_or(0).to_string(),
+        Err(_) => "?".to_owned(),
+    };
+    let size_str = match header.entry_type() {
+        tar::EntryType::Block | tar::EntryType::Char => format!(
+            "{}:{}",
+            device(header.device_major()),
+            device(header.device_minor()),
+        ),
+        _ => header
+            .size()
+            .map_or_else(|_| "?".to_owned(), |size| size.to_string()),
+    };
+    write!(
+        stderr,
+        "{marker} {type}{mod} {user} {group} {size:>12} {timestamp} {path}",
+        marker = volume_name,
+        type = entry_type_char(header),
+        mod = header
+            .mode()
+            .map_or_else(|_| "?".repeat(9), decode_mod),
+        user = owner_name(header.username_bytes(), header.uid()),
+        group = owner_name(header.groupname_bytes(), header.gid()),
+        size = size_str,
+        timestamp = timestamp,
+        path = String::from_utf8_lossy(&header.path_bytes()),
+    )?;
+
+    let link = header.link_name_bytes();
+    // We presume that link exist only for reason.
+    let link = link.as_ref().map(|c| String::from_utf8_lossy(c));
+    match header.entry_type() {
+        tar::EntryType::Link => {
+            write!(
+                stderr,
+                " link to {}",
+                // We might just call unwrap and fail.
+                link.unwrap_or_default(),
+            )?;
+        }
+        tar::EntryType::Symlink => {
+            write!(
+                stderr,
+                " -> {}",
+                // We might just call unwrap and fail.
+                link.unwrap_or_default(),
+            )?;
+        }
+        _ => {}
+    }
+    writeln!(stderr)?;
+    Ok(())
+}
+
+/// One JSON line per entry; malformed numbers are `null`.
+pub(crate) fn print_header_json(volume_name: &str, header: &tar::Header) -> io::Result<()> {
+    let number = |number: io::Result<u64>| number.map_or("null".to_owned(), |n| n.to_string());
+    let path = header.path_bytes();
+    let link = header.link_name_bytes();
+    let lossy = std::str::from_utf8(&path).is_err()
+        || link
+            .as_ref()
+            .is_some_and(|link| std::str::from_utf8(link).is_err());
+    let line = format!(
+        "{{\"volume\":{},\"path\":{},\"size\":{},\"mode\":{},\"uid\":{},\"gid\":{},\
+         \"user\":{},\"group\":{},\"mtime\":{},\"type\":\"{}\",\"link\":{},\"lossy\":{}}}",
+        json_string(volume_name.as_bytes()),
+        json_string(&path),
+        number(header.size()),
+        header.mode().map_or("null".to_owned(), |mode| format!(
+            "\"{:04o}\"",
+            mode & 0o7777
+        )),
+        number(header.uid()),
+        number(header.gid()),
+        header
+            .username_bytes()
+            .map_or("null".to_owned(), json_string),
+        header
+            .groupname_bytes()
+            .map_or("null".to_owned(), json_string),
+        number(header.mtime()),
+        entry_type_name(header),
+        link.as_deref().map_or("null".to_owned(), json_string),
+        lossy,
+    );
+    // The bar is on stderr, but may share the terminal.
+    suspend_progress_bar(|| writeln!(io::stdout().lock(), "{}", line))
+}
+
+/// Old V7 archives have no user and group names, only numeric ids.
+fn owner_name(name: Option<&[u8]>, id: io::Result<u64>) -> String {
+    match (name, id) {
+        (Some(name), _) => String::from_utf8_lossy(name).into_owned(),
+        (None, Ok(id)) => id.to_string(),
+        (None, Err(_)) => "?".to_owned(),
+    }
+}
+
+fn format_flag_group(group: u32) -> &'static str {
+    match group {
+        0 => "---",
+        1 => "--x",
+        2 => "-w-",
+        3 => "-wx",
+        4 => "r--",
+        5 => "r-x",
+        6 => "rw-",
+        7 => "rwx",
+        _ => unreachable!(),
+    }
+}
+
+fn decode_mod(mode: u32) -> String {
+    let mut res = String::with_capacity(9);
+    // setuid, setgid and sticky replace the exec flag of their group, in
+    // lower case when the exec bit is set too.
+    for (offset, special, flag) in [(6u32, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let group = format_flag_group((mode >> offset) & 0x7);
+        if mode & special == 0 {
+            res.push_str(group);
+        } else {
+            res.push_str(&group[..2]);
+            res.push(if group.ends_with('x') {
+                flag
+            } else {
+                flag.to_ascii_uppercase()
+            });
+        }
+    }
+    res
+}
+
+/// Old V7 archives have no dedicated entry type for dirs, marking them with
+/// the trailing slash.
+pub(crate) fn is_dir_header(header: &tar::Header) -> bool {
+    match header.entry_type() {
+        tar::EntryType::Directory => true,
+        tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => {
+            header.path_bytes().ends_with(b"/")
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn entry_type_char(header: &tar::Header) -> char {
+    if is_dir_header(header) {
+        return 'd';
+    }
+    if header.entry_type().as_byte() == GNU_MULTIVOLUME_TYPE {
+        return 'M';
+    }
+    match header.entry_type() {
+        tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => '-',
+        tar::EntryType::Link => 'h',
+        tar::EntryType::Symlink => 'l',
+        tar::EntryType::Char => 'c',
+        tar::EntryType::Block => 'b',
+        tar::EntryType::Fifo => 'p',
+        tar::EntryType::GNULongName | tar::EntryType::GNULongLink => 'L',
+        _ => '?',
+    }
+}
+
+/// The header the entry is written with.  The tar crate reads GNU sparse
+/// files expanded, so they are written as regular files of their real size,
+/// which is what their data takes in the volume.  The size of a PAX `size`
+/// record goes to the header too, for the volume sizes to count it.
+pub(crate) fn data_header<R: io::Read>(entry: &tar::Entry<R>) -> tar::Header {
+    let mut header = entry.header().clone();
+    if header.entry_size().ok() != Some(entry.size()) && !header.entry_type().is_gnu_sparse() {
+        header.set_size(entry.size());
+        header.set_cksum();
+    }
+    if header.entry_type().is_gnu_sparse() {
+        header.set_entry_type(tar::EntryType::Regular);
+        if let Some(gnu) = header.as_gnu_mut() {
+            for chunk in &mut gnu.sparse {
+                chunk.offset = [0; 12];
+                chunk.numbytes = [0; 12];
+            }
+            gnu.isextended = [0];
+            gnu.realsize = [0; 12];
+        }
+        header.set_size(entry.size());
+        header.set_cksum();
+    }
+    header
+}
+
+/// Size of the entry's data in the input: a PAX `size` record overrides the
+/// header's, and GNU sparse files take what their header says rather than
+/// their expanded size.
+pub(crate) fn stream_size<R: io::Read>(entry: &tar::Entry<R>) -> io::Result<u64> {
+    match entry.header().entry_type().is_gnu_sparse() {
+        true => entry.header().entry_size(),
+        false => Ok(entry.size()),
+    }
+}
+
+/// Members that describe the following entry instead of being entries
+/// themselves.
+pub(crate) fn is_extension(header: &tar::Header) -> bool {
+    let entry_type = header.entry_type();
+    entry_type.is_gnu_longname()
+        || entry_type.is_gnu_longlink()
+        || entry_type.is_pax_local_extensions()
+}
+
+/// Header checksum is computed with the checksum field filled by spaces.
+pub(crate) fn is_valid_header(block: &[u8; TAR_HEADER_SIZE as usize]) -> bool {
+    let header = tar::Header::from_byte_slice(block);
+    let expected: u32 = block[..148]
+        .iter()
+        .chain(&[b' '; 8])
+        .chain(&block[156..])
+        .map(|&b| u32::from(b))
+        .sum();
+    header.cksum().map(|c| c == expected).unwrap_or(false)
+}
+
+/// Extension members that go before a rewritten entry, as its header alone
+/// cannot hold them.
+#[derive(Clone, Default)]
+pub(crate) struct Extensions {
+    /// The path and the link name, if they don't fit into the header.
+    path: Option<Vec<u8>>,
+    pub(crate) link_name: Option<Vec<u8>>,
+    /// Whether the names go to PAX records rather than to GNU members, the
+    /// way they came in the input.
+    pax_names: bool,
+    /// Other PAX records, keys and values, in the input order.
+    pub(crate) pax: Vec<(String, Vec<u8>)>,
+}
+
+impl Extensions {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.path.is_none() && self.link_name.is_none() && self.pax.is_empty()
+    }
+
+    /// GNU long name and link members, encoded as tar blocks.
+    pub(crate) fn long_names(&self) -> Vec<u8> {
+        let mut members = vec![];
+        if !self.pax_names {
+            if let Some(path) = &self.path {
+                encode_long_name(&mut members, tar::EntryType::GNULongName, path);
+            }
+            if let Some(link_name) = &self.link_name {
+                encode_long_name(&mut members, tar::EntryType::GNULongLink, link_name);
+            }
+        }
+        members
+    }
+
+    /// Encoded PAX records, including the names if they go there.
+    pub(crate) fn pax_records(&self) -> Vec<u8> {
+        let mut records = vec![];
+        if self.pax_names {
+            if let Some(path) = &self.path {
+                encode_pax_record(&mut records, "path", path);
+            }
+            if let Some(link_name) = &self.link_name {
+                encode_pax_record(&mut records, "linkpath", link_name);
+            }
+        }
+        for (key, value) in &self.pax {
+            encode_pax_record(&mut records, key, value);
+        }
+        records
+    }
+
+    /// Set the PAX record, replacing the one with the key, if any.
+    pub(crate) fn set_pax(&mut self, key: &str, value: &[u8]) {
+        self.remove_pax(key);
+        self.pax.push((key.to_owned(), value.to_vec()));
+    }
+
+    /// Drop the PAX record of a header field that is rewritten, as it would
+    /// override the field.
+    fn remove_pax(&mut self, key: &str) {
+        self.pax.retain(|(k, _)| k != key);
+    }
+
+    pub(crate) fn footprint(&self) -> u64 {
+        self.long_names().len() as u64 + pax_footprint(&self.pax_records())
+    }
+}
+
+/// The PAX record of the first part of a --multi-volume file with the whole
+/// file size.
+pub(crate) const MULTI_VOLUME_SIZE_KEY: &str = "SPLITAR.multivolume.size";
+/// Name of the GNU long name and link members.
+const LONG_LINK_NAME: &[u8] = b"././@LongLink";
+/// Size of the header name and link name fields.
+const NAME_FIELD_SIZE: usize = 100;
+/// GNU entry type of a file part continued from the previous volume.
+pub(crate) const GNU_MULTIVOLUME_TYPE: u8 = b'M';
+
+/// Collect the entry's extension members, which are written again with the
+/// rewritten entry: long names and all the PAX records, like `size` and
+/// `SCHILY.xattr.*`.  The records of the fields that options rewrite are
+/// dropped then.  Long names come in the form they had in the input, GNU
+/// members or PAX records.
+pub(crate) fn preserved_extensions<R: io::Read>(
+    entry: &mut tar::Entry<R>,
+) -> ah::Result<Extensions> {
+    let mut extensions = Extensions::default();
+    if let Some(pax_extensions) = entry.pax_extensions()? {
+        for extension in pax_extensions {
+            let extension = extension?;
+            let key = extension.key().context("non-UTF8 PAX record key")?;
+            let value = extension.value_bytes();
+            match key {
+                "path" => extensions.path = Some(value.to_vec()),
+                "linkpath" => extensions.link_name = Some(value.to_vec()),
+                _ => extensions.set_pax(key, value),
+            }
+        }
+        extensions.pax_names = extensions.path.is_some() || extensions.link_name.is_some();
+    }
+    // Otherwise, the names that differ from the header ones came in GNU
+    // members.
+    let header = entry.header();
+    let path = entry.path_bytes();
+    if extensions.path.is_none() && path != header.path_bytes() {
+        extensions.path = Some(path.into_owned());
+    }
+    if let Some(link_name) = entry.link_name_bytes() {
+        if extensions.link_name.is_none() && Some(&link_name) != header.link_name_bytes().as_ref() {
+            extensions.link_name = Some(link_name.into_owned());
+        }
+    }
+    Ok(extensions)
+}
+
+/// Set the path of a rewritten entry.  A path that doesn't fit into the
+/// header goes to the extension members, with its start in the header.
+pub(crate) fn set_entry_path(header: &mut tar::Header, extensions: &mut Extensions, path: &[u8]) {
+    let mut fits = path.len() <= NAME_FIELD_SIZE;
+    let mut name = path;
+    if let Some(ustar) = header.as_ustar_mut() {
+        ustar.prefix = [0; 155];
+        // Like tar does, the dirs that don't fit go to the prefix.
+        if !fits {
+            let split = path.iter().enumerate().position(|(pos, &c)| {
+                c == b'/' && pos <= ustar.prefix.len() && path.len() - pos - 1 <= NAME_FIELD_SIZE
+            });
+            if let Some(pos) = split.filter(|&pos| pos > 0 && pos + 1 < path.len()) {
+                ustar.prefix[..pos].copy_from_slice(&path[..pos]);
+                name = &path[pos + 1..];
+                fits = true;
+            }
+        }
+    }
+    set_name_field(&mut header.as_old_mut().name, name);
+    extensions.path = (!fits).then(|| path.to_vec());
+}
+
+/// Set the link name of a rewritten entry, like [`set_entry_path`] does.
+pub(crate) fn set_entry_link_name(
+    header: &mut tar::Header,
+    extensions: &mut Extensions,
+    link_name: &[u8],
+) {
+    set_name_field(&mut header.as_old_mut().linkname, link_name);
+    extensions.link_name = (link_name.len() > NAME_FIELD_SIZE).then(|| link_name.to_vec());
+}
+
+/// Largest numbers that the octal fields of ustar headers hold: mode, owner
+/// and device ones, and size and mtime ones.
+const MAX_OCTAL_SHORT: u64 = 0o7777777;
+const MAX_OCTAL_LONG: u64 = 0o77777777777;
+
+/// A header of the --format with the fields of the given one, and the path
+/// and link name it is written with.  The names and the numbers that don't
+/// fit go to the extension members of the format; in ustar, they are an
+/// error, and other PAX records are dropped.
+pub(crate) fn convert_header(
+    header: &tar::Header,
+    format: TarFormat,
+    path: &[u8],
+    link_name: Option<&[u8]>,
+    extensions: &mut Extensions,
+) -> ah::Result<tar::Header> {
+    let entry_type = header.entry_type();
+    let posix_type = matches!(entry_type.as_byte(), b'0'..=b'7' | 0);
+    let supported = match format {
+        TarFormat::Gnu => !entry_type.is_pax_global_extensions(),
+        TarFormat::Pax => posix_type || entry_type.is_pax_global_extensions(),
+        TarFormat::Ustar => posix_type,
+    };
+    ah::ensure!(
+        supported,
+        "entry type {:?} has no equivalent in the format",
+        char::from(entry_type.as_byte())
+    );
+    // GNU headers are kept with their extra fields, like atime and ctime.
+    let mut converted = match format {
+        TarFormat::Gnu if header.as_gnu().is_some() => header.clone(),
+        TarFormat::Gnu => tar::Header::new_gnu(),
+        TarFormat::Pax | TarFormat::Ustar => tar::Header::new_ustar(),
+    };
+    converted.set_entry_type(entry_type);
+    // Owner names and device numbers are at the same place in both.
+    if header.as_ustar().is_some() || header.as_gnu().is_some() {
+        converted.as_mut_bytes()[265..345].copy_from_slice(&header.as_bytes()[265..345]);
+    }
+    let (mode, uid, gid) = (header.mode()?, header.uid()?, header.gid()?);
+    let (size, mtime) = (header.size()?, header.mtime()?);
+    for (key, value, max) in [
+        ("mode", u64::from(mode), MAX_OCTAL_SHORT),
+        ("uid", uid, MAX_OCTAL_SHORT),
+        ("gid", gid, MAX_OCTAL_SHORT),
+        ("size", size, MAX_OCTAL_LONG),
+        ("mtime", mtime, MAX_OCTAL_LONG),
+    ] {
+        if value > max {
+            match format {
+                TarFormat::Gnu => {}
+                // The header keeps the base-256 number for the readers that
+                // ignore the record.
+                TarFormat::Pax => extensions.set_pax(key, value.to_string().as_bytes()),
+                TarFormat::Ustar => ah::bail!("{} {} doesn't fit into the header", key, value),
+            }
+        }
+    }
+    converted.set_mode(mode);
+    converted.set_uid(uid);
+    converted.set_gid(gid);
+    converted.set_size(size);
+    converted.set_mtime(mtime);
+    set_entry_path(&mut converted, extensions, path);
+    match link_name {
+        Some(link_name) => set_entry_link_name(&mut converted, extensions, link_name),
+        None => extensions.link_name = None,
+    }
+    extensions.pax_names = format == TarFormat::Pax;
+    if format == TarFormat::Ustar {
+        ah::ensure!(
+            extensions.path.is_none() && extensions.link_name.is_none(),
+            "the name doesn't fit into the header"
+        );
+        if !extensions.pax.is_empty() {
+            log::warn!(
+                "Dropping the PAX records of {:?}: --format ustar has no place for them",
+                String::from_utf8_lossy(path)
+            );
+            extensions.pax.clear();
+        }
+    }
+    converted.set_cksum();
+    Ok(converted)
+}
+
+/// The name is NUL-padded, or truncated if it doesn't fit.
+fn set_name_field(field: &mut [u8; NAME_FIELD_SIZE], name: &[u8]) {
+    let len = name.len().min(NAME_FIELD_SIZE);
+    *field = [0; NAME_FIELD_SIZE];
+    field[..len].copy_from_slice(&name[..len]);
+}
+
+/// The dir at the given depth that the path is in or is, like `a/b` for
+/// `a/b/c` and depth 2, or `None` for shallower paths.  Empty and `.`
+/// components are not counted.
+pub(crate) fn dir_at_depth(path: &[u8], is_dir: bool, depth: usize) -> Option<&[u8]> {
+    let mut seen = 0;
+    let mut start = 0;
+    while start < path.len() {
+        let end = path[start..]
+            .iter()
+            .position(|&c| c == b'/')
+            .map_or(path.len(), |pos| start + pos);
+        let component = &path[start..end];
+        if !component.is_empty() && component != b"." {
+            seen += 1;
+            if seen == depth {
+                let in_dir = path[end..].iter().any(|&c| c != b'/');
+                return (in_dir || is_dir).then_some(&path[..end]);
+            }
+        }
+        start = end + 1;
+    }
+    None
+}
+
+/// The path without its first `count` components, like `tar
+/// --strip-components` makes it, or `None` if nothing is left.  Repeated
+/// slashes delimit a single component.
+pub(crate) fn strip_components(path: &[u8], count: usize) -> Option<&[u8]> {
+    fn trim_slashes(path: &[u8]) -> &[u8] {
+        let start = path.iter().position(|&c| c != b'/').unwrap_or(path.len());
+        &path[start..]
+    }
+    let mut rest = path;
+    for _ in 0..count {
+        rest = trim_slashes(rest);
+        let end = rest.iter().position(|&c| c == b'/')?;
+        rest = &rest[end..];
+    }
+    let rest = trim_slashes(rest);
+    (!rest.is_empty()).then_some(rest)
+}
+
+/// A GNU long name member is a NUL-terminated name as the data of a
+/// `././@LongLink` entry, the way GNU tar writes it.
+fn encode_long_name(out: &mut Vec<u8>, entry_type: tar::EntryType, name: &[u8]) {
+    let mut header = tar::Header::new_gnu();
+    header.as_gnu_mut().unwrap().name[..LONG_LINK_NAME.len()].copy_from_slice(LONG_LINK_NAME);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(name.len() as u64 + 1);
+    header.set_entry_type(entry_type);
+    header.set_cksum();
+    out.extend_from_slice(header.as_bytes());
+    out.extend_from_slice(name);
+    let len = name.len() as u64 + 1;
+    out.resize(out.len() + (padded_size(len) - len + 1) as usize, 0);
+}
+
+/// The first malformed numeric header field that is not needed to read the
+/// archive, unlike the size.
+pub(crate) fn bad_header_field(header: &tar::Header) -> Option<&'static str> {
+    if header.mode().is_err() {
+        return Some("mode");
+    }
+    if header.uid().is_err() {
+        return Some("uid");
+    }
+    if header.gid().is_err() {
+        return Some("gid");
+    }
+    if header.mtime().is_err() {
+        return Some("mtime");
+    }
+    if matches!(
+        header.entry_type(),
+        tar::EntryType::Block | tar::EntryType::Char
+    ) && (header.device_major().is_err() || header.device_minor().is_err())
+    {
+        return Some("device");
+    }
+    None
+}
+
+/// The header of a --multi-volume file part that starts at the offset.
+/// Only the 100-byte name field is kept, as the parts are matched by offsets.
+pub(crate) fn continuation_header(header: &tar::Header, offset: u64, size: u64) -> tar::Header {
+    let mut part = tar::Header::new_gnu();
+    let gnu = part.as_gnu_mut().unwrap();
+    gnu.name = header.as_old().name;
+    set_gnu_number(&mut gnu.offset, offset);
+    part.set_mode(header.mode().unwrap_or(0o644));
+    part.set_mtime(header.mtime().unwrap_or(0));
+    part.set_uid(header.uid().unwrap_or(0));
+    part.set_gid(header.gid().unwrap_or(0));
+    // Names are shorter than the fields they come from.
+    if let Ok(Some(name)) = header.username() {
+        let _ = part.set_username(name);
+    }
+    if let Ok(Some(name)) = header.groupname() {
+        let _ = part.set_groupname(name);
+    }
+    part.set_entry_type(tar::EntryType::new(GNU_MULTIVOLUME_TYPE));
+    part.set_size(size);
+    part.set_cksum();
+    part
+}
+
+/// GNU numeric fields are octal, or base-256 with the high bit set for
+/// values that don't fit.
+fn set_gnu_number(field: &mut [u8; 12], value: u64) {
+    if value < 1 << 33 {
+        field.copy_from_slice(format!("{:011o}\0", value).as_bytes());
+    } else {
+        field.fill(0);
+        field[0] = 0x80;
+        field[4..].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+pub(crate) fn gnu_number(field: &[u8; 12]) -> ah::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(u64::from_be_bytes(field[4..].try_into().unwrap()));
+    }
+    let digits = std::str::from_utf8(field)
+        .ok()
+        .map(|s| s.trim_matches(|c| c == '\0' || c == ' '))
+        .ok_or_else(|| ah::anyhow!("malformed GNU numeric field {:?}", field))?;
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .with_context(|| format!("malformed GNU numeric field {:?}", field))
+}
+
+/// A record is "<length> <key>=<value>\n", the length including itself.
+pub(crate) fn encode_pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    out.extend(format!("{} {}=", len, key).as_bytes());
+    out.extend(value);
+    out.push(b'\n');
+}
+
+/// The `size` record of PAX records, which overrides the size in the header
+/// of their entry.
+pub(crate) fn pax_size(records: &[u8]) -> Option<u64> {
+    tar::PaxExtensions::new(records)
+        .filter_map(Result::ok)
+        .filter(|record| record.key() == Ok("size"))
+        .last()
+        .and_then(|record| record.value().ok()?.parse().ok())
+}
+
+pub(crate) fn pax_footprint(records: &[u8]) -> u64 {
+    if records.is_empty() {
+        0
+    } else {
+        TAR_HEADER_SIZE + padded_size(records.len() as _)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_mod_plain() {
+        assert_eq!(decode_mod(0o755), "rwxr-xr-x");
+        assert_eq!(decode_mod(0o640), "rw-r-----");
+    }
+
+    #[test]
+    fn decode_mod_setuid() {
+        assert_eq!(decode_mod(0o4755), "rwsr-xr-x");
+        assert_eq!(decode_mod(0o4644), "rwSr--r--");
+    }
+
+    #[test]
+    fn decode_mod_setgid() {
+        assert_eq!(decode_mod(0o2755), "rwxr-sr-x");
+        assert_eq!(decode_mod(0o2644), "rw-r-Sr--");
+    }
+
+    #[test]
+    fn decode_mod_sticky() {
+        assert_eq!(decode_mod(0o1777), "rwxrwxrwt");
+        assert_eq!(decode_mod(0o1666), "rw-rw-rwT");
+    }
+}
@@ -0,0 +1,459 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! The input archive: decompressing it and filtering its stream.
+
+use crate::{
+    args::SizeZeroPolicy,
+    header::{is_dir_header, is_extension, is_valid_header, pax_size},
+    padded_size, shell,
+    threads::read_in_background,
+    SubprocessFailure, TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    io,
+    process::{Child, ChildStdout, Command, Stdio},
+    thread,
+};
+
+/// Detects entries that have zero size in the header, but carry data
+/// nevertheless, desyncing the stream; the tar crate fails on the first data
+/// block then, and cannot recover.  Such an entry header is held back until
+/// the next block is seen, then the `SizeZeroPolicy` is applied.  With
+/// `trust-stream`, the entry's data is kept in memory until the next valid
+/// header is found.
+///
+/// Bytes after the end-of-archive marker are passed as is, but counted, so
+/// that trailing garbage can be reported.
+pub(crate) struct SizeZeroFilter<R> {
+    inner: R,
+    policy: SizeZeroPolicy,
+    // Data bytes of the current entry to pass as is.
+    remaining: u64,
+    // Blocks to be returned before reading more.
+    pending: Vec<u8>,
+    pos: usize,
+    // A zero-size header that waits for the next block.
+    held: Option<Box<[u8; TAR_HEADER_SIZE as usize]>>,
+    // The size record of the PAX extended header of the next entry, which
+    // overrides the size in its header.
+    pax_size: Option<u64>,
+    // Past the end-of-archive marker, everything is passed as is.
+    ended: bool,
+    // Bytes read after the marker, and if any of them is not zero.
+    pub(crate) trailing: u64,
+    pub(crate) trailing_garbage: bool,
+}
+
+impl<R: io::Read> SizeZeroFilter<R> {
+    pub(crate) fn new(inner: R, policy: SizeZeroPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            remaining: 0,
+            pending: vec![],
+            pos: 0,
+            held: None,
+            pax_size: None,
+            ended: false,
+            trailing: 0,
+            trailing_garbage: false,
+        }
+    }
+
+    /// Read a full block; a partial one is returned as is for the tar crate
+    /// to report.
+    fn read_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        use io::Read as _;
+
+        let mut block = vec![];
+        self.inner
+            .by_ref()
+            .take(TAR_HEADER_SIZE)
+            .read_to_end(&mut block)?;
+        Ok(if block.is_empty() { None } else { Some(block) })
+    }
+
+    /// Fill `pending` with the next blocks to return.
+    fn refill(&mut self) -> io::Result<()> {
+        let block = match self.read_block()? {
+            Some(block) => block,
+            None => {
+                self.pending
+                    .extend(self.held.take().iter().flat_map(|h| h.iter()));
+                self.ended = true;
+                return Ok(());
+            }
+        };
+        let block: Box<[u8; TAR_HEADER_SIZE as usize]> = match block.try_into() {
+            Ok(block) => block,
+            Err(partial) => {
+                self.pending = partial;
+                self.ended = true;
+                return Ok(());
+            }
+        };
+        let is_zero = block.iter().all(|&b| b == 0);
+        if is_zero || is_valid_header(&block) {
+            self.pending
+                .extend(self.held.take().iter().flat_map(|h| h.iter()));
+            return self.start_entry(block, is_zero);
+        }
+
+        let held = match self.held.take() {
+            Some(held) => held,
+            None => {
+                // Not our business; let the tar crate report it.
+                self.pending.extend(block.iter());
+                return Ok(());
+            }
+        };
+        let path = String::from_utf8_lossy(&tar::Header::from_byte_slice(&*held).path_bytes())
+            .into_owned();
+        if self.policy == SizeZeroPolicy::Fail {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "entry {:?} has zero size in its header, but is followed by data; \
+                     see --on-size-zero",
+                    path
+                ),
+            ));
+        }
+
+        // Collect the data up to the next valid header or end of the input.
+        let mut data = block.to_vec();
+        let next = loop {
+            match self.read_block()? {
+                None => break None,
+                Some(block) => match Box::<[u8; TAR_HEADER_SIZE as usize]>::try_from(block) {
+                    Ok(block) if block.iter().all(|&b| b == 0) || is_valid_header(&block) => {
+                        break Some(block)
+                    }
+                    Ok(block) => data.extend(block.iter()),
+                    Err(partial) => {
+                        data.extend(partial);
+                        break None;
+                    }
+                },
+            }
+        };
+        if self.policy == SizeZeroPolicy::TrustStream {
+            log::warn!(
+                "Entry {:?} has zero size in its header, but {} bytes of data follow; \
+                 keeping them",
+                path,
+                data.len()
+            );
+            let mut header = tar::Header::from_byte_slice(&*held).clone();
+            header.set_size(data.len() as _);
+            header.set_cksum();
+            self.pending.extend(header.as_bytes());
+            self.pending.extend(&data);
+        } else {
+            log::warn!(
+                "Entry {:?} has zero size in its header, but {} bytes of data follow; \
+                 skipping them",
+                path,
+                data.len()
+            );
+        }
+        match next {
+            Some(block) => {
+                let is_zero = block.iter().all(|&b| b == 0);
+                self.start_entry(block, is_zero)
+            }
+            None => {
+                self.ended = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle a valid header block (or an end-of-archive marker).  The
+    /// records of a PAX extended header are read ahead, as they may set the
+    /// size of the next entry.
+    fn start_entry(
+        &mut self,
+        block: Box<[u8; TAR_HEADER_SIZE as usize]>,
+        is_zero: bool,
+    ) -> io::Result<()> {
+        use io::Read as _;
+
+        if is_zero {
+            self.pending.extend(block.iter());
+            self.ended = true;
+            return Ok(());
+        }
+        let header = tar::Header::from_byte_slice(&*block);
+        let entry_type = header.entry_type();
+        let mut size = header.entry_size().unwrap_or(0);
+        if entry_type.is_pax_local_extensions() {
+            let mut records = vec![];
+            self.inner
+                .by_ref()
+                .take(padded_size(size))
+                .read_to_end(&mut records)?;
+            self.pax_size = pax_size(&records[..records.len().min(size as usize)]);
+            self.pending.extend(block.iter());
+            self.pending.extend(records);
+            return Ok(());
+        }
+        // Long names go between the PAX records and their entry.
+        if !is_extension(header) {
+            size = self.pax_size.take().unwrap_or(size);
+        }
+        let carries_data = matches!(
+            entry_type,
+            tar::EntryType::Regular | tar::EntryType::Continuous
+        );
+        if size == 0 && carries_data && !is_dir_header(header) {
+            self.held = Some(block);
+        } else {
+            self.pending.extend(block.iter());
+            self.remaining = padded_size(size);
+        }
+        Ok(())
+    }
+}
+
+impl<R: io::Read> io::Read for SizeZeroFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.pending.len() {
+                let len = buf.len().min(self.pending.len() - self.pos);
+                buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+                self.pos += len;
+                if self.pos == self.pending.len() {
+                    self.pending.clear();
+                    self.pos = 0;
+                }
+                return Ok(len);
+            }
+            if self.ended {
+                let len = self.inner.read(buf)?;
+                self.trailing += len as u64;
+                self.trailing_garbage |= buf[..len].iter().any(|&b| b != 0);
+                return Ok(len);
+            }
+            if self.remaining > 0 {
+                let len = buf
+                    .len()
+                    .min(self.remaining.try_into().unwrap_or(usize::MAX));
+                let len = self.inner.read(&mut buf[..len])?;
+                if len == 0 {
+                    return Ok(0);
+                }
+                self.remaining -= len as u64;
+                return Ok(len);
+            }
+            self.refill()?;
+        }
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Input with the peeked bytes put back.
+type Peeked<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Read the magic bytes, returning them together with a reader that yields
+/// the whole input again.
+pub(crate) fn peek_magic<R: io::Read>(
+    mut input: R,
+    len: usize,
+) -> io::Result<(Vec<u8>, Peeked<R>)> {
+    use io::Read as _;
+
+    let mut magic = vec![];
+    input.by_ref().take(len as _).read_to_end(&mut magic)?;
+    Ok((magic.clone(), io::Cursor::new(magic).chain(input)))
+}
+
+/// Decompress the input in a background thread.  Xz input is decompressed
+/// by its command, which runs in parallel anyway, and uncompressed input is
+/// returned as is.
+pub(crate) fn decompress_in_background(
+    input: Box<dyn io::Read + Send>,
+) -> ah::Result<Box<dyn io::Read>> {
+    let (block, input) =
+        peek_magic(input, TAR_HEADER_SIZE as _).context("failed to read the input")?;
+    match InputCompression::detect(&block) {
+        Some(compression) if compression.command().is_none() => {}
+        Some(_) => return Ok(decompressed_input(Box::new(input), None)?),
+        None => {
+            log::warn!("Input is not compressed, reading it as is");
+            return Ok(Box::new(input));
+        }
+    }
+
+    // A handful of chunks in flight is enough for overlapping.
+    let decoder = decompressed_input(Box::new(input), None)?;
+    Ok(Box::new(read_in_background("decompress", decoder, 16)?))
+}
+
+/// Input compressions detected by magic bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InputCompression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl InputCompression {
+    /// A tar header is checked first, so that a plain archive whose first
+    /// name happens to start with a magic is not taken for a compressed one.
+    pub(crate) fn detect(block: &[u8]) -> Option<Self> {
+        if let Ok(block) = block.try_into() {
+            if is_valid_header(block) {
+                return None;
+            }
+        }
+        let magics: [(&[u8], Self); 4] = [
+            (GZIP_MAGIC, Self::Gzip),
+            (&[0x28, 0xb5, 0x2f, 0xfd], Self::Zstd),
+            (b"BZh", Self::Bzip2),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0], Self::Xz),
+        ];
+        magics
+            .into_iter()
+            .find(|(magic, _)| block.starts_with(magic))
+            .map(|(_, compression)| compression)
+    }
+
+    /// The command to decompress with; gzip, zstd and bzip2 are
+    /// decompressed natively.
+    fn command(self) -> Option<&'static str> {
+        match self {
+            Self::Gzip | Self::Zstd | Self::Bzip2 => None,
+            Self::Xz => Some("xz -dc"),
+        }
+    }
+}
+
+/// The input as a tar stream: it is decompressed with the
+/// --decompress-input command, or by the compression detected by its magic
+/// bytes.  Uncompressed input is returned as is.
+pub(crate) fn decompressed_input(
+    input: Box<dyn io::Read + Send>,
+    decompress: Option<&str>,
+) -> ah::Result<Box<dyn io::Read + Send>> {
+    if let Some(command) = decompress {
+        return Ok(Box::new(DecompressorStdout::spawn(command, input)?));
+    }
+    let (block, input) =
+        peek_magic(input, TAR_HEADER_SIZE as _).context("failed to read the input")?;
+    let compression = match InputCompression::detect(&block) {
+        Some(compression) => compression,
+        None => return Ok(Box::new(input)),
+    };
+    log::info!("Input is {:?}-compressed, decompressing it", compression);
+    if let Some(command) = compression.command() {
+        return Ok(Box::new(DecompressorStdout::spawn(
+            command,
+            Box::new(input),
+        )?));
+    }
+    Ok(match compression {
+        InputCompression::Zstd => {
+            Box::new(zstd::Decoder::new(input).context("failed to start zstd decompression")?)
+        }
+        InputCompression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(input)),
+        _ => Box::new(flate2::read::MultiGzDecoder::new(io::BufReader::new(input))),
+    })
+}
+
+/// Output of a decompressing subprocess, which is fed the input by a
+/// separate thread.  The subprocess status is checked at the end of its
+/// output, so that a failure is not taken for the end of the archive.
+struct DecompressorStdout {
+    child: Child,
+    stdout: ChildStdout,
+    feeder: Option<thread::JoinHandle<io::Result<u64>>>,
+    command: String,
+}
+
+impl DecompressorStdout {
+    fn spawn(command: &str, mut input: Box<dyn io::Read + Send>) -> ah::Result<Self> {
+        let shell = shell();
+        let mut child = Command::new(&shell)
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to start {:?} with shell {:?}", command, shell))?;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let feeder = thread::Builder::new()
+            .name("decompress-input".to_owned())
+            .spawn(move || io::copy(&mut input, &mut stdin))?;
+        Ok(Self {
+            child,
+            stdout,
+            feeder: Some(feeder),
+            command: command.to_owned(),
+        })
+    }
+
+    fn check_finished(&mut self) -> io::Result<()> {
+        let Some(feeder) = self.feeder.take() else {
+            return Ok(());
+        };
+        let fed = feeder.join().expect("internal: input feeder panicked");
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(SubprocessFailure(format!(
+                "input decompressor {:?} failed: exit code {}",
+                self.command,
+                status.code().unwrap_or(-1)
+            ))));
+        }
+        // Decompressors may stop reading at the end of their stream.
+        match fed {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl io::Read for DecompressorStdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stdout.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            self.check_finished()?;
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for DecompressorStdout {
+    fn drop(&mut self) {
+        if self.feeder.is_some() {
+            // Stopped early on an error; the feeder gets a broken pipe.
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
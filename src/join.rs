@@ -0,0 +1,374 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! `splitar join`, which reassembles the volumes.
+
+use crate::{
+    compress::{decompress_command, COMPRESSOR_EXTENSIONS},
+    control::{control_file_path, read_control_file, ControlFile},
+    header::{
+        encode_pax_record, gnu_number, is_dir_header, is_extension, GNU_MULTIVOLUME_TYPE,
+        MULTI_VOLUME_SIZE_KEY,
+    },
+    init_umask,
+    input::decompressed_input,
+    padded_size, set_umasked_mode,
+    volume::VOLUME_INFO_FILE_NAME,
+    TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use clap::Parser;
+use std::{
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+
+/// `splitar join` reassembles the volumes into a single archive.  It is
+/// dispatched by the first argument, as the split itself takes positional
+/// arguments; an input file named `join` can still be given as `./join`.
+#[derive(Parser, Debug)]
+#[command(
+    name = "splitar join",
+    bin_name = "splitar join",
+    about = "Join volumes written by splitar into a single tar archive",
+    version
+)]
+pub(crate) struct JoinArgs {
+    #[arg(
+        long,
+        help = "the --compress command the volumes were written with; \
+                the decompressor is guessed by its program name"
+    )]
+    compress: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "compress",
+        help = "shell command that decompresses a volume from stdin to stdout; \
+                gzip, zstd, bzip2 and xz volumes are detected without it"
+    )]
+    decompress: Option<String>,
+
+    #[arg(
+        short = 'o',
+        long,
+        value_hint = clap::ValueHint::FilePath,
+        help = "write the archive to this file instead of stdout"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        required = true,
+        value_hint = clap::ValueHint::FilePath,
+        help = "volume files in the extraction order, or the output prefix of the \
+                split; its --control-file is used if it has one"
+    )]
+    volumes: Vec<PathBuf>,
+}
+
+/// Volumes written with the output prefix, in the extraction order: the
+/// --dirs-first volume, the main sequence, then the --route-by-extension
+/// sequences by category.  Other files, like scripts and signatures, are
+/// skipped.
+fn find_volumes(prefix: &str) -> ah::Result<Vec<PathBuf>> {
+    let (dir, name_prefix) = match prefix.rsplit_once('/') {
+        Some(("", name_prefix)) => ("/", name_prefix),
+        Some(split) => split,
+        None => (".", prefix),
+    };
+    let is_index = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+    let mut volumes = vec![];
+    for dir_entry in
+        std::fs::read_dir(dir).with_context(|| format!("failed to list volumes in {:?}", dir))?
+    {
+        let path = dir_entry?.path();
+        let Some(rest) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(name_prefix))
+        else {
+            continue;
+        };
+        // Volumes written with --auto-extension.
+        let rest = match rest.rsplit_once('.') {
+            Some((rest, extension)) if COMPRESSOR_EXTENSIONS.contains(&extension) => rest,
+            _ => rest,
+        };
+        let key = match rest.rsplit_once('-') {
+            _ if rest == "dirs" => (0, String::new(), String::new()),
+            _ if is_index(rest) => (1, String::new(), rest.to_owned()),
+            Some((category, index)) if !category.is_empty() && is_index(index) => {
+                (2, category.to_owned(), index.to_owned())
+            }
+            _ => continue,
+        };
+        volumes.push((key, path));
+    }
+    ah::ensure!(
+        !volumes.is_empty(),
+        "no volumes found with prefix {:?}",
+        prefix
+    );
+    // Indices have the same width.
+    volumes.sort();
+    Ok(volumes.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Volumes of the split with the output prefix, listed by its
+/// --control-file if it has one.
+pub(crate) fn split_volumes(prefix: &str) -> ah::Result<(Vec<PathBuf>, Option<ControlFile>)> {
+    let path = control_file_path(prefix);
+    if !path.is_file() {
+        return Ok((find_volumes(prefix)?, None));
+    }
+    let control = read_control_file(&path)?;
+    let volumes = control
+        .volumes
+        .iter()
+        .map(|volume| volume.path.clone())
+        .collect();
+    Ok((volumes, Some(control)))
+}
+
+pub(crate) fn join(args: JoinArgs) -> ah::Result<()> {
+    init_umask();
+    let (volumes, control) = match args.volumes.as_slice() {
+        [prefix] if !prefix.is_file() => split_volumes(&prefix.to_string_lossy())?,
+        volumes => (volumes.to_vec(), None),
+    };
+    if let Some(control) = &control {
+        control.check_sizes()?;
+    }
+    let compress = args
+        .compress
+        .clone()
+        .or_else(|| control.and_then(|control| control.compress));
+    let decompress = match (&args.decompress, &compress) {
+        (Some(decompress), _) => Some(decompress.as_str()),
+        (None, Some(compress)) => Some(decompress_command(compress).ok_or_else(|| {
+            ah::anyhow!(
+                "cannot find out decompressor for --compress {:?}, use --decompress",
+                compress
+            )
+        })?),
+        (None, None) => None,
+    };
+    match &args.output {
+        Some(path) => {
+            // Like volumes, the output appears only when it is complete.
+            let temp_file = tempfile::Builder::new()
+                .prefix(path.file_name().unwrap_or_default())
+                .suffix(".tmp")
+                .tempfile_in(path.parent().unwrap_or_else(|| Path::new(".")))
+                .context("failed to create output tempfile")?;
+            let mut out = io::BufWriter::new(temp_file);
+            join_volumes(&volumes, decompress, &mut out)?;
+            out.into_inner()
+                .map_err(io::IntoInnerError::into_error)
+                .context("failed to write the joined archive")?
+                .persist(path)
+                .with_context(|| format!("failed to write the joined archive {:?}", path))?;
+            set_umasked_mode(path, 0o666)
+        }
+        None => {
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            join_volumes(&volumes, decompress, &mut out)?;
+            out.flush().context("failed to write the joined archive")
+        }
+    }
+}
+
+/// Copy the entries of the volumes as is, without their end-of-archive
+/// blocks.  Dirs recreated by --recreate-dirs are written only at their
+/// first occurrence, with their extension members if any.
+fn join_volumes(
+    volumes: &[PathBuf],
+    decompress: Option<&str>,
+    out: &mut dyn io::Write,
+) -> ah::Result<()> {
+    let mut seen_dirs = std::collections::HashSet::new();
+    // The global PAX headers so far, which every volume starts with.
+    let mut global_headers: Vec<Vec<u8>> = vec![];
+    // A --multi-volume file being joined: its size and bytes joined so far.
+    let mut continued: Option<(u64, u64)> = None;
+    // The size from the PAX records of the first part that comes next.
+    let mut first_part_size = None;
+    for volume in volumes {
+        log::info!("Joining volume {:?}", volume);
+        let file = std::fs::File::open(volume)
+            .with_context(|| format!("failed to open volume {:?}", volume))?;
+        let input = decompressed_input(Box::new(file), decompress)?;
+        let mut archive = tar::Archive::new(io::BufReader::new(input));
+        // Extension members go with the entry they describe; the dir key is
+        // their data and the path, as the header path may be truncated.
+        let mut pending = vec![];
+        let mut pending_key = vec![];
+        let mut repeated_globals = 0;
+        let mut first_member = true;
+        for ent in archive.entries()?.raw(true) {
+            let mut ent = ent.with_context(|| format!("failed to read volume {:?}", volume))?;
+            let mut header = ent.header().clone();
+            // The --volume-info file is about the volume, not a part of the
+            // archive.
+            if std::mem::take(&mut first_member)
+                && header.entry_type().is_file()
+                && &*header.path_bytes() == VOLUME_INFO_FILE_NAME.as_bytes()
+            {
+                continue;
+            }
+            if header.entry_type().as_byte() == GNU_MULTIVOLUME_TYPE {
+                let (size, joined) = continued.as_mut().ok_or_else(|| {
+                    ah::anyhow!(
+                        "volume {:?} continues a file that no previous volume starts",
+                        volume
+                    )
+                })?;
+                let offset = gnu_number(&header.as_gnu().unwrap().offset)?;
+                ah::ensure!(
+                    offset == *joined,
+                    "volume {:?} continues {:?} at offset {}, but {} bytes of it are \
+                     joined; is a volume missing?",
+                    volume,
+                    String::from_utf8_lossy(&header.path_bytes()),
+                    offset,
+                    joined,
+                );
+                *joined += io::copy(&mut ent, out)?;
+                ah::ensure!(
+                    *joined <= *size,
+                    "volume {:?} continues {:?} beyond its size {}",
+                    volume,
+                    String::from_utf8_lossy(&header.path_bytes()),
+                    size,
+                );
+                if *joined == *size {
+                    out.write_all(
+                        &[0; TAR_HEADER_SIZE as usize][..(padded_size(*size) - *size) as usize],
+                    )?;
+                    continued = None;
+                }
+                continue;
+            }
+            if let Some((size, joined)) = continued {
+                ah::bail!(
+                    "volume {:?} does not continue a file of {} bytes, of which {} are \
+                     joined; is a volume missing?",
+                    volume,
+                    size,
+                    joined,
+                );
+            }
+            if is_extension(&header) {
+                let mut data = vec![];
+                io::Read::read_to_end(&mut ent, &mut data)?;
+                if header.entry_type().is_pax_local_extensions() {
+                    if let Some(size) = take_multi_volume_size(&mut data)? {
+                        first_part_size = Some(size);
+                        header.set_size(data.len() as _);
+                        header.set_cksum();
+                        if data.is_empty() {
+                            continue;
+                        }
+                    }
+                }
+                pending.extend_from_slice(header.as_bytes());
+                pending.extend_from_slice(&data);
+                pending.resize(padded_size(pending.len() as _) as _, 0);
+                pending_key.extend_from_slice(&data);
+                continue;
+            }
+            if header.entry_type().is_pax_global_extensions() {
+                let mut data = vec![];
+                io::Read::read_to_end(&mut ent, &mut data)?;
+                if global_headers.get(repeated_globals) == Some(&data) {
+                    repeated_globals += 1;
+                    continue;
+                }
+                out.write_all(header.as_bytes())?;
+                out.write_all(&data)?;
+                out.write_all(
+                    &[0; TAR_HEADER_SIZE as usize]
+                        [..(padded_size(data.len() as _) - data.len() as u64) as usize],
+                )?;
+                global_headers.push(data);
+                repeated_globals = global_headers.len();
+                continue;
+            }
+            if is_dir_header(&header) {
+                pending_key.extend_from_slice(&header.path_bytes());
+                if !seen_dirs.insert(std::mem::take(&mut pending_key)) {
+                    pending.clear();
+                    continue;
+                }
+            }
+            pending_key.clear();
+            out.write_all(&pending)?;
+            pending.clear();
+            if let Some(size) = first_part_size.take() {
+                // The rest and the padding follow in the next volumes.
+                header.set_size(size);
+                header.set_cksum();
+                out.write_all(header.as_bytes())?;
+                continued = Some((size, io::copy(&mut ent, out)?));
+                continue;
+            }
+            out.write_all(header.as_bytes())?;
+            let len = io::copy(&mut ent, out)?;
+            out.write_all(&[0; TAR_HEADER_SIZE as usize][..(padded_size(len) - len) as usize])?;
+        }
+        // Malformed volume; keep it as is.
+        out.write_all(&pending)?;
+        // A decompressor is checked at the end of its output.
+        io::copy(&mut archive.into_inner(), &mut io::sink())
+            .with_context(|| format!("failed to read volume {:?}", volume))?;
+    }
+    if let Some((size, joined)) = continued {
+        ah::bail!(
+            "the last volume ends {} bytes into a file of {} bytes; is a volume missing?",
+            joined,
+            size,
+        );
+    }
+    out.write_all(&[0; 2 * TAR_HEADER_SIZE as usize])?;
+    Ok(())
+}
+
+/// Remove the --multi-volume size record from PAX records, returning the
+/// size if it is there.
+fn take_multi_volume_size(records: &mut Vec<u8>) -> ah::Result<Option<u64>> {
+    let mut size = None;
+    let mut rest = vec![];
+    for record in tar::PaxExtensions::new(records) {
+        let record = record?;
+        let key = record.key().context("non-UTF8 PAX record key")?;
+        if key == MULTI_VOLUME_SIZE_KEY {
+            let value = record.value().ok().and_then(|value| value.parse().ok());
+            size = Some(value.ok_or_else(|| ah::anyhow!("malformed {} PAX record", key))?);
+        } else {
+            encode_pax_record(&mut rest, key, record.value_bytes());
+        }
+    }
+    if size.is_some() {
+        *records = rest;
+    }
+    Ok(size)
+}
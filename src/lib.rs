@@ -0,0 +1,496 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Content-aware splitting of tar archives into volumes that never split
+//! a file in the middle; the library behind the `splitar` command.
+//!
+//! A [`Splitter`] takes the entries of an archive one by one, so that the
+//! caller may filter them first:
+//!
+//! ```no_run
+//! let mut splitter = splitar::Splitter::builder("backup.tar.")
+//!     .max_size(4 << 30)
+//!     .compress("zstd")
+//!     .recreate_dirs()
+//!     .build()?;
+//! let mut archive = tar::Archive::new(std::fs::File::open("backup.tar")?);
+//! for entry in archive.entries()? {
+//!     let entry = entry?;
+//!     if !entry.path_bytes().ends_with(b".tmp") {
+//!         splitter.push_entry(entry)?;
+//!     }
+//! }
+//! let summary = splitter.finish()?;
+//! println!("{} volumes", summary.volumes.len());
+//! # Ok::<(), splitar::Error>(())
+//! ```
+
+mod args;
+mod checksum;
+mod cli;
+mod compress;
+mod control;
+mod dirs;
+mod filter;
+mod header;
+mod input;
+mod join;
+mod manifest;
+mod plan;
+mod progress;
+mod split;
+mod threads;
+mod verify;
+mod volume;
+
+pub use cli::cli_main;
+
+use crate::{
+    args::Args,
+    checksum::ChecksumAlgorithm,
+    dirs::StoredDir,
+    filter::{PathFilter, Selection},
+    manifest::VolumeManifest,
+    plan::Plan,
+    split::CreatedFiles,
+    volume::Volume,
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+const TAR_HEADER_SIZE: u64 = 512;
+/// Two EOF blocks and a header of a single empty entry.
+const MIN_VOLUME_SIZE: u64 = 3 * TAR_HEADER_SIZE;
+
+/// Splitting errors; the `splitar` command exits with the code of
+/// [`Error::exit_code`].  The ones caused by a failed command, a malformed
+/// input or an interrupt are told from `Other` by the causes they chain.
+///
+/// The `anyhow::Error` payloads are not a stable part of the API, and may
+/// become an opaque error type: use them only as [`std::error::Error`]s,
+/// through their messages and `source()` chains.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("file {:?} with its header is larger than --max-size", .0)]
+    FileTooLarge(String),
+    #[error("input has more than --max-entries-total {} entries", .0)]
+    TooManyEntries(u64),
+    /// The entry needs a volume beyond --max-volumes.  It is not written,
+    /// but the split can still be finished with the volumes so far.
+    #[error("input needs more than --max-volumes {} volumes; the split is truncated", .0)]
+    TooManyVolumes(u64),
+    /// The split is stopped by --graceful-interrupt, with the volumes so far
+    /// finished.
+    #[error("interrupted; the split is truncated")]
+    Stopped,
+    /// The next volume may take the volumes over --total-size.  Like with
+    /// `TooManyVolumes`, the split can still be finished.
+    #[error("the next volume may exceed --total-size {}; the split is truncated", .0)]
+    OverTotalSize(u64),
+    /// A --compress, --decompress-input, --sign or other command failed.
+    #[error(transparent)]
+    SubprocessFailed(ah::Error),
+    /// The input is not a well-formed tar archive.
+    #[error(transparent)]
+    MalformedTar(ah::Error),
+    /// Ctrl-C or SIGTERM without --graceful-interrupt, or the interrupt
+    /// flag of the [`Splitter`], left the split incomplete.
+    #[error(transparent)]
+    Interrupted(ah::Error),
+    /// The options are invalid together, or for the input, beyond what
+    /// clap checks.
+    #[error(transparent)]
+    Usage(ah::Error),
+    /// Any other failure, e.g. an I/O error on the output.  Its payload type
+    /// is unstable, like the ones above.
+    #[error(transparent)]
+    Other(ah::Error),
+}
+
+impl Error {
+    /// The exit code of the `splitar` command: 1 for `Other`, 2 for `Usage`
+    /// like for the options clap rejects, and then from 3 on in the order of
+    /// the variants.  The codes are stable.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::FileTooLarge(_) => 3,
+            Self::TooManyEntries(_) => 4,
+            Self::TooManyVolumes(_) => 5,
+            Self::Stopped => 6,
+            Self::OverTotalSize(_) => 7,
+            Self::SubprocessFailed(_) => 8,
+            Self::MalformedTar(_) => 9,
+            Self::Interrupted(_) => 10,
+            Self::Usage(_) => 2,
+            Self::Other(_) => 1,
+        }
+    }
+}
+
+impl From<ah::Error> for Error {
+    fn from(source: ah::Error) -> Self {
+        if has_cause(&source, |cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::Interrupted)
+        }) {
+            Self::Interrupted(source)
+        } else if has_cause(&source, |cause| cause.is::<SubprocessFailure>()) {
+            Self::SubprocessFailed(source)
+        } else if has_cause(&source, |cause| cause.is::<MalformedTar>()) {
+            Self::MalformedTar(source)
+        } else {
+            Self::Other(source)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        ah::Error::from(source).into()
+    }
+}
+
+/// Whether the error or any of its causes, including the ones I/O errors
+/// are made of, is the one.
+fn has_cause(error: &ah::Error, is: impl Fn(&(dyn std::error::Error + 'static)) -> bool) -> bool {
+    error.chain().any(|cause| {
+        is(cause)
+            || cause
+                .downcast_ref::<io::Error>()
+                .and_then(io::Error::get_ref)
+                .is_some_and(|inner| is(inner))
+    })
+}
+
+/// A command that exited with an error, for [`Error::SubprocessFailed`].
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct SubprocessFailure(String);
+
+/// An error of the tar parser of the input, for [`Error::MalformedTar`].
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+struct MalformedTar(io::Error);
+
+/// Tell the errors of the tar parser from the reading ones: read errors
+/// are not `Other`, unless they are of the input decompressor.
+fn input_error(e: io::Error) -> ah::Error {
+    let decompressor = e
+        .get_ref()
+        .is_some_and(|inner| inner.is::<SubprocessFailure>());
+    match e.kind() {
+        io::ErrorKind::Other | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+            if !decompressor =>
+        {
+            MalformedTar(e).into()
+        }
+        _ => e.into(),
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Size rounded up to the tar block boundary.
+fn padded_size(size: u64) -> u64 {
+    size.div_ceil(TAR_HEADER_SIZE) * TAR_HEADER_SIZE
+}
+
+fn shell() -> OsString {
+    std::env::var_os("SHELL")
+        .unwrap_or_else(|| OsString::from_str("/bin/bash").expect("internal: can't run on this os"))
+}
+
+/// The split in progress: entries pushed into it are written to volumes
+/// `<output prefix><index>` the way the `splitar` command writes them.
+pub struct Splitter {
+    vol_idx: usize,
+    args: Args,
+    dirs: patricia_tree::PatriciaMap<Box<StoredDir>>,
+    // Bytes the dirs take when they are recreated.
+    dirs_footprint: u64,
+    // We keep it optional, as we take and set back.
+    // I.e. it is optional only *within* certain functions.
+    volume: Option<Volume>,
+    interrupt_flag: Arc<AtomicBool>,
+    // In verbatim mode, extension members (long names, PAX records like
+    // ACLs) come as separate entries, and they must not be separated from
+    // the entry they describe.  They are kept until the entry comes, and
+    // written together with it.
+    pending_extensions: Vec<(tar::Header, Vec<u8>)>,
+    // Global PAX headers apply to all the entries after them, so every new
+    // volume starts with the ones so far.
+    global_headers: Vec<(tar::Header, Vec<u8>)>,
+    plan: Option<Plan>,
+    selection: Option<Selection>,
+    filter: Option<PathFilter>,
+    entry_idx: usize,
+    // Input offsets of the current entry, including its extension members,
+    // and of the next one.
+    entry_start: u64,
+    next_entry_start: u64,
+    // Entries without extension members, for --max-entries-total, and if
+    // the current entry is one of them.
+    entries_total: u64,
+    entry_counted: bool,
+    // The --keep-dirs-together dir of the last entry in one.
+    kept_dir: Option<Vec<u8>>,
+    // Finished volumes, in the extraction order.
+    finished_volumes: Vec<PathBuf>,
+    // Volumes written, but possibly still compressed with --jobs, oldest
+    // first.  They are finished in this order, so that the volumes are
+    // listed, signed and passed to --tape-command in order.
+    finishing: VecDeque<Volume>,
+    // The --route-by-extension category of the current volume, empty for
+    // the main sequence.  Other sequences wait in `idle_streams` with
+    // their volume indices.
+    stream: String,
+    idle_streams: HashMap<String, (Volume, usize)>,
+    // Shared with the volumes, which record their entries.
+    manifest: Option<Rc<RefCell<VolumeManifest>>>,
+    // The --checksums lines of the finished volumes.
+    checksums: String,
+    // The checksums of the finished volumes, for --control-file.
+    volume_checksums: HashMap<PathBuf, Vec<(ChecksumAlgorithm, String)>>,
+    // Tar stream bytes of the finished volumes, and the sizes of their
+    // files.
+    bytes_written: u64,
+    bytes_stored: u64,
+    // Entries that don't fit into a volume, written anyway.
+    oversized: u64,
+    // With --dereference-split-links, the input headers and their offsets
+    // of the regular files written, by their paths; hard links to them map
+    // to them too.
+    link_targets: HashMap<Vec<u8>, (tar::Header, u64)>,
+    // With --atomic, the files of the run, removed if it fails.
+    created_files: Option<CreatedFiles>,
+    // Volumes started, of all the streams, for --max-volumes; the split
+    // has stopped at the limit.
+    volumes_started: u64,
+    truncated: bool,
+    // --recreate-dirs-limit is hit, and the next dirs are not kept.
+    dirs_limit_reached: bool,
+}
+
+/// What [`Splitter::finish`] has written.
+#[derive(Clone, Debug)]
+pub struct Summary {
+    /// Volume files in the extraction order.
+    pub volumes: Vec<PathBuf>,
+    /// Entries pushed, not counting extension members and skipped entries.
+    pub entries: u64,
+    /// Size of the tar streams of the volumes, before compression.
+    pub bytes: u64,
+    /// Size of the volume files, after compression, if any.
+    pub stored_bytes: u64,
+    /// Entries larger than `--max-size`, written anyway.
+    pub oversized: u64,
+}
+
+/// Options of a [`Splitter`].  They are the `splitar` command line options
+/// and are checked the same way, so that any of them can be given with
+/// [`option`](Self::option) or [`flag`](Self::flag).  Options that scan the
+/// input first, like `--num-volumes`, are not supported.
+pub struct SplitterBuilder {
+    output_prefix: String,
+    args: Vec<OsString>,
+    interrupt_flag: Arc<AtomicBool>,
+}
+
+impl SplitterBuilder {
+    /// `--max-size` in bytes.
+    pub fn max_size(self, size: u64) -> Self {
+        self.option("--max-size", size.to_string())
+    }
+
+    /// `--max-files`: max number of entries per volume.
+    pub fn max_files(self, count: u64) -> Self {
+        self.option("--max-files", count.to_string())
+    }
+
+    /// `--compress`: a shell command that compresses each volume.
+    pub fn compress(self, command: &str) -> Self {
+        self.option("--compress", command)
+    }
+
+    /// `--compress-program` with its `--compress-arg`s, run without a shell.
+    pub fn compress_program(mut self, program: &str, args: &[&str]) -> Self {
+        self = self.option("--compress-program", program);
+        for arg in args {
+            self = self.option("--compress-arg", *arg);
+        }
+        self
+    }
+
+    /// `--jobs`: how many volumes may be compressed at once.
+    pub fn jobs(self, count: u64) -> Self {
+        self.option("--jobs", count.to_string())
+    }
+
+    /// `--gzip` with `--compress-level`.
+    pub fn gzip(self, level: u32) -> Self {
+        self.flag("--gzip")
+            .option("--compress-level", level.to_string())
+    }
+
+    /// `--zstd` with `--compress-level`.
+    pub fn zstd(self, level: u32) -> Self {
+        self.flag("--zstd")
+            .option("--compress-level", level.to_string())
+    }
+
+    /// `--bzip2` with `--compress-level`.
+    pub fn bzip2(self, level: u32) -> Self {
+        self.flag("--bzip2")
+            .option("--compress-level", level.to_string())
+    }
+
+    /// `--recreate-dirs`.
+    pub fn recreate_dirs(self) -> Self {
+        self.flag("--recreate-dirs")
+    }
+
+    /// `--verbatim`; the entries have to be read with
+    /// [`tar::Entries::raw`] then.
+    pub fn verbatim(self) -> Self {
+        self.flag("--verbatim")
+    }
+
+    /// Any command line option with a value, e.g.
+    /// `.option("--size-schedule", "0:100M,*:4G")`.
+    pub fn option(mut self, name: &str, value: impl Into<OsString>) -> Self {
+        self.args.push(name.into());
+        self.args.push(value.into());
+        self
+    }
+
+    /// Any command line flag, e.g. `.flag("--fail-on-large-file")`.
+    pub fn flag(mut self, name: &str) -> Self {
+        self.args.push(name.into());
+        self
+    }
+
+    /// Stop at the next write when the flag is set, like Ctrl-C stops the
+    /// command.
+    pub fn interrupt_flag(mut self, interrupt_flag: Arc<AtomicBool>) -> Self {
+        self.interrupt_flag = interrupt_flag;
+        self
+    }
+
+    /// Check the options and start the first volume.
+    pub fn build(self) -> Result<Splitter> {
+        // The input is the caller's business.
+        let argv: Vec<_> = [OsString::from("splitar")]
+            .into_iter()
+            .chain(self.args)
+            .chain(["-".into(), self.output_prefix.into()])
+            .collect();
+        let matches = <Args as clap::CommandFactory>::command()
+            .try_get_matches_from(&argv)
+            .context("invalid splitter options")
+            .map_err(Error::Usage)?;
+        let args = Args::from_matches(&argv, &matches).map_err(Error::Usage)?;
+        args.validate().map_err(Error::Usage)?;
+        if args.num_volumes.is_some() || args.pack {
+            return Err(Error::Usage(ah::anyhow!(
+                "{} scans the input first; only the splitar command can do it",
+                if args.pack { "--pack" } else { "--num-volumes" },
+            )));
+        }
+        Splitter::start(args, self.interrupt_flag)
+    }
+}
+
+impl Splitter {
+    /// Options for a split into `<output_prefix><index>` volumes.
+    pub fn builder(output_prefix: impl Into<String>) -> SplitterBuilder {
+        SplitterBuilder {
+            output_prefix: output_prefix.into(),
+            args: vec![],
+            interrupt_flag: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// The umask of the process, read once when a [`Splitter`] starts: reading
+/// it takes setting it, and a file that another thread creates meanwhile
+/// would get the temporary one.  The umask is the process's, so a library
+/// caller that creates files in other threads has to start splitters
+/// before them.
+#[cfg(unix)]
+static UMASK: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+
+#[cfg(unix)]
+fn init_umask() -> u32 {
+    *UMASK.get_or_init(|| {
+        // Is safe as the umask is set back right away.
+        //
+        // N.B. On Linux, one can get own umask by reading the
+        // `/proc/self/status` file.
+        let umask = unsafe {
+            let umask = libc::umask(0);
+            libc::umask(umask);
+            umask
+        };
+        umask as u32
+    })
+}
+
+#[cfg(not(unix))]
+fn init_umask() {}
+
+/// tempfile crate creates files that only owner can read; we reset
+/// the file permissions to a default mode, masked by the umask.
+#[cfg(unix)]
+fn set_umasked_mode(file: &Path, mode: u32) -> ah::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let result_mode = mode & !init_umask();
+    std::fs::set_permissions(file, std::fs::Permissions::from_mode(result_mode)).with_context(
+        || {
+            format!(
+                "failed to set permission {} to the output file {:?}",
+                result_mode, file
+            )
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_umasked_mode(file: &Path, _mode: u32) -> ah::Result<()> {
+    // I have no better idea.
+    log::warn!(
+        "tempfile permissions on the output path {:?} haven't been changed on this OS",
+        file
+    );
+    Ok(())
+}
@@ -0,0 +1,284 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! The --manifest and --bundle-metadata files.
+
+use crate::{
+    args::Args,
+    checksum::checksum_file_path,
+    control::control_file_path,
+    header::entry_type_char,
+    set_umasked_mode,
+    volume::{file_header, UNPACK_SCRIPT_SUFFIX},
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    collections::HashMap,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+
+/// JSON Lines records of the written entries for --manifest.  Like volumes,
+/// it is written to a temp file that is renamed when the split is done.
+/// The records are checked against --resume-manifest for the volumes that
+/// are resumed.
+pub(crate) struct VolumeManifest {
+    output: Option<(io::BufWriter<tempfile::NamedTempFile>, PathBuf)>,
+    // Records of --resume-manifest by the volume name in JSON, and the
+    // records of this split for the same volumes.
+    earlier: Option<HashMap<String, Vec<String>>>,
+    current: HashMap<String, Vec<String>>,
+}
+
+impl VolumeManifest {
+    pub(crate) fn new(
+        path: Option<&Path>,
+        resume_manifest: Option<&Path>,
+    ) -> ah::Result<Option<Self>> {
+        if path.is_none() && resume_manifest.is_none() {
+            return Ok(None);
+        }
+        let output = match path {
+            Some(path) => {
+                let temp_file = tempfile::Builder::new()
+                    .prefix(path.file_name().unwrap_or_default())
+                    .suffix(".tmp")
+                    .tempfile_in(path.parent().unwrap_or_else(|| Path::new(".")))
+                    .context("failed to create manifest tempfile")?;
+                Some((io::BufWriter::new(temp_file), path.to_owned()))
+            }
+            None => None,
+        };
+        let earlier = match resume_manifest {
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read --resume-manifest {:?}", path))?;
+                let mut earlier = HashMap::<_, Vec<_>>::new();
+                for (lineno, line) in data.lines().enumerate() {
+                    let volume = line
+                        .strip_prefix("{\"volume\":")
+                        .and_then(|rest| rest.split_once(",\"path\":"))
+                        .map(|(volume, _)| volume)
+                        .with_context(|| {
+                            format!(
+                                "--resume-manifest {:?} has no volume at line {}",
+                                path,
+                                lineno + 1
+                            )
+                        })?;
+                    earlier
+                        .entry(volume.to_owned())
+                        .or_default()
+                        .push(line.to_owned());
+                }
+                Some(earlier)
+            }
+            None => None,
+        };
+        Ok(Some(Self {
+            output,
+            earlier,
+            current: HashMap::new(),
+        }))
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        volume: &Path,
+        header: &tar::Header,
+        offset: Option<u64>,
+        recreated: bool,
+    ) -> ah::Result<()> {
+        let volume = json_string(volume.file_name().unwrap_or_default().as_encoded_bytes());
+        let line = format!(
+            "{{\"volume\":{},\"path\":{},\"size\":{},\"type\":\"{}\",\"offset\":{},\"recreated\":{}}}",
+            volume,
+            json_string(&header.path_bytes()),
+            header.size()?,
+            entry_type_name(header),
+            offset.map_or("null".to_owned(), |offset| offset.to_string()),
+            recreated,
+        );
+        if let Some((out, _)) = &mut self.output {
+            writeln!(out, "{}", line).context("failed to write manifest")?;
+        }
+        if self
+            .earlier
+            .as_ref()
+            .is_some_and(|earlier| earlier.contains_key(&volume))
+        {
+            self.current.entry(volume).or_default().push(line);
+        }
+        Ok(())
+    }
+
+    /// Check that the resumed volume has the records it had in
+    /// --resume-manifest, if any.
+    pub(crate) fn check_resumed(&mut self, volume: &Path) -> ah::Result<()> {
+        let Some(earlier) = &mut self.earlier else {
+            return Ok(());
+        };
+        let key = json_string(volume.file_name().unwrap_or_default().as_encoded_bytes());
+        let earlier = earlier.remove(&key).with_context(|| {
+            format!(
+                "existing volume {:?} is not in --resume-manifest; remove it or run \
+                 without --resume",
+                volume
+            )
+        })?;
+        let current = self.current.remove(&key).unwrap_or_default();
+        let mismatch =
+            (0..earlier.len().max(current.len())).find(|&i| earlier.get(i) != current.get(i));
+        if let Some(i) = mismatch {
+            ah::bail!(
+                "existing volume {:?} does not match --resume-manifest: record {} is {} \
+                 instead of {}; remove it or run without --resume",
+                volume,
+                i + 1,
+                current.get(i).map_or("missing", String::as_str),
+                earlier.get(i).map_or("missing", String::as_str),
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn persist(self) -> ah::Result<()> {
+        let Some((out, path)) = self.output else {
+            return Ok(());
+        };
+        let temp_file = out
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)
+            .context("failed to write manifest")?;
+        temp_file
+            .persist(&path)
+            .with_context(|| format!("failed to write manifest {:?}", path))?;
+        set_umasked_mode(&path, 0o666)
+    }
+}
+
+/// JSON string literal; bytes that are not UTF-8 are replaced.
+pub(crate) fn json_string(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) fn entry_type_name(header: &tar::Header) -> &'static str {
+    match entry_type_char(header) {
+        'd' => "dir",
+        '-' => "file",
+        'h' => "hardlink",
+        'l' => "symlink",
+        'c' => "char",
+        'b' => "block",
+        'p' => "fifo",
+        'M' => "continued",
+        _ => "other",
+    }
+}
+
+/// Write `<prefix>manifest.tar` with a `volumes.txt` list of the volumes in
+/// the extraction order and the metadata files written along with them.
+/// The files are still kept as is, so that nothing has to be unpacked to
+/// use the volumes.
+pub(crate) fn write_metadata_bundle(args: &Args, volumes: &[PathBuf]) -> ah::Result<()> {
+    let bundle_path = PathBuf::from(format!("{}manifest.tar", args.output_prefix));
+    let mut index = String::new();
+    for volume in volumes {
+        index.push_str(&volume.file_name().unwrap().to_string_lossy());
+        index.push('\n');
+    }
+    let mut files = vec![];
+    if args.emit_extract_script {
+        files.push(PathBuf::from(format!("{}extract.sh", args.output_prefix)));
+    }
+    if args.coalesce_small.is_some() {
+        files.push(PathBuf::from(format!(
+            "{}{}",
+            args.output_prefix, UNPACK_SCRIPT_SUFFIX
+        )));
+    }
+    if args.control_file {
+        files.push(control_file_path(&args.output_prefix));
+    }
+    if args.sign.is_some() {
+        for volume in volumes {
+            let mut signature = volume.as_os_str().to_owned();
+            signature.push(".sig");
+            let signature = PathBuf::from(signature);
+            // Only --allow-sign-failure leaves volumes unsigned.
+            if signature.exists() {
+                files.push(signature);
+            }
+        }
+    }
+    match &args.checksums {
+        Some(path) => files.push(path.clone()),
+        None => {
+            for volume in volumes {
+                for algorithm in &args.checksum {
+                    files.push(checksum_file_path(volume, *algorithm));
+                }
+            }
+        }
+    }
+    files.extend(args.manifest.iter().cloned());
+    files.extend(args.entries_manifest_out.iter().cloned());
+
+    let temp_file = tempfile::Builder::new()
+        .prefix(bundle_path.file_name().unwrap())
+        .suffix(".tmp")
+        .tempfile_in(bundle_path.parent().unwrap_or_else(|| Path::new(".")))
+        .context("failed to create metadata bundle tempfile")?;
+    let mut builder = tar::Builder::new(io::BufWriter::new(temp_file));
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(index.len() as _);
+    header.set_mode(0o644);
+    header.set_mtime(args.creation_mtime()?);
+    builder.append_data(&mut header, "volumes.txt", index.as_bytes())?;
+    for file in &files {
+        let mut add = || -> ah::Result<()> {
+            let data = std::fs::File::open(file)?;
+            let mut header = file_header(args, &data.metadata()?)?;
+            Ok(builder.append_data(&mut header, file.file_name().unwrap(), data)?)
+        };
+        add().with_context(|| format!("failed to add {:?} to the metadata bundle", file))?;
+    }
+    let temp_file = builder
+        .into_inner()?
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    temp_file
+        .persist(&bundle_path)
+        .with_context(|| format!("failed to write metadata bundle {:?}", bundle_path))?;
+    set_umasked_mode(&bundle_path, 0o666)
+}
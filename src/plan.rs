@@ -0,0 +1,813 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Volume plans of the modes that scan the input or read a layout first.
+
+use crate::{
+    args::{path_extension, Args, Balance, BuiltinCompression, SizeZeroPolicy},
+    compress::Encoder,
+    header::is_extension,
+    input::{decompressed_input, peek_magic, InputCompression, SizeZeroFilter},
+    input_error, padded_size, SubprocessFailure, TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::AtomicBool,
+};
+
+/// Entry info collected by the first pass over a seekable input.
+struct ScannedEntry {
+    footprint: u64,
+    extension: bool,
+    /// Index of the file extension for --balance compressed, 0 otherwise.
+    category: u32,
+}
+
+/// Size of a spilled ScannedEntry: footprint, extension flag and category.
+const SPILLED_ENTRY_SIZE: usize = 13;
+
+/// Data sampled from each file extension for --balance compressed.
+const CALIBRATION_SAMPLE_SIZE: usize = 256 * 1024;
+
+/// Extensions beyond this many share the category of files without one.
+const MAX_CALIBRATION_CATEGORIES: usize = 256;
+
+/// Fixed-point unit of calibrated compression ratios, so that weights stay
+/// integer and their sum is exact.
+const RATIO_SCALE: u64 = 1024;
+
+/// Scanned entries in the input order.  They are kept in memory up to
+/// --memory-limit, the rest is spilled into an anonymous temp file in
+/// --spill-dir.  Totals are kept, so that planning reads the entries only
+/// once.
+struct ScannedEntries {
+    memory: Vec<ScannedEntry>,
+    memory_capacity: usize,
+    spill_dir: Option<PathBuf>,
+    spill: Option<io::BufWriter<std::fs::File>>,
+    len: usize,
+    total_footprint: u64,
+    total_entries: u64,
+    /// Category indices by extension; category 0 is for everything else.
+    categories: HashMap<String, u32>,
+    category_footprints: Vec<u64>,
+    /// Data samples by category, if calibrating.
+    samples: Option<Vec<Vec<u8>>>,
+    /// Compressed to tar size ratios by category, in RATIO_SCALE units.
+    ratios: Vec<u64>,
+}
+
+impl ScannedEntries {
+    fn new(args: &Args) -> Self {
+        let memory_capacity = match &args.spill_dir {
+            Some(_) => {
+                (args.memory_limit.unwrap_or(0) / std::mem::size_of::<ScannedEntry>() as u64)
+                    as usize
+            }
+            None => usize::MAX,
+        };
+        Self {
+            memory: vec![],
+            memory_capacity,
+            spill_dir: args.spill_dir.clone(),
+            spill: None,
+            len: 0,
+            total_footprint: 0,
+            total_entries: 0,
+            categories: HashMap::new(),
+            category_footprints: vec![0],
+            samples: (args.balance == Balance::Compressed).then(|| vec![vec![]]),
+            ratios: vec![],
+        }
+    }
+
+    fn push(&mut self, entry: ScannedEntry) -> ah::Result<()> {
+        self.len += 1;
+        self.total_footprint += entry.footprint;
+        self.total_entries += u64::from(!entry.extension);
+        self.category_footprints[entry.category as usize] += entry.footprint;
+        if self.spill.is_none() && self.memory.len() < self.memory_capacity {
+            self.memory.push(entry);
+            return Ok(());
+        }
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => {
+                // The capacity is finite only with a spill dir.
+                let dir = self.spill_dir.as_ref().unwrap();
+                log::info!("Spilling scanned entries to {:?}", dir);
+                let file = tempfile::tempfile_in(dir)
+                    .with_context(|| format!("failed to create a spill file in {:?}", dir))?;
+                self.spill.insert(io::BufWriter::new(file))
+            }
+        };
+        spill
+            .write_all(&entry.footprint.to_le_bytes())
+            .and_then(|()| spill.write_all(&[u8::from(entry.extension)]))
+            .and_then(|()| spill.write_all(&entry.category.to_le_bytes()))
+            .context("failed to write to the spill file")?;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Category of an entry path, allocated on first sight if calibrating.
+    fn category(&mut self, path: &[u8]) -> u32 {
+        let (Some(samples), Some(ext)) = (&mut self.samples, path_extension(path)) else {
+            return 0;
+        };
+        if let Some(&category) = self.categories.get(&ext) {
+            return category;
+        }
+        if samples.len() >= MAX_CALIBRATION_CATEGORIES {
+            return 0;
+        }
+        let category = samples.len() as u32;
+        samples.push(vec![]);
+        self.category_footprints.push(0);
+        self.categories.insert(ext, category);
+        category
+    }
+
+    /// Take up to the rest of the category sample from the entry data.
+    fn sample(&mut self, category: u32, data: &mut impl io::Read) -> io::Result<()> {
+        if let Some(sample) = self
+            .samples
+            .as_mut()
+            .map(|samples| &mut samples[category as usize])
+        {
+            let room = CALIBRATION_SAMPLE_SIZE - sample.len();
+            io::Read::read_to_end(&mut io::Read::take(data, room as u64), sample)?;
+        }
+        Ok(())
+    }
+
+    /// Compress each category sample with the --compress command or --gzip
+    /// to find out compression ratios.  A category without data, e.g. of
+    /// empty files, is taken as incompressible.
+    fn calibrate(&mut self, compress: impl Fn(Vec<u8>) -> ah::Result<u64>) -> ah::Result<()> {
+        let Some(samples) = self.samples.take() else {
+            return Ok(());
+        };
+        let mut names = vec![String::new(); samples.len()];
+        for (ext, &category) in &self.categories {
+            names[category as usize] = format!(".{}", ext);
+        }
+        self.ratios = samples
+            .into_iter()
+            .zip(&names)
+            .map(|(sample, name)| {
+                if sample.is_empty() {
+                    return Ok(RATIO_SCALE);
+                }
+                let len = sample.len() as u64;
+                let compressed = compress(sample)?;
+                log::info!(
+                    "Calibrated {}: {} bytes compressed to {}",
+                    if name.is_empty() { "other files" } else { name },
+                    len,
+                    compressed,
+                );
+                let ratio = (compressed * RATIO_SCALE).div_ceil(len);
+                Ok(ratio.max(1))
+            })
+            .collect::<ah::Result<_>>()?;
+        Ok(())
+    }
+
+    fn total_weight(&self, balance: Balance) -> u64 {
+        match balance {
+            Balance::Bytes => self.total_footprint,
+            Balance::Entries => self.total_entries,
+            Balance::Compressed => self
+                .category_footprints
+                .iter()
+                .zip(&self.ratios)
+                .map(|(footprint, ratio)| footprint * ratio)
+                .sum(),
+        }
+    }
+
+    /// All the entries, the spilled ones read back sequentially.
+    fn into_iter(self) -> ah::Result<impl Iterator<Item = ah::Result<ScannedEntry>>> {
+        let mut spilled = match self.spill {
+            Some(spill) => {
+                let mut file = spill
+                    .into_inner()
+                    .map_err(io::IntoInnerError::into_error)
+                    .context("failed to write to the spill file")?;
+                io::Seek::rewind(&mut file).context("failed to read the spill file")?;
+                Some(io::BufReader::new(file))
+            }
+            None => None,
+        };
+        let spilled = std::iter::from_fn(move || {
+            let mut buf = [0; SPILLED_ENTRY_SIZE];
+            match io::Read::read_exact(spilled.as_mut()?, &mut buf) {
+                Ok(()) => Some(Ok(ScannedEntry {
+                    footprint: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+                    extension: buf[8] != 0,
+                    category: u32::from_le_bytes(buf[9..].try_into().unwrap()),
+                })),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+                Err(e) => Some(Err(
+                    ah::Error::new(e).context("failed to read the spill file")
+                )),
+            }
+        });
+        Ok(self.memory.into_iter().map(Ok).chain(spilled))
+    }
+}
+
+/// Size of the data compressed with the --compress command.
+fn compressed_size(args: &Args, data: Vec<u8>) -> ah::Result<u64> {
+    let compress = args.compress_line().unwrap_or_default();
+    let mut command = args
+        .compress_command()
+        .expect("internal: calibration without --compress");
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to start {:?}", command))?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Written in a separate thread, as the output has to be read meanwhile.
+    let writer = std::thread::spawn(move || stdin.write_all(&data));
+    let size = io::copy(child.stdout.as_mut().unwrap(), &mut io::sink());
+    let written = writer
+        .join()
+        .expect("internal: calibration writer panicked");
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(SubprocessFailure(format!(
+            "--compress command {:?} failed on a calibration sample: exit code {}",
+            compress,
+            status.code().unwrap_or(-1)
+        ))
+        .into());
+    }
+    written.with_context(|| format!("failed to write to --compress command {:?}", compress))?;
+    Ok(size?)
+}
+
+fn builtin_compressed_size(
+    compression: BuiltinCompression,
+    level: u32,
+    data: &[u8],
+) -> ah::Result<u64> {
+    // Samples are small enough to be compressed in memory.
+    let out = Encoder::new(compression, level, vec![])
+        .and_then(|mut encoder| encoder.write_all(data).map(|_| encoder))
+        .and_then(Encoder::finish)
+        .context("failed to compress a calibration sample")?;
+    Ok(out.len() as u64)
+}
+
+/// First pass over the input file for the modes that need to know all the
+/// entries in advance.  It has to be run in the same raw mode as the second
+/// pass, so that entries match one-to-one.
+fn scan_input(
+    path: &Path,
+    raw: bool,
+    on_size_zero: SizeZeroPolicy,
+    decompress: Option<&str>,
+    manifest_out: Option<&Path>,
+    entries: &mut ScannedEntries,
+    interrupt_flag: &AtomicBool,
+) -> ah::Result<()> {
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open input file {:?}", path))?;
+    let metadata = file.metadata()?;
+    ah::ensure!(
+        metadata.is_file(),
+        "input {:?} is not a regular file, it cannot be read twice",
+        path
+    );
+    let (block, _) = peek_magic(&mut file, TAR_HEADER_SIZE as _)?;
+    let compressed = decompress.is_some() || InputCompression::detect(&block).is_some();
+    io::Seek::rewind(&mut file)?;
+
+    let mut manifest = match manifest_out {
+        Some(manifest_path) => {
+            let mut manifest =
+                io::BufWriter::new(std::fs::File::create(manifest_path).with_context(|| {
+                    format!("failed to create entries manifest {:?}", manifest_path)
+                })?);
+            writeln!(
+                manifest,
+                "{}",
+                manifest_stamp(&metadata, raw, on_size_zero)?
+            )?;
+            Some(manifest)
+        }
+        None => None,
+    };
+    let manifest_writer = manifest.as_mut().map(|m| m as &mut dyn io::Write);
+
+    // Entries that may be skipped or resized have to be filtered the same
+    // way as on the second pass; otherwise, seeking is faster, unless the
+    // input is compressed.
+    if on_size_zero == SizeZeroPolicy::Fail && !compressed {
+        scan_entries(
+            tar::Archive::new(io::BufReader::new(file))
+                .entries_with_seek()?
+                .raw(raw),
+            entries,
+            manifest_writer,
+            interrupt_flag,
+        )?;
+    } else {
+        let input = io::BufReader::new(decompressed_input(Box::new(file), decompress)?);
+        scan_entries(
+            tar::Archive::new(SizeZeroFilter::new(input, on_size_zero))
+                .entries()?
+                .raw(raw),
+            entries,
+            manifest_writer,
+            interrupt_flag,
+        )?;
+    }
+    if let Some(mut manifest) = manifest {
+        manifest
+            .flush()
+            .context("failed to write entries manifest")?;
+    }
+    log::info!("Scanned {} entries", entries.len());
+    Ok(())
+}
+
+fn scan_entries<R: io::Read>(
+    archive_entries: tar::Entries<R>,
+    entries: &mut ScannedEntries,
+    mut manifest: Option<&mut dyn io::Write>,
+    interrupt_flag: &AtomicBool,
+) -> ah::Result<()> {
+    for ent in archive_entries {
+        if interrupt_flag.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(io::Error::from(io::ErrorKind::Interrupted).into());
+        }
+        let mut ent = ent.map_err(input_error)?;
+        let header = ent.header();
+        let extension = is_extension(header);
+        let category = if extension {
+            0
+        } else {
+            entries.category(&header.path_bytes())
+        };
+        let scanned = ScannedEntry {
+            footprint: TAR_HEADER_SIZE + padded_size(ent.size()),
+            extension,
+            category,
+        };
+        if !extension && header.entry_type().is_file() {
+            entries.sample(category, &mut ent)?;
+        }
+        let header = ent.header();
+        if let Some(manifest) = manifest.as_mut() {
+            writeln!(
+                manifest,
+                "{}\t{}\t{}\t{}",
+                scanned.footprint,
+                u8::from(scanned.extension),
+                ent.raw_header_position(),
+                header.path_bytes().escape_ascii(),
+            )
+            .context("failed to write entries manifest")?;
+        }
+        entries.push(scanned)?;
+    }
+    Ok(())
+}
+
+const MANIFEST_MAGIC: &str = "splitar-entries-v1";
+
+/// The first line of an entries manifest that identifies the input it was
+/// made for.  Entries of the raw mode differ, as well as entries filtered by
+/// --on-size-zero, so they are a part of it too.
+fn manifest_stamp(
+    metadata: &std::fs::Metadata,
+    raw: bool,
+    on_size_zero: SizeZeroPolicy,
+) -> ah::Result<String> {
+    let mtime = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(format!(
+        "{}\t{}\t{}.{:09}\t{}\t{:?}",
+        MANIFEST_MAGIC,
+        metadata.len(),
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        u8::from(raw),
+        on_size_zero,
+    ))
+}
+
+/// Read entries saved by `scan_input`, checking that the input has not
+/// changed since.  Lines are "<footprint>\t<is extension>\t<offset>\t<path>";
+/// only the first two fields matter for planning.
+fn read_manifest(
+    path: &Path,
+    input: &Path,
+    raw: bool,
+    on_size_zero: SizeZeroPolicy,
+    entries: &mut ScannedEntries,
+) -> ah::Result<()> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("failed to read entries manifest {:?}", path))?;
+    let metadata = std::fs::metadata(input)
+        .with_context(|| format!("failed to open input file {:?}", input))?;
+    let mut lines = io::BufRead::lines(io::BufReader::new(file));
+    let stamp = lines
+        .next()
+        .transpose()
+        .with_context(|| format!("failed to read entries manifest {:?}", path))?;
+    ah::ensure!(
+        stamp.as_deref() == Some(manifest_stamp(&metadata, raw, on_size_zero)?.as_str()),
+        "entries manifest {:?} doesn't match the input {:?} \
+         (size, mtime, --verbatim or --on-size-zero)",
+        path,
+        input,
+    );
+    for (lineno, line) in lines.enumerate() {
+        let line = line.with_context(|| format!("failed to read entries manifest {:?}", path))?;
+        let mut fields = line.split('\t');
+        let parsed = (|| {
+            let footprint = fields.next()?.parse().ok()?;
+            let extension = match fields.next()? {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            };
+            Some(ScannedEntry {
+                footprint,
+                extension,
+                category: 0,
+            })
+        })();
+        entries.push(parsed.ok_or_else(|| {
+            ah::anyhow!(
+                "malformed entries manifest {:?} at line {}",
+                path,
+                lineno + 2
+            )
+        })?)?;
+    }
+    log::info!("Read {} entries from the manifest", entries.len());
+    Ok(())
+}
+
+/// Assign a volume to each entry, wrt to the balancing objective.  Volume
+/// numbers are non-decreasing, so only the first entry index of each volume
+/// is returned; as entries are never split, there may be less than
+/// `num_volumes` volumes.
+fn plan_volumes(
+    entries: ScannedEntries,
+    num_volumes: u64,
+    balance: Balance,
+) -> ah::Result<IndexedPlan> {
+    let ratios = entries.ratios.clone();
+    let weight = |ent: &ScannedEntry| match balance {
+        Balance::Bytes => ent.footprint,
+        Balance::Entries => u64::from(!ent.extension),
+        Balance::Compressed => ent.footprint * ratios[ent.category as usize],
+    };
+    let total = entries.total_weight(balance);
+    let len = entries.len();
+
+    let mut starts = vec![];
+    let mut prev_vol = None;
+    // Extension members always go together with the entry they describe,
+    // so their run waits for it.
+    let mut extensions_start = None;
+    let mut cumulative = 0u64;
+    for (idx, ent) in entries.into_iter()?.enumerate() {
+        let ent = ent?;
+        let w = weight(&ent);
+        // Place each entry by its middle point, so that a large entry lands
+        // in the volume it mostly belongs to.
+        let vol =
+            (cumulative as u128 * 2 + w as u128) * num_volumes as u128 / (2 * total.max(1) as u128);
+        let vol = (vol as u64).min(num_volumes - 1);
+        cumulative += w;
+        if ent.extension && idx + 1 < len {
+            extensions_start.get_or_insert(idx);
+            continue;
+        }
+        let first = extensions_start.take().unwrap_or(idx);
+        // Renumber the volumes, so that numbers are not skipped.
+        if prev_vol.is_none_or(|prev| vol > prev) {
+            starts.push(first);
+        }
+        prev_vol = Some(vol);
+    }
+    Ok(IndexedPlan { starts, len })
+}
+
+/// Assign entries to as few volumes as --max-size allows for --pack, so
+/// that they are about equally full instead of the last one being a runt.
+fn pack_volumes(entries: ScannedEntries, args: &Args) -> ah::Result<IndexedPlan> {
+    let max_size = args.max_size.expect("internal: --pack without --max-size");
+    let len = entries.len();
+    // First entry index and footprint of each entry with its extension
+    // members, which always go together.
+    let mut units = vec![];
+    let mut extensions = None;
+    for (idx, ent) in entries.into_iter()?.enumerate() {
+        let ent = ent?;
+        let (first, footprint) = extensions.take().unwrap_or((idx, 0));
+        if ent.extension && idx + 1 < len {
+            extensions = Some((first, footprint + ent.footprint));
+            continue;
+        }
+        units.push((first, footprint + ent.footprint));
+    }
+    let empty_size = 2 * TAR_HEADER_SIZE + args.injected_size();
+    for &(first, footprint) in &units {
+        ah::ensure!(
+            args.estimated_size(empty_size + footprint) <= max_size,
+            "entry {} of {} bytes does not fit into --max-size {} with --pack",
+            first,
+            footprint,
+            max_size,
+        );
+    }
+    // Filling volumes one by one makes the fewest of them for a limit.
+    let starts_under = |limit: u64| {
+        let mut starts = vec![0];
+        let mut size = empty_size;
+        for &(first, footprint) in &units {
+            if size > empty_size && args.estimated_size(size + footprint) > limit {
+                starts.push(first);
+                size = empty_size;
+            }
+            size += footprint;
+        }
+        starts
+    };
+    let num_volumes = starts_under(max_size).len();
+    // The smallest limit that needs no more volumes.
+    let (mut low, mut limit) = (empty_size, max_size);
+    while low < limit {
+        let mid = low + (limit - low) / 2;
+        if starts_under(mid).len() <= num_volumes {
+            limit = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    // Filling up to it still may leave a runt at the end, so entries are
+    // placed by their middle points instead, like with --num-volumes,
+    // unless a volume gets larger than the limit this way.
+    let total: u64 = units.iter().map(|&(_, footprint)| footprint).sum();
+    let mut starts = vec![];
+    let mut sizes = vec![];
+    let mut cumulative = 0u64;
+    for &(first, footprint) in &units {
+        let vol = (cumulative as u128 * 2 + footprint as u128) * num_volumes as u128
+            / (2 * total.max(1) as u128);
+        cumulative += footprint;
+        if vol as usize >= starts.len() {
+            starts.push(first);
+            sizes.push(empty_size);
+        }
+        *sizes.last_mut().unwrap() += footprint;
+    }
+    if sizes.iter().any(|&size| args.estimated_size(size) > limit) {
+        starts = starts_under(limit);
+    }
+    Ok(IndexedPlan { starts, len })
+}
+
+/// Volumes of the entries by index, as the first entry index of each
+/// volume.
+pub(crate) struct IndexedPlan {
+    pub(crate) starts: Vec<usize>,
+    len: usize,
+}
+
+/// Volume assignment decided before splitting.
+pub(crate) enum Plan {
+    /// Volume for each entry by its index.
+    Indexed(IndexedPlan),
+    /// Volumes for entries by their paths, in the input order.
+    Layout(HashMap<Vec<u8>, VecDeque<usize>>),
+    /// Volumes start at these input offsets.
+    Boundaries(Boundaries),
+}
+
+/// Increasing input offsets of volume starts but the first one, and how
+/// many of them are passed.
+pub(crate) struct Boundaries {
+    offsets: Vec<u64>,
+    passed: usize,
+}
+
+impl Plan {
+    /// `offset` is where the entry starts in the input, including its
+    /// extension members.
+    pub(crate) fn volume_for(
+        &mut self,
+        entry_idx: usize,
+        path: &[u8],
+        offset: u64,
+    ) -> ah::Result<usize> {
+        match self {
+            Plan::Indexed(plan) => {
+                ah::ensure!(
+                    entry_idx < plan.len,
+                    "input has changed since it was scanned"
+                );
+                Ok(plan.starts.partition_point(|&start| start <= entry_idx) - 1)
+            }
+            Plan::Layout(layout) => layout
+                .get_mut(layout_key(path))
+                .and_then(VecDeque::pop_front)
+                .ok_or_else(|| {
+                    ah::anyhow!(
+                        "layout doesn't cover entry {:?}",
+                        String::from_utf8_lossy(path)
+                    )
+                }),
+            Plan::Boundaries(boundaries) => {
+                while let Some(&boundary) = boundaries.offsets.get(boundaries.passed) {
+                    if boundary > offset {
+                        break;
+                    }
+                    ah::ensure!(
+                        boundary == offset,
+                        "--boundaries offset {} is not at an entry boundary; \
+                         the next entry {:?} starts at {}",
+                        boundary,
+                        String::from_utf8_lossy(path),
+                        offset,
+                    );
+                    boundaries.passed += 1;
+                }
+                Ok(boundaries.passed)
+            }
+        }
+    }
+
+    /// Check that every planned entry has been met.
+    pub(crate) fn check_complete(&self) -> ah::Result<()> {
+        if let Plan::Layout(layout) = self {
+            if let Some((path, _)) = layout.iter().find(|(_, vols)| !vols.is_empty()) {
+                ah::bail!(
+                    "layout entry {:?} is not found in the input",
+                    String::from_utf8_lossy(path)
+                );
+            }
+        }
+        if let Plan::Boundaries(boundaries) = self {
+            if let Some(boundary) = boundaries.offsets.get(boundaries.passed) {
+                ah::bail!("--boundaries offset {} is beyond the last entry", boundary);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Dirs may be written with or without trailing slash.
+pub(crate) fn layout_key(path: &[u8]) -> &[u8] {
+    path.strip_suffix(b"/").unwrap_or(path)
+}
+
+fn read_layout(path: &Path) -> ah::Result<HashMap<Vec<u8>, VecDeque<usize>>> {
+    let data =
+        std::fs::read(path).with_context(|| format!("failed to read layout file {:?}", path))?;
+    let mut layout = HashMap::<_, VecDeque<_>>::new();
+    for (lineno, line) in data.split(|&c| c == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.iter().position(|&c| c == b'\t').and_then(|tab| {
+            let vol = std::str::from_utf8(&line[..tab]).ok()?.parse().ok()?;
+            Some((vol, &line[tab + 1..]))
+        });
+        let (vol, entry_path) = parsed.ok_or_else(|| {
+            ah::anyhow!("malformed layout file {:?} at line {}", path, lineno + 1)
+        })?;
+        layout
+            .entry(layout_key(entry_path).to_vec())
+            .or_default()
+            .push_back(vol);
+    }
+    Ok(layout)
+}
+
+/// Read --boundaries offsets.  The first volume starts at 0 anyway, so
+/// offset 0 is dropped.
+fn read_boundaries(path: &Path) -> ah::Result<Boundaries> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to read boundaries file {:?}", path))?;
+    let mut offsets: Vec<u64> = vec![];
+    for (lineno, line) in data.split(|&c| c == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let offset = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.parse().ok())
+            .ok_or_else(|| {
+                ah::anyhow!(
+                    "malformed boundaries file {:?} at line {}",
+                    path,
+                    lineno + 1
+                )
+            })?;
+        ah::ensure!(
+            offsets.last().is_none_or(|&last| offset > last),
+            "boundaries file {:?} at line {}: offsets have to be increasing",
+            path,
+            lineno + 1
+        );
+        offsets.push(offset);
+    }
+    if offsets.first() == Some(&0) {
+        offsets.remove(0);
+    }
+    Ok(Boundaries { offsets, passed: 0 })
+}
+
+/// Make a volume plan for the modes that need one.
+pub(crate) fn plan_split(
+    args: &Args,
+    raw: bool,
+    interrupt_flag: &AtomicBool,
+) -> ah::Result<Option<Plan>> {
+    if let Some(layout) = &args.layout {
+        return Ok(Some(Plan::Layout(read_layout(layout)?)));
+    }
+    if let Some(boundaries) = &args.boundaries {
+        return Ok(Some(Plan::Boundaries(read_boundaries(boundaries)?)));
+    }
+    if args.num_volumes.is_none() && !args.pack {
+        return Ok(None);
+    }
+    ah::ensure!(
+        !args.input_is_stream(),
+        "{} cannot be used with stdin or a pipe, as the input is read twice",
+        if args.pack { "--pack" } else { "--num-volumes" },
+    );
+    let mut entries = ScannedEntries::new(args);
+    match &args.entries_manifest_in {
+        Some(manifest) => read_manifest(
+            manifest,
+            &args.input_file,
+            raw,
+            args.on_size_zero,
+            &mut entries,
+        )?,
+        None => scan_input(
+            &args.input_file,
+            raw,
+            args.on_size_zero,
+            args.decompress_input.as_deref(),
+            args.entries_manifest_out.as_deref(),
+            &mut entries,
+            interrupt_flag,
+        )?,
+    }
+    let Some(num_volumes) = args.num_volumes else {
+        return Ok(Some(Plan::Indexed(pack_volumes(entries, args)?)));
+    };
+    if args.compress_command().is_some() {
+        entries.calibrate(|sample| compressed_size(args, sample))?;
+    } else if let Some(compression) = args.builtin_compression() {
+        entries.calibrate(|sample| {
+            builtin_compressed_size(compression, args.compress_level(), &sample)
+        })?;
+    }
+    Ok(Some(Plan::Indexed(plan_volumes(
+        entries,
+        num_volumes,
+        args.balance,
+    )?)))
+}
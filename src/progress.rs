@@ -0,0 +1,253 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Progress of the split: the progress bar and the status socket.
+
+use anyhow::{self as ah, Context as _};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Progress counters, updated by the main loop and read by the status
+/// socket listener.
+#[derive(Default)]
+pub(crate) struct Progress {
+    volume: AtomicUsize,
+    bytes: AtomicU64,
+    entries: AtomicU64,
+    // Input file bytes, before the decompression, if any.
+    pub(crate) input_read: AtomicU64,
+    volumes_written: AtomicUsize,
+}
+
+impl Progress {
+    pub(crate) fn update(&self, volume: usize, volumes_written: usize, entry_footprint: u64) {
+        self.volume.store(volume, Ordering::Relaxed);
+        self.volumes_written
+            .store(volumes_written, Ordering::Relaxed);
+        self.bytes.fetch_add(entry_footprint, Ordering::Relaxed);
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Like "volume 3 [=====     ] 1.5 GiB/3.0 GiB  50%, 3 written, ETA 0:05:00".
+    fn bar_line(&self, input_size: Option<u64>, elapsed: Duration) -> String {
+        const WIDTH: usize = 30;
+
+        let read = self.input_read.load(Ordering::Relaxed);
+        let mut line = format!("volume {}", self.volume.load(Ordering::Relaxed));
+        match input_size {
+            Some(size) if size > 0 => {
+                let ratio = (read as f64 / size as f64).min(1.0);
+                line += &format!(
+                    " [{:<WIDTH$}] {}/{} {:3.0}%",
+                    "=".repeat((ratio * WIDTH as f64) as usize),
+                    format_bytes(read),
+                    format_bytes(size),
+                    ratio * 100.0,
+                );
+            }
+            _ => line += &format!(" {} read", format_bytes(read)),
+        }
+        line += &format!(", {} written", self.volumes_written.load(Ordering::Relaxed));
+        if let Some(size) = input_size.filter(|&size| read > 0 && read < size) {
+            let eta = elapsed
+                .mul_f64((size - read) as f64 / read as f64)
+                .as_secs();
+            line += &format!(", ETA {}:{:02}:{:02}", eta / 3600, eta / 60 % 60, eta % 60);
+        }
+        line
+    }
+
+    #[cfg(unix)]
+    fn status_line(&self) -> String {
+        format!(
+            "volume={} bytes={} entries={}\n",
+            self.volume.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.entries.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// Counts the input file bytes for the progress bar.
+pub(crate) struct ProgressReader<R> {
+    pub(crate) inner: R,
+    pub(crate) progress: Arc<Progress>,
+}
+
+impl<R: io::Read> io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.progress
+            .input_read
+            .fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+/// The progress bar while it is shown, for the output that has to go above
+/// it.
+static PROGRESS_BAR: Mutex<Option<indicatif::ProgressBar>> = Mutex::new(None);
+
+/// Run `f`, which writes to stderr or stdout, with the progress bar erased;
+/// the bar is redrawn below the output.  `f` must not log, as the bar stays
+/// locked.
+pub(crate) fn suspend_progress_bar<T>(f: impl FnOnce() -> T) -> T {
+    let bar = PROGRESS_BAR.lock().unwrap().clone();
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+/// The env_logger logger, erasing the progress bar before every log line
+/// with stderr locked, so that the bar is redrawn below it.
+pub(crate) struct Logger(env_logger::Logger);
+
+impl Logger {
+    pub(crate) fn init() {
+        // Warnings are a part of the output, e.g. of --warn-broken-links.
+        let env = env_logger::Env::default().default_filter_or("warn");
+        let logger = env_logger::Builder::from_env(env).build();
+        log::set_max_level(logger.filter());
+        // Nothing else sets a logger.
+        let _ = log::set_boxed_logger(Box::new(Self(logger)));
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.0.matches(record) {
+            suspend_progress_bar(|| self.0.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// The progress bar on stderr, redrawn by indicatif until dropped.
+pub(crate) struct ProgressBar(indicatif::ProgressBar);
+
+impl ProgressBar {
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+    pub(crate) fn start(progress: Arc<Progress>, input_size: Option<u64>) -> ah::Result<Self> {
+        // The stderr target of indicatif hides the bar if it is not
+        // a terminal, but --progress shows it anyway.
+        let target = indicatif::ProgressDrawTarget::term_like_with_hz(
+            Box::new(console::Term::stderr()),
+            (Duration::from_secs(1).as_millis() / Self::REDRAW_INTERVAL.as_millis()) as u8,
+        );
+        let style = indicatif::ProgressStyle::with_template("{line}")
+            .context("invalid progress bar template")?
+            .with_key(
+                "line",
+                move |state: &indicatif::ProgressState, out: &mut dyn fmt::Write| {
+                    let _ = out.write_str(&progress.bar_line(input_size, state.elapsed()));
+                },
+            );
+        let bar = indicatif::ProgressBar::with_draw_target(None, target).with_style(style);
+        // Even a short split shows it once.
+        bar.tick();
+        bar.enable_steady_tick(Self::REDRAW_INTERVAL);
+        *PROGRESS_BAR.lock().unwrap() = Some(bar.clone());
+        Ok(Self(bar))
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        PROGRESS_BAR.lock().unwrap().take();
+        self.0.finish_and_clear();
+    }
+}
+
+/// Listening Unix socket, which is removed on drop.  The listener thread
+/// is not joined; it dies with the process.
+#[cfg(unix)]
+pub(crate) struct StatusSocket {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl StatusSocket {
+    pub(crate) fn bind(path: &Path, progress: Arc<Progress>) -> ah::Result<Self> {
+        use io::Write as _;
+
+        let listener = std::os::unix::net::UnixListener::bind(path)
+            .with_context(|| format!("failed to bind status socket {:?}", path))?;
+        // Remove the socket even if the thread fails to start.
+        let socket = Self {
+            path: path.to_owned(),
+        };
+        std::thread::Builder::new()
+            .name("status".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    // A client may go away before reading; it is its problem.
+                    let res = stream
+                        .and_then(|mut stream| stream.write_all(progress.status_line().as_bytes()));
+                    if let Err(e) = res {
+                        log::debug!("Status socket client failed: {}", e);
+                    }
+                }
+            })
+            .context("failed to start status socket thread")?;
+        Ok(socket)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for StatusSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to remove status socket {:?}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,1198 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! The entries of a split, packed into volumes.
+
+use crate::{
+    args::{route_for, Args, OutputFormat, SizeCounts, SizeMetric},
+    checksum::{checksum_file_path, ChecksumAlgorithm},
+    control::{control_file_path, write_control_file},
+    dirs::{recreated_dirname, recreated_dirs, StoredDir},
+    filter::{PathFilter, Selection},
+    header::{
+        bad_header_field, continuation_header, convert_header, data_header, dir_at_depth,
+        is_dir_header, is_extension, normalize_header, preserved_extensions, scrub_header,
+        set_entry_link_name, set_entry_path, stream_size, Extensions, MULTI_VOLUME_SIZE_KEY,
+    },
+    init_umask,
+    manifest::{write_metadata_bundle, VolumeManifest},
+    padded_size,
+    plan::{plan_split, Plan},
+    progress::suspend_progress_bar,
+    set_umasked_mode,
+    volume::{
+        pack_record, stored_size, write_extract_script, write_unpack_script, PrependedFile, Volume,
+        PACK_MAGIC, UNPACK_SCRIPT_SUFFIX,
+    },
+    Error, Result, Splitter, Summary, TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+/// Why a volume ends before an entry, told with --verbose.
+enum RollReason {
+    Planned(usize),
+    MaxFiles(u64),
+    MaxSize(u64),
+    KeptDir(u64),
+}
+
+/// Files written by a split, removed on drop unless it has succeeded.
+#[derive(Default)]
+pub(crate) struct CreatedFiles {
+    paths: Vec<PathBuf>,
+    succeeded: bool,
+}
+
+impl CreatedFiles {
+    /// A volume with its checksum files and signature, whichever exist.
+    /// It is added before it is finished, as it may fail half way, e.g. in
+    /// the sign command; an old volume with the name would be replaced
+    /// anyway.
+    fn add_volume(&mut self, volume: &Path) {
+        self.paths.push(volume.to_owned());
+        for algorithm in ChecksumAlgorithm::ALL {
+            self.paths.push(checksum_file_path(volume, algorithm));
+        }
+        let mut signature = volume.as_os_str().to_owned();
+        signature.push(".sig");
+        self.paths.push(signature.into());
+    }
+}
+
+impl Drop for CreatedFiles {
+    fn drop(&mut self) {
+        if self.succeeded {
+            return;
+        }
+        let mut removed = 0;
+        for path in self.paths.iter().rev() {
+            match std::fs::remove_file(path) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Failed to remove {:?}: {}", path, e),
+            }
+        }
+        if removed > 0 {
+            log::warn!("Removed {} files written before the failure", removed);
+        }
+    }
+}
+
+impl Splitter {
+    /// Read the lists and plans the options refer to, and start the first
+    /// volume.  The options are validated already.
+    pub(crate) fn start(mut args: Args, interrupt_flag: Arc<AtomicBool>) -> Result<Self> {
+        // Before the --jobs and --threads threads.
+        init_umask();
+        args.apply_separator();
+        args.resolve_output_dir()?;
+        // The list has to be read before stdin is taken by the input.
+        let selection = match &args.files_from {
+            Some(path) if path == Path::new("-") => Some(
+                Selection::read(io::stdin().lock())
+                    .context("failed to read --files-from list from stdin")?,
+            ),
+            Some(path) => Some(
+                std::fs::File::open(path)
+                    .map(io::BufReader::new)
+                    .and_then(Selection::read)
+                    .with_context(|| format!("failed to read --files-from list {:?}", path))?,
+            ),
+            None => None,
+        };
+        let filter = PathFilter::new(&args.include, &args.exclude)?;
+        let prepend = match &args.prepend {
+            Some(path) => {
+                let prepend = PrependedFile::read(path, &args)?;
+                prepend.check_fits(&args)?;
+                Some(prepend)
+            }
+            None => None,
+        };
+        let plan = plan_split(&args, args.is_verbatim(), &interrupt_flag)?;
+        if let Some(Plan::Indexed(plan)) = &plan {
+            args.volume_count = Some(plan.starts.len());
+        }
+        Ok(Self::new(
+            args,
+            plan,
+            selection,
+            filter,
+            prepend,
+            interrupt_flag,
+        )?)
+    }
+
+    fn new(
+        args: Args,
+        plan: Option<Plan>,
+        selection: Option<Selection>,
+        filter: Option<PathFilter>,
+        prepend: Option<PrependedFile>,
+        interrupt_flag: Arc<AtomicBool>,
+    ) -> ah::Result<Self> {
+        let vol_idx = args.start_index;
+        let manifest =
+            VolumeManifest::new(args.manifest.as_deref(), args.resume_manifest.as_deref())?
+                .map(|manifest| Rc::new(RefCell::new(manifest)));
+        let mut volume = Volume::new(
+            "",
+            Some(vol_idx),
+            &args,
+            interrupt_flag.clone(),
+            manifest.clone(),
+        )?;
+        if let Some(prepend) = prepend {
+            volume.write_data(&prepend.header, prepend.data.as_slice(), args.verbose)?;
+        }
+        let created_files = args.atomic.then(CreatedFiles::default);
+
+        Ok(Self {
+            vol_idx,
+            args,
+            dirs: Default::default(),
+            dirs_footprint: 0,
+            volume: Some(volume),
+            interrupt_flag,
+            pending_extensions: vec![],
+            global_headers: vec![],
+            plan,
+            selection,
+            filter,
+            entry_idx: 0,
+            kept_dir: None,
+            entry_start: 0,
+            next_entry_start: 0,
+            entries_total: 0,
+            entry_counted: false,
+            finished_volumes: vec![],
+            finishing: VecDeque::new(),
+            stream: String::new(),
+            idle_streams: HashMap::new(),
+            manifest,
+            checksums: String::new(),
+            volume_checksums: HashMap::new(),
+            bytes_written: 0,
+            bytes_stored: 0,
+            oversized: 0,
+            link_targets: HashMap::new(),
+            created_files,
+            volumes_started: 1,
+            truncated: false,
+            dirs_limit_reached: false,
+        })
+    }
+
+    /// Make the volume of the stream current, starting the stream if it is
+    /// new.
+    fn switch_stream(&mut self, stream: &str) -> Result<()> {
+        if self.stream == stream {
+            return Ok(());
+        }
+        if !self.idle_streams.contains_key(stream) {
+            self.count_new_volume()?;
+        }
+        let (volume, vol_idx) = match self.idle_streams.remove(stream) {
+            Some(idle) => idle,
+            None => {
+                let mut volume = Volume::new(
+                    stream,
+                    Some(self.args.start_index),
+                    &self.args,
+                    self.interrupt_flag.clone(),
+                    self.manifest.clone(),
+                )?;
+                volume.dirs_reserve = self.dirs_footprint;
+                (volume, self.args.start_index)
+            }
+        };
+        let prev_volume = self.volume.replace(volume).unwrap();
+        let prev_stream = std::mem::replace(&mut self.stream, stream.to_owned());
+        let prev_idx = std::mem::replace(&mut self.vol_idx, vol_idx);
+        self.idle_streams
+            .insert(prev_stream, (prev_volume, prev_idx));
+        Ok(())
+    }
+
+    /// Write the next entry of the archive.  Entries have to come in the
+    /// archive order, as offsets, plans and extension members depend on it.
+    pub fn push_entry<R: io::Read>(&mut self, mut entry: tar::Entry<R>) -> Result<()> {
+        // Without the raw mode, extension members are already a part of the
+        // entry, and there are no pending ones.
+        if self.pending_extensions.is_empty() {
+            self.entry_start = self.next_entry_start;
+        }
+        self.next_entry_start =
+            entry.raw_header_position() + TAR_HEADER_SIZE + padded_size(stream_size(&entry)?);
+        self.entry_counted = false;
+        if entry.header().entry_type().is_pax_global_extensions() {
+            return self.push_global_header(entry);
+        }
+        if let Some(selection) = &mut self.selection {
+            if !is_extension(entry.header()) && !selection.select(&entry.path_bytes()) {
+                // Extension members go away with their entry.
+                self.pending_extensions.clear();
+                return Ok(());
+            }
+        }
+        if let Some(filter) = &self.filter {
+            // Filtered out dirs are not recorded for --recreate-dirs either.
+            if !is_extension(entry.header()) && !filter.accepts(&entry.path_bytes()) {
+                self.pending_extensions.clear();
+                return Ok(());
+            }
+        }
+        if !is_extension(entry.header()) {
+            self.entries_total += 1;
+            self.entry_counted = true;
+            match self.args.max_entries_total {
+                Some(max) if self.entries_total > max => return Err(Error::TooManyEntries(max)),
+                _ => {}
+            }
+        }
+        let bad_field = match self.args.skip_bad_headers || self.args.copy_bad_headers {
+            true if !is_extension(entry.header()) => bad_header_field(entry.header()),
+            _ => None,
+        };
+        // In verbatim mode, extension members come as separate entries.
+        let mut extensions = if self.args.is_verbatim() {
+            Extensions::default()
+        } else {
+            preserved_extensions(&mut entry)?
+        };
+        // The header and the path the entry is written with, unless it is
+        // copied as is.
+        let mut header = data_header(&entry);
+        let path = match self.args.is_verbatim() {
+            true => entry.path_bytes().into_owned(),
+            false => match self.rewrite_header(&entry, &mut header, &mut extensions)? {
+                Some(path) => path,
+                None => {
+                    log::debug!(
+                        "Skipping {:?}: nothing is left of the path",
+                        String::from_utf8_lossy(&entry.path_bytes())
+                    );
+                    return Ok(());
+                }
+            },
+        };
+        let link_target = match self.args.checks_links() && header.entry_type().is_hard_link() {
+            true => Some(extensions.link_name.clone().unwrap_or_else(|| {
+                header
+                    .link_name_bytes()
+                    .map(|name| name.into_owned())
+                    .unwrap_or_default()
+            })),
+            false => None,
+        };
+        // Extension members go with the entry that follows them.
+        if !self.args.route_by_extension.is_empty() && !is_extension(entry.header()) {
+            let stream = route_for(
+                &self.args.route_by_extension,
+                entry.header(),
+                &entry.path_bytes(),
+            )
+            .unwrap_or("")
+            .to_owned();
+            self.switch_stream(&stream)?;
+        }
+        let record = match self.args.coalesce_small {
+            Some(threshold) if extensions.is_empty() && bad_field.is_none() => {
+                pack_record(&header, &path, threshold)
+            }
+            _ => None,
+        };
+        let volume = self.volume.as_mut().unwrap();
+        // Global PAX headers go with the entry that follows them.
+        let pending_size: u64 = self
+            .pending_extensions
+            .iter()
+            .chain(&self.global_headers[volume.globals_written..])
+            .map(|(_, data)| TAR_HEADER_SIZE + padded_size(data.len() as _))
+            .sum();
+        // Recreated dirs count when they are written, with the ones the entry
+        // needs; data-only reserves the room for all of them instead.
+        let acc_size = match self.args.size_counts {
+            // Dirs have no data.
+            _ if self.args.size_metric == SizeMetric::Payload => volume.payload_size,
+            _ if !self.args.recreate_dirs => volume.acc_size,
+            SizeCounts::All => {
+                volume.acc_size
+                    + recreated_dirname(&path, &volume.prev_dir)
+                        .map(|dirname| {
+                            volume.recreated_dirs_size(
+                                dirname,
+                                &self.dirs,
+                                self.args.recreate_dirs_mode,
+                            )
+                        })
+                        .unwrap_or(0)
+            }
+            SizeCounts::DataOnly => volume.acc_size - volume.recreated_size + volume.dirs_reserve,
+        };
+        // A hard link to a file of another volume may become a copy of it.
+        let copied_data = match &link_target {
+            Some(target)
+                if self.args.dereference_split_links && !volume.stored_files.contains(target) =>
+            {
+                self.link_targets
+                    .get(target)
+                    .map(|(header, _)| header.entry_size().unwrap_or(0))
+                    .unwrap_or(0)
+            }
+            _ => 0,
+        };
+        // Rolling from a volume without entries would leave it empty, as
+        // the entry doesn't fit into any volume then.
+        let has_entries = volume.file_count > 0;
+        // Files joining a pack don't add entries to the volume.
+        let too_many_files = match self.args.max_files {
+            Some(max) => {
+                let new_files = match &record {
+                    Some(_) => volume.pack.is_empty() as u64,
+                    None => 1,
+                };
+                volume.file_count > 0 && volume.file_count + new_files > max
+            }
+            None => false,
+        };
+        let entry_size = match &record {
+            Some(record) => {
+                let new_pack_size = match volume.pack.is_empty() {
+                    true => TAR_HEADER_SIZE + PACK_MAGIC.len() as u64,
+                    false => 0,
+                };
+                record.len() as u64 + entry.size() + new_pack_size
+            }
+            // The entry goes with its extension members as a whole, so that
+            // none of them is left in the previous volume.
+            None => {
+                TAR_HEADER_SIZE
+                    + padded_size(entry.size())
+                    + extensions.footprint()
+                    + pending_size
+                    + padded_size(copied_data)
+            }
+        };
+        let payload = match is_extension(entry.header()) {
+            true => 0,
+            false => entry.size() + copied_data,
+        };
+        let entry_size = match self.args.size_metric {
+            SizeMetric::Archive => entry_size,
+            SizeMetric::Payload => payload,
+        };
+        let entry_idx = self.entry_idx;
+        self.entry_idx += 1;
+        // Entries outside of the dirs, like top-level files, don't end one.
+        let new_kept_dir = match self.args.keep_dirs_together {
+            Some(depth) if !is_extension(entry.header()) => {
+                match dir_at_depth(&path, is_dir_header(&header), depth) {
+                    Some(dir) if self.kept_dir.as_deref() != Some(dir) => {
+                        self.kept_dir = Some(dir.to_vec());
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+
+        let mut multi_volume = false;
+        let planned = match &mut self.plan {
+            Some(plan) => {
+                Some(plan.volume_for(entry_idx, &entry.path_bytes(), self.entry_start)?)
+            }
+            None => None,
+        };
+        let roll = match (planned, self.args.volume_max_size(self.vol_idx)) {
+            (Some(planned), _) => {
+                if planned < self.vol_idx {
+                    return Err(ah::anyhow!(
+                        "entry {:?} is planned for volume {}, but volume {} is being written; \
+                         volumes have to be non-decreasing in the input order",
+                        String::from_utf8_lossy(&entry.path_bytes()),
+                        planned,
+                        self.vol_idx,
+                    )
+                    .into());
+                }
+                // Skipped volume numbers produce empty volumes.
+                while planned > self.vol_idx + 1 {
+                    self.start_new_volume()?;
+                }
+                (planned > self.vol_idx).then_some(RollReason::Planned(planned))
+            }
+            (None, Some(max_size)) => {
+                // In a new volume, the entry comes with all its dirs.
+                let new_dirs_size: u64 =
+                    match self.args.recreate_dirs && self.args.size_metric == SizeMetric::Archive {
+                        true => recreated_dirname(&path, b"")
+                            .map(|dirname| {
+                                recreated_dirs(dirname, &self.dirs, self.args.recreate_dirs_mode)
+                                    .iter()
+                                    .map(|(_, dir)| dir.footprint())
+                                    .sum()
+                            })
+                            .unwrap_or(0),
+                        false => 0,
+                    };
+                // And the global headers, some of which are counted with it.
+                let written_globals = self.volume.as_ref().unwrap().globals_written;
+                let new_dirs_size = new_dirs_size
+                    + self.global_headers[..written_globals]
+                        .iter()
+                        .map(|(_, data)| TAR_HEADER_SIZE + padded_size(data.len() as _))
+                        .sum::<u64>();
+                if self.args.estimated_size(new_dirs_size + entry_size) > max_size {
+                    if self.args.fail_on_large_file {
+                        return Err(Error::FileTooLarge(
+                            String::from_utf8_lossy(&entry.path_bytes()).to_string(),
+                        ));
+                    }
+                    self.oversized += 1;
+                }
+                // A file that doesn't fit into an empty volume starts in the
+                // current one anyway.
+                multi_volume = self.args.multi_volume
+                    && record.is_none()
+                    && entry.header().entry_type().is_file()
+                    && 2 * TAR_HEADER_SIZE + self.args.injected_size() + entry_size > max_size;
+                let space = max_size.saturating_sub(self.args.estimated_size(acc_size));
+                if too_many_files {
+                    self.args.max_files.map(RollReason::MaxFiles)
+                } else if !multi_volume
+                    && has_entries
+                    && self.args.estimated_size(acc_size + entry_size) > max_size
+                {
+                    Some(RollReason::MaxSize(max_size))
+                } else if new_kept_dir && has_entries && space < self.args.dir_min_space(max_size) {
+                    // A dir that doesn't fit anyway is split as usual.
+                    Some(RollReason::KeptDir(space))
+                } else {
+                    None
+                }
+            }
+            (None, None) if self.args.max_files.is_some() => self
+                .args
+                .max_files
+                .filter(|_| too_many_files)
+                .map(RollReason::MaxFiles),
+            (None, None) => unreachable!("clap requires a max size or a plan"),
+        };
+
+        if let Some(field) = bad_field {
+            let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let offset = entry.raw_header_position();
+            if self.args.skip_bad_headers {
+                log::warn!(
+                    "Skipping {:?} at input offset {}: malformed {} header field",
+                    path,
+                    offset,
+                    field
+                );
+                self.pending_extensions.clear();
+                return Ok(());
+            }
+            log::warn!(
+                "Copying {:?} at input offset {} as is: malformed {} header field",
+                path,
+                offset,
+                field
+            );
+            if let Some(reason) = roll {
+                self.roll_volume(reason, &entry.path_bytes(), entry_size)?;
+            }
+            self.write_global_headers()?;
+            self.write_pending_extensions()?;
+            let header = data_header(&entry);
+            let volume = self.volume.as_mut().unwrap();
+            volume.payload_size += payload;
+            volume.write_extensions(&extensions)?;
+            volume.write_raw(&header, &mut entry, self.args.verbose)?;
+            return Ok(());
+        }
+
+        if self.args.dirs_first && is_dir_header(entry.header()) {
+            // Dirs are written at the end, when all of them are known.
+            self.dirs
+                .insert(path, Box::new(StoredDir { header, extensions }));
+            return Ok(());
+        }
+
+        if self.args.is_verbatim() && is_extension(entry.header()) {
+            let mut data = vec![];
+            io::Read::read_to_end(&mut entry, &mut data)?;
+            self.pending_extensions.push((entry.header().clone(), data));
+            return Ok(());
+        }
+
+        if let Some(reason) = roll {
+            self.roll_volume(reason, &path, entry_size)?;
+        }
+        self.write_global_headers()?;
+
+        if self.args.is_verbatim() {
+            self.write_pending_extensions()?;
+            let volume = self.volume.as_mut().unwrap();
+            volume.payload_size += payload;
+            volume.write_raw(&header, &mut entry, self.args.verbose)?;
+            return Ok(());
+        }
+
+        // The link is checked in the volume it goes to.
+        let copy = match &link_target {
+            Some(target) if !self.volume.as_ref().unwrap().stored_files.contains(target) => {
+                self.split_link(&path, target, &mut header, &mut extensions)?
+            }
+            _ => None,
+        };
+        let packed = record.is_some();
+        let volume = self.volume.as_mut().unwrap();
+        volume.payload_size += payload;
+
+        if self.args.recreate_dirs {
+            log::debug!("Checking path {:?}", String::from_utf8_lossy(&path));
+            if let Some(dirname) = recreated_dirname(&path, &volume.prev_dir) {
+                volume.inject_dirs_for_path(
+                    dirname,
+                    &self.dirs,
+                    self.args.recreate_dirs_mode,
+                    self.args.verbose,
+                )?;
+                volume.prev_dir = dirname.to_vec();
+            } else {
+                log::debug!("Dirname is same, skip it.")
+            }
+        }
+
+        match record {
+            Some(record) => volume.write_packed(&header, &record, &mut entry, self.args.verbose)?,
+            None if multi_volume => {
+                volume.write_pack()?;
+                self.write_multi_volume(&header, &extensions, &mut entry)?;
+            }
+            None => {
+                // Only consecutive files are packed together.
+                volume.write_pack()?;
+                volume.write_extensions(&extensions)?;
+                match copy {
+                    Some(data) => volume.write_data(&header, data, self.args.verbose)?,
+                    None => volume.write_data(&header, &mut entry, self.args.verbose)?,
+                }
+            }
+        }
+
+        // A multi-volume file counts for the volume it ends in.
+        let volume = self.volume.as_mut().unwrap();
+        if self.args.checks_links() && !packed && !is_dir_header(&header) {
+            volume.stored_files.insert(&path);
+            if self.args.dereference_split_links {
+                let source = match &link_target {
+                    Some(target) => self.link_targets.get(target).cloned(),
+                    // The data of sparse files is not where the input has it.
+                    None if entry.header().entry_type().is_file() => {
+                        Some((entry.header().clone(), entry.raw_header_position()))
+                    }
+                    None => None,
+                };
+                if let Some(source) = source {
+                    self.link_targets.insert(path.clone(), source);
+                }
+            }
+        }
+
+        // Symlinks may stand for dirs in the paths of the next entries, and
+        // are recreated instead of plain dirs then, so they are kept as dirs
+        // with a trailing slash.
+        let dir_path = match header.entry_type() {
+            _ if is_dir_header(&header) => Some(path),
+            tar::EntryType::Symlink => Some([path.as_slice(), b"/"].concat()),
+            _ => None,
+        };
+        if let Some(path) = dir_path.filter(|_| self.args.recreate_dirs) {
+            volume.stored_dirs.insert(&path);
+            // The memory for the dirs is bounded by these; the dirs that are
+            // not kept are not recreated.
+            let too_deep = self
+                .args
+                .recreate_dirs_depth
+                .is_some_and(|depth| dir_at_depth(&path, true, depth as usize + 1).is_some());
+            // A dir that comes again is updated anyway.
+            let full = self
+                .args
+                .recreate_dirs_limit
+                .filter(|&limit| self.dirs.len() as u64 >= limit && !self.dirs.contains_key(&path));
+            if let Some(limit) = full.filter(|_| !self.dirs_limit_reached) {
+                log::warn!(
+                    "More than --recreate-dirs-limit {} dirs; the next ones are not \
+                     recreated in new volumes",
+                    limit
+                );
+                self.dirs_limit_reached = true;
+            }
+            if !too_deep && full.is_none() {
+                let dir = Box::new(StoredDir { header, extensions });
+                let footprint = dir.footprint();
+                let prev_footprint = self.dirs.insert(path, dir).map_or(0, |dir| dir.footprint());
+                self.dirs_footprint = self.dirs_footprint + footprint - prev_footprint;
+                // The dir is in the current volume, but the volumes of the
+                // other streams may get it.
+                for (volume, _) in self.idle_streams.values_mut() {
+                    volume.dirs_reserve += footprint.saturating_sub(prev_footprint);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Modify the header of the entry being written, wrt options, returning
+    /// the path it is written with, or `None` if the entry is skipped.  The
+    /// entry's data is not affected.
+    fn rewrite_header<R: io::Read>(
+        &self,
+        entry: &tar::Entry<R>,
+        header: &mut tar::Header,
+        extensions: &mut Extensions,
+    ) -> ah::Result<Option<Vec<u8>>> {
+        let mut path = entry.path_bytes().into_owned();
+        let mut link_name = entry.link_name_bytes().map(|name| name.into_owned());
+        if self.args.renames_paths() {
+            let Some(renamed) = self.args.renamed_path(&path) else {
+                return Ok(None);
+            };
+            path = renamed;
+            set_entry_path(header, extensions, &path);
+            // Hard links point to paths in the archive, which are renamed
+            // too; symlinks are relative to the link, if not absolute.
+            if header.entry_type().is_hard_link() {
+                let renamed = self
+                    .args
+                    .renamed_path(link_name.as_deref().unwrap_or_default());
+                let Some(renamed) = renamed else {
+                    return Ok(None);
+                };
+                set_entry_link_name(header, extensions, &renamed);
+                link_name = Some(renamed);
+            }
+            header.set_cksum();
+        }
+        if self.args.rewrites_metadata() {
+            scrub_header(header, extensions, &self.args.scrub);
+            normalize_header(header, extensions, &self.args);
+            header.set_cksum();
+        }
+        if let Some(format) = self.args.format {
+            *header = convert_header(header, format, &path, link_name.as_deref(), extensions)
+                .with_context(|| {
+                    format!(
+                        "failed to convert {:?} to --format {}",
+                        String::from_utf8_lossy(&path),
+                        format.name()
+                    )
+                })?;
+        }
+        Ok(Some(path))
+    }
+
+    /// Warn about a hard link whose target is not in the current volume, or
+    /// with --dereference-split-links, turn it into a regular file and
+    /// return the target's data, read from the input file again.
+    fn split_link(
+        &self,
+        path: &[u8],
+        target: &[u8],
+        header: &mut tar::Header,
+        extensions: &mut Extensions,
+    ) -> ah::Result<Option<io::Take<std::fs::File>>> {
+        let volume_name = &self.volume.as_ref().unwrap().volume_name;
+        let path = String::from_utf8_lossy(path);
+        let target_name = String::from_utf8_lossy(target);
+        if !self.args.dereference_split_links {
+            log::warn!(
+                "Hard link {:?} in {} points to {:?}, which is not in the volume",
+                path,
+                volume_name,
+                target_name,
+            );
+            return Ok(None);
+        }
+        let (target_header, offset) = match self.link_targets.get(target) {
+            Some(source) => source,
+            None => {
+                log::warn!(
+                    "Keeping hard link {:?} in {}: {:?} is not a regular file written before it",
+                    path,
+                    volume_name,
+                    target_name,
+                );
+                return Ok(None);
+            }
+        };
+        let mut input = std::fs::File::open(&self.args.input_file)
+            .with_context(|| format!("failed to open {:?} again", self.args.input_file))?;
+        let mut block = [0; TAR_HEADER_SIZE as usize];
+        let read = io::Seek::seek(&mut input, io::SeekFrom::Start(*offset))
+            .and_then(|_| io::Read::read_exact(&mut input, &mut block));
+        // A shorter input is not the one the offsets are in either.
+        match read {
+            Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                return Err(e).with_context(|| format!("failed to read {:?} again", target_name));
+            }
+            _ => {}
+        }
+        ah::ensure!(
+            read.is_ok() && block == *target_header.as_bytes(),
+            "cannot find {:?} at input offset {} for hard link {:?}; \
+             --dereference-split-links needs an uncompressed input",
+            target_name,
+            offset,
+            path,
+        );
+        log::info!(
+            "Copying {:?} into {} for hard link {:?}",
+            target_name,
+            volume_name,
+            path
+        );
+        let size = target_header.entry_size()?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(size);
+        set_entry_link_name(header, extensions, b"");
+        header.set_cksum();
+        Ok(Some(io::Read::take(input, size)))
+    }
+
+    /// Write a --multi-volume file in parts, starting in the current volume
+    /// and continuing in the next ones.  The first part is a regular entry
+    /// of the part size with the whole size in a PAX record, the others are
+    /// GNU `M` entries with their offsets in the file.
+    fn write_multi_volume<R: io::Read>(
+        &mut self,
+        header: &tar::Header,
+        extensions: &Extensions,
+        mut data: R,
+    ) -> ah::Result<()> {
+        let size = header.entry_size()?;
+        let mut extensions = extensions.clone();
+        extensions.set_pax(MULTI_VOLUME_SIZE_KEY, size.to_string().as_bytes());
+        let mut offset = 0;
+        let mut fresh = false;
+        loop {
+            let volume = self.volume.as_mut().unwrap();
+            let max_size = self
+                .args
+                .volume_max_size(self.vol_idx)
+                .expect("internal: --multi-volume without --max-size");
+            let overhead = match offset {
+                0 => TAR_HEADER_SIZE + extensions.footprint(),
+                _ => TAR_HEADER_SIZE,
+            };
+            let space = max_size.saturating_sub(volume.acc_size + overhead) / TAR_HEADER_SIZE
+                * TAR_HEADER_SIZE;
+            let part = space.min(size - offset);
+            if part == 0 {
+                ah::ensure!(
+                    !fresh,
+                    "{} {} is too small for a part of {:?} with its headers",
+                    self.args.max_size_option(),
+                    max_size,
+                    String::from_utf8_lossy(&header.path_bytes()),
+                );
+                self.start_new_volume()?;
+                fresh = true;
+                continue;
+            }
+            let part_header = if offset == 0 {
+                volume.write_extensions(&extensions)?;
+                let mut part_header = header.clone();
+                part_header.set_size(part);
+                part_header.set_cksum();
+                part_header
+            } else {
+                continuation_header(header, offset, part)
+            };
+            volume.write_data(
+                &part_header,
+                io::Read::take(&mut data, part),
+                self.args.verbose,
+            )?;
+            offset += part;
+            if offset == size {
+                return Ok(());
+            }
+            log::info!(
+                "Continuing {:?} at offset {} in the next volume",
+                String::from_utf8_lossy(&header.path_bytes()),
+                offset
+            );
+            self.start_new_volume()?;
+            fresh = true;
+        }
+    }
+
+    /// With a plan, --max-size is not used for rolling, but still has to
+    /// be respected.
+    fn check_planned_size(&self) -> ah::Result<()> {
+        // None after --total-size stops the split.
+        let Some(volume) = self.volume.as_ref() else {
+            return Ok(());
+        };
+        if let (Some(_), Some(max_size)) = (&self.plan, self.args.volume_max_size(self.vol_idx)) {
+            let size = self
+                .args
+                .estimated_size(volume.measured_size(self.args.size_metric));
+            ah::ensure!(
+                size <= max_size,
+                "planned volume {} is {} bytes, which exceeds {}",
+                volume.volume_name,
+                size,
+                self.args.max_size_option(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Count a volume about to be started against --max-volumes.
+    fn count_new_volume(&mut self) -> Result<()> {
+        if let Some(max) = self.args.max_volumes {
+            if self.volumes_started >= max {
+                // The entry that needed a new volume is not written.
+                self.drop_current_entry();
+                return Err(Error::TooManyVolumes(max));
+            }
+        }
+        self.volumes_started += 1;
+        Ok(())
+    }
+
+    /// Truncate the split before the current entry, which is not counted
+    /// then; its extension members go with it.
+    fn drop_current_entry(&mut self) {
+        self.truncated = true;
+        if std::mem::take(&mut self.entry_counted) {
+            self.entries_total -= 1;
+        }
+        self.pending_extensions.clear();
+    }
+
+    /// Check that the next volume, at its max size, still fits into
+    /// --total-size with the finished ones, which are measured on disk.
+    fn check_total_size(&mut self) -> Result<()> {
+        let Some(total_size) = self.args.total_size else {
+            return Ok(());
+        };
+        self.finish_volumes(0)?;
+        let next_size = self.args.volume_max_size(self.vol_idx + 1).unwrap_or(0);
+        if self.bytes_stored + next_size > total_size {
+            // As with --max-volumes, the entry is not written.
+            self.drop_current_entry();
+            return Err(Error::OverTotalSize(total_size));
+        }
+        Ok(())
+    }
+
+    /// Start a new volume for the entry of the given size, telling why with
+    /// --verbose.
+    fn roll_volume(&mut self, reason: RollReason, path: &[u8], entry_size: u64) -> Result<()> {
+        if self.args.verbose {
+            let why = match reason {
+                RollReason::Planned(vol_idx) => format!("is planned for volume {}", vol_idx),
+                RollReason::MaxFiles(max) => format!("would be over --max-files {}", max),
+                RollReason::MaxSize(max) => {
+                    format!("doesn't fit into {} {}", self.args.max_size_option(), max)
+                }
+                RollReason::KeptDir(space) => {
+                    format!("starts a dir, but only {} bytes are left for it", space)
+                }
+            };
+            let volume = self.volume.as_ref().unwrap();
+            suspend_progress_bar(|| {
+                writeln!(
+                    io::stderr().lock(),
+                    "{} ends at {} bytes: {:?} ({} bytes) {}",
+                    volume.volume_name,
+                    volume.measured_size(self.args.size_metric),
+                    String::from_utf8_lossy(path),
+                    entry_size,
+                    why,
+                )
+            })?;
+        }
+        self.start_new_volume()
+    }
+
+    fn start_new_volume(&mut self) -> Result<()> {
+        if self.args.stdout_output() {
+            return Err(ah::anyhow!(
+                "the input doesn't fit into a single volume, which output to stdout \
+                 needs; give an output prefix instead of `-`"
+            )
+            .into());
+        }
+        self.count_new_volume()?;
+        self.check_planned_size()?;
+        self.finish_volume()?;
+        self.check_total_size()?;
+        self.vol_idx += 1;
+        let mut volume = Volume::new(
+            &self.stream,
+            Some(self.vol_idx),
+            &self.args,
+            self.interrupt_flag.clone(),
+            self.manifest.clone(),
+        )?;
+        // Any of the known dirs may be recreated in it.
+        volume.dirs_reserve = self.dirs_footprint;
+        self.volume = Some(volume);
+
+        Ok(())
+    }
+
+    /// Keep a global PAX header for the entries that follow it.  Filters
+    /// and renames don't apply to it, and it goes into every volume from
+    /// the one of the next entry on.
+    fn push_global_header<R: io::Read>(&mut self, mut entry: tar::Entry<R>) -> Result<()> {
+        let entry_idx = self.entry_idx;
+        self.entry_idx += 1;
+        // A layout may list it, though the volume it is planned for doesn't
+        // matter.
+        if let Some(plan) = &mut self.plan {
+            plan.volume_for(entry_idx, &entry.path_bytes(), self.entry_start)?;
+        }
+        // Dropped before it counts for the volume size.
+        if self.args.output_format == OutputFormat::Zip {
+            log::warn!(
+                "Skipping {:?}: zip cannot represent global PAX headers",
+                String::from_utf8_lossy(&entry.path_bytes())
+            );
+            return Ok(());
+        }
+        let mut data = vec![];
+        io::Read::read_to_end(&mut entry, &mut data)?;
+        self.global_headers.push((entry.header().clone(), data));
+        Ok(())
+    }
+
+    fn write_global_headers(&mut self) -> ah::Result<()> {
+        let volume = self.volume.as_mut().unwrap();
+        volume.write_global_headers(&self.global_headers, self.args.verbose)
+    }
+
+    fn write_pending_extensions(&mut self) -> ah::Result<()> {
+        let volume = self.volume.as_mut().unwrap();
+        for (header, data) in self.pending_extensions.drain(..) {
+            volume.write_raw(&header, data.as_slice(), self.args.verbose)?;
+        }
+        Ok(())
+    }
+
+    /// Stop before the next entry, e.g. on an interrupt; finish() completes
+    /// the volumes with the entries so far.  Returns where the rest of the
+    /// input starts in the tar stream.
+    pub fn stop(&mut self) -> u64 {
+        self.truncated = true;
+        match self.pending_extensions.is_empty() {
+            true => self.next_entry_start,
+            // They go with the entry that is not written.
+            false => {
+                self.pending_extensions.clear();
+                self.entry_start
+            }
+        }
+    }
+
+    /// Finish all the volumes and write the files that go with them.
+    pub fn finish(mut self) -> Result<Summary> {
+        if let Some(plan) = self.plan.as_ref().filter(|_| !self.truncated) {
+            plan.check_complete()?;
+        }
+        if !self.pending_extensions.is_empty() {
+            // Malformed input; keep it as is.
+            log::warn!("Input ends with extension members without an entry");
+            self.write_pending_extensions()?;
+        }
+        if let Some(volume) = &self.volume {
+            if volume.globals_written < self.global_headers.len() {
+                log::warn!("Input ends with global PAX headers without an entry");
+                self.write_global_headers()?;
+            }
+        }
+        self.check_planned_size()?;
+        if let Some(volume) = self.volume.take() {
+            self.idle_streams
+                .insert(std::mem::take(&mut self.stream), (volume, self.vol_idx));
+        }
+        // The main sequence, which has the dirs, goes first.
+        let mut streams: Vec<_> = self.idle_streams.drain().collect();
+        streams.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, (volume, _)) in streams {
+            self.volume = Some(volume);
+            self.finish_volume()?;
+        }
+        self.finish_volumes(0)?;
+        if self.args.dirs_first {
+            self.write_dirs_volume()?;
+        }
+        if let Some(created_files) = &mut self.created_files {
+            let prefix = &self.args.output_prefix;
+            let files = [
+                self.args.manifest.clone(),
+                self.args.checksums.clone(),
+                (self.args.coalesce_small.is_some() && !self.args.dry_run)
+                    .then(|| format!("{}{}", prefix, UNPACK_SCRIPT_SUFFIX).into()),
+                (self.args.emit_extract_script).then(|| format!("{}extract.sh", prefix).into()),
+                self.args.restore_script.clone(),
+                (self.args.bundle_metadata).then(|| format!("{}manifest.tar", prefix).into()),
+                (self.args.control_file).then(|| control_file_path(prefix)),
+            ];
+            created_files.paths.extend(files.into_iter().flatten());
+        }
+        if let Some(manifest) = self.manifest.take() {
+            // All the volumes are finished and dropped.
+            Rc::into_inner(manifest)
+                .expect("internal: manifest is still shared")
+                .into_inner()
+                .persist()?;
+        }
+        if let Some(path) = &self.args.checksums {
+            std::fs::write(path, &self.checksums)
+                .with_context(|| format!("failed to write checksums file {:?}", path))?;
+            set_umasked_mode(path, 0o666)?;
+        }
+        if self.args.coalesce_small.is_some() && !self.args.dry_run {
+            write_unpack_script(&self.args)?;
+        }
+        if self.args.emit_extract_script {
+            let script_path = PathBuf::from(format!("{}extract.sh", self.args.output_prefix));
+            write_extract_script(&self.args, &self.finished_volumes, &script_path, false)?;
+        }
+        if let Some(script_path) = &self.args.restore_script {
+            write_extract_script(&self.args, &self.finished_volumes, script_path, true)?;
+        }
+        if self.args.control_file {
+            write_control_file(&self.args, &self.finished_volumes, &self.volume_checksums)?;
+        }
+        if self.args.bundle_metadata {
+            write_metadata_bundle(&self.args, &self.finished_volumes)?;
+        }
+        if let Some(selection) = self.selection.as_ref().filter(|_| !self.truncated) {
+            selection.check_complete()?;
+        }
+        if let Some(created_files) = &mut self.created_files {
+            created_files.succeeded = true;
+        }
+        Ok(Summary {
+            volumes: self.finished_volumes,
+            entries: self.entries_total,
+            bytes: self.bytes_written,
+            stored_bytes: self.bytes_stored,
+            oversized: self.oversized,
+        })
+    }
+
+    fn finish_volume(&mut self) -> ah::Result<()> {
+        let mut volume = self.volume.take().unwrap();
+        volume.finish_writing()?;
+        self.finishing.push_back(volume);
+        // With the volume being written, --jobs compressors run at once.
+        self.finish_volumes(self.args.jobs as usize - 1)
+    }
+
+    /// Finish the written volumes, oldest first, until `keep` of them are
+    /// left.
+    fn finish_volumes(&mut self, keep: usize) -> ah::Result<()> {
+        while self.finishing.len() > keep {
+            let volume = self.finishing.pop_front().unwrap();
+            let target_file = volume.target_file.clone();
+            let written = volume.written.clone();
+            if let Some(created_files) = &mut self.created_files {
+                if !volume.resumed {
+                    created_files.add_volume(&target_file);
+                }
+            }
+            let checksums = volume.finish()?;
+            self.bytes_written += written.get();
+            if !self.args.dry_run && !self.args.stdout_output() {
+                self.bytes_stored += stored_size(&target_file)?;
+            }
+            self.record_checksums(&target_file, &checksums);
+            self.finished_volumes.push(target_file);
+        }
+        Ok(())
+    }
+
+    fn record_checksums(&mut self, volume: &Path, checksums: &[(ChecksumAlgorithm, String)]) {
+        if self.args.checksums.is_some() {
+            for (algorithm, digest) in checksums {
+                self.checksums +=
+                    &format!("{} ({}) = {}\n", algorithm.tag(), volume.display(), digest);
+            }
+        }
+        if self.args.control_file {
+            self.volume_checksums
+                .insert(volume.to_owned(), checksums.to_vec());
+        }
+    }
+
+    /// Write all the dirs into a dedicated volume.  PatriciaMap iterates in
+    /// lexicographic order, so parents always come before their children.
+    fn write_dirs_volume(&mut self) -> ah::Result<()> {
+        let mut volume = Volume::new(
+            "",
+            None,
+            &self.args,
+            self.interrupt_flag.clone(),
+            self.manifest.clone(),
+        )?;
+        volume.write_global_headers(&self.global_headers, self.args.verbose)?;
+        for (_, dir) in self.dirs.iter() {
+            volume.write_extensions(&dir.extensions)?;
+            volume.write_data(&dir.header, io::empty(), self.args.verbose)?;
+        }
+        let target_file = volume.target_file.clone();
+        let written = volume.written.clone();
+        if let Some(created_files) = &mut self.created_files {
+            if !volume.resumed {
+                created_files.add_volume(&target_file);
+            }
+        }
+        let checksums = volume.finish()?;
+        self.bytes_written += written.get();
+        if !self.args.dry_run {
+            self.bytes_stored += stored_size(&target_file)?;
+        }
+        self.record_checksums(&target_file, &checksums);
+        // The dirs have to be extracted first.
+        self.finished_volumes.insert(0, target_file);
+        Ok(())
+    }
+}
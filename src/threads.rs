@@ -0,0 +1,138 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! Reading and writing in background threads.
+
+use std::{
+    io::{self, Write as _},
+    sync::{mpsc, Arc},
+    thread,
+};
+
+/// Data chunks produced by a background thread.
+pub(crate) struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The sender is gone: it has finished.
+                Err(mpsc::RecvError) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Read the input in a background thread, keeping up to `capacity` chunks
+/// ready.
+pub(crate) fn read_in_background<R: io::Read + Send + 'static>(
+    name: &str,
+    mut input: R,
+    capacity: usize,
+) -> io::Result<ChannelReader> {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            loop {
+                let mut chunk = vec![0; 1 << 16];
+                let res = match input.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        chunk.truncate(len);
+                        Ok(chunk)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = res.is_err();
+                // The receiver is gone if the main thread has failed.
+                if sender.send(res).is_err() || failed {
+                    break;
+                }
+            }
+        })?;
+    Ok(ChannelReader {
+        receiver,
+        chunk: vec![],
+        pos: 0,
+    })
+}
+
+/// Passes written data to a background thread.  Write errors are reported
+/// by the thread when it is joined; the writer gets a copy when it notices
+/// the thread is gone.
+pub(crate) struct ChannelWriter {
+    sender: mpsc::SyncSender<Vec<u8>>,
+    failure: Arc<std::sync::Mutex<Option<io::Error>>>,
+}
+
+impl io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender.send(buf.to_vec()).map_err(|_| {
+            self.failure.lock().unwrap().take().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "output writer thread has failed")
+            })
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The thread flushes when the writer is dropped.
+        Ok(())
+    }
+}
+
+pub(crate) fn write_in_background(
+    mut output: Box<dyn io::Write + Send>,
+    capacity: usize,
+) -> io::Result<(ChannelWriter, thread::JoinHandle<io::Result<()>>)> {
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(capacity);
+    let failure = Arc::new(std::sync::Mutex::new(None));
+    let thread_failure = failure.clone();
+    let handle = thread::Builder::new()
+        .name("output".to_owned())
+        .spawn(move || {
+            // The receiver is kept until the failure is stored.
+            let res = receiver
+                .iter()
+                .try_for_each(|chunk| output.write_all(&chunk))
+                .and_then(|()| output.flush());
+            if let Err(e) = &res {
+                *thread_failure.lock().unwrap() = Some(io::Error::new(e.kind(), e.to_string()));
+            }
+            res
+        })?;
+    Ok((ChannelWriter { sender, failure }, handle))
+}
@@ -0,0 +1,267 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! `splitar verify`, which checks the volumes.
+
+use crate::{
+    checksum::{read_checksum_files, read_checksums, Checksums},
+    compress::decompress_command,
+    header::{is_extension, is_valid_header, pax_size},
+    input::decompressed_input,
+    join::split_volumes,
+    padded_size, TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use clap::Parser;
+use std::{
+    collections::HashMap,
+    io::{self, Read as _, Write as _},
+    path::PathBuf,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "splitar verify",
+    bin_name = "splitar verify",
+    about = "Check volumes written by splitar against their checksums or as tar archives",
+    version
+)]
+pub(crate) struct VerifyArgs {
+    #[arg(
+        long,
+        help = "check that each volume, decompressed if needed, is a well-formed tar \
+                archive instead: valid header checksums, whole entries and the two \
+                end-of-archive blocks; the first bad offset is reported"
+    )]
+    structure: bool,
+
+    #[arg(
+        long,
+        value_hint = clap::ValueHint::FilePath,
+        conflicts_with = "volumes",
+        help = "check the volumes listed in this --checksums file, with paths \
+                relative to the current dir"
+    )]
+    checksums: Option<PathBuf>,
+
+    #[arg(
+        required_unless_present = "checksums",
+        value_hint = clap::ValueHint::FilePath,
+        help = "volume files, or the output prefix of the split; each volume is \
+                checked against its `<volume>.sha256` and `<volume>.crc32` files, or \
+                the --control-file of the split"
+    )]
+    volumes: Vec<PathBuf>,
+}
+
+/// Check the volumes against their checksum files or the --checksums file,
+/// reporting each volume like `sha256sum --check` does.
+pub(crate) fn verify(args: VerifyArgs) -> ah::Result<()> {
+    if args.structure {
+        return verify_structure(&args);
+    }
+    // The --control-file has the sizes too.
+    let mut sizes = HashMap::new();
+    let expected = match &args.checksums {
+        Some(path) => read_checksums(path)?,
+        None => {
+            let (volumes, control) = match args.volumes.as_slice() {
+                [prefix] if !prefix.is_file() => split_volumes(&prefix.to_string_lossy())?,
+                volumes => (volumes.to_vec(), None),
+            };
+            match control {
+                Some(control) => control
+                    .volumes
+                    .into_iter()
+                    .map(|volume| {
+                        sizes.insert(volume.path.clone(), volume.size);
+                        (volume.path, volume.checksums)
+                    })
+                    .collect(),
+                None => volumes
+                    .into_iter()
+                    .map(|volume| {
+                        let checksums = read_checksum_files(&volume)?;
+                        Ok((volume, checksums))
+                    })
+                    .collect::<ah::Result<_>>()?,
+            }
+        }
+    };
+    let mut failed = 0;
+    for (volume, checksums) in &expected {
+        let algorithms: Vec<_> = checksums.iter().map(|(algorithm, _)| *algorithm).collect();
+        let mut actual = Checksums::new(&algorithms);
+        let mut file = std::fs::File::open(volume)
+            .with_context(|| format!("failed to open volume {:?}", volume))?;
+        let mut buf = vec![0; 1 << 16];
+        let mut size = 0;
+        loop {
+            let len = io::Read::read(&mut file, &mut buf)
+                .with_context(|| format!("failed to read volume {:?}", volume))?;
+            if len == 0 {
+                break;
+            }
+            actual.update(&buf[..len]);
+            size += len as u64;
+        }
+        let mut mismatched: Vec<_> = actual
+            .finish()
+            .into_iter()
+            .filter(|actual| !checksums.contains(actual))
+            .map(|(algorithm, _)| algorithm.tag())
+            .collect();
+        if sizes.get(volume).is_some_and(|&expected| expected != size) {
+            mismatched.insert(0, "size");
+        }
+        if mismatched.is_empty() {
+            writeln!(io::stdout(), "{}: OK", volume.display())?;
+        } else {
+            failed += 1;
+            writeln!(
+                io::stdout(),
+                "{}: FAILED {}",
+                volume.display(),
+                mismatched.join(", ")
+            )?;
+        }
+    }
+    ah::ensure!(
+        failed == 0,
+        "{} of {} volumes failed the check",
+        failed,
+        expected.len()
+    );
+    Ok(())
+}
+
+/// Check that every volume can be extracted alone.
+fn verify_structure(args: &VerifyArgs) -> ah::Result<()> {
+    let (volumes, control) = match (&args.checksums, args.volumes.as_slice()) {
+        (Some(path), _) => {
+            let volumes = read_checksums(path)?
+                .into_iter()
+                .map(|(volume, _)| volume)
+                .collect();
+            (volumes, None)
+        }
+        (None, [prefix]) if !prefix.is_file() => split_volumes(&prefix.to_string_lossy())?,
+        (None, volumes) => (volumes.to_vec(), None),
+    };
+    // Other volumes are detected by their magic.
+    let decompress = control
+        .and_then(|control| control.compress)
+        .and_then(|compress| decompress_command(&compress));
+    let mut failed = 0;
+    for volume in &volumes {
+        let file = std::fs::File::open(volume)
+            .with_context(|| format!("failed to open volume {:?}", volume))?;
+        let input = decompressed_input(Box::new(file), decompress)
+            .with_context(|| format!("failed to read volume {:?}", volume))?;
+        match check_tar_structure(io::BufReader::new(input)) {
+            Ok(_) => writeln!(io::stdout(), "{}: OK", volume.display())?,
+            Err((offset, problem)) => {
+                failed += 1;
+                writeln!(
+                    io::stdout(),
+                    "{}: FAILED at offset {}: {}",
+                    volume.display(),
+                    offset,
+                    problem
+                )?;
+            }
+        }
+    }
+    ah::ensure!(
+        failed == 0,
+        "{} of {} volumes failed the check",
+        failed,
+        volumes.len()
+    );
+    Ok(())
+}
+
+/// Walk the headers of a tar stream, returning the number of its entries,
+/// not counting extension members, or the offset of the first problem in
+/// it, after decompression.
+pub(crate) fn check_tar_structure(
+    mut input: impl io::Read,
+) -> std::result::Result<u64, (u64, String)> {
+    let mut offset = 0;
+    let mut entries = 0;
+    // The size record of the last PAX extended header, for its entry.
+    let mut next_size = None;
+    let mut block = [0; TAR_HEADER_SIZE as usize];
+    let read_error = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => "the archive ends without the end-of-archive blocks".into(),
+        _ => format!("failed to read: {}", e),
+    };
+    loop {
+        input
+            .read_exact(&mut block)
+            .map_err(|e| (offset, read_error(e)))?;
+        if block.iter().all(|&b| b == 0) {
+            offset += TAR_HEADER_SIZE;
+            input
+                .read_exact(&mut block)
+                .map_err(|e| (offset, read_error(e)))?;
+            if block.iter().any(|&b| b != 0) {
+                return Err((offset, "only one end-of-archive block".into()));
+            }
+            // The rest is padding, e.g. to --record-size; a decompressor
+            // failure is found at its end.
+            return io::copy(&mut input, &mut io::sink())
+                .map(|_| entries)
+                .map_err(|e| (offset + TAR_HEADER_SIZE, read_error(e)));
+        }
+        if !is_valid_header(&block) {
+            return Err((offset, "invalid header checksum".into()));
+        }
+        let header = tar::Header::from_byte_slice(&block);
+        let path = String::from_utf8_lossy(&header.path_bytes()).into_owned();
+        let mut size = header
+            .entry_size()
+            .map_err(|_| (offset, format!("malformed size of {:?}", path)))?;
+        if !is_extension(header) {
+            size = next_size.take().unwrap_or(size);
+        }
+        let data = padded_size(size);
+        let mut records = vec![];
+        let copied = match header.entry_type().is_pax_local_extensions() {
+            true => io::Read::take(&mut input, data).read_to_end(&mut records),
+            false => io::copy(&mut io::Read::take(&mut input, data), &mut io::sink())
+                .map(|copied| copied as usize),
+        }
+        .map_err(|e| (offset, read_error(e)))?;
+        if (copied as u64) < data {
+            return Err((offset, format!("the data of {:?} is truncated", path)));
+        }
+        if header.entry_type().is_pax_local_extensions() {
+            next_size = pax_size(&records[..records.len().min(size as usize)]);
+        }
+        // Global PAX headers are repeated in every volume.
+        if !is_extension(header) && !header.entry_type().is_pax_global_extensions() {
+            entries += 1;
+        }
+        offset += TAR_HEADER_SIZE + data;
+    }
+}
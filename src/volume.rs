@@ -0,0 +1,1421 @@
+/* Copyright 2022 Ivan Boldyrev
+ *
+ * Licensed under the MIT License.
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+
+//! A volume being written, and the files that go with it.
+
+use crate::{
+    args::{
+        Args, BuiltinCompression, OutputFormat, RecreateDirsMode, SizeMetric, TarFormat,
+        VerboseFormat,
+    },
+    checksum::{write_checksum_file, ChecksumAlgorithm, ChecksummedFile, Checksums},
+    compress::{
+        decompress_command, spawn_compressor, CompressorStdin, EncodedOutput, Encoder, Replay,
+    },
+    dirs::{recreated_dirs, StoredDir},
+    header::{
+        entry_type_char, is_dir_header, is_extension, new_header, pax_footprint, print_header,
+        print_header_json, Extensions,
+    },
+    input::decompressed_input,
+    manifest::VolumeManifest,
+    padded_size, set_umasked_mode, shell,
+    threads::write_in_background,
+    verify::check_tar_structure,
+    SubprocessFailure, MIN_VOLUME_SIZE, TAR_HEADER_SIZE,
+};
+use anyhow::{self as ah, Context as _};
+use chrono::TimeZone;
+use interruptable::Interruptable;
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+type SplitarRead =
+    Interruptable<CountingWriter<io::BufWriter<Box<dyn io::Write>>>, Arc<AtomicBool>>;
+
+/// Counts bytes that reached the underlying writer.
+struct CountingWriter<W> {
+    inner: W,
+    written: Rc<Cell<u64>>,
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written.set(self.written.get() + len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer of the volume entries in the output format.
+enum Packer {
+    Tar(tar::Builder<SplitarRead>),
+    // ZipWriter is a large struct.
+    Zip(Box<zip::ZipWriter<zip::write::StreamWriter<SplitarRead>>>),
+}
+
+impl Packer {
+    /// Returns whether the entry is written; zip skips the entries it
+    /// cannot represent.
+    fn append<R: io::Read>(&mut self, header: &tar::Header, mut data: R) -> ah::Result<bool> {
+        let zip = match self {
+            Packer::Tar(builder) => {
+                builder.append(header, data)?;
+                return Ok(true);
+            }
+            Packer::Zip(zip) => zip,
+        };
+        let path = String::from_utf8_lossy(&header.path_bytes()).into_owned();
+        let size = header.size()?;
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(header.mode()?)
+            .last_modified_time(zip_datetime(header.mtime()?))
+            .large_file(size >= u32::MAX as u64);
+        if is_dir_header(header) {
+            zip.add_directory(path, options)?;
+            return Ok(true);
+        }
+        match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => {
+                zip.start_file(path, options)?;
+                io::copy(&mut data, zip)?;
+            }
+            tar::EntryType::Symlink => {
+                let target = header.link_name_bytes().unwrap_or_default();
+                zip.add_symlink(path, String::from_utf8_lossy(&target), options)?;
+            }
+            _ => {
+                log::warn!(
+                    "Skipping {:?}: zip cannot represent entries of type {}",
+                    path,
+                    entry_type_char(header),
+                );
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Write a PAX extended header member for the next entry.  Zip has no
+    /// place for the records, so they are dropped.
+    fn append_pax(&mut self, records: &[u8]) -> ah::Result<()> {
+        match self {
+            Packer::Tar(builder) => {
+                let mut header = tar::Header::new_ustar();
+                // Python's tarfile uses the same name; the real one is in the
+                // following entry.
+                header.set_path("././@PaxHeader")?;
+                header.set_entry_type(tar::EntryType::XHeader);
+                header.set_size(records.len() as _);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append(&header, records)?;
+            }
+            Packer::Zip(_) => log::debug!("Dropping PAX records for zip"),
+        }
+        Ok(())
+    }
+
+    /// Write GNU long name members for the next entry.  Zip names are
+    /// taken from the entry headers, so the members are dropped.
+    fn append_long_names(&mut self, members: &[u8]) -> ah::Result<()> {
+        match self {
+            Packer::Tar(builder) => builder.get_mut().write_all(members)?,
+            Packer::Zip(_) => log::debug!("Dropping GNU long name members for zip"),
+        }
+        Ok(())
+    }
+
+    /// Raw tar stream, for the tar-only modes.
+    fn tar_stream(&mut self) -> &mut SplitarRead {
+        match self {
+            Packer::Tar(builder) => builder.get_mut(),
+            Packer::Zip(_) => unreachable!("internal: raw output is checked to be tar-only"),
+        }
+    }
+
+    /// Finish the archive, returning the underlying writer.
+    fn into_inner(self) -> ah::Result<SplitarRead> {
+        Ok(match self {
+            Packer::Tar(builder) => builder.into_inner()?,
+            Packer::Zip(zip) => zip.finish()?.into_inner(),
+        })
+    }
+}
+
+/// Zip stores local time with 2-second precision, and only since 1980.
+fn zip_datetime(mtime: u64) -> zip::DateTime {
+    use chrono::{Datelike as _, Timelike as _};
+
+    chrono::Local {}
+        .timestamp_opt(mtime as _, 0)
+        .single()
+        .and_then(|t| {
+            zip::DateTime::from_date_and_time(
+                t.year().try_into().ok()?,
+                t.month() as _,
+                t.day() as _,
+                t.hour() as _,
+                t.minute() as _,
+                t.second() as _,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+// This struct has some Option<T> field.  They are always
+// Some(_), except Drop::drop or similar methods.
+pub(crate) struct Volume {
+    pub(crate) acc_size: u64,
+    // Data bytes of the input entries, for --size-metric payload.
+    pub(crate) payload_size: u64,
+    // Global PAX headers of the input that are in the volume already.
+    pub(crate) globals_written: usize,
+    // Entries written, for --max-files; a pack of --coalesce-small is one.
+    pub(crate) file_count: u64,
+    builder: Option<Packer>,
+    temp_output: Option<tempfile::TempPath>,
+    // The volume exists already, and it is checked instead of written.
+    pub(crate) resumed: bool,
+    // Nothing is written with --dry-run, the volume is only listed.
+    dry_run: bool,
+    // --verbose mtimes are printed in UTC.
+    utc: bool,
+    verbose_format: VerboseFormat,
+    compressed: bool,
+    pub(crate) target_file: PathBuf,
+    // --mode of the target file.
+    mode: u32,
+    subprocess: Option<Child>,
+    replay: Option<Replay>,
+    encoder: Option<EncodedOutput>,
+    // Copies the --compress output to the file with --checksum.
+    checksum_thread: Option<thread::JoinHandle<io::Result<u64>>>,
+    checksums: Option<Arc<Mutex<Checksums>>>,
+    // Whether the checksums go to `<volume>.<algorithm>` files.
+    checksum_files: bool,
+    // Drains the subprocess' stderr, if it is captured.
+    subprocess_stderr: Option<thread::JoinHandle<io::Result<Vec<u8>>>>,
+    // Writes the output with --threads.
+    output_thread: Option<thread::JoinHandle<io::Result<()>>>,
+    pub(crate) prev_dir: Vec<u8>,
+    pub(crate) stored_dirs: patricia_tree::PatriciaSet,
+    // Bytes of the dirs recreated in it, and the most they may take, for
+    // --size-counts data-only.
+    pub(crate) recreated_size: u64,
+    pub(crate) dirs_reserve: u64,
+    // Paths of the other entries written, except packed ones, for hard
+    // links to check their targets against.
+    pub(crate) stored_files: patricia_tree::PatriciaSet,
+    pub(crate) volume_name: String,
+    // Tar stream bytes written so far, before the compression, if any.
+    pub(crate) written: Rc<Cell<u64>>,
+    record_size: Option<u64>,
+    tape_command: Option<String>,
+    sign: Option<String>,
+    allow_sign_failure: bool,
+    list_volumes: bool,
+    // Records of --coalesce-small files not written yet.
+    pub(crate) pack: Vec<u8>,
+    // --format of the packs.
+    format: Option<TarFormat>,
+    packs_written: usize,
+    keep_partial: bool,
+    // Members written, not counting extension ones, for --self-check.
+    entries_written: u64,
+    self_check: bool,
+    decompress: Option<&'static str>,
+    interrupt_flag: Arc<AtomicBool>,
+    manifest: Option<Rc<RefCell<VolumeManifest>>>,
+    // Where the PAX records or extension members of the next entry start.
+    entry_start: Option<u64>,
+}
+
+impl Volume {
+    /// `vol_idx` is `None` for the --dirs-first volume.
+    pub(crate) fn new(
+        stream: &str,
+        vol_idx: Option<usize>,
+        args: &Args,
+        interrupt_flag: Arc<AtomicBool>,
+        manifest: Option<Rc<RefCell<VolumeManifest>>>,
+    ) -> ah::Result<Self> {
+        let volume_name = match vol_idx {
+            Some(vol_idx) => volume_name(stream, vol_idx, args),
+            None => "dirs".to_owned(),
+        };
+        let mut target_name = args.name_template.render(args, stream, vol_idx);
+        if let Some(extension) = args.appended_extension() {
+            target_name += ".";
+            target_name += extension;
+        }
+        // The tempfile name is still unique, it just has the extension in
+        // the middle.
+        let target_file = match args.stdout_output() {
+            true => PathBuf::from("-"),
+            false => PathBuf::from_str(&target_name)
+                .context("internal: failed to contstruct output path")?,
+        };
+        // With --resume, an existing volume is not written again, but its
+        // entries are accounted as usual, so that the next volumes start
+        // with the same entries as before.
+        let resumed = args.resume && target_file.exists();
+        let mut temp_output = None;
+        let mut maybe_subprocess = None;
+        let mut maybe_stderr = None;
+        let mut maybe_encoder = None;
+        let mut maybe_checksum_thread = None;
+        let mut maybe_replay = None;
+        let algorithms = args.checksum_algorithms();
+        let checksums =
+            (!algorithms.is_empty()).then(|| Arc::new(Mutex::new(Checksums::new(&algorithms))));
+        let out_file: Box<dyn io::Write + Send> = if args.dry_run {
+            log::info!("Planning volume: {:?}", target_file);
+            Box::new(io::sink())
+        } else if resumed {
+            log::info!("Keeping existing volume: {:?}", target_file);
+            Box::new(io::sink())
+        } else {
+            // The only volume goes to stdout as it is written; there are no
+            // checksums then.
+            let out_file = if args.stdout_output() {
+                log::info!("Writing the volume to stdout");
+                None
+            } else {
+                log::info!("Starting new volume: {:?}", target_file);
+                log::debug!("Creating temp file for output");
+                let out_temp_file = tempfile::Builder::new()
+                    // Unwrap is ok as we construct the path with a non-empty suffix, see above
+                    .prefix(target_file.file_name().unwrap())
+                    .rand_bytes(args.suffix_length as _)
+                    .suffix(".tmp")
+                    .tempfile_in(match &args.temp_dir {
+                        Some(dir) => dir.as_path(),
+                        None => target_file.parent().unwrap_or_else(|| Path::new(".")),
+                    })
+                    .context("failed to create output tempfile")?;
+                let (out_file, temp_path) = out_temp_file.into_parts();
+                log::debug!("Output temp file {:?}", temp_path);
+                temp_output = Some(temp_path);
+                Some(out_file)
+            };
+
+            let checksummed = |file| -> Box<dyn io::Write + Send> {
+                match (file, &checksums) {
+                    (Some(file), Some(checksums)) => Box::new(ChecksummedFile {
+                        file,
+                        checksums: checksums.clone(),
+                    }),
+                    (Some(file), None) => Box::new(file),
+                    (None, _) => Box::new(io::stdout()),
+                }
+            };
+
+            match args.compress_command() {
+                Some(command) if args.compress_retries > 0 => {
+                    // The compressor is started by finish_writing.
+                    std::mem::drop(out_file);
+                    let (tar_file, tar_path) = tempfile::Builder::new()
+                        .prefix(target_file.file_name().unwrap())
+                        .suffix(".tar.tmp")
+                        .tempfile_in(match &args.temp_dir {
+                            Some(dir) => dir.as_path(),
+                            None => target_file.parent().unwrap_or_else(|| Path::new(".")),
+                        })
+                        .context("failed to create uncompressed output tempfile")?
+                        .into_parts();
+                    log::debug!("Uncompressed output temp file {:?}", tar_path);
+                    maybe_replay = Some(Replay {
+                        tar: tar_path,
+                        command,
+                        retries: args.compress_retries,
+                        capture_stderr: args.capture_compress_stderr,
+                        algorithms: algorithms.clone(),
+                    });
+                    Box::new(tar_file)
+                }
+                Some(mut command) => {
+                    let compress = args.compress_line().unwrap_or_default();
+                    let (mut subprocess, checksum_thread, stderr) = spawn_compressor(
+                        &mut command,
+                        Stdio::piped(),
+                        out_file,
+                        checksums.as_ref(),
+                        args.capture_compress_stderr,
+                    )?;
+                    maybe_checksum_thread = checksum_thread;
+                    maybe_stderr = stderr;
+
+                    let out = Box::new(CompressorStdin {
+                        stdin: subprocess
+                            .stdin
+                            .take()
+                            .expect("internal: expecting subprocess stdin"),
+                        command: compress,
+                    }) as Box<dyn io::Write + Send>;
+                    // This supborcess has stdin field empty, but we do not use it anyway.
+                    maybe_subprocess = Some(subprocess);
+
+                    out
+                }
+                None => match args.builtin_compression() {
+                    Some(compression) => {
+                        let encoder =
+                            Encoder::new(compression, args.compress_level(), checksummed(out_file))
+                                .context("failed to start the compression")?;
+                        let encoder = EncodedOutput(Arc::new(Mutex::new(encoder)));
+                        maybe_encoder = Some(encoder.clone());
+                        Box::new(encoder)
+                    }
+                    None => checksummed(out_file),
+                },
+            }
+        };
+        let mut output_thread = None;
+        let out_file = if args.threads > 1 {
+            let (writer, handle) = write_in_background(out_file, args.threads as _)?;
+            output_thread = Some(handle);
+            Box::new(writer)
+        } else {
+            out_file as Box<dyn io::Write>
+        };
+        // Counted before buffering, so that the count is the current tar
+        // stream position.
+        let written = Rc::new(Cell::new(0));
+        let out = Interruptable::new(
+            CountingWriter {
+                inner: io::BufWriter::with_capacity(args.write_buffer(), out_file),
+                written: written.clone(),
+            },
+            interrupt_flag.clone(),
+        );
+        let builder = match args.output_format {
+            OutputFormat::Tar => Packer::Tar(tar::Builder::new(out)),
+            OutputFormat::Zip => Packer::Zip(Box::new(zip::ZipWriter::new_stream(out))),
+        };
+
+        let mut volume = Self {
+            acc_size: 2 * TAR_HEADER_SIZE, // Account two EOF empty headers
+            file_count: 0,
+            builder: Some(builder),
+            temp_output,
+            resumed,
+            dry_run: args.dry_run,
+            utc: args.utc,
+            verbose_format: args.verbose_format,
+            compressed: args.compress_line().is_some() || args.builtin_compression().is_some(),
+            target_file,
+            mode: args.mode,
+            subprocess: maybe_subprocess,
+            replay: maybe_replay,
+            encoder: maybe_encoder,
+            checksum_thread: maybe_checksum_thread,
+            checksums,
+            checksum_files: args.checksums.is_none() && !args.checksum.is_empty(),
+            subprocess_stderr: maybe_stderr,
+            output_thread,
+            prev_dir: vec![],
+            recreated_size: 0,
+            dirs_reserve: 0,
+            stored_dirs: Default::default(),
+            stored_files: Default::default(),
+            volume_name,
+            written,
+            record_size: args.record_size(),
+            tape_command: args.tape_command.clone(),
+            sign: args.sign.clone(),
+            allow_sign_failure: args.allow_sign_failure,
+            list_volumes: args.list_volumes,
+            format: args.format,
+            entries_written: 0,
+            payload_size: 0,
+            globals_written: 0,
+            self_check: args.self_check,
+            decompress: args
+                .compress_line()
+                .and_then(|compress| decompress_command(&compress)),
+            pack: vec![],
+            packs_written: 0,
+            keep_partial: args.keep_partial,
+            interrupt_flag,
+            manifest,
+            entry_start: None,
+        };
+        if args.volume_info {
+            let info = volume_info(args, stream, vol_idx);
+            let header = note_header(
+                VOLUME_INFO_FILE_NAME,
+                info.len() as _,
+                args.creation_mtime()?,
+                args.format,
+            );
+            volume.write_data(&header, info.as_bytes(), args.verbose)?;
+            volume.file_count = 0;
+        }
+        if let Some(note) = &args.volume_note {
+            let header = note_header(
+                NOTE_FILE_NAME,
+                note.len() as _,
+                args.creation_mtime()?,
+                args.format,
+            );
+            volume.write_data(&header, note.as_bytes(), args.verbose)?;
+            volume.file_count = 0;
+        }
+        Ok(volume)
+    }
+
+    pub(crate) fn write_data<R: io::Read>(
+        &mut self,
+        header: &tar::Header,
+        data: R,
+        verbose: bool,
+    ) -> ah::Result<()> {
+        self.write_entry(header, data, verbose, false)
+    }
+
+    /// Bytes of the volume that count towards --max-size.
+    pub(crate) fn measured_size(&self, metric: SizeMetric) -> u64 {
+        match metric {
+            SizeMetric::Archive => self.acc_size,
+            SizeMetric::Payload => self.payload_size,
+        }
+    }
+
+    fn write_entry<R: io::Read>(
+        &mut self,
+        header: &tar::Header,
+        data: R,
+        verbose: bool,
+        recreated: bool,
+    ) -> ah::Result<()> {
+        let offset = self.written.get();
+        let written = self
+            .builder
+            .as_mut()
+            .unwrap()
+            .append(header, data)
+            .context("failed to write an entry to output file")?;
+        if !written {
+            return Ok(());
+        }
+        if verbose {
+            self.print_header(header)?;
+        }
+        self.acc_size += TAR_HEADER_SIZE + padded_size(header.size()?);
+        self.entries_written += 1;
+        if !recreated {
+            self.file_count += 1;
+        }
+        self.record(header, Some(offset), recreated)
+    }
+
+    /// Add the entry to the --manifest, if any.  The offset is where the
+    /// entry starts in the tar stream, including its PAX records; zip has no
+    /// such offsets.
+    fn record(
+        &mut self,
+        header: &tar::Header,
+        offset: Option<u64>,
+        recreated: bool,
+    ) -> ah::Result<()> {
+        let start = self.entry_start.take();
+        let Some(manifest) = &self.manifest else {
+            return Ok(());
+        };
+        let offset = match self.builder {
+            Some(Packer::Zip(_)) => None,
+            _ => offset.map(|offset| start.unwrap_or(offset)),
+        };
+        manifest
+            .borrow_mut()
+            .record(&self.target_file, header, offset, recreated)
+    }
+
+    /// Write the PAX records that go with the next entry, if any.
+    fn write_pax(&mut self, records: &[u8]) -> ah::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.entry_start.get_or_insert(self.written.get());
+        self.builder
+            .as_mut()
+            .unwrap()
+            .append_pax(records)
+            .context("failed to write PAX records to output file")?;
+        self.acc_size += pax_footprint(records);
+        Ok(())
+    }
+
+    /// Write the extension members that go with the next entry, if any.
+    pub(crate) fn write_extensions(&mut self, extensions: &Extensions) -> ah::Result<()> {
+        let long_names = extensions.long_names();
+        if !long_names.is_empty() {
+            self.entry_start.get_or_insert(self.written.get());
+            self.builder
+                .as_mut()
+                .unwrap()
+                .append_long_names(&long_names)
+                .context("failed to write long names to output file")?;
+            self.acc_size += long_names.len() as u64;
+        }
+        self.write_pax(&extensions.pax_records())
+    }
+
+    /// Copy entry's header block and data as is, bypassing the
+    /// `tar::Builder::append`.  Padding is zero-filled, as the format mandates.
+    pub(crate) fn write_raw<R: io::Read>(
+        &mut self,
+        header: &tar::Header,
+        mut data: R,
+        verbose: bool,
+    ) -> ah::Result<()> {
+        if verbose {
+            self.print_header(header)?;
+        }
+        let offset = self.written.get();
+        let out = self.builder.as_mut().unwrap().tar_stream();
+        out.write_all(header.as_bytes())
+            .context("failed to write an entry header to output file")?;
+        let len = io::copy(&mut data, out).context("failed to write an entry to output file")?;
+        let padding = padded_size(len) - len;
+        out.write_all(&[0; TAR_HEADER_SIZE as usize][..padding as usize])
+            .context("failed to write an entry padding to output file")?;
+        self.acc_size += padded_size(len) + TAR_HEADER_SIZE;
+        // Global PAX headers are repeated in every volume.
+        if header.entry_type().is_pax_global_extensions() {
+            return Ok(());
+        }
+        if is_extension(header) {
+            self.entry_start.get_or_insert(offset);
+            return Ok(());
+        }
+        self.entries_written += 1;
+        self.file_count += 1;
+        self.record(header, Some(offset), false)
+    }
+
+    /// Write the global PAX headers of the input that the volume doesn't
+    /// have yet, so that a new volume starts with all of them.
+    pub(crate) fn write_global_headers(
+        &mut self,
+        headers: &[(tar::Header, Vec<u8>)],
+        verbose: bool,
+    ) -> ah::Result<()> {
+        for (header, data) in &headers[self.globals_written..] {
+            self.write_raw(header, data.as_slice(), verbose)?;
+        }
+        self.globals_written = headers.len();
+        Ok(())
+    }
+
+    /// Add a file to the pack of --coalesce-small instead of writing it as
+    /// an entry.
+    pub(crate) fn write_packed<R: io::Read>(
+        &mut self,
+        header: &tar::Header,
+        record: &[u8],
+        mut data: R,
+        verbose: bool,
+    ) -> ah::Result<()> {
+        if verbose {
+            self.print_header(header)?;
+        }
+        let size = header.size()?;
+        if !self.pack.is_empty() && self.pack.len() + record.len() + size as usize > PACK_LIMIT {
+            self.write_pack()?;
+        }
+        if self.pack.is_empty() {
+            self.pack.extend_from_slice(PACK_MAGIC);
+            self.acc_size += TAR_HEADER_SIZE + PACK_MAGIC.len() as u64;
+            self.file_count += 1;
+        }
+        self.pack.extend_from_slice(record);
+        let len = io::Read::read_to_end(&mut data, &mut self.pack)
+            .context("failed to read an entry to pack")?;
+        self.acc_size += (record.len() + len) as u64;
+        // It is in the pack written next.
+        self.record(header, None, false)
+    }
+
+    /// Write the pending pack of --coalesce-small, if any, as a regular file.
+    pub(crate) fn write_pack(&mut self) -> ah::Result<()> {
+        if self.pack.is_empty() {
+            return Ok(());
+        }
+        let pack = std::mem::take(&mut self.pack);
+        let mut header = new_header(self.format);
+        header
+            .set_path(format!(
+                "{}{}.{}",
+                PACK_FILE_PREFIX, self.volume_name, self.packs_written
+            ))
+            .context("internal: failed to name a pack")?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(pack.len() as _);
+        header.set_mode(0o644);
+        header.set_cksum();
+        // Accounted by write_packed already.
+        self.acc_size -= TAR_HEADER_SIZE + pack.len() as u64;
+        self.file_count -= 1;
+        self.packs_written += 1;
+        self.write_data(&header, pack.as_slice(), false)
+    }
+
+    /// Insert dirs known so far for particular path, unless they was already
+    /// inserted into particular volume.
+    pub(crate) fn inject_dirs_for_path(
+        &mut self,
+        dirname: &[u8],
+        known_dirs: &patricia_tree::PatriciaMap<Box<StoredDir>>,
+        mode: RecreateDirsMode,
+        verbose: bool,
+    ) -> ah::Result<()> {
+        let start_size = self.acc_size;
+        // Paths are the keys; long ones are not in the headers.
+        for (path, dir) in recreated_dirs(dirname, known_dirs, mode) {
+            if !self.stored_dirs.contains(&path) {
+                log::debug!(
+                    "Dirname {:?} is new for the volume, inserting...",
+                    String::from_utf8_lossy(&path),
+                );
+                self.write_extensions(&dir.extensions)?;
+                self.write_entry(&dir.header, vec![].as_slice(), verbose, true)?;
+                self.stored_dirs.insert(&path);
+            } else {
+                log::debug!(
+                    "Dirname {:?} already inserted, skipping...",
+                    String::from_utf8_lossy(&path),
+                );
+            }
+        }
+        self.recreated_size += self.acc_size - start_size;
+        Ok(())
+    }
+
+    /// Bytes that inject_dirs_for_path() would write.
+    pub(crate) fn recreated_dirs_size(
+        &self,
+        dirname: &[u8],
+        known_dirs: &patricia_tree::PatriciaMap<Box<StoredDir>>,
+        mode: RecreateDirsMode,
+    ) -> u64 {
+        recreated_dirs(dirname, known_dirs, mode)
+            .iter()
+            .filter(|(path, _)| !self.stored_dirs.contains(path))
+            .map(|(_, dir)| dir.footprint())
+            .sum()
+    }
+
+    /// Read the written volume back for --self-check: it has to be a
+    /// complete tar with the entries written into it.
+    fn check_written(&self, path: &Path) -> ah::Result<()> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open temp file {:?}", path))?;
+        let input = decompressed_input(Box::new(file), self.decompress)
+            .with_context(|| format!("failed to read volume {} back", self.volume_name))?;
+        let entries =
+            check_tar_structure(io::BufReader::new(input)).map_err(|(offset, problem)| {
+                ah::anyhow!(
+                    "volume {} failed the self-check at offset {}: {}",
+                    self.volume_name,
+                    offset,
+                    problem
+                )
+            })?;
+        ah::ensure!(
+            entries == self.entries_written,
+            "volume {} failed the self-check: {} entries are read back, {} were written",
+            self.volume_name,
+            entries,
+            self.entries_written,
+        );
+        log::debug!("Volume {} passed the self-check", self.volume_name);
+        Ok(())
+    }
+
+    /// Check that the existing volume is what this split would write: its
+    /// size, unless it is compressed, and its --resume-manifest records.
+    /// The checksums are taken from it, if needed.
+    fn check_resumed(&mut self) -> ah::Result<()> {
+        let mut file = std::fs::File::open(&self.target_file)
+            .with_context(|| format!("failed to open existing volume {:?}", self.target_file))?;
+        let size = file.metadata()?.len();
+        ah::ensure!(
+            self.compressed || size == self.written.get(),
+            "existing volume {:?} has {} bytes instead of {}; remove it or run \
+             without --resume",
+            self.target_file,
+            size,
+            self.written.get(),
+        );
+        if let Some(manifest) = &self.manifest {
+            manifest.borrow_mut().check_resumed(&self.target_file)?;
+        }
+        if let Some(checksums) = &self.checksums {
+            let mut checksums = checksums.lock().unwrap();
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let len = io::Read::read(&mut file, &mut buf)
+                    .with_context(|| format!("failed to read {:?}", self.target_file))?;
+                if len == 0 {
+                    break;
+                }
+                checksums.update(&buf[..len]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist the temp file as `<volume>.partial` for --keep-partial.
+    /// Errors are only reported, as it is a Drop-time salvage.
+    fn keep_partial_output(&mut self) {
+        // The writer thread finishes as the builder is gone.
+        if let Some(handle) = self.output_thread.take() {
+            let _ = handle.join();
+        }
+        // The compressor is killed already.
+        if let Some(handle) = self.checksum_thread.take() {
+            let _ = handle.join();
+        }
+        if let Some(temp_output) = self.temp_output.take() {
+            let mut partial = self.target_file.as_os_str().to_owned();
+            partial.push(".partial");
+            log::warn!("Keeping interrupted volume as {:?}", partial);
+            if let Err(e) = persist_temp(temp_output, Path::new(&partial)) {
+                log::error!("failed to keep interrupted volume {:?}: {}", partial, e);
+            }
+        }
+    }
+
+    /// Write the rest of the tar stream.  A --compress command may still be
+    /// running then, and it is waited by [`finish`](Self::finish).
+    pub(crate) fn finish_writing(&mut self) -> ah::Result<()> {
+        self.write_pack()?;
+        // Finish the builder, and drop it, closing the
+        // underlying file.
+        let mut out = self
+            .builder
+            .take()
+            .unwrap()
+            .into_inner()
+            .context("failed to write final data to output file")?;
+        if let Some(record_size) = self.record_size {
+            out.flush()
+                .context("failed to write final data to output file")?;
+            let padding = self.written.get().next_multiple_of(record_size) - self.written.get();
+            io::copy(&mut io::Read::take(io::repeat(0), padding), &mut out)
+                .context("failed to write record padding to output file")?;
+            log::debug!("Padded the volume with {} bytes", padding);
+        }
+        out.flush()
+            .context("failed to write final data to output file")?;
+        std::mem::drop(out);
+        // The thread finishes when all the data is written, closing the file.
+        if let Some(handle) = self.output_thread.take() {
+            handle
+                .join()
+                .expect("internal: output writer panicked")
+                .context("failed to write output file")?;
+        }
+        if let Some(encoder) = self.encoder.take() {
+            encoder
+                .finish()
+                .context("failed to write final compressed data to output file")?;
+        }
+        if self.replay.is_some() {
+            self.start_compressor()?;
+        }
+        Ok(())
+    }
+
+    /// Start the --compress command on the complete tar stream with
+    /// --compress-retries, overwriting the output of an earlier attempt.
+    fn start_compressor(&mut self) -> ah::Result<()> {
+        let replay = self.replay.as_mut().unwrap();
+        let temp_output = self.temp_output.as_ref().unwrap();
+        let out_file = std::fs::File::create(temp_output)
+            .with_context(|| format!("failed to truncate output temp file {:?}", temp_output))?;
+        let tar_file = std::fs::File::open(&replay.tar)
+            .with_context(|| format!("failed to open uncompressed temp file {:?}", replay.tar))?;
+        if let Some(checksums) = &self.checksums {
+            *checksums.lock().unwrap() = Checksums::new(&replay.algorithms);
+        }
+        let (subprocess, checksum_thread, stderr) = spawn_compressor(
+            &mut replay.command,
+            Stdio::from(tar_file),
+            Some(out_file),
+            self.checksums.as_ref(),
+            replay.capture_stderr,
+        )?;
+        self.subprocess = Some(subprocess);
+        self.checksum_thread = checksum_thread;
+        self.subprocess_stderr = stderr;
+        Ok(())
+    }
+
+    /// Wait for the --compress command, if any, to finish.  Its failure is
+    /// returned as `Ok(Some(_))`, so that it can be retried.
+    fn wait_compressor(&mut self) -> ah::Result<Option<ah::Error>> {
+        // It ends when the compressor closes its output.
+        if let Some(handle) = self.checksum_thread.take() {
+            handle
+                .join()
+                .expect("internal: checksum writer panicked")
+                .context("failed to write output file")?;
+        }
+
+        // It is important that we call the Builder::finish first
+        let Some(mut subprocess) = self.subprocess.take() else {
+            return Ok(None);
+        };
+        log::info!("Waiting subprocess {} to finish", subprocess.id());
+        let ret = subprocess
+            .wait()
+            .context("failed to wait for subprocess completion")?;
+        let stderr = match self.subprocess_stderr.take() {
+            Some(handle) => handle
+                .join()
+                .expect("internal: stderr reader panicked")
+                .context("failed to read subprocess stderr")?,
+            None => vec![],
+        };
+        let stderr = String::from_utf8_lossy(&stderr);
+
+        if !ret.success() {
+            let msg = format!("subprocess exited with error: {}", ret.code().unwrap_or(-1));
+            return Ok(Some(if stderr.is_empty() {
+                SubprocessFailure(msg).into()
+            } else {
+                ah::Error::from(SubprocessFailure(stderr.trim_end().to_owned())).context(msg)
+            }));
+        }
+        if !stderr.is_empty() {
+            log::debug!("Subprocess stderr: {}", stderr.trim_end());
+        }
+        Ok(None)
+    }
+
+    /// Complete writing the volume: finish the builder, wait the subprocess
+    /// to finish, and rename the temp file to the target file.  Returns the
+    /// checksums of the volume, if any.
+    /// If this method is not called, the Drop implementation will rollback
+    /// everything.
+    pub(crate) fn finish(mut self) -> ah::Result<Vec<(ChecksumAlgorithm, String)>> {
+        if self.builder.is_some() {
+            self.finish_writing()?;
+        }
+        if self.dry_run {
+            writeln!(
+                io::stdout(),
+                "{}\t{}\t{}",
+                self.volume_name,
+                self.file_count,
+                self.written.get()
+            )
+            .context("failed to list the volume")?;
+            return Ok(vec![]);
+        }
+
+        let mut retries = self.replay.as_ref().map_or(0, |replay| replay.retries);
+        while let Some(failure) = self.wait_compressor()? {
+            if retries == 0 {
+                return Err(failure);
+            }
+            retries -= 1;
+            log::warn!(
+                "Compressing {:?} failed, retrying ({} retries left): {:#}",
+                self.target_file,
+                retries,
+                failure
+            );
+            self.start_compressor()?;
+        }
+
+        if self.resumed {
+            self.check_resumed()?;
+        } else if let Some(temp_output) = self.temp_output.take() {
+            if self.self_check {
+                // The temp file is removed on failure.
+                self.check_written(&temp_output)?;
+            }
+            log::debug!("Moving {:?} to {:?}", temp_output, self.target_file);
+            let temp_path = temp_output.as_os_str().to_os_string();
+            persist_temp(temp_output, &self.target_file).with_context(|| {
+                format!(
+                    "failed to rename temp file {:?} to output file {:?}",
+                    temp_path, self.target_file
+                )
+            })?;
+            set_umasked_mode(&self.target_file, self.mode)?;
+        }
+        let checksums = match self.checksums.take() {
+            Some(checksums) => Arc::into_inner(checksums)
+                .expect("internal: checksums are still in use")
+                .into_inner()
+                .unwrap()
+                .finish(),
+            None => vec![],
+        };
+        if self.checksum_files {
+            for (algorithm, digest) in &checksums {
+                write_checksum_file(&self.target_file, *algorithm, digest)?;
+            }
+        }
+        if self.resumed {
+            // It was listed, signed and so on by the earlier run.
+            return Ok(checksums);
+        }
+        if self.list_volumes {
+            // GNU split prints it on creation; the volume is only final here.
+            writeln!(
+                io::stdout(),
+                "creating file '{}'",
+                self.target_file.display()
+            )
+            .context("failed to list the volume")?;
+        }
+
+        // The signature has to be ready before the tape command takes the
+        // volume away.
+        if let Some(sign) = &self.sign {
+            if let Err(e) = sign_volume(sign, &self.target_file) {
+                if !self.allow_sign_failure {
+                    return Err(e);
+                }
+                log::warn!("{:#}", e);
+            }
+        }
+        if let Some(tape_command) = &self.tape_command {
+            run_volume_command("tape command", tape_command, &self.target_file, &[])?;
+        }
+        Ok(checksums)
+    }
+}
+
+/// Move a finished temp file to its target.  A --temp-dir on another
+/// filesystem cannot be renamed into the target, so the file is copied into
+/// a temp file next to the target then; the target still appears complete.
+fn persist_temp(temp_path: tempfile::TempPath, target: &Path) -> io::Result<()> {
+    let temp_path = match temp_path.persist(target) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.error.kind() == io::ErrorKind::CrossesDevices => e.path,
+        Err(e) => return Err(e.error),
+    };
+    log::debug!("Copying {:?} to the filesystem of {:?}", temp_path, target);
+    let mut copy = tempfile::Builder::new()
+        .prefix(target.file_name().unwrap_or_default())
+        .suffix(".tmp")
+        .tempfile_in(target.parent().unwrap_or_else(|| Path::new(".")))?;
+    io::copy(&mut std::fs::File::open(&temp_path)?, copy.as_file_mut())?;
+    copy.persist(target).map_err(|e| e.error)?;
+    // The original is removed as it is dropped.
+    Ok(())
+}
+
+/// Run a per-volume hook, waiting for it to finish, as e.g. the next volume
+/// may need the tape to be changed.
+fn run_volume_command(
+    what: &str,
+    command: &str,
+    volume: &Path,
+    env: &[(&str, &std::ffi::OsStr)],
+) -> ah::Result<()> {
+    let shell = shell();
+    log::info!("Running {} for {:?}", what, volume);
+    let status = Command::new(&shell)
+        .arg("-c")
+        .arg(command)
+        .env("SPLITAR_VOLUME", volume)
+        .envs(env.iter().copied())
+        .status()
+        .with_context(|| format!("failed to start {:?} with shell {:?}", command, shell))?;
+    if !status.success() {
+        return Err(SubprocessFailure(format!(
+            "{} failed for volume {:?}: exit code {}",
+            what,
+            volume,
+            status.code().unwrap_or(-1)
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Make a detached `<volume>.sig` signature with the --sign command.
+fn sign_volume(sign: &str, volume: &Path) -> ah::Result<()> {
+    let mut signature = volume.as_os_str().to_owned();
+    signature.push(".sig");
+    let signature = PathBuf::from(signature);
+    // A stale signature could be taken for a new one, and gpg refuses
+    // to overwrite it anyway.
+    match std::fs::remove_file(&signature) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(ah::Error::new(e)
+                .context(format!("failed to remove old signature {:?}", signature)))
+        }
+        _ => {}
+    }
+    run_volume_command(
+        "sign command",
+        sign,
+        volume,
+        &[("SPLITAR_SIGNATURE", signature.as_os_str())],
+    )?;
+    ah::ensure!(
+        signature.exists(),
+        "sign command has not created signature {:?}",
+        signature
+    );
+    Ok(())
+}
+
+const NOTE_FILE_NAME: &str = ".splitar-note";
+pub(crate) const VOLUME_INFO_FILE_NAME: &str = "VOLUME_INFO.txt";
+
+/// Text of the --volume-info file of a volume.  The number of volumes is
+/// known only for a split planned before, like with --num-volumes.
+pub(crate) fn volume_info(args: &Args, stream: &str, vol_idx: Option<usize>) -> String {
+    let what = match vol_idx {
+        Some(vol_idx) => format!(
+            "volume {} of {}",
+            vol_idx - args.start_index + 1,
+            args.volume_count
+                .map(|count| count.to_string())
+                .unwrap_or_else(|| "?".to_owned()),
+        ),
+        None => "the dirs volume, which is extracted first,".to_owned(),
+    };
+    let prefix = match stream {
+        "" => args.output_prefix.clone(),
+        stream => format!("{}{}-", args.output_prefix, stream),
+    };
+    let compress = match args.compress_line() {
+        Some(compress) => format!(" --compress {}", shell_quote(&compress)),
+        None => String::new(),
+    };
+    format!(
+        "This is {} of a tar archive split by splitar {}.\n\
+         To get the archive back from all the volumes, run:\n\
+         \n    splitar join{} {} -o archive.tar\n",
+        what,
+        env!("CARGO_PKG_VERSION"),
+        compress,
+        shell_word(&prefix),
+    )
+}
+
+const PACK_FILE_PREFIX: &str = ".splitar-pack.";
+pub(crate) const PACK_MAGIC: &[u8] = b"splitar-pack-v1\n";
+/// Packs are kept in memory until written.
+const PACK_LIMIT: usize = 1 << 24;
+pub(crate) const UNPACK_SCRIPT_SUFFIX: &str = "unpack-small.sh";
+
+/// Index record of a file coalesced by --coalesce-small, if it can be:
+/// "<size>\t<octal mode>\t<UTC mtime for touch -t>\t<path>\n", followed by
+/// the file data in the pack.  The path is escaped for `printf %b`.
+pub(crate) fn pack_record(header: &tar::Header, path: &[u8], threshold: u64) -> Option<Vec<u8>> {
+    if !matches!(
+        header.entry_type(),
+        tar::EntryType::Regular | tar::EntryType::Continuous
+    ) || is_dir_header(header)
+    {
+        return None;
+    }
+    let size = header.size().ok()?;
+    // The unpacker writes where the path says, while tar would strip these.
+    if size >= threshold
+        || path.is_empty()
+        || path.starts_with(b"/")
+        || path.split(|&c| c == b'/').any(|part| part == b"..")
+    {
+        return None;
+    }
+    let mtime = chrono::Utc
+        .timestamp_opt(header.mtime().ok()?.try_into().ok()?, 0)
+        .single()?;
+    let mut record = format!(
+        "{}\t{:o}\t{}\t",
+        size,
+        header.mode().ok()? & 0o7777,
+        mtime.format("%Y%m%d%H%M.%S"),
+    )
+    .into_bytes();
+    for &c in path {
+        if c == b'\\' || !(0x20..0x7f).contains(&c) {
+            record.extend_from_slice(format!("\\0{:03o}", c).as_bytes());
+        } else {
+            record.push(c);
+        }
+    }
+    record.push(b'\n');
+    Some(record)
+}
+
+const UNPACK_SCRIPT: &str = "#!/bin/sh
+# Unpack files coalesced by splitar --coalesce-small; run it in the dir
+# where the volumes are extracted.
+set -e
+tab=$(printf '\\t')
+for pack in .splitar-pack.*; do
+    [ -f \"$pack\" ] || continue
+    {
+        read -r magic
+        if [ \"$magic\" != splitar-pack-v1 ]; then
+            echo \"$pack: unknown pack format\" >&2
+            exit 1
+        fi
+        while IFS=$tab read -r size mode mtime name; do
+            name=$(printf '%bx' \"$name\")
+            name=${name%x}
+            mkdir -p -- \"$(dirname -- \"$name\")\"
+            if [ \"$size\" -gt 0 ]; then
+                dd bs=\"$size\" count=1 of=\"$name\" 2>/dev/null
+            else
+                : > \"$name\"
+            fi
+            chmod -- \"$mode\" \"$name\"
+            TZ=UTC touch -t \"$mtime\" -- \"$name\"
+        done
+    } < \"$pack\"
+    rm -- \"$pack\"
+done
+";
+
+pub(crate) fn write_unpack_script(args: &Args) -> ah::Result<()> {
+    let script_path = PathBuf::from(format!("{}{}", args.output_prefix, UNPACK_SCRIPT_SUFFIX));
+    std::fs::write(&script_path, UNPACK_SCRIPT)
+        .with_context(|| format!("failed to write unpack script {:?}", script_path))?;
+    set_umasked_mode(&script_path, 0o777)
+}
+
+fn note_header(name: &str, size: u64, mtime: u64, format: Option<TarFormat>) -> tar::Header {
+    let mut header = new_header(format);
+    // The names are short enough.
+    header.set_path(name).unwrap();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    header
+}
+
+/// Header of a file that splitar puts into its output, like --prepend or
+/// the --bundle-metadata files; with --reproducible, it doesn't depend on
+/// the file's mtime, owner and umask.
+pub(crate) fn file_header(args: &Args, metadata: &std::fs::Metadata) -> ah::Result<tar::Header> {
+    let mut header = new_header(args.format);
+    if args.reproducible {
+        header.set_metadata_in_mode(metadata, tar::HeaderMode::Deterministic);
+        header.set_mtime(args.creation_mtime()?);
+    } else {
+        header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    }
+    Ok(header)
+}
+
+/// Quote a string for POSIX shell.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// A shell word for the string, quoted only if needed.
+pub(crate) fn shell_word(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"-_./=:,+@%".contains(&c));
+    match plain {
+        true => s.to_owned(),
+        false => shell_quote(s),
+    }
+}
+
+/// Write a shell script that extracts the volumes in the given order into
+/// the current dir.  The `<prefix>extract.sh` one looks for the volumes
+/// next to itself, and a --restore-script has the volume dir in it instead.
+pub(crate) fn write_extract_script(
+    args: &Args,
+    volumes: &[PathBuf],
+    script_path: &Path,
+    elsewhere: bool,
+) -> ah::Result<()> {
+    let filter = match args.compress_line() {
+        // Checked by Args::validate.
+        Some(compress) => decompress_command(&compress).expect("internal: unknown compressor"),
+        None => args
+            .builtin_compression()
+            .map_or("cat", BuiltinCompression::decompress_command),
+    };
+    let mut script = String::from(
+        "#!/bin/sh\n\
+         # Extract volumes written by splitar into the current dir.\n\
+         # Dirs recreated in several volumes are just extracted again.\n",
+    );
+    if elsewhere {
+        let dir = match volumes.first().and_then(|volume| volume.parent()) {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = std::fs::canonicalize(dir)
+            .with_context(|| format!("failed to find the volume dir {:?}", dir))?;
+        script.push_str(&format!(
+            "# The volumes are in the dir given as the argument, or here:\n\
+             set -e\n\
+             dir=${{1:-{}}}\n",
+            shell_quote(&dir.to_string_lossy())
+        ));
+    } else {
+        script.push_str("set -e\ndir=$(dirname \"$0\")\n");
+    }
+    script.push_str("for volume in");
+    for volume in volumes {
+        // The volume names are derived from the prefix, which is a String.
+        let name = volume.file_name().unwrap().to_string_lossy();
+        script.push_str(" \\\n    ");
+        script.push_str(&shell_quote(&name));
+    }
+    script.push_str(&format!(
+        "\ndo\n    {} < \"$dir/$volume\" | tar -xf -\ndone\n",
+        filter
+    ));
+    if args.coalesce_small.is_some() {
+        let unpack = PathBuf::from(format!("{}{}", args.output_prefix, UNPACK_SCRIPT_SUFFIX));
+        let name = unpack.file_name().unwrap().to_string_lossy();
+        script.push_str(&format!("sh \"$dir/\"{}\n", shell_quote(&name)));
+    }
+    std::fs::write(script_path, script)
+        .with_context(|| format!("failed to write extract script {:?}", script_path))?;
+    set_umasked_mode(script_path, 0o777)
+}
+
+/// A file put at the start of the first volume.
+pub(crate) struct PrependedFile {
+    pub(crate) header: tar::Header,
+    pub(crate) data: Vec<u8>,
+}
+
+impl PrependedFile {
+    /// The file is small enough to be read at once, and it is read before
+    /// the input, so that a missing file is reported early.
+    pub(crate) fn read(path: &Path, args: &Args) -> ah::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("failed to read {:?} to prepend", path))?;
+        let metadata = std::fs::metadata(path)?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| ah::anyhow!("file {:?} to prepend has no name", path))?;
+
+        let mut header = file_header(args, &metadata)?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header
+            .set_path(name)
+            .with_context(|| format!("cannot use {:?} as a name to prepend", name))?;
+        header.set_size(data.len() as _);
+        header.set_cksum();
+        Ok(Self { header, data })
+    }
+
+    /// Check that the first volume is still possible.
+    pub(crate) fn check_fits(&self, args: &Args) -> ah::Result<()> {
+        if let Some(max_size) = args.volume_max_size(args.start_index) {
+            let min_size = MIN_VOLUME_SIZE
+                + args.injected_size()
+                + TAR_HEADER_SIZE
+                + padded_size(self.data.len() as _);
+            ah::ensure!(
+                min_size <= max_size,
+                "{} {} is too small for the first volume with the prepended file, \
+                 which needs at least {} bytes",
+                args.max_size_option(),
+                max_size,
+                min_size,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Name of the volume in the main sequence (empty `stream`) or in a
+/// --route-by-extension category.
+pub(crate) fn volume_name(stream: &str, vol_idx: usize, args: &Args) -> String {
+    let index = format!(
+        "{index:0>width$}",
+        width = args.suffix_length as _,
+        index = vol_idx,
+    );
+    match stream {
+        "" => index,
+        category => format!("{}-{}", category, index),
+    }
+}
+
+impl Volume {
+    fn print_header(&self, header: &tar::Header) -> ah::Result<()> {
+        match self.verbose_format {
+            VerboseFormat::Pretty => print_header(&self.volume_name, header, self.utc),
+            VerboseFormat::Json => print_header_json(&self.volume_name, header),
+        }
+        .context("failed to output verbose file info")
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        // Close the builder file first, if any
+        self.builder.take();
+
+        // TODO It would be nice to have some kind of wrapper with .wait and Drop::drop.
+        if let Some(mut subprocess) = self.subprocess.take() {
+            log::warn!("Shouldn't happen: killing subprocess {}", subprocess.id());
+            let _ = subprocess.kill();
+        }
+
+        if self.keep_partial && self.interrupt_flag.load(Ordering::SeqCst) {
+            self.keep_partial_output();
+        }
+    }
+}
+
+/// Size of a finished volume file.
+pub(crate) fn stored_size(volume: &Path) -> ah::Result<u64> {
+    Ok(std::fs::metadata(volume)
+        .with_context(|| format!("failed to stat volume {:?}", volume))?
+        .len())
+}
@@ -10,11 +10,12 @@ class RunMode(Enum):
 
 @pytest.fixture(params=(RunMode.DEBUG, RunMode.RELEASE))
 def cargo_run(request):
-    def run(args):
+    def run(args, input=None):
         return subprocess.run(
             ["cargo", "run", "--quiet"] + list(request.param.value) + ["--"] + args,
             check=True,
             capture_output=True,
+            input=input,
         )
 
     return run
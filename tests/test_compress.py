@@ -1,7 +1,11 @@
 """ Test the --compress """
+import base64
+import gzip
+import io
 import os
 import time
 import pytest
+import shutil
 import tarfile
 import subprocess
 from tests.test_dirs import DIRS
@@ -93,7 +97,150 @@ def test_basic(cargo_run, tmpdir):
                 g.read()
 
 
-def test_failure(cargo_run, tmpdir):
+@pytest.mark.parametrize("compression", ["--gzip", "--zstd", "--bzip2"])
+@pytest.mark.parametrize(
+    "options", [[], ["--compress-level", "1"], ["--threads", "2"]]
+)
+def test_builtin_compression(cargo_run, tmpdir, compression, options):
+    import bz2
+    import gzip
+
+    plaindir = tmpdir.mkdir("plain")
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    common = ["-S", "30K", "--recreate-dirs", str(inp)]
+    cargo_run(common + [str(plaindir.join("output.tar."))])
+    cargo_run([compression] + options + common + [str(outdir.join("output.tar."))])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == sorted(os.listdir(str(plaindir)))
+    for file in files:
+        data = outdir.join(file).read_binary()
+        if compression == "--gzip":
+            data = gzip.decompress(data)
+        elif compression == "--bzip2":
+            data = bz2.decompress(data)
+        else:
+            data = subprocess.run(
+                ["zstd", "-dc"], input=data, check=True, capture_output=True
+            ).stdout
+        assert data == plaindir.join(file).read_binary()
+
+    # Volumes read back natively, whatever the compression.
+    joined = tmpdir.join("joined.tar")
+    cargo_run(["join", str(outdir.join("output.tar.")), "-o", str(joined)])
+    with tarfile.open(str(joined), "r") as tar:
+        assert tar.getnames()
+
+
+@pytest.mark.parametrize(
+    "options",
+    [["--zstd", "--compress-level", "0"], ["--bzip2", "--compress-level", "10"]],
+)
+def test_compress_level_invalid(cargo_run, tmpdir, options):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarfile.TarInfo("theobject"))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K"] + options + [str(inp), str(tmpdir.join("output.tar."))])
+    assert exc.value.returncode == 2
+    assert b"is out of range" in exc.value.stderr
+
+
+def test_compress_program(cargo_run, tmpdir, bin, monkeypatch):
+    import gzip
+
+    # No shell is involved.
+    monkeypatch.setenv("SHELL", "/nonexistent")
+    bin("show-args", "#!/bin/sh\ncat > /dev/null\nprintf '%s|' \"$@\"\n")
+    plaindir = tmpdir.mkdir("plain")
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    common = ["-S", "30K", "--recreate-dirs", str(inp)]
+    cargo_run(common + [str(plaindir.join("output.tar."))])
+    compress = ["--compress-program", "gzip", "--compress-arg", "-1"]
+    extra = ["--auto-extension", "--emit-extract-script"]
+    cargo_run(compress + extra + common + [str(outdir.join("output.tar."))])
+    files = sorted(os.listdir(str(plaindir)))
+    assert sorted(os.listdir(str(outdir))) == sorted(
+        [file + ".gz" for file in files] + ["output.tar.extract.sh"]
+    )
+    for file in files:
+        with gzip.open(str(outdir.join(file + ".gz")), "rb") as g:
+            assert g.read() == plaindir.join(file).read_binary()
+    assert b"gzip -dc" in outdir.join("output.tar.extract.sh").read_binary()
+
+    args = ["--compress-program", "show-args"]
+    args += ["--compress-arg", "-v", "--compress-arg", "$HOME *"]
+    cargo_run(args + ["-S", "30K", str(inp), str(tmpdir.join("args."))])
+    assert tmpdir.join("args.00000").read_binary() == b"-v|$HOME *|"
+
+
+def test_gzip_conflicts_with_compress(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            [
+                "-S",
+                "30K",
+                "--gzip",
+                "--compress",
+                "gzip",
+                str(inp),
+                str(tmpdir.join("out.")),
+            ]
+        )
+    assert b"cannot be used with" in exc.value.stderr
+
+
+@pytest.mark.parametrize(
+    "options,extension",
+    [
+        (["--gzip", "--auto-extension"], ".gz"),
+        (["--zstd", "--auto-extension"], ".zst"),
+        (["--bzip2", "--auto-extension"], ".bz2"),
+        (["--compress", "gzip -1", "--auto-extension"], ".gz"),
+        (["--compress", "cat", "--auto-extension"], ""),
+        (["--compress", "cat", "--extension", ".raw"], ".raw"),
+    ],
+)
+def test_auto_extension(cargo_run, tmpdir, options, extension):
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(["-S", "30K"] + options + [str(inp), str(outdir.join("output.tar."))])
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000" + extension,
+        "output.tar.00001" + extension,
+    ]
+
+
+def test_auto_extension_unknown(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    options = ["-S", "30K", "--compress", "lzop", "--auto-extension"]
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(options + [str(inp), str(tmpdir.join("out."))])
+    assert b'extension for --compress "lzop", use --extension' in exc.value.stderr
+    assert not tmpdir.join("out.00000").check()
+
+@pytest.mark.parametrize("options", [[], ["--jobs", "2"]])
+def test_failure(cargo_run, tmpdir, options):
     outdir = tmpdir.mkdir("out")
     output = outdir.join("output.tar.")
     inp = tmpdir.join("input.tar")
@@ -109,9 +256,9 @@ def test_failure(cargo_run, tmpdir):
                 "--compress",
                 "false",
                 "--recreate-dirs",
-                str(inp),
-                str(output),
             ]
+            + options
+            + [str(inp), str(output)]
         )
     assert os.listdir(str(outdir)) == []
 
@@ -140,3 +287,572 @@ def test_completion(cargo_run, tmpdir, exclusive_compress, monkeypatch):
             str(output),
         ]
     )
+
+
+@pytest.mark.parametrize("jobs,starts", [(1, 1), (3, 3)])
+def test_jobs(cargo_run, tmpdir, jobs, starts):
+    import gzip
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    log = tmpdir.join("compress.log")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(4):
+            data = b"1234" * 1024
+            ti = tarfile.TarInfo("theobject" + str(i))
+            ti.size = len(data)
+            tar.addfile(ti, io.BytesIO(data))
+    compress = "echo start >> {0}; gzip; sleep 0.5; echo end >> {0}".format(log)
+    res = cargo_run(
+        [
+            "-S",
+            "6K",
+            "--compress",
+            compress,
+            "--jobs",
+            str(jobs),
+            "--list-volumes",
+            str(inp),
+            str(output),
+        ]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == 4
+    # The volumes are still finished in order.
+    assert res.stdout.decode() == "".join(
+        "creating file '{}'\n".format(outdir.join(file)) for file in files
+    )
+    for i, file in enumerate(files):
+        with tarfile.open(fileobj=gzip.open(str(outdir.join(file)))) as tar:
+            assert tar.getnames() == ["theobject" + str(i)]
+    lines = log.read().split()
+    assert lines[:starts] == ["start"] * starts
+    assert lines[starts] == "end"
+    assert len(lines) == 8
+
+
+def test_capture_stderr(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            [
+                "-S",
+                "30K",
+                "--compress",
+                "cat > /dev/null; echo compressor is broken >&2; false",
+                "--capture-compress-stderr",
+                str(inp),
+                str(output),
+            ]
+        )
+    stderr = exc.value.stderr
+    assert b"subprocess exited with error: 1" in stderr
+    # Reported as the error cause, not just inherited.
+    assert stderr.index(b"compressor is broken") > stderr.index(b"Caused by")
+    assert os.listdir(str(outdir)) == []
+
+
+def test_compress_retries(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    counter = tmpdir.join("counter")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+        names = [name.rstrip("/") for name in tar.getnames()]
+    # Fails on its first two runs, after reading some of the input.
+    compress = (
+        f"n=$(cat {counter} 2>/dev/null || echo 0); echo $((n + 1)) > {counter}; "
+        "if [ $n -lt 2 ]; then head -c 100 > /dev/null; exit 3; fi; gzip -c"
+    )
+    common = ["-S", "30K", "--compress", compress, "--checksum", "sha256"]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(common + ["--compress-retries", "1", str(inp), str(output)])
+    assert b"subprocess exited with error: 3" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+    counter.remove()
+    res = cargo_run(common + ["--compress-retries", "2", str(inp), str(output)])
+    assert res.stderr.count(b"subprocess exited with error: 3") == 2
+    assert b"retrying (1 retries left)" in res.stderr
+    assert b"retrying (0 retries left)" in res.stderr
+    volumes = sorted(
+        file for file in os.listdir(str(outdir)) if not file.endswith(".sha256")
+    )
+    assert len(volumes) > 1
+    # The uncompressed temp files are gone.
+    assert len(os.listdir(str(outdir))) == 2 * len(volumes)
+    extracted = []
+    for file in volumes:
+        with tarfile.open(str(outdir.join(file)), "r:gz") as tar:
+            extracted.extend(tar.getnames())
+    assert sorted(extracted) == sorted(names)
+    # Checksums are of the final attempt.
+    subprocess.run(
+        ["sha256sum", "-c"] + [file + ".sha256" for file in volumes],
+        cwd=str(outdir),
+        check=True,
+        capture_output=True,
+    )
+
+
+def test_extract_script(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    extracted = tmpdir.mkdir("extracted")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+        names = set(name.rstrip("/") for name in tar.getnames())
+    cargo_run(
+        [
+            "-S",
+            "30K",
+            "--compress",
+            "gzip -1",
+            "--recreate-dirs",
+            "--emit-extract-script",
+            str(inp),
+            str(output),
+        ]
+    )
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00001",
+        "output.tar.extract.sh",
+    ]
+    subprocess.run(
+        [str(outdir.join("output.tar.extract.sh"))], check=True, cwd=str(extracted)
+    )
+    found = set()
+    for root, dirs, files in os.walk(str(extracted)):
+        for name in dirs + files:
+            found.add(os.path.relpath(os.path.join(root, name), str(extracted)))
+    assert found == names
+
+
+def test_restore_script(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    script = tmpdir.join("restore.sh")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+        names = set(name.rstrip("/") for name in tar.getnames())
+    cargo_run(
+        [
+            "-S",
+            "30K",
+            "--compress",
+            "gzip -1",
+            "--recreate-dirs",
+            "--restore-script",
+            str(script),
+            str(inp),
+            str(output),
+        ]
+    )
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+
+    def extracted_by(*args):
+        extracted = tmpdir.mkdir("extracted%d" % len(args))
+        subprocess.run([str(script), *args], check=True, cwd=str(extracted))
+        found = set()
+        for root, dirs, files in os.walk(str(extracted)):
+            for name in dirs + files:
+                found.add(os.path.relpath(os.path.join(root, name), str(extracted)))
+        return found
+
+    assert extracted_by() == names
+    # The volumes may be moved elsewhere.
+    os.rename(str(outdir), str(tmpdir.join("moved")))
+    assert extracted_by(str(tmpdir.join("moved"))) == names
+
+
+def test_bundle_metadata(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(
+        [
+            "-S",
+            "30K",
+            "--compress",
+            "gzip -1",
+            "--emit-extract-script",
+            "--bundle-metadata",
+            str(inp),
+            str(output),
+        ]
+    )
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00001",
+        "output.tar.extract.sh",
+        "output.tar.manifest.tar",
+    ]
+    with tarfile.open(str(outdir.join("output.tar.manifest.tar")), "r") as tar:
+        assert tar.getnames() == ["volumes.txt", "output.tar.extract.sh"]
+        volumes = tar.extractfile("volumes.txt").read()
+        assert volumes == b"output.tar.00000\noutput.tar.00001\n"
+        script = tar.getmember("output.tar.extract.sh")
+        assert script.mode & 0o100
+        assert (
+            tar.extractfile(script).read()
+            == outdir.join("output.tar.extract.sh").read_binary()
+        )
+
+
+def test_reproducible(cargo_run, tmpdir, monkeypatch):
+    import hashlib
+
+    monkeypatch.setenv("SOURCE_DATE_EPOCH", "1700000000")
+    inp = tmpdir.join("input.tar")
+    readme = tmpdir.join("README")
+    readme.write("read me")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+
+    def split(name):
+        outdir = tmpdir.mkdir(name)
+        cargo_run(
+            ["-S", "30K", "--gzip", "--volume-note", "batch 42", "--prepend"]
+            + [str(readme), "--emit-extract-script", "--bundle-metadata"]
+            + ["--checksum", "sha256", "--reproducible"]
+            + [str(inp), str(outdir.join("output.tar."))]
+        )
+        return {
+            file: hashlib.sha256(outdir.join(file).read_binary()).hexdigest()
+            for file in os.listdir(str(outdir))
+        }
+
+    first = split("first")
+    # Notes would get the current time, and the prepended file its own.
+    time.sleep(1.1)
+    os.utime(str(readme), (0, 0))
+    assert split("second") == first
+    assert len(first) == 6
+
+    with tarfile.open(str(tmpdir.join("first", "output.tar.00000")), "r:gz") as tar:
+        assert [(m.name, m.mtime) for m in tar.getmembers()[:2]] == [
+            (".splitar-note", 1700000000),
+            ("README", 1700000000),
+        ]
+
+@pytest.mark.parametrize(
+    "option", [["--emit-extract-script"], ["--restore-script", "restore.sh"]]
+)
+def test_extract_script_unknown_compressor(cargo_run, tmpdir, option):
+    output = tmpdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            [
+                "-S",
+                "30K",
+                "--compress",
+                "mysterious-packer",
+                *option,
+                str(inp),
+                str(output),
+            ]
+        )
+    assert b"for " + option[0].encode() in exc.value.stderr
+
+
+def test_compression_ratio(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            info = tarfile.TarInfo("theobject" + str(i))
+            info.size = 4096
+            tar.addfile(info, io.BytesIO(b"1234" * 1024))
+    # Twice as much tar data fits into a volume.
+    cargo_run(
+        [
+            "-S",
+            "10K",
+            "--compress",
+            "gzip",
+            "--compression-ratio",
+            "0.5",
+            str(inp),
+            str(output),
+        ]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001", "output.tar.00002"]
+    with tarfile.open(str(outdir.join("output.tar.00002")), "r") as tar:
+        assert tar.getnames() == ["theobject8", "theobject9"]
+
+
+def test_threads_failure(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with pytest.raises(subprocess.CalledProcessError):
+        cargo_run(
+            [
+                "-S",
+                "30K",
+                "--compress",
+                "false",
+                "--threads",
+                "3",
+                str(inp),
+                str(output),
+            ]
+        )
+    assert os.listdir(str(outdir)) == []
+
+
+@pytest.mark.parametrize("options", [[], ["--threads", "2"]])
+def test_compressor_exits_early(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # Larger than a pipe buffer, so that writes cannot succeed unread.
+    with tarfile.open(str(inp), mode="w") as tar:
+        info = tarfile.TarInfo("theobject")
+        info.size = 1 << 20
+        tar.addfile(info, io.BytesIO(b"1234" * (1 << 18)))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["-S", "2M", "--compress", "head -c0"] + options + [str(inp), str(output)]
+        )
+    assert b'"head -c0" exited before reading all the data' in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+@pytest.mark.parametrize(
+    "options",
+    [
+        [],
+        ["--gzip"],
+        ["--compress", "gzip -c"],
+        ["--recreate-dirs", "--record-size", "10K", "--volume-note", "note"],
+    ],
+)
+def test_self_check(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(["-S", "30K", "--self-check"] + options + [str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+
+    # A volume that is not read back complete is not renamed into place.
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["-S", "30K", "--self-check", "--compress", "gzip -c | head -c 20"]
+            + [str(inp), str(tmpdir.join("broken.tar."))]
+        )
+    assert b"volume 00000 failed the self-check at offset" in exc.value.stderr
+    assert not any(name.startswith("broken") for name in os.listdir(str(tmpdir)))
+
+
+def test_balance_compressed(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # Random data doesn't compress and zeros do, so byte balancing would
+    # put almost all of the compressed output into the first volume.
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(4):
+            info = tarfile.TarInfo("random{}.bin".format(i))
+            info.size = 16384
+            tar.addfile(info, io.BytesIO(os.urandom(16384)))
+        for i in range(4):
+            info = tarfile.TarInfo("zeros{}.txt".format(i))
+            info.size = 16384
+            tar.addfile(info, io.BytesIO(bytes(16384)))
+    cargo_run(
+        [
+            "--num-volumes",
+            "2",
+            "--balance",
+            "compressed",
+            "--compress",
+            "gzip",
+            str(inp),
+            str(output),
+        ]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r:gz") as tar:
+        assert tar.getnames() == ["random0.bin", "random1.bin"]
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r:gz") as tar:
+        assert tar.getnames() == ["random2.bin", "random3.bin"] + [
+            "zeros{}.txt".format(i) for i in range(4)
+        ]
+
+
+def test_balance_compressed_needs_compress(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            [
+                "--num-volumes",
+                "2",
+                "--balance",
+                "compressed",
+                str(inp),
+                str(tmpdir.join("output.tar.")),
+            ]
+        )
+    assert b"--balance compressed needs" in exc.value.stderr
+
+
+def split_outputs(outdir):
+    return {
+        name: outdir.join(name).read_binary() for name in os.listdir(str(outdir))
+    }
+
+
+@pytest.mark.parametrize("from_stdin", [False, True])
+@pytest.mark.parametrize("compressor", ["gzip", "zstd", "bzip2", "xz"])
+def test_compressed_input(cargo_run, tmpdir, compressor, from_stdin):
+    if shutil.which(compressor) is None:
+        pytest.skip("{} is not installed".format(compressor))
+    plain_out = tmpdir.mkdir("plain")
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+    compressed = tmpdir.join("input.tar.z")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with open(str(inp), "rb") as src, open(str(compressed), "wb") as dst:
+        subprocess.run([compressor, "-c"], stdin=src, stdout=dst, check=True)
+
+    cargo_run(["-S", "30K", str(inp), str(plain_out.join("output.tar."))])
+    output = str(outdir.join("output.tar."))
+    if from_stdin:
+        cargo_run(["-S", "30K", "-", output], input=compressed.read_binary())
+    else:
+        cargo_run(["-S", "30K", str(compressed), output])
+    assert split_outputs(outdir) == split_outputs(plain_out)
+
+
+@pytest.mark.parametrize(
+    "options",
+    [
+        ["--gzip"],
+        ["--compress", "gzip -c"],
+        ["--decompress-input", "gzip -dc", "--gzip"],
+        ["--parallel-decompress", "--compress", "gzip -c", "--jobs", "2"],
+    ],
+)
+def test_recompressed_input(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+    compressed = tmpdir.join("input.tar.gz")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with tarfile.open(str(inp), "r") as tar:
+        contents = {
+            member.name: tar.extractfile(member).read()
+            for member in tar.getmembers()
+            if member.isfile()
+        }
+    compressed.write_binary(gzip.compress(inp.read_binary()))
+
+    cargo_run(
+        ["-S", "30K", "--recreate-dirs"]
+        + options
+        + [str(compressed), str(outdir.join("output.tar.gz-part-"))]
+    )
+    assert len(os.listdir(str(outdir))) > 1
+    # Each volume is gzipped once, and is a tar archive of its own.
+    split = {}
+    for name in os.listdir(str(outdir)):
+        data = gzip.decompress(outdir.join(name).read_binary())
+        with tarfile.open(fileobj=io.BytesIO(data), mode="r:") as tar:
+            split.update(
+                (member.name, tar.extractfile(member).read())
+                for member in tar.getmembers()
+                if member.isfile()
+            )
+    assert split == contents
+
+
+def test_decompress_input(cargo_run, tmpdir):
+    plain_out = tmpdir.mkdir("plain")
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+    encoded = tmpdir.join("input.tar.b64")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    # Not a compression, so it cannot be detected.
+    encoded.write_binary(base64.b64encode(inp.read_binary()))
+
+    cargo_run(["-n", "2", str(inp), str(plain_out.join("output.tar."))])
+    # Both passes of --num-volumes decompress it.
+    cargo_run(
+        [
+            "-n",
+            "2",
+            "--decompress-input",
+            "base64 -d",
+            str(encoded),
+            str(outdir.join("output.tar.")),
+        ]
+    )
+    assert split_outputs(outdir) == split_outputs(plain_out)
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            [
+                "-S",
+                "30K",
+                "--decompress-input",
+                "cat >/dev/null; false",
+                str(encoded),
+                str(tmpdir.join("failed.")),
+            ]
+        )
+    assert b'input decompressor "cat >/dev/null; false" failed' in exc.value.stderr
@@ -3,8 +3,12 @@ Test directory recreation.
 """
 import itertools
 import io
+import json
 import tarfile
 import os
+import pytest
+import subprocess
+import time
 
 
 class Dir:
@@ -122,6 +126,163 @@ def test_next_volume_create(cargo_run, tmpdir):
         ]
 
 
+def test_next_volume_create_minimal(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(
+        [
+            "-S",
+            "35K",
+            "--recreate-dirs",
+            "--recreate-dirs-mode",
+            "minimal",
+            str(inp),
+            str(output),
+        ]
+    )
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == [
+            "thedir/nested2",
+            "thedir/nested2/file2",
+            "thedir/nested1",
+            "thedir/nested1/out-of-order",
+            "thedir/nested1/somedir",
+        ]
+
+
+@pytest.mark.parametrize(
+    "options,kept",
+    [
+        ([], 10),
+        (["--recreate-dirs-depth", "3"], 3),
+        (["--recreate-dirs-limit", "4"], 4),
+    ],
+)
+def test_recreate_dirs_bounded(cargo_run, tmpdir, options, kept):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # A deep tree, with the files at the bottom.
+    dirs = ["/".join("d" + str(i) for i in range(depth + 1)) for depth in range(10)]
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in [Dir(name, []) for name in dirs] + [
+            File(dirs[-1] + "/file" + str(i), 10240) for i in range(3)
+        ]:
+            obj.add_to_tar(tar)
+    res = cargo_run(["-S", "20K", "--recreate-dirs", *options, str(inp), str(output)])
+    warned = b"More than --recreate-dirs-limit 4 dirs" in res.stderr
+    assert warned == ("--recreate-dirs-limit" in options)
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00001",
+        "output.tar.00002",
+    ]
+    # Only the kept dirs are recreated.
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == dirs[:kept] + [dirs[-1] + "/file1"]
+
+
+@pytest.mark.parametrize(
+    "size_counts, volumes",
+    [
+        ("all", [["f0"], ["f1", "f2"], ["f3", "f4"]]),
+        # The room of all the 10 dirs is reserved in each new volume.
+        ("data-only", [["f0"], ["f1"], ["f2"], ["f3"], ["f4"]]),
+    ],
+)
+def test_size_counts(cargo_run, tmpdir, size_counts, volumes):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # A deep tree, with the files at the top; only d0 is recreated for them.
+    dirs = ["/".join("d" + str(i) for i in range(depth + 1)) for depth in range(10)]
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in [Dir(name, []) for name in dirs] + [
+            File("d0/f" + str(i), 7168) for i in range(5)
+        ]:
+            obj.add_to_tar(tar)
+    args = ["-S", "20K", "--recreate-dirs", "--size-counts", size_counts]
+    cargo_run(args + [str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == len(volumes)
+    for file, expected in zip(files, volumes):
+        assert os.path.getsize(str(outdir.join(file))) <= 20 * 1024
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            names = [name for name in tar.getnames() if "/f" in name]
+            assert names == ["d0/" + name for name in expected]
+
+
+def test_size_counts_large_file(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # The second file fits into an empty volume alone, but not with its dirs.
+    dirs = ["/".join("d" + str(i) for i in range(depth + 1)) for depth in range(10)]
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in [Dir(name, []) for name in dirs] + [
+            File(dirs[-1] + "/small", 512),
+            File(dirs[-1] + "/large", 16384),
+        ]:
+            obj.add_to_tar(tar)
+    args = ["-S", "20K", "--recreate-dirs", "--fail-on-large-file"]
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(args + [str(inp), str(output)])
+    assert exc.value.returncode == 3
+
+
+def test_recreate_symlinked_dirs(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # The files are in a dir symlinked from another one.
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in [Dir("d", []), Dir("d/real", [])]:
+            obj.add_to_tar(tar)
+        ti = tarfile.TarInfo("d/link")
+        ti.type = tarfile.SYMTYPE
+        ti.linkname = "real"
+        tar.addfile(ti)
+        for i in range(2):
+            File("d/link/file" + str(i), 4096).add_to_tar(tar)
+    cargo_run(["-S", "8K", "--recreate-dirs", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+    # The symlink is recreated instead of a dir, with the dir it points to.
+    volume = outdir.join("output.tar.00001")
+    with tarfile.open(str(volume), "r") as tar:
+        assert tar.getnames() == ["d", "d/link", "d/real", "d/link/file1"]
+        assert tar.getmember("d/link").issym()
+    extracted = tmpdir.mkdir("extracted")
+    subprocess.run(["tar", "-xf", str(volume), "-C", str(extracted)], check=True)
+    assert extracted.join("d", "real", "file1").size() == 4096
+
+
+def test_recreate_parents_first(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # The input has the subdirs before their parents.
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in [Dir("a/b/c", []), Dir("a/b", []), Dir("a", [])]:
+            obj.add_to_tar(tar)
+        for i in range(2):
+            File("a/b/c/file" + str(i), 4096).add_to_tar(tar)
+    cargo_run(["-S", "9K", "--recreate-dirs", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == ["a", "a/b", "a/b/c", "a/b/c/file1"]
+
+
 def test_no_create(cargo_run, tmpdir):
     outdir = tmpdir.mkdir("out")
     output = outdir.join("output.tar.")
@@ -148,3 +309,697 @@ def test_no_create(cargo_run, tmpdir):
             "thedir/nested1/out-of-order",
             "thedir/nested1/somedir",
         ]
+
+
+def test_dirs_first(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(["-S", "35K", "--dirs-first", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00001",
+        "output.tar.dirs",
+    ]
+    with tarfile.open(str(outdir.join("output.tar.dirs")), "r") as tar:
+        assert tar.getnames() == [
+            "thedir",
+            "thedir/nested1",
+            "thedir/nested1/somedir",
+            "thedir/nested2",
+        ]
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.getnames() == [
+            "thedir/nested1/file1",
+            "thedir/nested1/file2",
+            "thedir/nested2/file1",
+        ]
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == [
+            "thedir/nested2/file2",
+            "thedir/nested1/out-of-order",
+        ]
+
+
+def test_route_by_extension(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    routed = Dir(
+        "thedir",
+        [
+            File("a.jpg", 4096),
+            File("t.txt", 4096),
+            File("b.JPG", 4096),
+            File("x", 4096),
+            File("c.png", 4096),
+        ],
+    )
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in routed.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(
+        [
+            "-S",
+            "11K",
+            "--recreate-dirs",
+            "--route-by-extension",
+            "imgs=jpg,png",
+            "--route-by-extension",
+            "text=txt",
+            str(inp),
+            str(output),
+        ]
+    )
+    volumes = {}
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes[file[len("output.tar.") :]] = tar.getnames()
+    assert volumes == {
+        "00000": ["thedir", "thedir/x"],
+        "imgs-00000": ["thedir", "thedir/a.jpg", "thedir/b.JPG"],
+        "imgs-00001": ["thedir", "thedir/c.png"],
+        "text-00000": ["thedir", "thedir/t.txt"],
+    }
+
+
+def test_scrub(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for obj in DIRS.get_children(""):
+            ti = tarfile.TarInfo(obj.name)
+            ti.uid, ti.gid, ti.uname, ti.gname = 1000, 100, "alice", "users"
+            ti.mtime = 1234567890
+            if isinstance(obj, Dir):
+                ti.type = tarfile.DIRTYPE
+                tar.addfile(ti)
+            else:
+                ti.size = obj.size
+                tar.addfile(ti, io.BytesIO(b"0" * obj.size))
+    cargo_run(
+        [
+            "-S",
+            "35K",
+            "--recreate-dirs",
+            "--scrub",
+            "uid,uname,mtime",
+            str(inp),
+            str(output),
+        ]
+    )
+    for volume in ["output.tar.00000", "output.tar.00001"]:
+        with tarfile.open(str(outdir.join(volume)), "r") as tar:
+            members = tar.getmembers()
+            # Recreated dirs are scrubbed too.
+            assert members[0].name.rstrip("/") == "thedir"
+            for member in members:
+                assert (member.uid, member.uname, member.mtime) == (0, "", 0)
+                assert (member.gid, member.gname) == (100, "users")
+
+
+def test_normalize_owner(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for obj in DIRS.get_children(""):
+            ti = tarfile.TarInfo(obj.name)
+            ti.uid, ti.gid, ti.uname, ti.gname = 1000, 100, "alice", "users"
+            ti.mtime = 1234567890
+            if isinstance(obj, Dir):
+                ti.type = tarfile.DIRTYPE
+                tar.addfile(ti)
+            else:
+                ti.size = obj.size
+                tar.addfile(ti, io.BytesIO(b"0" * obj.size))
+    cargo_run(
+        [
+            "-S",
+            "35K",
+            "--recreate-dirs",
+            "--numeric-owner",
+            "--owner",
+            "2000",
+            "--group",
+            "3000",
+            "--mtime",
+            "1700000000",
+            str(inp),
+            str(output),
+        ]
+    )
+    for volume in ["output.tar.00000", "output.tar.00001"]:
+        # The tarfile module checks the header checksums.
+        with tarfile.open(str(outdir.join(volume)), "r") as tar:
+            members = tar.getmembers()
+            # Recreated dirs are normalized too.
+            assert members[0].name.rstrip("/") == "thedir"
+            for member in members:
+                assert (member.uid, member.gid, member.mtime) == (2000, 3000, 1700000000)
+                assert (member.uname, member.gname) == ("", "")
+
+
+ACCESS_ACL = "user::rw-,user:1000:r--,group::r--,mask::r--,other::---"
+DEFAULT_ACL = "user::rwx,group::r-x,other::---"
+
+
+@pytest.mark.parametrize(
+    "options,expected",
+    [
+        ([], [["thedir", "thedir/file0", "thedir/file1"], ["thedir/file2"]]),
+        (
+            ["--verbatim"],
+            [["thedir", "thedir/file0", "thedir/file1"], ["thedir/file2"]],
+        ),
+        (
+            ["--recreate-dirs"],
+            [["thedir", "thedir/file0", "thedir/file1"], ["thedir", "thedir/file2"]],
+        ),
+    ],
+)
+def test_acl(cargo_run, tmpdir, options, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.PAX_FORMAT) as tar:
+        ti = tarfile.TarInfo("thedir")
+        ti.type = tarfile.DIRTYPE
+        ti.pax_headers = {"SCHILY.acl.default": DEFAULT_ACL}
+        tar.addfile(ti)
+        for i in range(3):
+            ti = tarfile.TarInfo("thedir/file" + str(i))
+            ti.size = 4096
+            ti.pax_headers = {"SCHILY.acl.access": ACCESS_ACL}
+            tar.addfile(ti, io.BytesIO(b"0" * 4096))
+    # The ACL records take two blocks per entry; the third file doesn't fit.
+    cargo_run(["-S", "14K"] + options + [str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    for file, names in zip(files, expected):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            members = tar.getmembers()
+            assert [m.name for m in members] == names
+            # ACLs are never split off their entries.
+            for member in members:
+                if member.isdir():
+                    assert member.pax_headers["SCHILY.acl.default"] == DEFAULT_ACL
+                else:
+                    assert member.pax_headers["SCHILY.acl.access"] == ACCESS_ACL
+
+
+@pytest.mark.parametrize(
+    "options",
+    [
+        ["--recreate-dirs"],
+        ["--recreate-dirs", "--compress", "gzip"],
+        ["--dirs-first"],
+    ],
+)
+def test_join(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    joined = tmpdir.join("joined.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    with tarfile.open(str(inp), "r") as tar:
+        # The input has a dir twice too.
+        expected = list(dict.fromkeys(tar.getnames()))
+        contents = {
+            member.name: tar.extractfile(member).read()
+            for member in tar.getmembers()
+            if member.isfile()
+        }
+    cargo_run(["-S", "30K"] + options + [str(inp), str(output)])
+    assert len(os.listdir(str(outdir))) > 1
+
+    cargo_run(["join", str(output), "-o", str(joined)])
+    with tarfile.open(str(joined), "r") as tar:
+        names = tar.getnames()
+        assert {
+            member.name: tar.extractfile(member).read()
+            for member in tar.getmembers()
+            if member.isfile()
+        } == contents
+    if "--dirs-first" in options:
+        assert sorted(names) == sorted(expected)
+    else:
+        assert names == expected
+
+    # Explicit volumes to stdout.
+    volumes = sorted(str(outdir.join(name)) for name in os.listdir(str(outdir)))
+    if "--dirs-first" in options:
+        volumes = volumes[-1:] + volumes[:-1]
+    assert cargo_run(["join"] + volumes).stdout == joined.read_binary()
+
+
+def test_control_file(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("backup")
+    inp = tmpdir.join("input.tar")
+    joined = tmpdir.join("joined.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    # The names are not the ones the volumes are looked for by.
+    template = "{prefix}.part{index:03}.{ext}"
+    cargo_run(
+        ["-S", "30K", "--recreate-dirs", "--compress", "xz -c", "--control-file"]
+        + ["--name-template", template, str(inp), str(output)]
+    )
+    control = outdir.join("backup.splitar")
+    lines = control.read().splitlines()
+    volumes = sorted(name for name in os.listdir(str(outdir)) if ".part" in name)
+    assert len(volumes) > 1
+    assert lines[0] == "splitar-control 1"
+    assert "compress xz -c" in lines
+    assert "volumes {}".format(len(volumes)) in lines
+    assert [line.rsplit(" ", 1)[1] for line in lines if line.startswith("volume ")] == (
+        volumes
+    )
+
+    cargo_run(["join", str(output), "-o", str(joined)])
+    with tarfile.open(str(joined), "r") as tar:
+        joined_names = tar.getnames()
+    with tarfile.open(str(inp), "r") as tar:
+        assert joined_names == list(dict.fromkeys(tar.getnames()))
+    res = cargo_run(["verify", str(output)])
+    assert res.stdout.count(b": OK\n") == len(volumes)
+    res = cargo_run(["verify", "--structure", str(output)])
+    assert res.stdout.count(b": OK\n") == len(volumes)
+
+    # A changed volume is told by its size and checksum.
+    with open(str(outdir.join(volumes[1])), "ab") as volume:
+        volume.write(b"\0")
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["verify", str(output)])
+    assert b"part001.tar.xz: FAILED size, SHA256\n" in exc.value.stdout
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["join", str(output), "-o", str(joined)])
+    assert b"but the control file has" in exc.value.stderr
+
+    # Newer formats are refused.
+    control.write("\n".join(["splitar-control 2"] + lines[1:]))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["join", str(output), "-o", str(joined)])
+    assert b"has format version 2" in exc.value.stderr
+
+
+@pytest.mark.parametrize("options", [[], ["--recreate-dirs", "--gzip"]])
+def test_multi_volume(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    joined = tmpdir.join("joined.tar")
+    big = os.urandom(3 * 30 * 1024)
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for name, data in [("a", b"a"), ("dir/big", big), ("z", b"z")]:
+            info = tarfile.TarInfo(name)
+            info.size = len(data)
+            tar.addfile(info, io.BytesIO(data))
+    cargo_run(["-S", "30K", "--multi-volume"] + options + [str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == 4
+    parts = []
+    for name in files:
+        path = outdir.join(name)
+        assert path.size() <= 30 * 1024 or "--gzip" in options
+        # Every volume is still a valid archive.
+        with tarfile.open(str(path), "r") as tar:
+            parts.extend(
+                (m.name, m.type, m.size) for m in tar.getmembers() if not m.isdir()
+            )
+    assert parts[0] == ("a", tarfile.REGTYPE, 1)
+    assert parts[-1] == ("z", tarfile.REGTYPE, 1)
+    assert parts[1][:2] == ("dir/big", tarfile.REGTYPE)
+    assert [part[:2] for part in parts[2:-1]] == [("dir/big", b"M")] * 3
+    assert sum(part[2] for part in parts[1:-1]) == len(big)
+
+    cargo_run(["join", str(output), "-o", str(joined)])
+    with tarfile.open(str(joined), "r") as tar:
+        names = [m.name for m in tar.getmembers() if not m.isdir()]
+        assert names == ["a", "dir/big", "z"]
+        assert "SPLITAR.multivolume.size" not in tar.getmember("dir/big").pax_headers
+        assert tar.extractfile("dir/big").read() == big
+
+    # A missing volume breaks the offsets.
+    volumes = [str(outdir.join(name)) for name in files]
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["join"] + volumes[:1] + volumes[2:])
+    assert b"is a volume missing?" in exc.value.stderr
+
+
+def test_manifest(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    manifest = tmpdir.join("manifest.jsonl")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+        names = tar.getnames()
+    cargo_run(
+        ["-S", "30K", "--recreate-dirs", "--manifest", str(manifest)]
+        + [str(inp), str(output)]
+    )
+    records = [json.loads(line) for line in manifest.read().splitlines()]
+    assert [r["path"] for r in records if not r["recreated"]] == names
+    assert {r["volume"] for r in records} == set(os.listdir(str(outdir)))
+    recreated = [r for r in records if r["recreated"]]
+    assert recreated and all(r["type"] == "dir" for r in recreated)
+    assert all(r["volume"] == "output.tar.00001" for r in recreated)
+    # Offsets point at the entry headers in the volumes.
+    for record in records:
+        with open(str(outdir.join(record["volume"])), "rb") as volume:
+            volume.seek(record["offset"])
+            info = tarfile.TarInfo.frombuf(
+                volume.read(tarfile.BLOCKSIZE), tarfile.ENCODING, "surrogateescape"
+            )
+        assert info.name == record["path"].rstrip("/")
+        assert info.size == record["size"]
+        assert record["type"] == ("dir" if info.isdir() else "file")
+
+
+def test_include_exclude(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+    cargo_run(
+        [
+            "-S",
+            "25K",
+            "--recreate-dirs",
+            "--include",
+            "thedir/**",
+            "--exclude",
+            "**/somedir",
+            "--exclude",
+            "thedir/nested[!2]/file1",
+            str(inp),
+            str(output),
+        ]
+    )
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append(tar.getnames())
+    # "thedir" itself is not included, so it is not recreated either.
+    assert volumes == [
+        [
+            "thedir/nested1",
+            "thedir/nested1/file2",
+            "thedir/nested2",
+            "thedir/nested2/file1",
+        ],
+        [
+            "thedir/nested2",
+            "thedir/nested2/file2",
+            "thedir/nested1",
+            "thedir/nested1/out-of-order",
+        ],
+    ]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "25K", "--exclude", "[a-", str(inp), str(output)])
+    assert b'invalid --exclude glob "[a-": unclosed character class' in (
+        exc.value.stderr
+    )
+
+
+@pytest.mark.parametrize(
+    "pattern,matches",
+    [
+        ("*a*a*a*a*a*a*a*a*b", False),
+        ("*a*a*a*a*a*a*a*a*", True),
+        ("**a**a**a**a**a**a**a**a**b", False),
+        ("**/**/**/**/**/**/x", False),
+    ],
+)
+def test_include_pathological(cargo_run, tmpdir, pattern, matches):
+    outdir = tmpdir.mkdir("out")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for name in ["a" * 60, "/".join(["a"] * 30)]:
+            tar.addfile(tarfile.TarInfo(name), io.BytesIO())
+    # Backtracking over the stars would take ages.
+    start = time.monotonic()
+    cargo_run(["-S", "25K", "--include", pattern, str(inp), str(outdir.join("o."))])
+    assert time.monotonic() - start < 30
+    with tarfile.open(str(outdir.join("o.00000")), "r") as tar:
+        assert tar.getnames() == (["a" * 60] if matches else [])
+
+
+@pytest.mark.parametrize("fmt", [tarfile.GNU_FORMAT, tarfile.PAX_FORMAT])
+@pytest.mark.parametrize("options", [[], ["--recreate-dirs"], ["--verbatim"]])
+def test_long_names(cargo_run, tmpdir, fmt, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # Long enough for the dir to need a long name too.
+    long_dir = "d" * 120
+    names = [long_dir + "/" + str(i) * 200 for i in range(3)]
+    with tarfile.open(str(inp), mode="w", format=fmt) as tar:
+        ti = tarfile.TarInfo(long_dir)
+        ti.type = tarfile.DIRTYPE
+        tar.addfile(ti)
+        for name in names:
+            ti = tarfile.TarInfo(name)
+            ti.size = 4096
+            tar.addfile(ti, io.BytesIO(b"0" * 4096))
+        ti = tarfile.TarInfo("link")
+        ti.type = tarfile.SYMTYPE
+        ti.linkname = "l" * 150
+        tar.addfile(ti)
+    cargo_run(["-S", "12K"] + options + [str(inp), str(output)])
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append(tar.getnames())
+            for member in tar.getmembers():
+                if member.issym():
+                    assert member.linkname == "l" * 150
+            tar.extractall(str(tmpdir.join("extracted")), filter="tar")
+    # The long name members take a block each and stay with their entries.
+    assert volumes[0] == [long_dir, names[0]]
+    if "--recreate-dirs" in options:
+        assert volumes[1] == [long_dir, names[1]]
+    assert sorted(os.listdir(str(tmpdir.join("extracted", long_dir)))) == [
+        name.split("/")[1] for name in names
+    ]
+
+
+@pytest.mark.parametrize("fmt", [tarfile.GNU_FORMAT, tarfile.PAX_FORMAT])
+@pytest.mark.parametrize("options", [[], ["--verbatim"]])
+def test_long_names_boundary(cargo_run, tmpdir, fmt, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    long_name = "l" * 200
+    with tarfile.open(str(inp), mode="w", format=fmt) as tar:
+        for name, size in [("short", 4000), (long_name, 4000)] + [
+            ("small" + str(i), 1) for i in range(8)
+        ]:
+            ti = tarfile.TarInfo(name)
+            ti.size = size
+            tar.addfile(ti, io.BytesIO(b"0" * size))
+    # The second file fits only without its long name member; the small
+    # files fit only without their padding.
+    cargo_run(["-S", "11000"] + options + [str(inp), str(output)])
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        assert os.path.getsize(str(outdir.join(file))) <= 11000
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append(tar.getnames())
+    assert volumes[0] == ["short"]
+    assert volumes[1][0] == long_name
+    assert sum(volumes, []) == ["short", long_name] + [
+        "small" + str(i) for i in range(8)
+    ]
+
+
+def test_strip_components(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+        ti = tarfile.TarInfo("thedir/nested2/link")
+        ti.type = tarfile.LNKTYPE
+        ti.linkname = "thedir/nested2/file2"
+        tar.addfile(ti)
+        ti = tarfile.TarInfo("thedir/" + "l" * 120)
+        ti.type = tarfile.SYMTYPE
+        ti.linkname = "thedir/nested2/file2"
+        tar.addfile(ti)
+    cargo_run(
+        [
+            "-S",
+            "25K",
+            "--recreate-dirs",
+            "--strip-components",
+            "1",
+            str(inp),
+            str(output),
+        ]
+    )
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append([(m.name, m.linkname) for m in tar.getmembers()])
+    # "thedir" itself has nothing left; dirs are recreated by the new paths.
+    # Symlink targets are relative to the link, so they are kept.
+    assert volumes == [
+        [
+            ("nested1", ""),
+            ("nested1/file1", ""),
+            ("nested1/somedir", ""),
+            ("nested1/file2", ""),
+            ("nested2", ""),
+        ],
+        [
+            ("nested2", ""),
+            ("nested2/file1", ""),
+            ("nested2/file2", ""),
+            ("nested1", ""),
+            ("nested1/out-of-order", ""),
+            ("nested1/somedir", ""),
+        ],
+        [
+            ("nested2", ""),
+            ("nested2/link", "nested2/file2"),
+            ("l" * 120, "thedir/nested2/file2"),
+        ],
+    ]
+
+
+def test_transform(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for obj in DIRS.get_children(""):
+            obj.add_to_tar(tar)
+        ti = tarfile.TarInfo("thedir/nested2/link")
+        ti.type = tarfile.LNKTYPE
+        ti.linkname = "thedir/nested2/file2"
+        tar.addfile(ti)
+    cargo_run(
+        [
+            "-S",
+            "25K",
+            "--recreate-dirs",
+            "--transform",
+            "s|^thedir/nested|thedir/renamed|",
+            "--transform",
+            "s,file,f,",
+            "--add-prefix",
+            "top/",
+            str(inp),
+            str(output),
+        ]
+    )
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append([(m.name, m.linkname) for m in tar.getmembers()])
+    # Recreated dirs have the new paths as well.
+    assert volumes == [
+        [
+            ("top/thedir", ""),
+            ("top/thedir/renamed1", ""),
+            ("top/thedir/renamed1/f1", ""),
+            ("top/thedir/renamed1/somedir", ""),
+            ("top/thedir/renamed1/f2", ""),
+            ("top/thedir/renamed2", ""),
+        ],
+        [
+            ("top/thedir", ""),
+            ("top/thedir/renamed2", ""),
+            ("top/thedir/renamed2/f1", ""),
+            ("top/thedir/renamed2/f2", ""),
+            ("top/thedir/renamed1", ""),
+            ("top/thedir/renamed1/out-of-order", ""),
+        ],
+        [
+            ("top/thedir", ""),
+            ("top/thedir/renamed1", ""),
+            ("top/thedir/renamed1/somedir", ""),
+            ("top/thedir/renamed2", ""),
+            ("top/thedir/renamed2/link", "top/thedir/renamed2/f2"),
+        ],
+    ]
+
+    for expression in ["s|a|b", "y|a|b|", "s|a|b|g", "s|||"]:
+        with pytest.raises(subprocess.CalledProcessError) as exc:
+            cargo_run(["-S", "25K", "--transform", expression, str(inp), str(output)])
+        assert b"invalid value" in exc.value.stderr
+
+
+def test_keep_dirs_together(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for name, size in [
+            ("p1/", None),
+            ("p1/f", 14000),
+            ("README", 100),
+            ("p2/", None),
+            ("p2/f", 2000),
+            ("p2/g", 2000),
+            ("p3/f", 9000),
+            ("p3/g", 9000),
+            ("p3/h", 9000),
+        ]:
+            ti = tarfile.TarInfo(name.rstrip("/"))
+            if size is None:
+                ti.type = tarfile.DIRTYPE
+                tar.addfile(ti)
+            else:
+                ti.size = size
+                tar.addfile(ti, io.BytesIO(b"x" * size))
+
+    def split(*options):
+        outdir = tmpdir.mkdir("out%d" % len(options))
+        cargo_run(["-S", "20K", *options, str(inp), str(outdir.join("out."))])
+        volumes = []
+        for file in sorted(os.listdir(str(outdir))):
+            with tarfile.open(str(outdir.join(file)), "r") as tar:
+                volumes.append(tar.getnames())
+        return volumes
+
+    assert split() == [
+        ["p1", "p1/f", "README", "p2", "p2/f"],
+        ["p2/g", "p3/f"],
+        ["p3/g", "p3/h"],
+    ]
+    # "p2" starts a new volume, as only 4K are left; "p3" has enough space
+    # to start, though it doesn't fit into a volume anyway.
+    assert split("--keep-dirs-together", "1", "--dir-min-space", "6K") == [
+        ["p1", "p1/f", "README"],
+        ["p2", "p2/f", "p2/g", "p3/f"],
+        ["p3/g", "p3/h"],
+    ]
@@ -1,7 +1,12 @@
+import gzip
 import io
+import json
 import os
 import pytest
+import subprocess
 import tarfile
+import tempfile
+import threading
 
 
 def tarinfo(name, type, linkname=None, data=None):
@@ -14,6 +19,34 @@ def tarinfo(name, type, linkname=None, data=None):
     return ti
 
 
+def v7_header(name, size=0, typeflag=b"0"):
+    """Pre-POSIX header: no magic, no user and group names."""
+
+    def octal(value, width):
+        return b"%0*o\x00" % (width - 1, value)
+
+    header = (
+        name.encode().ljust(100, b"\x00")
+        + octal(0o644, 8)
+        + octal(1000, 8)
+        + octal(100, 8)
+        + octal(size, 12)
+        + octal(0, 12)
+        + b" " * 8
+        + typeflag
+    ).ljust(512, b"\x00")
+    cksum = b"%06o\x00 " % sum(header)
+    return header[:148] + cksum + header[156:]
+
+
+def v7_archive(entries):
+    res = b""
+    for name, data in entries:
+        res += v7_header(name, len(data))
+        res += data.ljust((len(data) + 511) // 512 * 512, b"\x00")
+    return res + b"\x00" * 1024
+
+
 def test_is_sane(cargo_run):
     """Check that cargo run runs, otherwise all tests will fail"""
     cargo_run(["--help"])
@@ -42,6 +75,206 @@ def test_suffix(cargo_run, tmpdir):
     assert os.listdir(str(outdir)) == ["output.tar.00000000"]
 
 
+def test_start_index(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(["-S", "10K", "--start-index", "10", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00010", "output.tar.00011"]
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    # The schedule goes by the same indices.
+    cargo_run(
+        ["--start-index", "7", "-a", "2", "--size-schedule", "7:6K,*:10K"]
+        + [str(inp), str(output)]
+    )
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append((file, tar.getnames()))
+    assert volumes == [
+        ("output.tar.07", ["theobject0"]),
+        ("output.tar.08", ["theobject1", "theobject2"]),
+    ]
+
+def test_resume(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    manifest = tmpdir.join("manifest.jsonl")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(5):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    common = ["-S", "10K", str(inp), str(output)]
+    cargo_run(["--manifest", str(manifest)] + common)
+    volumes = {
+        file: outdir.join(file).read_binary() for file in os.listdir(str(outdir))
+    }
+    assert len(volumes) == 3
+    # As if interrupted while writing the second volume.
+    outdir.join("output.tar.00001").remove()
+    outdir.join("output.tar.00002").remove()
+    os.utime(str(outdir.join("output.tar.00000")), (0, 0))
+
+    cargo_run(["--resume", "--resume-manifest", str(manifest)] + common)
+    assert {
+        file: outdir.join(file).read_binary() for file in os.listdir(str(outdir))
+    } == volumes
+    # Kept as is.
+    assert os.stat(str(outdir.join("output.tar.00000"))).st_mtime == 0
+
+    outdir.join("output.tar.00000").write_binary(b"\x00" * 1024)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--resume"] + common)
+    assert b'output.tar.00000" has 1024 bytes instead of 10240' in exc.value.stderr
+
+    # Sizes of compressed volumes are not known, but the records are.
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+    cargo_run(["--gzip", "--manifest", str(manifest)] + common)
+    outdir.join("output.tar.00001").remove()
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["--gzip", "--resume", "--resume-manifest", str(manifest), "-S", "15K"]
+            + common[2:]
+        )
+    assert b'output.tar.00000" does not match --resume-manifest' in exc.value.stderr
+
+def test_name_template(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("backup")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    template = "{prefix}.part{index:03}.{ext}"
+    cargo_run(["-S", "10K", "--name-template", template, str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == [
+        "backup.part000.tar",
+        "backup.part001.tar",
+    ]
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    template = "{prefix}-{{{index}}}.{ext}"
+    cargo_run(
+        ["-S", "10K", "--gzip", "--name-template", template, str(inp), str(output)]
+    )
+    assert sorted(os.listdir(str(outdir))) == [
+        "backup-{00000}.tar.gz",
+        "backup-{00001}.tar.gz",
+    ]
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    # Templates are checked before any volume is started.
+    for template, message in [
+        ("{prefix}{volume}", b"unknown placeholder `{volume}`"),
+        ("{prefix}{index:3}", b"the index width is written like `{index:05}`"),
+        ("{prefix}.tar", b"no `{index}` placeholder"),
+        ("{prefix}{index", b"unterminated `{`"),
+    ]:
+        with pytest.raises(subprocess.CalledProcessError) as exc:
+            cargo_run(
+                ["-S", "10K", "--name-template", template, str(inp), str(output)]
+            )
+        assert message in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_stdout_output(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    for options, mode in [
+        ([], "r|"),
+        (["--gzip"], "r|gz"),
+        (["--compress", "xz"], "r|xz"),
+    ]:
+        res = cargo_run(["-S", "1M"] + options + [str(inp), "-"])
+        with tarfile.open(fileobj=io.BytesIO(res.stdout), mode=mode) as tar:
+            assert tar.getnames() == ["theobject0", "theobject1", "theobject2"]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", str(inp), "-"])
+    assert b"the input doesn't fit into a single volume" in exc.value.stderr
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "1M", "--checksum", "sha256", str(inp), "-"])
+    assert b"--checksum cannot be used with output to stdout" in exc.value.stderr
+    assert not exc.value.stdout
+
+
+def test_separator(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("backup")
+    inp = tmpdir.join("input.tar")
+    joined = tmpdir.join("joined.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    common = ["-S", "10K", "--separator", ".part", "--gzip"]
+    cargo_run(common + ["--auto-extension", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == [
+        "backup.part00000.gz",
+        "backup.part00001.gz",
+    ]
+    # The volumes are found by the prefix with the separator.
+    cargo_run(["join", str(output) + ".part", "-o", str(joined)])
+    with tarfile.open(str(joined)) as tar:
+        assert tar.getnames() == ["theobject0", "theobject1", "theobject2"]
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    cargo_run(common + ["--extension", "tar.gz", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == [
+        "backup.part00000.tar.gz",
+        "backup.part00001.tar.gz",
+    ]
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--separator", "/part", str(inp), str(output)])
+    assert b'--separator "/part" cannot contain path separators' in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
 @pytest.mark.parametrize(
     "tartype,linkname,contents",
     [
@@ -108,68 +341,2524 @@ def test_splits(cargo_run, tmpdir, size, expected):
     assert sorted(os.listdir(str(outdir))) == expected
 
 
-def test_file_too_large(cargo_run, tmpdir):
+@pytest.mark.parametrize("write_buffer", ["0", "1", "1M"])
+def test_write_buffer(cargo_run, tmpdir, write_buffer):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # The buffer size doesn't change the volumes.
+    volumes = []
+    for options in [[], ["--write-buffer", write_buffer]]:
+        outdir = tmpdir.mkdir("out" + str(len(volumes)))
+        cargo_run(options + ["-S", "40K", str(inp), str(outdir.join("output.tar."))])
+        names = sorted(os.listdir(str(outdir)))
+        volumes.append([outdir.join(name).read_binary() for name in names])
+    assert len(volumes[0]) > 1
+    assert volumes[0] == volumes[1]
+
+
+@pytest.mark.parametrize("size", ["100", "512", "1535"])
+def test_max_size_too_small(cargo_run, tmpdir, size):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    inp.write(b"\x00" * 1024)
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", size, str(inp), str(output)])
+    assert b"is too small" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_max_size_minimal(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(2):
+            tar.addfile(tarinfo("theobject" + str(i), tarfile.REGTYPE, data=b""))
+
+    cargo_run(["-S", "1536", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+
+
+def split_names(outdir):
+    names = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            names.append([name[len("theobject"):] for name in tar.getnames()])
+    return names
+
+
+def test_max_size_oversized(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(2):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # Entries that fit into no volume get one each, with no empty ones.
+    cargo_run(["-S", "1536", str(inp), str(output)])
+    assert split_names(outdir) == [["0"], ["1"]]
+
+
+@pytest.mark.parametrize(
+    "options, expected",
+    [
+        (["-S", "8K"], [["0", "1"], ["2"]]),
+        (["--si", "-S", "8K"], [["0"], ["1"], ["2"]]),
+        (["--si", "-S", "8KiB"], [["0", "1"], ["2"]]),
+        (["--si", "--size-schedule", "0:8K,*:8KiB"], [["0"], ["1", "2"]]),
+    ],
+)
+def test_si(cargo_run, tmpdir, options, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # 3.5K each with the header; 8K is 8192 bytes, but 8000 with --si.
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 768
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    cargo_run(options + [str(inp), str(output)])
+    assert split_names(outdir) == expected
+
+
+@pytest.mark.parametrize(
+    "metric, expected",
+    [
+        ("archive", [["0", "1"], ["2", "3"], ["4", "5"], ["6", "7"]]),
+        ("payload", [["0", "1", "2", "3"], ["4", "5", "6", "7"]]),
+    ],
+)
+def test_size_metric(cargo_run, tmpdir, metric, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # 1.5K each in the archive, with the EOF blocks counted, but only 1000
+    # bytes of data.
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(8):
+            data = b"1" * 1000
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    res = cargo_run(
+        ["-S", "4K", "--size-metric", metric, "-v", str(inp), str(output)]
+    )
+    assert split_names(outdir) == expected
+    rolls = [line for line in res.stderr.splitlines() if b" ends at " in line]
+    assert rolls[0] == {
+        "archive": b'00000 ends at 4096 bytes: "theobject2" (1536 bytes)'
+        b" doesn't fit into --max-size 4096",
+        "payload": b'00000 ends at 4000 bytes: "theobject4" (1000 bytes)'
+        b" doesn't fit into --max-size 4096",
+    }[metric]
+    # The payload volumes are larger on disk.
+    sizes = [outdir.join(file).size() for file in os.listdir(str(outdir))]
+    assert all((size > 4096) == (metric == "payload") for size in sizes)
+
+
+@pytest.mark.parametrize(
+    "options",
+    [["--pack"], ["--multi-volume"], ["--coalesce-small", "100"]],
+)
+def test_size_metric_invalid(cargo_run, tmpdir, options):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w"):
+        pass
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["-S", "4K", "--size-metric", "payload"]
+            + options
+            + [str(inp), str(tmpdir.join("output.tar."))]
+        )
+    assert b"cannot be used with --size-metric payload" in exc.value.stderr
+
+
+def test_max_size_env(cargo_run, tmpdir, monkeypatch):
+    monkeypatch.setenv("SPLITAR_MAX_SIZE", "8K")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 768
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    cargo_run([str(inp), str(output)])
+    assert split_names(outdir) == [["0", "1"], ["2"]]
+    # The same size is fine, and options that replace it ignore it.
+    for options in [["-S", "8192"], ["-n", "3"]]:
+        outdir.remove()
+        outdir.mkdir()
+        cargo_run(options + [str(inp), str(output)])
+    assert split_names(outdir) == [["0"], ["1"], ["2"]]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", str(inp), str(output)])
+    assert b'--max-size 10240 differs from $SPLITAR_MAX_SIZE "8K"' in exc.value.stderr
+
+
+def test_size_schedule(cargo_run, tmpdir):
     outdir = tmpdir.mkdir("out")
     output = outdir.join("output.tar.")
     inp = tmpdir.join("input.tar")
 
     with tarfile.open(str(inp), mode="w") as tar:
         for i in range(10):
-            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=b"1234" * 1024),
+                io.BytesIO(b"1234" * 1024),
+            )
+
+    cargo_run(["--size-schedule", "0:6K,*:11K,2:15K", str(inp), str(output)])
+    names = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            names.append([name[len("theobject"):] for name in tar.getnames()])
+    assert names == [["0"], ["1", "2"], ["3", "4", "5"], ["6", "7"], ["8", "9"]]
+
+
+@pytest.mark.parametrize(
+    "schedule", ["0:6K", "x:6K,*:6K", "*:6K,*:7K", "0:6K,0:7K,*:6K", "0:100,*:6K"]
+)
+def test_size_schedule_invalid(cargo_run, tmpdir, schedule):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    inp.write(b"\x00" * 1024)
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--size-schedule", schedule, str(inp), str(output)])
+    assert b"--size-schedule" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_volume_note(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(4):
+            data = b"1234" * 1024
             tar.addfile(
                 tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
                 io.BytesIO(data),
             )
 
-    with pytest.raises(Exception):
-        cargo_run(["-S", "20K", "--fail-on-large-file", str(inp), str(output)])
-    assert sorted(os.listdir(str(outdir))) == [
-        "output.tar.00000",
-        "output.tar.00001",
-    ]
+    # The note takes two blocks, so only one file fits.
+    cargo_run(["-S", "7K", "--volume-note", "batch 42", str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == 4
+    for i, file in enumerate(files):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == [".splitar-note", "theobject" + str(i)]
+            assert tar.extractfile(".splitar-note").read() == b"batch 42"
 
 
-def test_verbose(cargo_run, tmpdir, monkeypatch):
-    monkeypatch.setenv("TZ", "GMT-1")
+@pytest.mark.parametrize(
+    "options,count", [(["-S", "7K"], "?"), (["-n", "4"], "4")]
+)
+def test_volume_info(cargo_run, tmpdir, options, count):
     outdir = tmpdir.mkdir("out")
     output = outdir.join("output.tar.")
     inp = tmpdir.join("input.tar")
 
     with tarfile.open(str(inp), mode="w") as tar:
-        for i, (tartype, linkname, contents) in enumerate(
-            [
-                (tarfile.DIRTYPE, None, None),
-                (tarfile.REGTYPE, None, 1),
-                (tarfile.REGTYPE, None, 1024),
-                (tarfile.AREGTYPE, None, 1),
-                (tarfile.AREGTYPE, None, 1024),
-                (tarfile.LNKTYPE, "otherhard", None),
-                (tarfile.SYMTYPE, "othersym", None),
-                (tarfile.FIFOTYPE, None, None),
+        for i in range(4):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # The info takes two blocks, so only one file fits.
+    cargo_run(options + ["--volume-info", "--volume-note", "x", str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == 4
+    for i, file in enumerate(files):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == [
+                "VOLUME_INFO.txt",
+                ".splitar-note",
+                "theobject" + str(i),
             ]
-        ):
-            if contents is None:
-                tar.addfile(tarinfo("theobject" + str(i), tartype, linkname=linkname))
-            else:
-                data = b"1234" * contents
-                tar.addfile(
-                    tarinfo("theobject" + str(i), tartype, data=data),
-                    io.BytesIO(data),
-                )
+            info = tar.extractfile("VOLUME_INFO.txt").read().decode()
+        assert info.startswith("This is volume %d of %s of" % (i + 1, count))
+        assert "splitar join %s -o archive.tar\n" % output in info
 
-    res = cargo_run(["-S", "10K", "-v", str(inp), str(output)])
-    assert not res.stdout, repr(res.stdout)
-    assert res.stderr == (
-        b"""00000 drw-r--r--              0 1970-01-01 01:00:00 theobject0/
-00000 -rw-r--r--              4 1970-01-01 01:00:00 theobject1
-00000 -rw-r--r--           4096 1970-01-01 01:00:00 theobject2
-00000 -rw-r--r--              4 1970-01-01 01:00:00 theobject3
-00001 -rw-r--r--           4096 1970-01-01 01:00:00 theobject4
-00001 hrw-r--r--              0 1970-01-01 01:00:00 theobject5 link to otherhard
-00001 lrw-r--r--              0 1970-01-01 01:00:00 theobject6 -> othersym
-00001 prw-r--r--              0 1970-01-01 01:00:00 theobject7
-"""
-    ), repr(res.stderr)
+    joined = tmpdir.join("joined.tar")
+    cargo_run(["join", str(output), "-o", str(joined)])
+    with tarfile.open(str(joined), "r") as tar:
+        assert "VOLUME_INFO.txt" not in tar.getnames()
+        assert tar.getnames().count(".splitar-note") == 4
 
 
-# TODO test for some bugs...
+def test_prepend(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    readme = tmpdir.join("README")
+    readme.write("Extract all the volumes.\n")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # The README takes two blocks, so the first volume has room for one file.
+    cargo_run(["-S", "10K", "--prepend", str(readme), str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.getnames() == ["README", "theobject0"]
+        assert tar.extractfile("README").read() == b"Extract all the volumes.\n"
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == ["theobject1", "theobject2"]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["-S", "2K", "--prepend", str(readme), str(inp), str(tmpdir.join("small."))]
+        )
+    assert b"prepended file" in exc.value.stderr
+
+
+def test_coalesce_small(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    extracted = tmpdir.mkdir("extracted")
+
+    files = {
+        "thedir/a": b"hello",
+        "thedir/empty": b"",
+        "thedir/odd name\t\\\n": b"odd",
+        "thedir/-dash": b"dash",
+        "thedir/big": b"1234" * 1024,
+        "thedir/sub/b": b"world",
+    }
+    with tarfile.open(str(inp), mode="w") as tar:
+        for name, data in files.items():
+            info = tarinfo(name, tarfile.REGTYPE, data=data)
+            info.mode = 0o640
+            info.mtime = 1600000000
+            tar.addfile(info, io.BytesIO(data))
+
+    cargo_run(
+        [
+            "-S",
+            "10K",
+            "--coalesce-small",
+            "1K",
+            "--emit-extract-script",
+            str(inp),
+            str(output),
+        ]
+    )
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.extract.sh",
+        "output.tar.unpack-small.sh",
+    ]
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.getnames() == [
+            ".splitar-pack.00000.0",
+            "thedir/big",
+            ".splitar-pack.00000.1",
+        ]
+
+    subprocess.run(
+        [str(outdir.join("output.tar.extract.sh"))], check=True, cwd=str(extracted)
+    )
+    for name, data in files.items():
+        path = extracted.join(name)
+        assert path.read_binary() == data
+        assert path.stat().st_mode & 0o777 == 0o640
+        assert path.stat().st_mtime == 1600000000
+    assert not any(name.startswith(".splitar-pack") for name in os.listdir(str(extracted)))
+
+
+def test_record_size(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    log = tmpdir.join("tape.log")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(
+        [
+            "-S",
+            "10K",
+            "--record-size",
+            "10K",
+            "--tape-command",
+            'echo "$SPLITAR_VOLUME" >> {}'.format(log),
+            str(inp),
+            str(output),
+        ]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    # The last volume is padded too.
+    for file in files:
+        assert outdir.join(file).size() == 10240
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == ["theobject2"]
+    assert log.read() == "".join(str(outdir.join(file)) + "\n" for file in files)
+
+
+def test_blocking_factor(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(5):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(["-S", "20K", "-b", "20", str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    # The padding fits into --max-size, so volumes are filled as usual.
+    for file in files:
+        assert outdir.join(file).size() % 10240 == 0
+        assert outdir.join(file).size() <= 20480
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == ["theobject4"]
+
+
+@pytest.mark.parametrize(
+    "options",
+    [
+        ["-S", "10K", "--record-size", "1000"],
+        ["-S", "15K", "--record-size", "10K"],
+        ["-S", "15K", "--blocking-factor", "20"],
+        ["-S", "10K", "--blocking-factor", "0"],
+        ["-S", "10K", "--blocking-factor", "20", "--record-size", "10K"],
+        ["-S", "10K", "--tape-command", "false"],
+    ],
+)
+def test_record_size_invalid(cargo_run, tmpdir, options):
+    output = tmpdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarinfo("theobject", tarfile.REGTYPE))
+
+    with pytest.raises(subprocess.CalledProcessError):
+        cargo_run(options + [str(inp), str(output)])
+
+
+@pytest.mark.parametrize(
+    "options,umask,expected",
+    [
+        ([], 0o022, 0o644),
+        (["--mode", "600"], 0o022, 0o600),
+        (["--mode", "0o664"], 0o027, 0o640),
+        (["--mode", "666"], 0o000, 0o666),
+    ],
+)
+def test_mode(cargo_run, tmpdir, options, umask, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarinfo("theobject", tarfile.REGTYPE))
+
+    old_umask = os.umask(umask)
+    try:
+        cargo_run(["-S", "10K"] + options + [str(inp), str(output)])
+    finally:
+        os.umask(old_umask)
+    assert outdir.join("output.tar.00000").stat().st_mode & 0o7777 == expected
+
+    for mode in ["800", "17777", "rw", ""]:
+        with pytest.raises(subprocess.CalledProcessError):
+            cargo_run(["-S", "10K", "--mode", mode, str(inp), str(output)])
+
+
+def test_sign(cargo_run, tmpdir):
+    import hashlib
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    # A stale signature is replaced.
+    outdir.join("output.tar.00000.sig").write("stale")
+
+    cargo_run(
+        [
+            "-S",
+            "10K",
+            "--sign",
+            'sha256sum < "$SPLITAR_VOLUME" > "$SPLITAR_SIGNATURE"',
+            str(inp),
+            str(output),
+        ]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert files == [
+        "output.tar.00000",
+        "output.tar.00000.sig",
+        "output.tar.00001",
+        "output.tar.00001.sig",
+    ]
+    for file in files[::2]:
+        digest = hashlib.sha256(outdir.join(file).read_binary()).hexdigest()
+        assert outdir.join(file + ".sig").read().split()[0] == digest
+
+
+@pytest.mark.parametrize(
+    "options", [[], ["--compress", "gzip"], ["--gzip", "--threads", "2"]]
+)
+def test_checksum(cargo_run, tmpdir, options):
+    import hashlib
+    import zlib
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = os.urandom(4096)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    cargo_run(
+        ["-S", "10K", "--checksum", "crc32", "--checksum", "sha256"]
+        + options
+        + [str(inp), str(output)]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert files == [
+        "output.tar.00000",
+        "output.tar.00000.crc32",
+        "output.tar.00000.sha256",
+        "output.tar.00001",
+        "output.tar.00001.crc32",
+        "output.tar.00001.sha256",
+    ]
+    # Checksums are of the files as written, i.e. compressed.
+    for file in files[::3]:
+        data = outdir.join(file).read_binary()
+        sha256 = hashlib.sha256(data).hexdigest()
+        assert outdir.join(file + ".sha256").read() == f"{sha256}  {file}\n"
+        crc32 = "%08x" % zlib.crc32(data)
+        assert outdir.join(file + ".crc32").read() == f"{crc32}  {file}\n"
+    subprocess.run(["sha256sum", "-c", files[2], files[5]], check=True, cwd=str(outdir))
+
+    ret = cargo_run(["verify", str(output)])
+    assert ret.stdout.decode().splitlines() == [
+        f"{output}00000: OK",
+        f"{output}00001: OK",
+    ]
+    with open(str(outdir.join(files[3])), "r+b") as f:
+        f.seek(-1, io.SEEK_END)
+        last = f.read(1)
+        f.seek(-1, io.SEEK_END)
+        f.write(bytes([last[0] ^ 1]))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["verify", str(output)])
+    assert exc.value.stdout.decode().splitlines() == [
+        f"{output}00000: OK",
+        f"{output}00001: FAILED SHA256, CRC32",
+    ]
+    assert b"1 of 2 volumes failed the check" in exc.value.stderr
+
+
+def test_checksums(cargo_run, tmpdir):
+    import hashlib
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    checksums = tmpdir.join("SHA256SUMS")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    cargo_run(["-S", "10K", "--checksums", str(checksums), str(inp), str(output)])
+    # No separate checksum files.
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    expected = []
+    for file in files:
+        digest = hashlib.sha256(outdir.join(file).read_binary()).hexdigest()
+        expected.append("SHA256 (%s) = %s" % (outdir.join(file), digest))
+    assert checksums.read().splitlines() == expected
+    subprocess.run(["sha256sum", "-c", str(checksums)], check=True)
+    cargo_run(["verify", "--checksums", str(checksums)])
+
+
+def test_verify_structure(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    cargo_run(["-S", "6K", str(inp), str(output)])
+    ret = cargo_run(["verify", "--structure", str(output)])
+    assert ret.stdout.decode().splitlines() == [
+        f"{output}00000: OK",
+        f"{output}00001: OK",
+        f"{output}00002: OK",
+    ]
+    with open(str(outdir.join("output.tar.00001")), "r+b") as f:
+        f.write(b"X")
+    with open(str(outdir.join("output.tar.00002")), "r+b") as f:
+        f.truncate(512 + 100)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["verify", "--structure", str(output)])
+    assert exc.value.stdout.decode().splitlines() == [
+        f"{output}00000: OK",
+        f"{output}00001: FAILED at offset 0: invalid header checksum",
+        f'{output}00002: FAILED at offset 0: the data of "theobject2" is truncated',
+    ]
+    assert b"2 of 3 volumes failed the check" in exc.value.stderr
+
+    # Compressed volumes are decompressed; the end-of-archive blocks are required.
+    volume = outdir.join("output.tar.00000")
+    data = volume.read_binary()
+    volume.write_binary(gzip.compress(data[:-1024]))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["verify", "--structure", str(volume)])
+    assert exc.value.stdout.decode().splitlines() == [
+        f"{volume}: FAILED at offset 4608: "
+        "the archive ends without the end-of-archive blocks",
+    ]
+    volume.write_binary(gzip.compress(data))
+    cargo_run(["verify", "--structure", str(volume)])
+
+
+@pytest.mark.parametrize("sign", ["false", "true"])
+def test_sign_failure(cargo_run, tmpdir, sign):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarinfo("theobject", tarfile.REGTYPE))
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--sign", sign, str(inp), str(output)])
+    assert b"sign command" in exc.value.stderr
+
+    ret = cargo_run(
+        ["-S", "10K", "--sign", sign, "--allow-sign-failure", str(inp), str(output)]
+    )
+    assert b"sign command" in ret.stderr
+    assert os.listdir(str(outdir)) == ["output.tar.00000"]
+
+
+@pytest.mark.parametrize("options", [[], ["--keep-partial"]])
+def test_keep_partial(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # Interrupt splitar after the first volume; the sleep gives the signal
+    # time to be noticed.
+    with pytest.raises(subprocess.CalledProcessError):
+        cargo_run(
+            [
+                "-S",
+                "10K",
+                "--tape-command",
+                "kill -INT $PPID; sleep 0.5",
+                str(inp),
+                str(output),
+            ]
+            + options
+        )
+    expected = ["output.tar.00000"]
+    if options:
+        expected.append("output.tar.00001.partial")
+    assert sorted(os.listdir(str(outdir))) == expected
+
+
+@pytest.mark.parametrize("signal", ["INT", "TERM"])
+def test_graceful_interrupt(cargo_run, tmpdir, signal):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    signaled = tmpdir.join("signaled")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(5):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # Signal splitar once, after the first volume; the entry that starts the
+    # second one is still written.
+    command = (
+        f"[ -e {signaled} ] || {{ touch {signaled}; kill -{signal} $PPID; sleep 0.5; }}"
+    )
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            [
+                "-S",
+                "10K",
+                "--graceful-interrupt",
+                "--tape-command",
+                command,
+                str(inp),
+                str(output),
+            ]
+        )
+    assert exc.value.returncode == 6
+    assert (
+        b"Stopped after 3 entries; the input from offset 13824 (in the tar stream) "
+        b"is not split" in exc.value.stderr
+    )
+    assert split_names(outdir) == [["0", "1"], ["2"]]
+
+
+@pytest.mark.parametrize(
+    "case, code",
+    [
+        ("io", 1),
+        ("usage", 2),
+        ("validate", 2),
+        ("env", 2),
+        ("compress", 8),
+        ("decompress", 8),
+        ("sign", 8),
+        ("checksum", 9),
+        ("truncated", 9),
+        ("interrupt", 10),
+    ],
+)
+def test_exit_codes(cargo_run, tmpdir, monkeypatch, case, code):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(5):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    data = inp.read_binary()
+    options = {
+        "io": [],
+        "usage": ["--no-such-option"],
+        # Checked after clap.
+        "validate": ["--total-size", "1K"],
+        "compress": ["--compress", "false"],
+        "decompress": ["--decompress-input", "false"],
+        "sign": ["--sign", "false"],
+        # The abort, without --graceful-interrupt.
+        "interrupt": ["--tape-command", "kill -INT $PPID; sleep 0.5"],
+    }.get(case, [])
+    if case == "io":
+        output = tmpdir.join("missing", "output.tar.")
+    elif case == "env":
+        monkeypatch.setenv("SPLITAR_MAX_SIZE", "20K")
+    elif case == "checksum":
+        inp.write_binary(data[:148] + b"XXXXXXXX" + data[156:])
+    elif case == "truncated":
+        inp.write_binary(data[:10000])
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K"] + options + [str(inp), str(output)])
+    assert exc.value.returncode == code
+
+
+def test_status_socket(cargo_run, tmpdir):
+    import sys
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    log = tmpdir.join("status.log")
+    sock = tmpdir.join("status.sock")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # The tape command is run while splitting, so it can query the status.
+    query = (
+        "import socket, sys; s = socket.socket(socket.AF_UNIX); "
+        "s.connect(sys.argv[1]); sys.stdout.write(s.recv(100).decode())"
+    )
+    cargo_run(
+        [
+            "-S",
+            "10K",
+            "--status-socket",
+            str(sock),
+            "--tape-command",
+            "'{}' -c '{}' '{}' >> '{}'".format(sys.executable, query, sock, log),
+            str(inp),
+            str(output),
+        ]
+    )
+    assert log.read() == (
+        "volume=0 bytes=9216 entries=2\nvolume=1 bytes=13824 entries=3\n"
+    )
+    assert not sock.exists()
+
+
+def zero_size(archive, offset):
+    """Set the size of the member at the offset to zero, leaving its data."""
+    header = bytearray(archive[offset : offset + 512])
+    header[124:136] = b"00000000000\x00"
+    header[148:156] = b" " * 8
+    header[148:156] = b"%06o\x00 " % sum(header)
+    return archive[:offset] + bytes(header) + archive[offset + 512 :]
+
+
+def pax_size_archive(files):
+    """PAX archive of the files with their sizes only in PAX records, and
+    zero in their headers, the way large files are written."""
+    buf = io.BytesIO()
+    with tarfile.open(fileobj=buf, mode="w", format=tarfile.PAX_FORMAT) as tar:
+        for name, data in files.items():
+            ti = tarinfo(name, tarfile.REGTYPE, data=data)
+            ti.pax_headers = {
+                "size": str(len(data)),
+                "uid": "1234",
+                "SCHILY.xattr.user.k": name,
+            }
+            tar.addfile(ti, io.BytesIO(data))
+    archive = buf.getvalue()
+    with tarfile.open(fileobj=io.BytesIO(archive), mode="r") as tar:
+        for member in tar.getmembers():
+            archive = zero_size(archive, member.offset_data - 512)
+    return archive
+
+
+@pytest.mark.parametrize(
+    "options",
+    [
+        [],
+        ["--scrub", "uid"],
+        ["--format", "pax"],
+        ["--format", "gnu"],
+        ["--self-check"],
+    ],
+)
+def test_pax_size(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # Zero blocks and headers in the data are not taken for the end of the
+    # archive and for the next entries.
+    with tarfile.open(fileobj=io.BytesIO(), mode="w") as tar:
+        tar.addfile(tarinfo("inner", tarfile.REGTYPE))
+        inner = tar.fileobj.getvalue()[:512]
+    files = {
+        "theobject0": inner + b"1234" * 128,
+        "theobject1": b"\x00" * 1024 + b"1234",
+    }
+    inp.write_binary(pax_size_archive(files))
+
+    cargo_run(["-S", "100K"] + options + [str(inp), str(output)])
+    assert os.listdir(str(outdir)) == ["output.tar.00000"]
+    # Rewritten entries keep all their PAX records.
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        for member in tar.getmembers():
+            assert tar.extractfile(member).read() == files[member.name]
+            assert member.pax_headers["SCHILY.xattr.user.k"] == member.name
+            # Unless they are of the fields that are rewritten.
+            assert member.uid == (0 if "--scrub" in options else 1234)
+
+
+@pytest.mark.parametrize(
+    "policy,expected",
+    [
+        ("skip", {"theobject0": b"1234", "theobject2": b"1234"}),
+        (
+            "trust-stream",
+            {
+                "theobject0": b"1234",
+                "theobject1": b"1234" * 1024,
+                "theobject2": b"1234",
+            },
+        ),
+    ],
+)
+def test_on_size_zero(cargo_run, tmpdir, policy, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    buf = io.BytesIO()
+    with tarfile.open(fileobj=buf, mode="w") as tar:
+        for i, data in enumerate([b"1234", b"1234" * 1024, b"1234"]):
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    inp.write(zero_size(buf.getvalue(), 1024))
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "100K", str(inp), str(output)])
+    assert b"theobject1" in exc.value.stderr
+
+    cargo_run(["-S", "100K", "--on-size-zero", policy, str(inp), str(output)])
+    assert os.listdir(str(outdir)) == ["output.tar.00000"]
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert {
+            name: tar.extractfile(name).read() for name in tar.getnames()
+        } == expected
+
+
+def test_trailing_garbage(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    buf = io.BytesIO()
+    with tarfile.open(fileobj=buf, mode="w") as tar:
+        for i in range(2):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    inp.write(buf.getvalue() + b"garbage" * 100)
+
+    res = cargo_run(["-S", "6K", str(inp), str(output)])
+    assert b"after the end-of-archive marker" in res.stderr
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == ["theobject1"]
+
+
+@pytest.mark.parametrize("garbage", [b"", b"garbage" * 100])
+def test_trailing_garbage_pax_size(cargo_run, tmpdir, garbage):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # The data after the zero blocks is not taken for trailing garbage.
+    files = {"theobject0": b"\x00" * 1024 + b"garbage" * 100}
+    inp.write_binary(pax_size_archive(files) + garbage)
+
+    res = cargo_run(["-S", "100K", str(inp), str(output)])
+    assert (b"after the end-of-archive marker" in res.stderr) == bool(garbage)
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.extractfile("theobject0").read() == files["theobject0"]
+
+
+def test_zip(cargo_run, tmpdir):
+    import zipfile
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.zip.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarinfo("thedir", tarfile.DIRTYPE))
+        for i in range(3):
+            data = b"1234" * 1024
+            info = tarinfo("thedir/theobject" + str(i), tarfile.REGTYPE, data=data)
+            info.mode = 0o750
+            info.mtime = 1234567890
+            tar.addfile(info, io.BytesIO(data))
+        tar.addfile(tarinfo("thedir/symlink", tarfile.SYMTYPE, linkname="theobject2"))
+        # Zip has no hardlinks.
+        tar.addfile(tarinfo("thedir/hardlink", tarfile.LNKTYPE, linkname="theobject2"))
+
+    cargo_run(
+        ["-S", "11K", "--recreate-dirs", "--output-format", "zip", str(inp), str(output)]
+    )
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.zip.00000", "output.zip.00001"]
+    with zipfile.ZipFile(str(outdir.join("output.zip.00000"))) as z:
+        assert z.namelist() == ["thedir/", "thedir/theobject0", "thedir/theobject1"]
+        assert z.read("thedir/theobject0") == b"1234" * 1024
+        info = z.getinfo("thedir/theobject0")
+        assert info.external_attr >> 16 == 0o100750
+        assert info.compress_type == zipfile.ZIP_DEFLATED
+    with zipfile.ZipFile(str(outdir.join("output.zip.00001"))) as z:
+        assert z.namelist() == ["thedir/", "thedir/theobject2", "thedir/symlink"]
+        assert z.read("thedir/symlink") == b"theobject2"
+
+
+def test_zip_skipped(cargo_run, tmpdir):
+    import zipfile
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.zip.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(
+        str(inp), mode="w", format=tarfile.PAX_FORMAT, pax_headers={"comment": "x"}
+    ) as tar:
+        for i in range(8):
+            tar.addfile(tarinfo("fifo" + str(i), tarfile.FIFOTYPE))
+        for i in range(2):
+            data = b"1" * 3584
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # The entries zip cannot represent don't take room in the volumes.
+    cargo_run(["-S", "8K", "--output-format", "zip", str(inp), str(output)])
+    names = []
+    for file in sorted(os.listdir(str(outdir))):
+        with zipfile.ZipFile(str(outdir.join(file))) as z:
+            names.append(z.namelist())
+    assert names == [["theobject0"], ["theobject1"]]
+
+
+@pytest.mark.parametrize(
+    "option", [["--compress", "gzip"], ["--verbatim"], ["--record-size", "10K"]]
+)
+def test_zip_invalid(cargo_run, tmpdir, option):
+    output = tmpdir.join("output.zip.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarinfo("theobject", tarfile.REGTYPE))
+
+    with pytest.raises(subprocess.CalledProcessError):
+        cargo_run(["-S", "10K", "--output-format", "zip"] + option + [str(inp), str(output)])
+
+
+def test_threads(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    outputs = []
+    for threads in ["1", "4"]:
+        outdir = tmpdir.mkdir("out" + threads)
+        output = outdir.join("output.tar.")
+        cargo_run(["-S", "40K", "--threads", threads, str(inp), str(output)])
+        files = sorted(os.listdir(str(outdir)))
+        outputs.append([(file, outdir.join(file).read_binary()) for file in files])
+    assert len(outputs[0]) > 1
+    assert outputs[0] == outputs[1]
+
+
+def test_list_volumes(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    ret = cargo_run(["-S", "80K", "--list-volumes", str(inp), str(output)])
+    assert ret.stdout.decode().splitlines() == [
+        "creating file '{}{}'".format(output, file)
+        for file in ["00000", "00001", "00002"]
+    ]
+
+
+@pytest.mark.parametrize(
+    "routes", [["a=jpg", "b=jpg"], ["a=jpg", "a=png"], ["a="], ["=jpg"], ["jpg"]]
+)
+def test_route_by_extension_invalid(cargo_run, tmpdir, routes):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    inp.write(b"\x00" * 1024)
+
+    options = []
+    for route in routes:
+        options += ["--route-by-extension", route]
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K"] + options + [str(inp), str(output)])
+    assert b"--route-by-extension" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_files_from(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    files = tmpdir.join("files.txt")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # Like `find .` output; the list comes from stdin, the archive from a file.
+    cargo_run(
+        ["-S", "10K", "--files-from", "-", str(inp), str(output)],
+        input=b"./theobject1\ntheobject3\n./theobject8\n",
+    )
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append(tar.getnames())
+    assert volumes == [["theobject1", "theobject3"], ["theobject8"]]
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    files.write("theobject1\nmissing\n")
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--files-from", str(files), str(inp), str(output)])
+    assert b'"missing" from --files-from is not found' in exc.value.stderr
+    # The volumes are written anyway.
+    assert os.listdir(str(outdir)) == ["output.tar.00000"]
+
+
+def test_files_from_crlf(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    files = tmpdir.join("files.txt")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for name in names(0, 1, 2, 3) + [" spaced name "]:
+            data = b"1234"
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    # As saved by a Windows editor, with blank and indented lines.
+    files.write_binary(
+        b'theobject1\r\n\r\n   \r\n  theobject3 \r\n" spaced name "\r\n'
+    )
+    cargo_run(["-S", "10K", "--files-from", str(files), str(inp), str(output)])
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.getnames() == ["theobject1", "theobject3", " spaced name "]
+
+    files.write_binary(b'theobject1\r\n" spaced name\r\n')
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--files-from", str(files), str(inp), str(output)])
+    assert b"unterminated quote at line 2" in exc.value.stderr
+
+
+def test_file_too_large(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    with pytest.raises(Exception):
+        cargo_run(["-S", "20K", "--fail-on-large-file", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00001",
+    ]
+
+
+def test_max_entries_total(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(["-S", "80K", "--max-entries-total", "10", str(inp), str(output)])
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "80K", "--max-entries-total", "9", str(inp), str(output)])
+    assert exc.value.returncode == 4
+    assert b"more than --max-entries-total 9 entries" in exc.value.stderr
+    # Finished volumes are kept, like with --fail-on-large-file.
+    assert sorted(os.listdir(str(outdir))) == ["output.tar.00000", "output.tar.00001"]
+
+
+def test_max_volumes(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    common = ["-S", "80K", "--summary", str(inp), str(output)]
+    cargo_run(["--max-volumes", "3"] + common)
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--max-volumes", "2"] + common)
+    assert exc.value.returncode == 5
+    assert b"2 volumes, 8 entries" in exc.value.stderr
+    assert b"the input from offset 118784 (in the tar stream) is not split" in (
+        exc.value.stderr
+    )
+    assert b"more than --max-volumes 2 volumes" in exc.value.stderr
+    names = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            names.extend(tar.getnames())
+    assert names == ["theobject" + str(i) for i in range(8)]
+
+
+@pytest.mark.parametrize("options", [[], ["--gzip"]])
+def test_total_size(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # 4K each with the header, two of them for a volume.
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1" * 3584
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    common = ["-S", "10K", "--total-size", "30K", str(inp), str(output)]
+    if options:
+        # Compressed volumes are measured, and all of them fit.
+        cargo_run(options + common)
+        assert len(os.listdir(str(outdir))) == 5
+        return
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(common)
+    assert exc.value.returncode == 7
+    assert b"4 entries, 16384 bytes of the input from offset 24576" in (
+        exc.value.stderr
+    )
+    assert b"the next volume may exceed --total-size 30720" in exc.value.stderr
+    assert sum(split_names(outdir), []) == [str(i) for i in range(6)]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--total-size", "8K", str(inp), str(output)])
+    assert b"--total-size 8192 is smaller than the first volume's" in (
+        exc.value.stderr
+    )
+
+    # It would stop in the middle of a --multi-volume file.
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["-S", "10K", "--total-size", "30K", "--multi-volume"]
+            + [str(inp), str(output)]
+        )
+    assert exc.value.returncode == 2
+    assert b"cannot be used with" in exc.value.stderr
+
+
+@pytest.mark.parametrize(
+    "options",
+    [
+        [],
+        ["--verbatim"],
+        ["--recreate-dirs"],
+        ["--self-check"],
+        ["--self-check", "--verbatim"],
+        ["--self-check", "--multi-volume", "--recreate-dirs"],
+    ],
+)
+def test_global_pax_headers(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # The second global header comes in the middle of the input.
+    parts = []
+    for comment, start in [("one", 0), ("two", 3)]:
+        buf = io.BytesIO()
+        pax_headers = {"comment": comment}
+        with tarfile.open(
+            fileobj=buf, mode="w", format=tarfile.PAX_FORMAT, pax_headers=pax_headers
+        ) as tar:
+            for i in range(start, start + 3):
+                data = b"1" * 3584
+                tar.addfile(
+                    tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                    io.BytesIO(data),
+                )
+            end = tar.offset
+        parts.append(buf.getvalue()[:end])
+    inp.write_binary(b"".join(parts) + bytes(1024))
+
+    cargo_run(options + ["-S", "10K", str(inp), str(output)])
+    comments = []
+    for file in sorted(os.listdir(str(outdir))):
+        with open(str(outdir.join(file)), "rb") as f:
+            # The type flag of the first header.
+            assert f.read(512)[156:157] == b"g"
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            tar.getmembers()
+            comments.append(tar.pax_headers["comment"])
+    assert len(comments) > 2
+    assert comments[0] == "one" and comments[-1] == "two"
+
+    joined = tmpdir.join("joined.tar")
+    cargo_run(["join", str(output), "-o", str(joined)])
+    assert joined.read_binary().count(b"comment=") == 2
+    with tarfile.open(str(joined), "r") as tar:
+        assert [m.name for m in tar.getmembers() if not m.isdir()] == [
+            "theobject" + str(i) for i in range(6)
+        ]
+
+
+def test_atomic(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    common = ["-S", "80K", "--atomic", "--checksum", "sha256", str(inp), str(output)]
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--max-entries-total", "9", "--emit-extract-script"] + common)
+    assert exc.value.returncode == 4
+    assert os.listdir(str(outdir)) == []
+
+    # The volumes kept by --resume are not written by the run.
+    cargo_run(common)
+    for file in os.listdir(str(outdir)):
+        if not file.startswith("output.tar.00000"):
+            outdir.join(file).remove()
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--resume", "--max-entries-total", "9"] + common)
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00000.sha256",
+    ]
+
+
+@pytest.mark.parametrize("options", [[], ["-S", "1M"]])
+def test_max_files(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        tar.addfile(tarinfo("thedir", tarfile.DIRTYPE))
+        for i in range(7):
+            data = b"1234"
+            tar.addfile(
+                tarinfo("thedir/theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # The size limit is far away; recreated dirs are not counted.
+    cargo_run(
+        options + ["--max-files", "3", "--recreate-dirs", str(inp), str(output)]
+    )
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append(tar.getnames())
+    assert volumes == [
+        ["thedir", "thedir/theobject0", "thedir/theobject1"],
+        ["thedir"] + ["thedir/theobject" + str(i) for i in range(2, 5)],
+        ["thedir"] + ["thedir/theobject" + str(i) for i in range(5, 7)],
+    ]
+
+
+def test_max_files_and_size(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i, size in enumerate([1, 1, 1, 8192, 8192, 1, 1]):
+            data = b"1" * size
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # Whichever limit comes first starts a new volume, as -v tells.
+    res = cargo_run(["-S", "12K", "--max-files", "3", "-v", str(inp), str(output)])
+    assert [line for line in res.stderr.splitlines() if b" ends at " in line] == [
+        b'00000 ends at 4096 bytes: "theobject3" (8704 bytes)'
+        b" would be over --max-files 3",
+        b'00001 ends at 9728 bytes: "theobject4" (8704 bytes)'
+        b" doesn't fit into --max-size 12288",
+    ]
+    volumes = []
+    for file in sorted(os.listdir(str(outdir))):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            volumes.append(tar.getnames())
+    assert volumes == [
+        ["theobject0", "theobject1", "theobject2"],
+        ["theobject3"],
+        ["theobject4", "theobject5", "theobject6"],
+    ]
+
+def test_verbose(cargo_run, tmpdir, monkeypatch):
+    monkeypatch.setenv("TZ", "GMT-1")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i, (tartype, linkname, contents) in enumerate(
+            [
+                (tarfile.DIRTYPE, None, None),
+                (tarfile.REGTYPE, None, 1),
+                (tarfile.REGTYPE, None, 1024),
+                (tarfile.AREGTYPE, None, 1),
+                (tarfile.AREGTYPE, None, 1024),
+                (tarfile.LNKTYPE, "otherhard", None),
+                (tarfile.SYMTYPE, "othersym", None),
+                (tarfile.FIFOTYPE, None, None),
+            ]
+        ):
+            if contents is None:
+                tar.addfile(tarinfo("theobject" + str(i), tartype, linkname=linkname))
+            else:
+                data = b"1234" * contents
+                tar.addfile(
+                    tarinfo("theobject" + str(i), tartype, data=data),
+                    io.BytesIO(data),
+                )
+
+    res = cargo_run(["-S", "10K", "-v", str(inp), str(output)])
+    assert not res.stdout, repr(res.stdout)
+    assert res.stderr == (
+        b"""00000 drw-r--r--              0 1970-01-01 01:00:00 theobject0/
+00000 -rw-r--r--              4 1970-01-01 01:00:00 theobject1
+00000 -rw-r--r--           4096 1970-01-01 01:00:00 theobject2
+00000 -rw-r--r--              4 1970-01-01 01:00:00 theobject3
+00000 ends at 8192 bytes: "theobject4" (4608 bytes) doesn't fit into --max-size 10240
+00001 -rw-r--r--           4096 1970-01-01 01:00:00 theobject4
+00001 hrw-r--r--              0 1970-01-01 01:00:00 theobject5 link to otherhard
+00001 lrw-r--r--              0 1970-01-01 01:00:00 theobject6 -> othersym
+00001 prw-r--r--              0 1970-01-01 01:00:00 theobject7
+"""
+    ), repr(res.stderr)
+
+
+def test_verbose_special_bits(cargo_run, tmpdir, monkeypatch):
+    monkeypatch.setenv("TZ", "GMT-1")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i, mode in enumerate([0o4755, 0o1777, 0o2644, 0o7000]):
+            ti = tarinfo("theobject" + str(i), tarfile.REGTYPE)
+            ti.mode = mode
+            tar.addfile(ti)
+
+    res = cargo_run(["-S", "10K", "-v", str(inp), str(output)])
+    assert res.stderr == (
+        b"""00000 -rwsr-xr-x              0 1970-01-01 01:00:00 theobject0
+00000 -rwxrwxrwt              0 1970-01-01 01:00:00 theobject1
+00000 -rw-r-Sr--              0 1970-01-01 01:00:00 theobject2
+00000 ---S--S--T              0 1970-01-01 01:00:00 theobject3
+"""
+    ), repr(res.stderr)
+
+
+def test_verbose_utc(cargo_run, tmpdir, monkeypatch):
+    monkeypatch.setenv("TZ", "GMT-1")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # GNU base-256 fields allow mtimes far beyond the chrono range.
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for i, mtime in enumerate([1000000000, 2**62]):
+            ti = tarinfo("theobject" + str(i), tarfile.REGTYPE)
+            ti.mtime = mtime
+            tar.addfile(ti)
+
+    res = cargo_run(["-S", "10K", "-v", "--utc", str(inp), str(output)])
+    assert res.stderr == (
+        b"""00000 -rw-r--r--              0 2001-09-09T01:46:40Z theobject0
+00000 -rw-r--r--              0                    ? theobject1
+"""
+    ), repr(res.stderr)
+
+    res = cargo_run(["-S", "10K", "-v", str(inp), str(output)])
+    assert res.stderr == (
+        b"""00000 -rw-r--r--              0 2001-09-09 02:46:40 theobject0
+00000 -rw-r--r--              0                   ? theobject1
+"""
+    ), repr(res.stderr)
+
+
+def test_verbose_json(cargo_run, tmpdir):
+    import json
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(
+        str(inp),
+        mode="w",
+        format=tarfile.GNU_FORMAT,
+        encoding="utf-8",
+        errors="surrogateescape",
+    ) as tar:
+        ti = tarinfo("theobject0", tarfile.REGTYPE, data=b"1234")
+        ti.mode, ti.uid, ti.gid, ti.uname, ti.mtime = 0o4755, 10, 20, "joe", 1000
+        tar.addfile(ti, io.BytesIO(b"1234"))
+        tar.addfile(tarinfo("theobject1", tarfile.SYMTYPE, linkname="theobject0"))
+        bad_name = b"bad\xff".decode(errors="surrogateescape")
+        tar.addfile(tarinfo(bad_name, tarfile.REGTYPE))
+
+    res = cargo_run(
+        ["-S", "10K", "-v", "--verbose-format", "json", str(inp), str(output)]
+    )
+    assert not res.stderr, repr(res.stderr)
+    records = [json.loads(line) for line in res.stdout.splitlines()]
+    common = {"volume": "00000", "uid": 0, "gid": 0, "user": "", "group": ""}
+    assert records == [
+        dict(
+            common,
+            path="theobject0",
+            size=4,
+            mode="4755",
+            uid=10,
+            gid=20,
+            user="joe",
+            mtime=1000,
+            type="file",
+            link=None,
+            lossy=False,
+        ),
+        dict(
+            common,
+            path="theobject1",
+            size=0,
+            mode="0644",
+            mtime=0,
+            type="symlink",
+            link="theobject0",
+            lossy=False,
+        ),
+        dict(
+            common,
+            path="bad\ufffd",
+            size=0,
+            mode="0644",
+            mtime=0,
+            type="file",
+            link=None,
+            lossy=True,
+        ),
+    ]
+
+
+def strip_progress_bar(stderr):
+    """The output besides the progress bar, which is drawn and erased with
+    terminal escapes."""
+    import re
+
+    stderr = re.sub(rb"volume \d[^\r\n\x1b]*", b"", stderr)
+    return re.sub(rb"\r|\x1b\[\d*[A-Z]", b"", stderr)
+
+
+def test_progress(cargo_run, tmpdir, monkeypatch):
+    import re
+
+    monkeypatch.setenv("TZ", "GMT-1")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # stderr is not a terminal, so the bar is off by default.
+    res = cargo_run(["-S", "10K", str(inp), str(output)])
+    assert res.stderr == b""
+
+    res = cargo_run(["-S", "10K", "--progress", "-v", str(inp), str(output)])
+    # It may be drawn only once, at any volume.
+    assert re.search(rb"volume \d \[", res.stderr)
+    assert b"/20.0 KiB " in res.stderr
+    # The bar is erased at exit.
+    assert re.search(rb"\r\x1b\[2K(\x1b\[\d+A)?$", res.stderr), repr(res.stderr)
+    # The bar is erased before the verbose lines, which stay intact.
+    assert strip_progress_bar(res.stderr) == (
+        b"""00000 -rw-r--r--           4096 1970-01-01 01:00:00 theobject0
+00000 -rw-r--r--           4096 1970-01-01 01:00:00 theobject1
+00000 ends at 10240 bytes: "theobject2" (4608 bytes) doesn't fit into --max-size 10240
+00001 -rw-r--r--           4096 1970-01-01 01:00:00 theobject2
+"""
+    ), repr(res.stderr)
+
+    # The last one wins.
+    res = cargo_run(
+        ["-S", "10K", "--progress", "--no-progress", str(inp), str(output)]
+    )
+    assert res.stderr == b""
+
+
+def test_progress_log(cargo_run, tmpdir):
+    import re
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+        tar.addfile(tarinfo("thelink", tarfile.LNKTYPE, linkname="theobject0"))
+
+    args = ["-S", "10K", "--progress", "--warn-broken-links", str(inp), str(output)]
+    res = cargo_run(args)
+    # The bar is erased before the log line, which starts a line of its own.
+    log = strip_progress_bar(res.stderr)
+    pattern = rb"\[\S+ WARN  splitar\S*\] Hard link .*\n"
+    assert re.fullmatch(pattern, log), repr(res.stderr)
+
+
+def names(*indices):
+    return ["theobject" + str(i) for i in indices]
+
+
+@pytest.mark.parametrize(
+    "balance,expected",
+    [
+        ([], [names(0, 1, 2, 3, 4, 5), names(6, 7), names(8, 9)]),
+        (
+            ["--balance", "entries"],
+            [names(0, 1, 2), names(3, 4, 5, 6), names(7, 8, 9)],
+        ),
+    ],
+)
+def test_num_volumes(cargo_run, tmpdir, balance, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(["--num-volumes", "3"] + balance + [str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001", "output.tar.00002"]
+    for file, volume_names in zip(files, expected):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == volume_names
+
+
+def test_entries_manifest(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    manifest = tmpdir.join("manifest.txt")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(
+        ["-n", "3", "--entries-manifest-out", str(manifest), str(inp), str(output)]
+    )
+    assert len(manifest.read().splitlines()) == 11
+    for file in os.listdir(str(outdir)):
+        outdir.join(file).remove()
+
+    # The manifest is trusted, so another split is made without a scan.
+    cargo_run(["-n", "2", "--entries-manifest-in", str(manifest), str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001"]
+    for file, volume_names in zip(files, [names(0, 1, 2, 3, 4, 5, 6), names(7, 8, 9)]):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == volume_names
+
+    os.utime(str(inp), (0, 0))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["-n", "2", "--entries-manifest-in", str(manifest), str(inp), str(output)]
+        )
+    assert b"doesn't match the input" in exc.value.stderr
+
+
+@pytest.mark.parametrize("memory_limit", [[], ["--memory-limit", "32"]])
+def test_spill_dir(cargo_run, tmpdir, memory_limit):
+    inp = tmpdir.join("input.tar")
+    spill = tmpdir.mkdir("spill")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    outputs = []
+    for options in [[], ["--spill-dir", str(spill)] + memory_limit]:
+        outdir = tmpdir.mkdir("out" + str(len(outputs)))
+        output = outdir.join("output.tar.")
+        cargo_run(["-n", "3"] + options + [str(inp), str(output)])
+        files = sorted(os.listdir(str(outdir)))
+        outputs.append([(file, outdir.join(file).read_binary()) for file in files])
+    assert len(outputs[0]) == 3
+    assert outputs[0] == outputs[1]
+    assert os.listdir(str(spill)) == []
+
+
+@pytest.mark.parametrize(
+    "num_volumes,expected",
+    [
+        # The small file doesn't get a volume of its own.
+        ("3", [names(0), names(1), names(2, 3)]),
+        # Entries are not split, so there are less volumes than requested.
+        ("5", [names(0), names(1), names(2, 3)]),
+    ],
+)
+def test_volumes(cargo_run, tmpdir, num_volumes, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i, data in enumerate([b"1234" * 2048] * 3 + [b"1"]):
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(["--volumes", num_volumes, str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == len(expected)
+    for file, volume_names in zip(files, expected):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == volume_names
+
+
+def test_num_volumes_stdin(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--num-volumes", "3", "-", str(output)])
+    assert b"cannot be used with stdin" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_fifo_input(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    fifo = tmpdir.join("input.fifo")
+    os.mkfifo(str(fifo))
+
+    buf = io.BytesIO()
+    with tarfile.open(fileobj=buf, mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    # A pipe is read as a stream, like stdin.
+    writer = threading.Thread(target=fifo.write_binary, args=(buf.getvalue(),))
+    writer.start()
+    cargo_run(["-S", "10K", str(fifo), str(output)])
+    writer.join()
+    assert split_names(outdir) == [["0", "1"], ["2"]]
+
+    # The options that read the input twice or seek in it are rejected
+    # before it is opened, as nothing writes into the pipe now.
+    for options in [["--num-volumes", "2"], ["-S", "10K", "--dereference-split-links"]]:
+        with pytest.raises(subprocess.CalledProcessError) as exc:
+            cargo_run(options + [str(fifo), str(tmpdir.join("other.tar."))])
+        assert b"cannot be used with stdin or a pipe" in exc.value.stderr
+
+
+@pytest.mark.parametrize(
+    "options,expected",
+    [
+        ([], [names(0, 1, 2, 3), names(4, 5, 6, 7), names(8, 9)]),
+        (["--pack"], [names(0, 1, 2), names(3, 4, 5, 6), names(7, 8, 9)]),
+    ],
+)
+def test_pack(cargo_run, tmpdir, options, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+
+    cargo_run(["-S", "20K"] + options + [str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == len(expected)
+    for file, volume_names in zip(files, expected):
+        assert outdir.join(file).size() <= 20480
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == volume_names
+
+
+def test_pack_errors(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        data = b"1234" * 4096
+        tar.addfile(tarinfo("theobject", tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "20K", "--pack", "-", str(output)], input=inp.read_binary())
+    assert b"--pack cannot be used with stdin" in exc.value.stderr
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--pack", str(inp), str(output)])
+    assert b"entry 0 of 16896 bytes does not fit into --max-size" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_v7(cargo_run, tmpdir, monkeypatch):
+    monkeypatch.setenv("TZ", "GMT-1")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    inp.write(
+        v7_archive(
+            [
+                ("thedir/", b""),
+                ("thedir/file1", b"1234" * 1024),
+                ("thedir/file2", b"1234" * 1024),
+            ]
+        )
+    )
+
+    res = cargo_run(["-S", "6K", "-v", "--recreate-dirs", str(inp), str(output)])
+    assert res.stderr == (
+        b"""00000 drw-r--r-- 1000 100            0 1970-01-01 01:00:00 thedir/
+00000 -rw-r--r-- 1000 100         4096 1970-01-01 01:00:00 thedir/file1
+00000 ends at 6144 bytes: "thedir/file2" (4608 bytes) doesn't fit into --max-size 6144
+00001 drw-r--r-- 1000 100            0 1970-01-01 01:00:00 thedir/
+00001 -rw-r--r-- 1000 100         4096 1970-01-01 01:00:00 thedir/file2
+"""
+    ), repr(res.stderr)
+    with tarfile.open(str(outdir.join("output.tar.00001")), "r") as tar:
+        assert tar.getnames() == ["thedir/", "thedir/file2"]
+
+
+def sparse_member(name, chunks, real_size):
+    """GNU sparse file of (offset, data) chunks, with holes in between; an
+    empty chunk ends the map at the real size, as GNU tar does."""
+    data = b"".join(chunk for _, chunk in chunks)
+    ti = tarinfo(name, tarfile.REGTYPE, data=data)
+    header = bytearray(ti.tobuf(format=tarfile.GNU_FORMAT))
+    header[156:157] = tarfile.GNUTYPE_SPARSE
+    for i, (offset, chunk) in enumerate(chunks + [(real_size, b"")]):
+        pos = 386 + 24 * i
+        header[pos : pos + 24] = b"%011o\x00%011o\x00" % (offset, len(chunk))
+    header[483:495] = b"%011o\x00" % real_size
+    header[148:156] = b" " * 8
+    header[148:156] = b"%06o\x00 " % sum(header[:512])
+    return bytes(header) + data + b"\x00" * (-len(data) % 512)
+
+
+def test_sparse(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    chunks = [(0, b"1" * 512), (20000, b"2" * 1024)]
+    data = b"3" * 4096
+    file = tarinfo("file", tarfile.REGTYPE, data=data).tobuf(format=tarfile.GNU_FORMAT)
+    sparse = sparse_member("sparse", chunks, 30000)
+    inp.write_binary(sparse + file + data + b"\x00" * 1024)
+
+    # The sparse file is written with its holes, as a regular file, which
+    # the volume sizes account for.
+    args = ["-S", "40K", str(inp), str(output)]
+    res = cargo_run(["--dry-run"] + args)
+    cargo_run(args)
+    files = sorted(os.listdir(str(outdir)))
+    sizes = [os.path.getsize(str(outdir.join(file))) for file in files]
+    assert [int(line.split(b"\t")[2]) for line in res.stdout.splitlines()] == sizes
+    assert sizes == [512 + 30208 + 512 + 4096 + 1024]
+    with tarfile.open(str(outdir.join(files[0])), "r") as tar:
+        sparse = tar.getmember("sparse")
+        assert sparse.isreg() and sparse.size == 30000
+        expected = bytearray(30000)
+        for offset, chunk in chunks:
+            expected[offset : offset + len(chunk)] = chunk
+        assert tar.extractfile(sparse).read() == expected
+
+    # It doesn't fit into a volume with the file, so they are split.
+    cargo_run(["-S", "32K", str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    sizes = [os.path.getsize(str(outdir.join(file))) for file in files]
+    assert sizes == [512 + 30208 + 1024, 512 + 4096 + 1024]
+
+
+def bad_mtime_archive(names):
+    """The "bad" entry has a non-UTF-8 mtime field."""
+    res = b""
+    for name in names:
+        data = name.encode() * 100
+        ti = tarinfo(name, tarfile.REGTYPE, data=data)
+        header = bytearray(ti.tobuf(format=tarfile.USTAR_FORMAT))
+        if name == "bad":
+            header[136:148] = b"1\xff\xfe" + b"0" * 8 + b"\x00"
+            header[148:156] = b" " * 8
+            header[148:156] = b"%06o\x00 " % sum(header[:512])
+        res += bytes(header) + data + b"\x00" * (-len(data) % 512)
+    return res + b"\x00" * 1024
+
+
+@pytest.mark.parametrize("option", ["--skip-bad-headers", "--copy-bad-headers"])
+def test_bad_headers(cargo_run, tmpdir, option):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    inp.write_binary(bad_mtime_archive(["a", "bad", "z"]))
+
+    res = cargo_run(["-S", "100K", "-v", option, str(inp), str(output)])
+    assert os.listdir(str(outdir)) == ["output.tar.00000"]
+    volume = outdir.join("output.tar.00000").read_binary()
+    lines = res.stderr.decode().splitlines()
+    # The bad header is reported at the default log level.
+    warning = lines.pop(1)
+    assert "WARN" in warning and "malformed mtime header field" in warning
+    if option == "--skip-bad-headers":
+        assert warning.split("] ")[1].startswith('Skipping "bad"')
+        assert [line.split()[-1] for line in lines] == ["a", "z"]
+        assert volume.count(b"bad\x00") == 0
+    else:
+        assert warning.split("] ")[1].startswith('Copying "bad"')
+        # Malformed fields are shown as "?".
+        assert lines[1].split()[-3:] == ["300", "?", "bad"]
+        # The header block is intact.
+        start = volume.index(b"bad\x00")
+        assert volume[start : start + 512] == bad_mtime_archive(["bad"])[:512]
+
+
+def test_bad_headers_verbose(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    inp.write_binary(bad_mtime_archive(["a", "bad", "z"]))
+
+    res = cargo_run(["-S", "100K", "-v", str(inp), str(output)])
+    assert [line.split()[-1] for line in res.stderr.decode().splitlines()] == [
+        "a",
+        "bad",
+        "z",
+    ]
+
+
+@pytest.mark.parametrize("tarformat", [tarfile.GNU_FORMAT, tarfile.PAX_FORMAT])
+def test_verbatim(cargo_run, tmpdir, tarformat):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w", format=tarformat) as tar:
+        for name in ["short", "long" * 50]:
+            data = b"1234" * 100
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    cargo_run(["-S", "100K", "--verbatim", str(inp), str(output)])
+    assert os.listdir(str(outdir)) == ["output.tar.00000"]
+    # Input is padded to the full record by tarfile.
+    assert outdir.join("output.tar.00000").read_binary().rstrip(
+        b"\x00"
+    ) == inp.read_binary().rstrip(b"\x00")
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.getnames() == ["short", "long" * 50]
+
+
+@pytest.mark.parametrize("tarformat", [tarfile.GNU_FORMAT, tarfile.PAX_FORMAT])
+@pytest.mark.parametrize("format", ["gnu", "pax", "ustar"])
+def test_format(cargo_run, tmpdir, tarformat, format):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    # The long path fits into the ustar prefix and name fields, and needs
+    # no PAX record.
+    names = ["short", "dir" * 40 + "/" + "long" * 20]
+    with tarfile.open(str(inp), mode="w", format=tarformat) as tar:
+        for name in names:
+            data = b"1234" * 100
+            ti = tarinfo(name, tarfile.REGTYPE, data=data)
+            ti.uname = "user"
+            tar.addfile(ti, io.BytesIO(data))
+        tar.addfile(tarinfo("link", tarfile.SYMTYPE, linkname="short"))
+
+    cargo_run(["-S", "100K", "--format", format, str(inp), str(output)])
+    volume = outdir.join("output.tar.00000").read_binary()
+    magic = {"gnu": b"ustar  \x00", "pax": b"ustar\x0000", "ustar": b"ustar\x0000"}
+    headers = [volume[pos : pos + 512] for pos in range(0, len(volume), 512)]
+    assert all(h[257:265] == magic[format] for h in headers if h[257:262] == b"ustar")
+    assert (b"././@LongLink" in volume) == (format == "gnu")
+    assert b" path=" not in volume
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        assert tar.getnames() == names + ["link"]
+        assert [m.uname for m in tar.getmembers()] == ["user", "user", ""]
+        assert tar.extractfile(names[1]).read() == b"1234" * 100
+        assert tar.getmember("link").linkname == "short"
+
+
+@pytest.mark.parametrize(
+    "name,uid,expected",
+    [
+        ("long" * 50, 0, "the name doesn't fit into the header"),
+        ("file", 1 << 22, "uid 4194304 doesn't fit into the header"),
+    ],
+)
+def test_format_ustar_loss(cargo_run, tmpdir, name, uid, expected):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        ti = tarinfo(name, tarfile.REGTYPE)
+        ti.uid = uid
+        tar.addfile(ti)
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "100K", "--format", "ustar", str(inp), str(output)])
+    assert expected.encode() in exc.value.stderr
+
+    # PAX records hold them instead.
+    cargo_run(["-S", "100K", "--format", "pax", str(inp), str(output)])
+    with tarfile.open(str(outdir.join("output.tar.00000")), "r") as tar:
+        [member] = tar.getmembers()
+        assert (member.name, member.uid) == (name, uid)
+        assert member.pax_headers
+
+
+def test_layout(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    layout = tmpdir.join("layout.txt")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(5):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    # Volume 1 is skipped, so it is empty.
+    assignment = [0, 0, 2, 2, 3]
+    layout.write(
+        "".join("{}\ttheobject{}\n".format(v, i) for i, v in enumerate(assignment))
+    )
+
+    cargo_run(["--layout", str(layout), str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == [
+        "output.tar.00000",
+        "output.tar.00001",
+        "output.tar.00002",
+        "output.tar.00003",
+    ]
+    for file, volume_names in zip(files, [names(0, 1), [], names(2, 3), names(4)]):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert tar.getnames() == volume_names
+
+
+@pytest.mark.parametrize(
+    "assignment",
+    [
+        # Decreasing.
+        [0, 1, 0],
+        # Not covered.
+        [0, 0],
+        # Extra entry.
+        [0, 0, 0, 0],
+    ],
+)
+def test_layout_invalid(cargo_run, tmpdir, assignment):
+    output = tmpdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    layout = tmpdir.join("layout.txt")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234"
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    layout.write(
+        "".join("{}\ttheobject{}\n".format(v, i) for i, v in enumerate(assignment))
+    )
+    with pytest.raises(subprocess.CalledProcessError):
+        cargo_run(["--layout", str(layout), str(inp), str(output)])
+
+
+@pytest.mark.parametrize("options", [[], ["--verbatim"]])
+def test_boundaries(cargo_run, tmpdir, options):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    boundaries = tmpdir.join("boundaries.txt")
+
+    long_name = "long" * 30
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for name in names(0, 1, 2) + [long_name]:
+            data = b"1234" * 1024
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+    # Entries take 4608 bytes each; the long name adds a 1024-byte
+    # extension member before its entry.
+    boundaries.write("0\n9216\n\n13824\n")
+
+    cargo_run(["--boundaries", str(boundaries)] + options + [str(inp), str(output)])
+    files = sorted(os.listdir(str(outdir)))
+    assert files == ["output.tar.00000", "output.tar.00001", "output.tar.00002"]
+    # Without --verbatim, the long name is truncated.
+    for file, volume_names in zip(files, [names(0, 1), names(2), [long_name]]):
+        with tarfile.open(str(outdir.join(file)), "r") as tar:
+            assert [name[:100] for name in tar.getnames()] == [
+                name[:100] for name in volume_names
+            ]
+
+
+@pytest.mark.parametrize(
+    "boundaries,message",
+    [
+        ("1000\n", b"offset 1000 is not at an entry boundary"),
+        # Between the long name member and its entry.
+        ("14848\n", b"offset 14848 is not at an entry boundary"),
+        ("9216\n4608\n", b"offsets have to be increasing"),
+        ("100000\n", b"offset 100000 is beyond the last entry"),
+        ("4K\n", b"malformed boundaries file"),
+    ],
+)
+def test_boundaries_invalid(cargo_run, tmpdir, boundaries, message):
+    output = tmpdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    boundaries_file = tmpdir.join("boundaries.txt")
+
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for name in names(0, 1, 2) + ["long" * 30, "theobject3"]:
+            data = b"1234" * 1024
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+    boundaries_file.write(boundaries)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(
+            ["--boundaries", str(boundaries_file), "--verbatim", str(inp), str(output)]
+        )
+    assert message in exc.value.stderr
+
+
+def test_layout_max_size(cargo_run, tmpdir):
+    output = tmpdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    layout = tmpdir.join("layout.txt")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"1234" * 1024
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    layout.write("0\ttheobject0\n0\ttheobject1\n1\ttheobject2\n")
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--layout", str(layout), "-S", "8K", str(inp), str(output)])
+    assert b"exceeds --max-size" in exc.value.stderr
+
+
+@pytest.mark.parametrize("compression", ["gzip", "gzip2", "xz", "bz2"])
+def test_parallel_decompress(cargo_run, tmpdir, compression):
+    import bz2
+    import lzma
+
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    plain = tmpdir.join("input.tar")
+    inp = tmpdir.join("input.tar.gz")
+
+    with tarfile.open(str(plain), mode="w") as tar:
+        for i in range(10):
+            data = b"1234" * (1024 * i)
+            tar.addfile(
+                tarinfo("theobject" + str(i), tarfile.REGTYPE, data=data),
+                io.BytesIO(data),
+            )
+    data = plain.read_binary()
+    if compression.startswith("gzip"):
+        # Multi-member gzip file is a concatenation of gzip streams.
+        step = len(data) // (2 if compression == "gzip2" else 1) + 1
+        parts = [data[i : i + step] for i in range(0, len(data), step)]
+        inp.write(b"".join(gzip.compress(part) for part in parts))
+    else:
+        # Decompressed by the xz and bzip2 commands.
+        inp.write(lzma.compress(data) if compression == "xz" else bz2.compress(data))
+
+    cargo_run(["-S", "80K", "--parallel-decompress", str(inp), str(output)])
+    assert sorted(os.listdir(str(outdir))) == [
+        "output.tar.00000",
+        "output.tar.00001",
+        "output.tar.00002",
+    ]
+    with tarfile.open(str(outdir.join("output.tar.00002")), "r") as tar:
+        assert tar.getnames() == ["theobject8", "theobject9"]
+
+
+@pytest.mark.parametrize(
+    "color,no_color,colored",
+    [
+        ([], False, False),
+        (["--color", "never"], False, False),
+        (["--color", "always"], False, True),
+        # Explicit flag overrides the env.
+        (["--color", "always"], True, True),
+    ],
+)
+def test_color(cargo_run, tmpdir, monkeypatch, color, no_color, colored):
+    if no_color:
+        monkeypatch.setenv("NO_COLOR", "1")
+    output = tmpdir.join("output.tar.")
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "100K"] + color + [str(tmpdir.join("missing")), str(output)])
+    assert (b"\x1b[" in exc.value.stderr) == colored, repr(exc.value.stderr)
+
+
+# TODO test for some bugs...
+
+
+# With --si, 10K is 10000 bytes, too few for two files.
+@pytest.mark.parametrize("flags,count", [([], 2), (["--si"], 3)])
+def test_library_example(tmpdir, flags, count):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(6):
+            data = b"1234" * 1024
+            name = "dir/theobject%d%s" % (i, ".tmp" if i % 2 else "")
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+    ret = subprocess.run(
+        ["cargo", "run", "--quiet", "--example", "split_filtered", "--"]
+        + [str(inp), str(output), "10K", ".tmp"]
+        + flags,
+        check=True,
+        capture_output=True,
+    )
+    lines = ret.stdout.decode().splitlines()
+    volumes = sorted(str(outdir.join(name)) for name in os.listdir(str(outdir)))
+    assert lines[:-1] == volumes
+    assert len(volumes) == count
+    assert lines[-1] == "3 entries, %d bytes" % sum(
+        os.path.getsize(volume) for volume in volumes
+    )
+    names = []
+    for volume in volumes:
+        with tarfile.open(volume) as tar:
+            names.extend(tar.getnames())
+    assert names == ["dir/theobject0", "dir/theobject2", "dir/theobject4"]
+
+
+def test_split_links(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w", format=tarfile.GNU_FORMAT) as tar:
+        for name in ["a", "b"]:
+            data = name.encode() * 7000
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+        tar.addfile(tarinfo("la", tarfile.LNKTYPE, linkname="a"))
+        tar.addfile(tarinfo("lla", tarfile.LNKTYPE, linkname="la"))
+        tar.addfile(tarinfo("lb", tarfile.LNKTYPE, linkname="b"))
+
+    def split(*options):
+        outdir = tmpdir.join("out" + "".join(options))
+        outdir.mkdir()
+        res = cargo_run(["-S", "10K", *options, str(inp), str(outdir.join("out."))])
+        volumes = []
+        for file in sorted(os.listdir(str(outdir))):
+            with tarfile.open(str(outdir.join(file)), "r") as tar:
+                volumes.append(
+                    [
+                        (m.name, m.linkname, m.isreg() and tar.extractfile(m).read(1))
+                        for m in tar.getmembers()
+                    ]
+                )
+        return res, volumes
+
+    res, volumes = split("--warn-broken-links")
+    assert volumes == [
+        [("a", "", b"a")],
+        [("b", "", b"b"), ("la", "a", False), ("lla", "la", False), ("lb", "b", False)],
+    ]
+    assert b'"la" in 00001 points to "a", which is not in' in res.stderr
+    assert res.stderr.count(b"which is not in") == 1
+
+    # The copy does not fit into the volume of "b", and so "lb" is copied too.
+    res, volumes = split("--dereference-split-links")
+    assert volumes == [
+        [("a", "", b"a")],
+        [("b", "", b"b")],
+        [("la", "", b"a"), ("lla", "la", False)],
+        [("lb", "", b"b")],
+    ]
+    with tarfile.open(str(tmpdir.join("out--dereference-split-links/out.00002"))) as tar:
+        assert tar.extractfile("la").read() == b"a" * 7000
+
+    output = str(tmpdir.join("output.tar."))
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--dereference-split-links", "-", output])
+    assert b"--dereference-split-links cannot be used with stdin" in exc.value.stderr
+
+    gzipped = tmpdir.join("input.tar.gz")
+    with tarfile.open(str(gzipped), mode="w:gz") as tar, tarfile.open(str(inp)) as src:
+        for m in src.getmembers():
+            tar.addfile(m, src.extractfile(m) if m.isreg() else None)
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["-S", "10K", "--dereference-split-links", str(gzipped), output])
+    assert b"needs an uncompressed input" in exc.value.stderr
+
+
+def test_summary(cargo_run, tmpdir, monkeypatch):
+    monkeypatch.setenv("RUST_LOG", "info")
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for name, size in [("small1", 7000), ("large", 20000), ("small2", 7000)]:
+            data = b"x" * size
+            tar.addfile(tarinfo(name, tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    args = ["-S", "10K", "--gzip", str(inp), str(output)]
+    res = cargo_run(["--quiet", "--summary", "--summary-json"] + args)
+    files = sorted(os.listdir(str(outdir)))
+    assert len(files) == 3
+    summary = json.loads(res.stdout)
+    assert summary == {
+        "volumes": 3,
+        "entries": 3,
+        "oversized": 1,
+        "input_bytes": os.path.getsize(str(inp)),
+        "bytes": 3 * 1024 + 3 * 512 + 7168 + 20480 + 7168,
+        "stored_bytes": sum(os.path.getsize(str(outdir.join(f))) for f in files),
+    }
+    # Info logging is off.
+    assert res.stderr == (
+        b"3 volumes, 3 entries (1 oversized), 40.0 KiB read, 38.5 KiB written, "
+        + b"%d B on disk\n" % summary["stored_bytes"]
+    )
+
+    res = cargo_run(["-S", "10K", str(inp), str(tmpdir.join("other."))])
+    assert b"INFO" in res.stderr
+    assert res.stdout == b""
+
+
+def test_dry_run(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i, size in enumerate([3000, 7000, 100, 20000, 5000]):
+            data = b"x" * size
+            tar.addfile(tarinfo("f%d" % i, tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    args = ["-S", "10K", str(inp), str(output)]
+    # No compressor is started either.
+    res = cargo_run(["--dry-run", "-v", "--compress", "false"] + args)
+    assert os.listdir(str(outdir)) == []
+    rows = [line.split(b"\t") for line in res.stdout.splitlines()]
+    assert [(name, int(count)) for name, count, _ in rows] == [
+        (b"00000", 1),
+        (b"00001", 2),
+        (b"00002", 1),
+        (b"00003", 1),
+    ]
+    assert res.stderr.count(b"\n") == 8
+    assert b" f3\n" in res.stderr
+    assert res.stderr.count(b" doesn't fit into --max-size 10240\n") == 3
+
+    # The listed sizes are the ones of the volumes written without --dry-run.
+    cargo_run(args)
+    files = sorted(os.listdir(str(outdir)))
+    assert [int(size) for _, _, size in rows] == [
+        os.path.getsize(str(outdir.join(file))) for file in files
+    ]
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        cargo_run(["--dry-run", "--list-volumes"] + args)
+    assert b"cannot be used with" in exc.value.stderr
+
+
+def test_temp_dir(cargo_run, tmpdir):
+    outdir = tmpdir.mkdir("out")
+    output = outdir.join("output.tar.")
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(3):
+            data = b"x" * 7000
+            tar.addfile(tarinfo("f%d" % i, tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    scratch_dirs = [str(tmpdir.mkdir("scratch"))]
+    # Another filesystem, where temp files cannot be renamed from.
+    shm = "/dev/shm"
+    if os.path.isdir(shm) and os.stat(shm).st_dev != os.stat(str(tmpdir)).st_dev:
+        scratch_dirs.append(tempfile.mkdtemp(dir=shm))
+    for scratch in scratch_dirs:
+        cargo_run(["-S", "10K", "--temp-dir", scratch, str(inp), str(output)])
+        assert os.listdir(scratch) == []
+        os.rmdir(scratch)
+        files = sorted(os.listdir(str(outdir)))
+        assert files == ["output.tar.00000", "output.tar.00001", "output.tar.00002"]
+        for i, file in enumerate(files):
+            with tarfile.open(str(outdir.join(file))) as tar:
+                assert tar.getnames() == ["f%d" % i]
+            outdir.join(file).remove()
+
+    with pytest.raises(subprocess.CalledProcessError) as exc:
+        missing = str(tmpdir.join("missing"))
+        cargo_run(["-S", "10K", "--temp-dir", missing, str(inp), str(output)])
+    assert b"--temp-dir" in exc.value.stderr
+    assert b"is not writable" in exc.value.stderr
+    assert os.listdir(str(outdir)) == []
+
+
+def test_output_dir(cargo_run, tmpdir):
+    inp = tmpdir.join("input.tar")
+    with tarfile.open(str(inp), mode="w") as tar:
+        for i in range(2):
+            data = b"x" * 7000
+            tar.addfile(tarinfo("f%d" % i, tarfile.REGTYPE, data=data), io.BytesIO(data))
+
+    outdir = tmpdir.join("new", "out")
+    args = ["-S", "10K", "--emit-extract-script", str(inp), "backup-"]
+    cargo_run(["--output-dir", str(outdir)] + args)
+    assert sorted(os.listdir(str(outdir))) == [
+        "backup-00000",
+        "backup-00001",
+        "backup-extract.sh",
+    ]
+
+    # Without --output-dir, the prefix is just glued to the names.
+    res = cargo_run(["-S", "10K", str(inp), str(outdir)])
+    assert b"is a dir, but volumes are named like" in res.stderr
+    assert sorted(os.listdir(str(tmpdir.join("new")))) == [
+        "out",
+        "out00000",
+        "out00001",
+    ]